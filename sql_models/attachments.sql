@@ -0,0 +1,40 @@
+-- =============================================================================
+-- Migration: encrypted attachments
+--
+-- Run this after sql_models/message_expiry.sql.
+--
+-- Clients upload already-encrypted blobs out of band and reference them from
+-- messages by id; the blob itself lives in the configured storage backend,
+-- keyed by attachments.id. Only SHA-256 hashes of the upload and download
+-- tokens are stored.
+--
+-- message_attachments links every recipient copy of a message to the
+-- attachments it references. An attachment becomes garbage once every linked
+-- copy has been delivered for ATTACHMENT_RETENTION_HOURS or has expired
+-- (expired messages are deleted, which drops their links), and no federated
+-- forward still holds it (federated_until). Uploads never referenced by a
+-- message are collected after the same window.
+-- =============================================================================
+
+CREATE TABLE attachments (
+  id                  UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  owner_device_id     UUID        REFERENCES devices(id) ON DELETE SET NULL,
+  size_bytes          BIGINT      NOT NULL CHECK (size_bytes > 0),
+  upload_token_hash   TEXT        NOT NULL,
+  download_token_hash TEXT        NOT NULL,
+  -- base64 SHA-256 of the stored ciphertext, set on upload
+  content_digest      TEXT,
+  uploaded_at         TIMESTAMPTZ,
+  referenced_at       TIMESTAMPTZ,
+  -- peers may fetch the blob via GET /s2s/attachments/:id until then
+  federated_until     TIMESTAMPTZ,
+  created_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE message_attachments (
+  message_id    UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  attachment_id UUID NOT NULL REFERENCES attachments(id) ON DELETE CASCADE,
+  PRIMARY KEY (message_id, attachment_id)
+);
+
+CREATE INDEX idx_message_attachments_attachment ON message_attachments(attachment_id);
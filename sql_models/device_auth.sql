@@ -0,0 +1,19 @@
+-- =============================================================================
+-- Migration: signed device requests (X-Auth-Version: 2)
+--
+-- Run this after sql_models/federation.sql.
+--
+-- Version 2 device authentication signs method, path, timestamp, a nonce and
+-- the SHA-256 digest of the body. Each accepted (nonce, device_id) pair is
+-- stored here so the same signed request cannot be replayed within the
+-- 30-second timestamp window. Rows older than 5 minutes are purged by the
+-- housekeeping step of the outbox worker.
+-- =============================================================================
+CREATE TABLE used_device_nonces (
+  nonce      TEXT        NOT NULL,
+  device_id  UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  used_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (nonce, device_id)
+);
+
+CREATE INDEX idx_used_device_nonces_used_at ON used_device_nonces (used_at);
@@ -0,0 +1,29 @@
+-- =============================================================================
+-- Migration: device linking by approval
+--
+-- Run this after sql_models/device_revocation.sql.
+--
+-- A new device that wants to join an existing account files a link request
+-- carrying its identity key. Any enrolled device of that user can approve it
+-- with a signature; the server then stores a one-shot enrollment token bound
+-- to that identity key, which the new device fetches and spends on
+-- POST /users/:id/devices. Spent tokens land in used_tokens like any other
+-- enrollment token.
+--
+-- Requests that are not approved before expires_at are ignored and deleted
+-- the next time the same user files a request.
+-- =============================================================================
+CREATE TABLE device_link_requests (
+  id                UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id           UUID        NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  identity_pubkey   TEXT        NOT NULL,
+  device_label      TEXT,
+  status            TEXT        NOT NULL DEFAULT 'pending'
+                      CHECK (status IN ('pending', 'approved')),
+  approved_by       UUID        REFERENCES devices(id) ON DELETE SET NULL,
+  enrollment_token  TEXT,
+  created_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expires_at        TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '10 minutes'
+);
+
+CREATE INDEX idx_device_link_requests_user ON device_link_requests (user_id, expires_at);
@@ -0,0 +1,32 @@
+-- =============================================================================
+-- Migration: device revocation
+--
+-- Run this after sql_models/pq_prekeys.sql.
+--
+-- Revoking a device deletes its row. Prekeys, nonces, pending_sessions,
+-- sessions and messages addressed to it go with it through the existing
+-- ON DELETE CASCADE constraints. Messages the device already delivered to
+-- other devices are kept: their from_device_id is set to NULL instead.
+--
+-- device_federation_peers records every peer node this node forwarded
+-- traffic to on behalf of a local device. Those peers hold a shadow copy of
+-- the device (see upsert_shadow_device) and are sent a device_removed
+-- notification through the outbox when it is revoked.
+--
+-- federation_outbox.kind says which S2S endpoint an entry is for; existing
+-- rows are message forwards.
+-- =============================================================================
+ALTER TABLE messages
+  DROP CONSTRAINT messages_from_device_id_fkey,
+  ADD CONSTRAINT messages_from_device_id_fkey
+    FOREIGN KEY (from_device_id) REFERENCES devices(id) ON DELETE SET NULL;
+
+CREATE TABLE device_federation_peers (
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  node_id     TEXT        NOT NULL,
+  first_seen  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, node_id)
+);
+
+ALTER TABLE federation_outbox
+  ADD COLUMN kind TEXT NOT NULL DEFAULT 'messages';
@@ -0,0 +1,18 @@
+-- =============================================================================
+-- Migration: federated group chats
+--
+-- Run this after sql_models/sender_keys.sql.
+--
+-- A group lives on the node of its owner. That node is authoritative for
+-- name, membership and roles, and pushes a full, versioned snapshot to every
+-- node hosting a member after each change. Other nodes keep a mirror of the
+-- group under the same chats.id, with home_node_id pointing at the owner's
+-- node; chat_members of a mirror lists local users and shadow users alike.
+--
+-- membership_version orders the snapshots: the home node increments it on
+-- every change, and mirrors ignore snapshots not newer than what they have.
+-- =============================================================================
+
+ALTER TABLE chats
+  ADD COLUMN home_node_id       UUID   REFERENCES federation_nodes(id) ON DELETE CASCADE,
+  ADD COLUMN membership_version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,42 @@
+-- =============================================================================
+-- Migration: origins of forwarded messages, for S2S receipts
+--
+-- Run this after sql_models/prekey_batch_replay.sql.
+--
+-- POST /s2s/receipts is only accepted for a message this node forwarded to the
+-- reader. That used to be looked up in federation_outbox, whose rows are
+-- purged after OUTBOX_RETENTION_HOURS or by an operator, after which valid
+-- receipts were refused. Every direct or group message queued for a peer now
+-- leaves a row here, kept for FORWARDED_MESSAGE_RETENTION_HOURS.
+--
+-- to_user is set for a direct message (the recipient's username on the
+-- target node), chat_id for a group message (any member homed on the target
+-- node may acknowledge it).
+-- =============================================================================
+CREATE TABLE forwarded_messages (
+  target_node_id  TEXT        NOT NULL,
+  logical_msg_id  TEXT        NOT NULL,
+  sender_address  TEXT        NOT NULL,
+  to_user         TEXT,
+  chat_id         UUID        REFERENCES chats(id) ON DELETE CASCADE,
+  forwarded_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (target_node_id, logical_msg_id, sender_address),
+  CHECK ((to_user IS NULL) <> (chat_id IS NULL))
+);
+
+CREATE INDEX idx_forwarded_messages_forwarded ON forwarded_messages (forwarded_at);
+
+-- Backfill from what is still in the outbox.
+INSERT INTO forwarded_messages (target_node_id, logical_msg_id, sender_address, to_user, forwarded_at)
+SELECT target_node_id, logical_msg_id, payload->>'from_federated_address', payload->>'to_user', created_at
+FROM federation_outbox
+WHERE kind = 'messages'
+ON CONFLICT DO NOTHING;
+
+INSERT INTO forwarded_messages (target_node_id, logical_msg_id, sender_address, chat_id, forwarded_at)
+SELECT o.target_node_id, o.logical_msg_id, o.payload->>'from_federated_address',
+       (o.payload->>'chat_id')::uuid, o.created_at
+FROM federation_outbox o
+JOIN chats c ON c.id = (o.payload->>'chat_id')::uuid
+WHERE o.kind = 'group_message'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,25 @@
+-- =============================================================================
+-- Migration: group chat roles
+--
+-- Run this after sql_models/attachments.sql.
+--
+-- chat_members.role becomes one of owner / admin / member. Each group has
+-- exactly one owner row, mirrored in chats.owner_id; ownership moves only
+-- through an explicit transfer. Existing rows with other roles become
+-- members, and the member named by chats.owner_id becomes the owner.
+-- =============================================================================
+
+UPDATE chat_members SET role = 'member'
+WHERE role IS NULL OR role NOT IN ('owner', 'admin', 'member');
+
+UPDATE chat_members m SET role = 'owner'
+FROM chats c
+WHERE c.id = m.chat_id AND c.owner_id = m.user_id;
+
+ALTER TABLE chat_members
+  ALTER COLUMN role SET NOT NULL,
+  ADD CONSTRAINT chat_members_role CHECK (role IN ('owner', 'admin', 'member'));
+
+CREATE UNIQUE INDEX uniq_chat_owner ON chat_members(chat_id) WHERE role = 'owner';
+
+CREATE INDEX idx_chat_members_user ON chat_members(user_id);
@@ -0,0 +1,23 @@
+-- =============================================================================
+-- Migration: retire ML-KEM prekeys instead of deleting them
+--
+-- Run this after sql_models/node_key_rotation.sql.
+--
+-- A KEM prekey that is no longer handed out (a claimed one-time key, or a
+-- last-resort key replaced by a newer upload) gets retired_at instead of
+-- being deleted. Session inits naming it by kem_prekey_id are accepted until
+-- SIGNED_PREKEY_GRACE_HOURS after retirement, like rotated signed prekeys;
+-- the maintenance worker then deletes it. Keeping consumed one-time rows also
+-- stops a re-upload of the same key from putting it back in the pool.
+--
+-- Only one live (unretired) last-resort key per device.
+-- =============================================================================
+ALTER TABLE kem_prekeys
+  ADD COLUMN retired_at TIMESTAMPTZ;
+
+DROP INDEX idx_kem_prekeys_last_resort;
+CREATE UNIQUE INDEX idx_kem_prekeys_last_resort
+  ON kem_prekeys (device_id) WHERE is_last_resort AND retired_at IS NULL;
+
+CREATE INDEX idx_kem_prekeys_retired
+  ON kem_prekeys (retired_at) WHERE retired_at IS NOT NULL;
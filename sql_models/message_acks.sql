@@ -0,0 +1,13 @@
+-- =============================================================================
+-- Migration: explicit message acknowledgement
+--
+-- Run this after sql_models/realtime_cursors.sql.
+--
+-- GET /messages/pending no longer marks what it returns as delivered. Devices
+-- acknowledge messages with POST /messages/ack, which sets delivered_at, and
+-- the maintenance worker drops acknowledged rows once they are older than
+-- DELIVERED_MESSAGE_RETENTION_HOURS. The partial index keeps that purge cheap.
+-- =============================================================================
+
+CREATE INDEX idx_messages_delivered ON messages(delivered_at)
+  WHERE delivered_at IS NOT NULL;
@@ -0,0 +1,33 @@
+-- =============================================================================
+-- Migration: disappearing messages and retention
+--
+-- Run this after sql_models/message_acks.sql.
+--
+-- A chat can carry a message TTL. Every message inserted into it expires that
+-- many seconds after it was stored, or earlier if the sender asked for an
+-- earlier expires_at. The reaper worker hard-deletes expired messages whether
+-- or not they were delivered, and drops queued federated forwards whose
+-- message has expired before reaching the peer.
+--
+-- The same worker also purges acknowledged messages, finished outbox entries
+-- and pending sessions nobody picked up once their retention window is over.
+-- =============================================================================
+
+ALTER TABLE chats
+  ADD COLUMN message_ttl_seconds INT
+    CHECK (message_ttl_seconds IS NULL OR message_ttl_seconds > 0);
+
+ALTER TABLE messages ADD COLUMN expires_at TIMESTAMPTZ;
+
+-- Expiry a message stored now in chat `cid` gets from the chat's TTL, or NULL.
+CREATE FUNCTION chat_expiry(cid UUID) RETURNS TIMESTAMPTZ AS $$
+  SELECT NOW() + make_interval(secs => message_ttl_seconds) FROM chats WHERE id = cid;
+$$ LANGUAGE sql STABLE;
+
+CREATE INDEX idx_messages_expires ON messages(expires_at)
+  WHERE expires_at IS NOT NULL;
+
+CREATE INDEX idx_federation_outbox_finished ON federation_outbox(created_at)
+  WHERE status <> 'pending';
+
+CREATE INDEX idx_pending_sessions_created ON pending_sessions(created_at);
@@ -0,0 +1,24 @@
+-- =============================================================================
+-- Migration: peer node key rotation
+--
+-- Run this after sql_models/node_protocol.sql.
+--
+-- A node rotating its signing key announces the new key at the registry and,
+-- during an overlap window, serves both keys in GET /s2s/info together with a
+-- signature of the new key by the old one. A peer whose cached key no longer
+-- verifies a request looks the key up again and switches only when that
+-- endorsement checks out against the key it already had:
+--
+--   previous_public_key_b64  — key replaced by the last rotation; still
+--                              accepted until previous_key_expires_at, since
+--                              a rotating node may sign with either key
+--                              until it restarts.
+--   previous_key_expires_at  — end of the peer's announced overlap window.
+--   key_checked_at           — last key re-fetch, so a stream of badly
+--                              signed requests cannot hammer the registry.
+-- =============================================================================
+
+ALTER TABLE federation_nodes
+  ADD COLUMN previous_public_key_b64 TEXT,
+  ADD COLUMN previous_key_expires_at TIMESTAMPTZ,
+  ADD COLUMN key_checked_at          TIMESTAMPTZ;
@@ -0,0 +1,20 @@
+-- =============================================================================
+-- Migration: S2S protocol version negotiation
+--
+-- Run this after sql_models/federated_groups.sql.
+--
+-- Since protocol 0.0.3 nodes sign a Content-Digest of the request body along
+-- with method, path, timestamp and nonce. A peer still on 0.0.2 neither sends
+-- nor checks it, so each node remembers the protocol version of its peers:
+--
+--   protocol_version     — highest version seen for the peer, from its
+--                          GET /s2s/info or from a digest-signed request.
+--                          NULL until known. Never lowered, so a peer cannot
+--                          be talked back down to unsigned bodies.
+--   protocol_checked_at  — last GET /s2s/info probe, to re-check peers still
+--                          on an older version now and then.
+-- =============================================================================
+
+ALTER TABLE federation_nodes
+  ADD COLUMN protocol_version    TEXT,
+  ADD COLUMN protocol_checked_at TIMESTAMPTZ;
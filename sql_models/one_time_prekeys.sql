@@ -0,0 +1,46 @@
+-- =============================================================================
+-- Migration: one-time prekeys as individually consumable rows
+--
+-- Run this after sql_models/device_auth.sql.
+--
+-- devices.one_time_prekeys used to hold every OTPK of a device in one JSONB
+-- array that was returned whole to every bundle request and never shrank, so
+-- two initiators could pick the same key. Each OTPK is now its own row; a
+-- bundle fetch deletes exactly one row per device inside the same transaction
+-- (SELECT ... FOR UPDATE SKIP LOCKED), so a key is handed out at most once.
+--
+-- The JSONB column is kept (and emptied) so existing readers of the devices
+-- row keep working.
+-- =============================================================================
+CREATE TABLE one_time_prekeys (
+  id          BIGSERIAL   PRIMARY KEY,            -- insertion order = hand-out order
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey      TEXT        NOT NULL,               -- X25519 public key, base64
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (device_id, pubkey)
+);
+
+CREATE INDEX idx_one_time_prekeys_device ON one_time_prekeys (device_id, id);
+
+-- Backfill from the JSONB arrays, preserving array order.
+INSERT INTO one_time_prekeys (device_id, pubkey)
+SELECT d.id, k.pubkey
+FROM devices d,
+     jsonb_array_elements_text(d.one_time_prekeys) WITH ORDINALITY AS k(pubkey, ord)
+ORDER BY d.id, k.ord
+ON CONFLICT DO NOTHING;
+
+UPDATE devices SET one_time_prekeys = '[]'::jsonb
+WHERE one_time_prekeys <> '[]'::jsonb;
+
+CREATE OR REPLACE VIEW user_devices_view AS
+SELECT
+  u.username,
+  d.id AS device_id,
+  d.identity_pubkey,
+  d.signed_prekey_pub,
+  d.signed_prekey_sig,
+  (SELECT o.pubkey FROM one_time_prekeys o
+   WHERE o.device_id = d.id ORDER BY o.id LIMIT 1) AS one_time_prekey_pub
+FROM users u
+JOIN devices d ON u.id = d.user_id;
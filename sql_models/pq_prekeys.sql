@@ -0,0 +1,31 @@
+-- =============================================================================
+-- Migration: post-quantum (ML-KEM) prekeys
+--
+-- Run this after sql_models/signed_prekey_rotation.sql.
+--
+-- Each device may publish signed ML-KEM one-time prekeys and a single signed
+-- ML-KEM last-resort prekey. A bundle fetch pops one one-time KEM key and
+-- falls back to the last-resort key once they run out; the last-resort key is
+-- never consumed, only replaced by a newer upload. Signatures are made with
+-- the device identity key over the raw public key.
+--
+-- pending_sessions.kem_prekey_id / kem_ciphertext record which KEM prekey a
+-- hybrid (PQXDH) session init encapsulated to. Both NULL for classic inits.
+-- kem_prekey_id is not a foreign key: one-time keys are deleted on claim.
+-- =============================================================================
+CREATE TABLE kem_prekeys (
+  id              BIGSERIAL   PRIMARY KEY,
+  device_id       UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey          TEXT        NOT NULL,
+  signature       TEXT        NOT NULL,
+  is_last_resort  BOOLEAN     NOT NULL DEFAULT FALSE,
+  created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (device_id, pubkey)
+);
+
+CREATE INDEX idx_kem_prekeys_device ON kem_prekeys (device_id, id);
+CREATE UNIQUE INDEX idx_kem_prekeys_last_resort ON kem_prekeys (device_id) WHERE is_last_resort;
+
+ALTER TABLE pending_sessions
+  ADD COLUMN kem_prekey_id  BIGINT,
+  ADD COLUMN kem_ciphertext TEXT;
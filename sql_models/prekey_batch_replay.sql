@@ -0,0 +1,23 @@
+-- =============================================================================
+-- Migration: replay protection for one-time prekey batches
+--
+-- Run this after sql_models/kem_prekey_retirement.sql.
+--
+-- A POST /devices/me/prekeys batch is signed over
+-- "hushnet-otpk-batch\n{device_id}\n{timestamp}\n{keys}". The timestamp must be
+-- recent and strictly greater than the device's previous batch, which is kept
+-- in devices.last_prekey_batch_ts, so a captured batch cannot be posted again.
+--
+-- consumed_one_time_prekeys keeps a SHA-256 of every OTPK handed out in a
+-- bundle. Uploads skip keys found here, so a consumed key never re-enters the
+-- pool even if a client (or an old batch) offers it again.
+-- =============================================================================
+ALTER TABLE devices
+  ADD COLUMN last_prekey_batch_ts BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE consumed_one_time_prekeys (
+  device_id    UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  key_hash     BYTEA       NOT NULL,              -- sha256 of the base64 pubkey
+  consumed_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, key_hash)
+);
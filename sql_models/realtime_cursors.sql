@@ -0,0 +1,145 @@
+-- =============================================================================
+-- Migration: resumable realtime stream
+--
+-- Run this after sql_models/realtime_device_routing.sql.
+--
+-- Every realtime event is now addressed to one device and stamped with that
+-- device's next sequence number. The event is written to device_events in
+-- the same transaction as the change that caused it, then sent with
+-- pg_notify, so a client reconnecting with ?since=<last seq> can be replayed
+-- everything it missed. Events for a user are fanned out to each of the
+-- user's local devices. Shadow devices of remote users get no events.
+--
+-- Counters live in their own table: bumping a column on devices would fire
+-- devices_notify_trigger and recurse.
+--
+-- device_events rows older than the retention window are purged by the
+-- maintenance worker; a cursor older than that gets a resync event instead.
+-- =============================================================================
+CREATE TABLE device_event_counters (
+  device_id  UUID    PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+  last_seq   BIGINT  NOT NULL
+);
+
+CREATE TABLE device_events (
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  seq         BIGINT      NOT NULL,
+  channel     TEXT        NOT NULL,
+  payload     JSONB       NOT NULL,
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, seq)
+);
+
+CREATE INDEX idx_device_events_created ON device_events (created_at);
+
+-- Record and send one event for a local device. Returns its seq, or NULL if
+-- the device does not exist or belongs to a shadow user.
+CREATE OR REPLACE FUNCTION emit_device_event(p_device_id UUID, p_channel TEXT, p_payload JSONB)
+RETURNS BIGINT AS $$
+DECLARE
+  owner UUID;
+  next_seq BIGINT;
+  full_payload JSONB;
+BEGIN
+  SELECT d.user_id INTO owner
+  FROM devices d JOIN users u ON u.id = d.user_id
+  WHERE d.id = p_device_id AND u.home_node_id IS NULL;
+  IF owner IS NULL THEN
+    RETURN NULL;
+  END IF;
+
+  INSERT INTO device_event_counters (device_id, last_seq) VALUES (p_device_id, 1)
+  ON CONFLICT (device_id) DO UPDATE SET last_seq = device_event_counters.last_seq + 1
+  RETURNING last_seq INTO next_seq;
+
+  full_payload := p_payload || jsonb_build_object(
+    'user_id', owner,
+    'to_device_id', p_device_id,
+    'seq', next_seq
+  );
+  INSERT INTO device_events (device_id, seq, channel, payload)
+  VALUES (p_device_id, next_seq, p_channel, full_payload);
+  PERFORM pg_notify(p_channel, full_payload::text);
+  RETURN next_seq;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Send one event to every device of a user. Returns the number of devices.
+CREATE OR REPLACE FUNCTION emit_user_event(p_user_id UUID, p_channel TEXT, p_payload JSONB)
+RETURNS INT AS $$
+DECLARE
+  d RECORD;
+  sent INT := 0;
+BEGIN
+  FOR d IN SELECT id FROM devices WHERE user_id = p_user_id ORDER BY created_at LOOP
+    IF emit_device_event(d.id, p_channel, p_payload) IS NOT NULL THEN
+      sent := sent + 1;
+    END IF;
+  END LOOP;
+  RETURN sent;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_message() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_device_event(
+    NEW.to_device_id,
+    'messages_channel',
+    jsonb_build_object('type', 'message', 'chat_id', NEW.chat_id)
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_session() RETURNS trigger AS $$
+DECLARE
+  sender_user UUID;
+  receiver_user UUID;
+  payload JSONB;
+BEGIN
+  SELECT user_id INTO sender_user FROM devices WHERE id = NEW.sender_device_id;
+  SELECT user_id INTO receiver_user FROM devices WHERE id = NEW.receiver_device_id;
+  payload := jsonb_build_object(
+    'type', 'session',
+    'sender_device_id', NEW.sender_device_id,
+    'receiver_device_id', NEW.receiver_device_id
+  );
+
+  IF receiver_user IS NOT NULL THEN
+    PERFORM emit_user_event(receiver_user, 'sessions_channel', payload);
+  END IF;
+  IF sender_user IS NOT NULL AND sender_user IS DISTINCT FROM receiver_user THEN
+    PERFORM emit_user_event(sender_user, 'sessions_channel', payload);
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_device_update() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_user_event(
+    NEW.user_id,
+    'devices_channel',
+    jsonb_build_object('type', 'device', 'device_id', NEW.id)
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_pending_session() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_device_event(
+    NEW.recipient_device_id,
+    'pending_sessions_channel',
+    jsonb_build_object(
+      'type', 'pending_session',
+      'recipient_device_id', NEW.recipient_device_id,
+      'sender_device_id', NEW.sender_device_id,
+      'pending_session_id', NEW.id,
+      'created_at', NEW.created_at
+    )
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,48 @@
+-- =============================================================================
+-- Migration: device-scoped realtime events
+--
+-- Run this after sql_models/device_linking.sql.
+--
+-- WebSocket connections are now bound to one device. Events that concern a
+-- single device carry its id in to_device_id so that only that device's
+-- socket receives them; events without it go to every device of user_id.
+-- =============================================================================
+CREATE OR REPLACE FUNCTION notify_new_message() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify(
+    'messages_channel',
+    json_build_object(
+      'type', 'message',
+      'chat_id', NEW.chat_id,
+      'user_id', NEW.to_user_id,
+      'to_device_id', NEW.to_device_id
+    )::text
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_pending_session() RETURNS trigger AS $$
+DECLARE
+  recipient_user UUID;
+BEGIN
+  SELECT user_id INTO recipient_user FROM devices WHERE id = NEW.recipient_device_id;
+
+  IF recipient_user IS NOT NULL THEN
+    PERFORM pg_notify(
+      'pending_sessions_channel',
+      json_build_object(
+        'type', 'pending_session',
+        'user_id', recipient_user,
+        'to_device_id', NEW.recipient_device_id,
+        'recipient_device_id', NEW.recipient_device_id,
+        'sender_device_id', NEW.sender_device_id,
+        'pending_session_id', NEW.id,
+        'created_at', NEW.created_at
+      )::text
+    );
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -451,3 +451,718 @@ ALTER TABLE users
 -- -----------------------------------------------------------------------------
 ALTER TABLE messages
   ADD CONSTRAINT uniq_message_per_device UNIQUE (logical_msg_id, to_device_id);
+
+-- =============================================================================
+-- Migration: signed device requests (X-Auth-Version: 2)
+--
+-- Run this after sql_models/federation.sql.
+--
+-- Version 2 device authentication signs method, path, timestamp, a nonce and
+-- the SHA-256 digest of the body. Each accepted (nonce, device_id) pair is
+-- stored here so the same signed request cannot be replayed within the
+-- 30-second timestamp window. Rows older than 5 minutes are purged by the
+-- housekeeping step of the outbox worker.
+-- =============================================================================
+CREATE TABLE used_device_nonces (
+  nonce      TEXT        NOT NULL,
+  device_id  UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  used_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (nonce, device_id)
+);
+
+CREATE INDEX idx_used_device_nonces_used_at ON used_device_nonces (used_at);
+
+-- =============================================================================
+-- Migration: one-time prekeys as individually consumable rows
+--
+-- Run this after sql_models/device_auth.sql.
+--
+-- devices.one_time_prekeys used to hold every OTPK of a device in one JSONB
+-- array that was returned whole to every bundle request and never shrank, so
+-- two initiators could pick the same key. Each OTPK is now its own row; a
+-- bundle fetch deletes exactly one row per device inside the same transaction
+-- (SELECT ... FOR UPDATE SKIP LOCKED), so a key is handed out at most once.
+--
+-- The JSONB column is kept (and emptied) so existing readers of the devices
+-- row keep working.
+-- =============================================================================
+CREATE TABLE one_time_prekeys (
+  id          BIGSERIAL   PRIMARY KEY,            -- insertion order = hand-out order
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey      TEXT        NOT NULL,               -- X25519 public key, base64
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (device_id, pubkey)
+);
+
+CREATE INDEX idx_one_time_prekeys_device ON one_time_prekeys (device_id, id);
+
+-- Backfill from the JSONB arrays, preserving array order.
+INSERT INTO one_time_prekeys (device_id, pubkey)
+SELECT d.id, k.pubkey
+FROM devices d,
+     jsonb_array_elements_text(d.one_time_prekeys) WITH ORDINALITY AS k(pubkey, ord)
+ORDER BY d.id, k.ord
+ON CONFLICT DO NOTHING;
+
+UPDATE devices SET one_time_prekeys = '[]'::jsonb
+WHERE one_time_prekeys <> '[]'::jsonb;
+
+CREATE OR REPLACE VIEW user_devices_view AS
+SELECT
+  u.username,
+  d.id AS device_id,
+  d.identity_pubkey,
+  d.signed_prekey_pub,
+  d.signed_prekey_sig,
+  (SELECT o.pubkey FROM one_time_prekeys o
+   WHERE o.device_id = d.id ORDER BY o.id LIMIT 1) AS one_time_prekey_pub
+FROM users u
+JOIN devices d ON u.id = d.user_id;
+
+-- =============================================================================
+-- Migration: signed prekey rotation
+--
+-- Run this after sql_models/one_time_prekeys.sql.
+--
+-- devices.signed_prekey_pub/signed_prekey_sig always hold the current SPK.
+-- When a device rotates, the previous SPK is copied here with an expiry; an
+-- initiator that fetched the old bundle shortly before the rotation can still
+-- open a session against it until expires_at. The prekey maintenance worker
+-- deletes expired rows.
+--
+-- pending_sessions.recipient_spk_pub records which of the recipient's SPKs
+-- the initiator used, so the recipient knows which private key to use and the
+-- server can reject sessions built on an SPK that is past its grace window.
+-- NULL for clients that do not send it.
+-- =============================================================================
+CREATE TABLE signed_prekey_history (
+  id          BIGSERIAL   PRIMARY KEY,
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey      TEXT        NOT NULL,
+  signature   TEXT        NOT NULL,
+  retired_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expires_at  TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_signed_prekey_history_device  ON signed_prekey_history (device_id, pubkey);
+CREATE INDEX idx_signed_prekey_history_expires ON signed_prekey_history (expires_at);
+
+ALTER TABLE pending_sessions
+  ADD COLUMN recipient_spk_pub TEXT;
+
+-- =============================================================================
+-- Migration: post-quantum (ML-KEM) prekeys
+--
+-- Run this after sql_models/signed_prekey_rotation.sql.
+--
+-- Each device may publish signed ML-KEM one-time prekeys and a single signed
+-- ML-KEM last-resort prekey. A bundle fetch pops one one-time KEM key and
+-- falls back to the last-resort key once they run out; the last-resort key is
+-- never consumed, only replaced by a newer upload. Signatures are made with
+-- the device identity key over the raw public key.
+--
+-- pending_sessions.kem_prekey_id / kem_ciphertext record which KEM prekey a
+-- hybrid (PQXDH) session init encapsulated to. Both NULL for classic inits.
+-- kem_prekey_id is not a foreign key: one-time keys are deleted on claim.
+-- =============================================================================
+CREATE TABLE kem_prekeys (
+  id              BIGSERIAL   PRIMARY KEY,
+  device_id       UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey          TEXT        NOT NULL,
+  signature       TEXT        NOT NULL,
+  is_last_resort  BOOLEAN     NOT NULL DEFAULT FALSE,
+  created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (device_id, pubkey)
+);
+
+CREATE INDEX idx_kem_prekeys_device ON kem_prekeys (device_id, id);
+CREATE UNIQUE INDEX idx_kem_prekeys_last_resort ON kem_prekeys (device_id) WHERE is_last_resort;
+
+ALTER TABLE pending_sessions
+  ADD COLUMN kem_prekey_id  BIGINT,
+  ADD COLUMN kem_ciphertext TEXT;
+
+-- =============================================================================
+-- Migration: device revocation
+--
+-- Run this after sql_models/pq_prekeys.sql.
+--
+-- Revoking a device deletes its row. Prekeys, nonces, pending_sessions,
+-- sessions and messages addressed to it go with it through the existing
+-- ON DELETE CASCADE constraints. Messages the device already delivered to
+-- other devices are kept: their from_device_id is set to NULL instead.
+--
+-- device_federation_peers records every peer node this node forwarded
+-- traffic to on behalf of a local device. Those peers hold a shadow copy of
+-- the device (see upsert_shadow_device) and are sent a device_removed
+-- notification through the outbox when it is revoked.
+--
+-- federation_outbox.kind says which S2S endpoint an entry is for; existing
+-- rows are message forwards.
+-- =============================================================================
+ALTER TABLE messages
+  DROP CONSTRAINT messages_from_device_id_fkey,
+  ADD CONSTRAINT messages_from_device_id_fkey
+    FOREIGN KEY (from_device_id) REFERENCES devices(id) ON DELETE SET NULL;
+
+CREATE TABLE device_federation_peers (
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  node_id     TEXT        NOT NULL,
+  first_seen  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, node_id)
+);
+
+ALTER TABLE federation_outbox
+  ADD COLUMN kind TEXT NOT NULL DEFAULT 'messages';
+
+-- =============================================================================
+-- Migration: device linking by approval
+--
+-- Run this after sql_models/device_revocation.sql.
+--
+-- A new device that wants to join an existing account files a link request
+-- carrying its identity key. Any enrolled device of that user can approve it
+-- with a signature; the server then stores a one-shot enrollment token bound
+-- to that identity key, which the new device fetches and spends on
+-- POST /users/:id/devices. Spent tokens land in used_tokens like any other
+-- enrollment token.
+--
+-- Requests that are not approved before expires_at are ignored and deleted
+-- the next time the same user files a request.
+-- =============================================================================
+CREATE TABLE device_link_requests (
+  id                UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id           UUID        NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  identity_pubkey   TEXT        NOT NULL,
+  device_label      TEXT,
+  status            TEXT        NOT NULL DEFAULT 'pending'
+                      CHECK (status IN ('pending', 'approved')),
+  approved_by       UUID        REFERENCES devices(id) ON DELETE SET NULL,
+  enrollment_token  TEXT,
+  created_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expires_at        TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '10 minutes'
+);
+
+CREATE INDEX idx_device_link_requests_user ON device_link_requests (user_id, expires_at);
+
+-- =============================================================================
+-- Migration: device-scoped realtime events
+--
+-- Run this after sql_models/device_linking.sql.
+--
+-- WebSocket connections are now bound to one device. Events that concern a
+-- single device carry its id in to_device_id so that only that device's
+-- socket receives them; events without it go to every device of user_id.
+-- =============================================================================
+CREATE OR REPLACE FUNCTION notify_new_message() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify(
+    'messages_channel',
+    json_build_object(
+      'type', 'message',
+      'chat_id', NEW.chat_id,
+      'user_id', NEW.to_user_id,
+      'to_device_id', NEW.to_device_id
+    )::text
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_pending_session() RETURNS trigger AS $$
+DECLARE
+  recipient_user UUID;
+BEGIN
+  SELECT user_id INTO recipient_user FROM devices WHERE id = NEW.recipient_device_id;
+
+  IF recipient_user IS NOT NULL THEN
+    PERFORM pg_notify(
+      'pending_sessions_channel',
+      json_build_object(
+        'type', 'pending_session',
+        'user_id', recipient_user,
+        'to_device_id', NEW.recipient_device_id,
+        'recipient_device_id', NEW.recipient_device_id,
+        'sender_device_id', NEW.sender_device_id,
+        'pending_session_id', NEW.id,
+        'created_at', NEW.created_at
+      )::text
+    );
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- =============================================================================
+-- Migration: resumable realtime stream
+--
+-- Run this after sql_models/realtime_device_routing.sql.
+--
+-- Every realtime event is now addressed to one device and stamped with that
+-- device's next sequence number. The event is written to device_events in
+-- the same transaction as the change that caused it, then sent with
+-- pg_notify, so a client reconnecting with ?since=<last seq> can be replayed
+-- everything it missed. Events for a user are fanned out to each of the
+-- user's local devices. Shadow devices of remote users get no events.
+--
+-- Counters live in their own table: bumping a column on devices would fire
+-- devices_notify_trigger and recurse.
+--
+-- device_events rows older than the retention window are purged by the
+-- maintenance worker; a cursor older than that gets a resync event instead.
+-- =============================================================================
+CREATE TABLE device_event_counters (
+  device_id  UUID    PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+  last_seq   BIGINT  NOT NULL
+);
+
+CREATE TABLE device_events (
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  seq         BIGINT      NOT NULL,
+  channel     TEXT        NOT NULL,
+  payload     JSONB       NOT NULL,
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, seq)
+);
+
+CREATE INDEX idx_device_events_created ON device_events (created_at);
+
+-- Record and send one event for a local device. Returns its seq, or NULL if
+-- the device does not exist or belongs to a shadow user.
+CREATE OR REPLACE FUNCTION emit_device_event(p_device_id UUID, p_channel TEXT, p_payload JSONB)
+RETURNS BIGINT AS $$
+DECLARE
+  owner UUID;
+  next_seq BIGINT;
+  full_payload JSONB;
+BEGIN
+  SELECT d.user_id INTO owner
+  FROM devices d JOIN users u ON u.id = d.user_id
+  WHERE d.id = p_device_id AND u.home_node_id IS NULL;
+  IF owner IS NULL THEN
+    RETURN NULL;
+  END IF;
+
+  INSERT INTO device_event_counters (device_id, last_seq) VALUES (p_device_id, 1)
+  ON CONFLICT (device_id) DO UPDATE SET last_seq = device_event_counters.last_seq + 1
+  RETURNING last_seq INTO next_seq;
+
+  full_payload := p_payload || jsonb_build_object(
+    'user_id', owner,
+    'to_device_id', p_device_id,
+    'seq', next_seq
+  );
+  INSERT INTO device_events (device_id, seq, channel, payload)
+  VALUES (p_device_id, next_seq, p_channel, full_payload);
+  PERFORM pg_notify(p_channel, full_payload::text);
+  RETURN next_seq;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Send one event to every device of a user. Returns the number of devices.
+CREATE OR REPLACE FUNCTION emit_user_event(p_user_id UUID, p_channel TEXT, p_payload JSONB)
+RETURNS INT AS $$
+DECLARE
+  d RECORD;
+  sent INT := 0;
+BEGIN
+  FOR d IN SELECT id FROM devices WHERE user_id = p_user_id ORDER BY created_at LOOP
+    IF emit_device_event(d.id, p_channel, p_payload) IS NOT NULL THEN
+      sent := sent + 1;
+    END IF;
+  END LOOP;
+  RETURN sent;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_message() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_device_event(
+    NEW.to_device_id,
+    'messages_channel',
+    jsonb_build_object('type', 'message', 'chat_id', NEW.chat_id)
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_session() RETURNS trigger AS $$
+DECLARE
+  sender_user UUID;
+  receiver_user UUID;
+  payload JSONB;
+BEGIN
+  SELECT user_id INTO sender_user FROM devices WHERE id = NEW.sender_device_id;
+  SELECT user_id INTO receiver_user FROM devices WHERE id = NEW.receiver_device_id;
+  payload := jsonb_build_object(
+    'type', 'session',
+    'sender_device_id', NEW.sender_device_id,
+    'receiver_device_id', NEW.receiver_device_id
+  );
+
+  IF receiver_user IS NOT NULL THEN
+    PERFORM emit_user_event(receiver_user, 'sessions_channel', payload);
+  END IF;
+  IF sender_user IS NOT NULL AND sender_user IS DISTINCT FROM receiver_user THEN
+    PERFORM emit_user_event(sender_user, 'sessions_channel', payload);
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_device_update() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_user_event(
+    NEW.user_id,
+    'devices_channel',
+    jsonb_build_object('type', 'device', 'device_id', NEW.id)
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_new_pending_session() RETURNS trigger AS $$
+BEGIN
+  PERFORM emit_device_event(
+    NEW.recipient_device_id,
+    'pending_sessions_channel',
+    jsonb_build_object(
+      'type', 'pending_session',
+      'recipient_device_id', NEW.recipient_device_id,
+      'sender_device_id', NEW.sender_device_id,
+      'pending_session_id', NEW.id,
+      'created_at', NEW.created_at
+    )
+  );
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- =============================================================================
+-- Migration: explicit message acknowledgement
+--
+-- Run this after sql_models/realtime_cursors.sql.
+--
+-- GET /messages/pending no longer marks what it returns as delivered. Devices
+-- acknowledge messages with POST /messages/ack, which sets delivered_at, and
+-- the maintenance worker drops acknowledged rows once they are older than
+-- DELIVERED_MESSAGE_RETENTION_HOURS. The partial index keeps that purge cheap.
+-- =============================================================================
+
+CREATE INDEX idx_messages_delivered ON messages(delivered_at)
+  WHERE delivered_at IS NOT NULL;
+
+-- =============================================================================
+-- Migration: disappearing messages and retention
+--
+-- Run this after sql_models/message_acks.sql.
+--
+-- A chat can carry a message TTL. Every message inserted into it expires that
+-- many seconds after it was stored, or earlier if the sender asked for an
+-- earlier expires_at. The reaper worker hard-deletes expired messages whether
+-- or not they were delivered, and drops queued federated forwards whose
+-- message has expired before reaching the peer.
+--
+-- The same worker also purges acknowledged messages, finished outbox entries
+-- and pending sessions nobody picked up once their retention window is over.
+-- =============================================================================
+
+ALTER TABLE chats
+  ADD COLUMN message_ttl_seconds INT
+    CHECK (message_ttl_seconds IS NULL OR message_ttl_seconds > 0);
+
+ALTER TABLE messages ADD COLUMN expires_at TIMESTAMPTZ;
+
+-- Expiry a message stored now in chat `cid` gets from the chat's TTL, or NULL.
+CREATE FUNCTION chat_expiry(cid UUID) RETURNS TIMESTAMPTZ AS $$
+  SELECT NOW() + make_interval(secs => message_ttl_seconds) FROM chats WHERE id = cid;
+$$ LANGUAGE sql STABLE;
+
+CREATE INDEX idx_messages_expires ON messages(expires_at)
+  WHERE expires_at IS NOT NULL;
+
+CREATE INDEX idx_federation_outbox_finished ON federation_outbox(created_at)
+  WHERE status <> 'pending';
+
+CREATE INDEX idx_pending_sessions_created ON pending_sessions(created_at);
+
+-- =============================================================================
+-- Migration: encrypted attachments
+--
+-- Run this after sql_models/message_expiry.sql.
+--
+-- Clients upload already-encrypted blobs out of band and reference them from
+-- messages by id; the blob itself lives in the configured storage backend,
+-- keyed by attachments.id. Only SHA-256 hashes of the upload and download
+-- tokens are stored.
+--
+-- message_attachments links every recipient copy of a message to the
+-- attachments it references. An attachment becomes garbage once every linked
+-- copy has been delivered for ATTACHMENT_RETENTION_HOURS or has expired
+-- (expired messages are deleted, which drops their links), and no federated
+-- forward still holds it (federated_until). Uploads never referenced by a
+-- message are collected after the same window.
+-- =============================================================================
+
+CREATE TABLE attachments (
+  id                  UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  owner_device_id     UUID        REFERENCES devices(id) ON DELETE SET NULL,
+  size_bytes          BIGINT      NOT NULL CHECK (size_bytes > 0),
+  upload_token_hash   TEXT        NOT NULL,
+  download_token_hash TEXT        NOT NULL,
+  -- base64 SHA-256 of the stored ciphertext, set on upload
+  content_digest      TEXT,
+  uploaded_at         TIMESTAMPTZ,
+  referenced_at       TIMESTAMPTZ,
+  -- peers may fetch the blob via GET /s2s/attachments/:id until then
+  federated_until     TIMESTAMPTZ,
+  created_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE message_attachments (
+  message_id    UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  attachment_id UUID NOT NULL REFERENCES attachments(id) ON DELETE CASCADE,
+  PRIMARY KEY (message_id, attachment_id)
+);
+
+CREATE INDEX idx_message_attachments_attachment ON message_attachments(attachment_id);
+
+-- =============================================================================
+-- Migration: group chat roles
+--
+-- Run this after sql_models/attachments.sql.
+--
+-- chat_members.role becomes one of owner / admin / member. Each group has
+-- exactly one owner row, mirrored in chats.owner_id; ownership moves only
+-- through an explicit transfer. Existing rows with other roles become
+-- members, and the member named by chats.owner_id becomes the owner.
+-- =============================================================================
+
+UPDATE chat_members SET role = 'member'
+WHERE role IS NULL OR role NOT IN ('owner', 'admin', 'member');
+
+UPDATE chat_members m SET role = 'owner'
+FROM chats c
+WHERE c.id = m.chat_id AND c.owner_id = m.user_id;
+
+ALTER TABLE chat_members
+  ALTER COLUMN role SET NOT NULL,
+  ADD CONSTRAINT chat_members_role CHECK (role IN ('owner', 'admin', 'member'));
+
+CREATE UNIQUE INDEX uniq_chat_owner ON chat_members(chat_id) WHERE role = 'owner';
+
+CREATE INDEX idx_chat_members_user ON chat_members(user_id);
+
+-- =============================================================================
+-- Migration: sender-key group messaging
+--
+-- Run this after sql_models/group_chats.sql.
+--
+-- A member device encrypts a group message once with its sender key. The
+-- ciphertext is stored once in group_messages, and every recipient device
+-- gets a messages row that points at it (group_message_id) instead of
+-- carrying its own header and ciphertext. Pending delivery, acks, receipts
+-- and expiry keep working on messages rows as before.
+--
+-- The sender key itself reaches each recipient device as a pairwise
+-- encrypted distribution message, stored as an ordinary messages row of
+-- kind 'sender_key'. sender_keys records, per chat and sending device, the
+-- current key and the device set it was distributed to. All of a chat's
+-- sender_keys rows are dropped when its membership changes, which forces
+-- every member to rotate before its next group send.
+-- =============================================================================
+
+CREATE TABLE group_messages (
+  id             UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  chat_id        UUID        NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  logical_msg_id TEXT        NOT NULL,
+  from_device_id UUID        REFERENCES devices(id) ON DELETE CASCADE,
+  sender_key_id  UUID        NOT NULL,
+  header         JSONB       NOT NULL,
+  ciphertext     TEXT        NOT NULL,
+  created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  CONSTRAINT uniq_group_message UNIQUE (from_device_id, logical_msg_id)
+);
+
+CREATE INDEX idx_group_messages_created ON group_messages(created_at);
+
+ALTER TABLE messages
+  ADD COLUMN kind TEXT NOT NULL DEFAULT 'pairwise'
+    CHECK (kind IN ('pairwise', 'sender_key', 'group')),
+  ADD COLUMN group_message_id UUID REFERENCES group_messages(id) ON DELETE CASCADE,
+  ALTER COLUMN header DROP NOT NULL,
+  ALTER COLUMN ciphertext DROP NOT NULL,
+  ADD CONSTRAINT messages_payload_shape CHECK (
+    (kind = 'group') = (group_message_id IS NOT NULL)
+    AND (group_message_id IS NOT NULL OR (header IS NOT NULL AND ciphertext IS NOT NULL))
+  );
+
+CREATE INDEX idx_messages_group_message ON messages(group_message_id)
+  WHERE group_message_id IS NOT NULL;
+
+CREATE TABLE sender_keys (
+  chat_id              UUID        NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  device_id            UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  key_id               UUID        NOT NULL,
+  recipient_device_ids UUID[]      NOT NULL,
+  created_at           TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (chat_id, device_id)
+);
+
+-- =============================================================================
+-- Migration: federated group chats
+--
+-- Run this after sql_models/sender_keys.sql.
+--
+-- A group lives on the node of its owner. That node is authoritative for
+-- name, membership and roles, and pushes a full, versioned snapshot to every
+-- node hosting a member after each change. Other nodes keep a mirror of the
+-- group under the same chats.id, with home_node_id pointing at the owner's
+-- node; chat_members of a mirror lists local users and shadow users alike.
+--
+-- membership_version orders the snapshots: the home node increments it on
+-- every change, and mirrors ignore snapshots not newer than what they have.
+-- =============================================================================
+
+ALTER TABLE chats
+  ADD COLUMN home_node_id       UUID   REFERENCES federation_nodes(id) ON DELETE CASCADE,
+  ADD COLUMN membership_version BIGINT NOT NULL DEFAULT 0;
+
+-- =============================================================================
+-- Migration: S2S protocol version negotiation
+--
+-- Run this after sql_models/federated_groups.sql.
+--
+-- Since protocol 0.0.3 nodes sign a Content-Digest of the request body along
+-- with method, path, timestamp and nonce. A peer still on 0.0.2 neither sends
+-- nor checks it, so each node remembers the protocol version of its peers:
+--
+--   protocol_version     — highest version seen for the peer, from its
+--                          GET /s2s/info or from a digest-signed request.
+--                          NULL until known. Never lowered, so a peer cannot
+--                          be talked back down to unsigned bodies.
+--   protocol_checked_at  — last GET /s2s/info probe, to re-check peers still
+--                          on an older version now and then.
+-- =============================================================================
+
+ALTER TABLE federation_nodes
+  ADD COLUMN protocol_version    TEXT,
+  ADD COLUMN protocol_checked_at TIMESTAMPTZ;
+
+-- =============================================================================
+-- Migration: peer node key rotation
+--
+-- Run this after sql_models/node_protocol.sql.
+--
+-- A node rotating its signing key announces the new key at the registry and,
+-- during an overlap window, serves both keys in GET /s2s/info together with a
+-- signature of the new key by the old one. A peer whose cached key no longer
+-- verifies a request looks the key up again and switches only when that
+-- endorsement checks out against the key it already had:
+--
+--   previous_public_key_b64  — key replaced by the last rotation; still
+--                              accepted until previous_key_expires_at, since
+--                              a rotating node may sign with either key
+--                              until it restarts.
+--   previous_key_expires_at  — end of the peer's announced overlap window.
+--   key_checked_at           — last key re-fetch, so a stream of badly
+--                              signed requests cannot hammer the registry.
+-- =============================================================================
+
+ALTER TABLE federation_nodes
+  ADD COLUMN previous_public_key_b64 TEXT,
+  ADD COLUMN previous_key_expires_at TIMESTAMPTZ,
+  ADD COLUMN key_checked_at          TIMESTAMPTZ;
+
+-- =============================================================================
+-- Migration: retire ML-KEM prekeys instead of deleting them
+--
+-- Run this after sql_models/node_key_rotation.sql.
+--
+-- A KEM prekey that is no longer handed out (a claimed one-time key, or a
+-- last-resort key replaced by a newer upload) gets retired_at instead of
+-- being deleted. Session inits naming it by kem_prekey_id are accepted until
+-- SIGNED_PREKEY_GRACE_HOURS after retirement, like rotated signed prekeys;
+-- the maintenance worker then deletes it. Keeping consumed one-time rows also
+-- stops a re-upload of the same key from putting it back in the pool.
+--
+-- Only one live (unretired) last-resort key per device.
+-- =============================================================================
+ALTER TABLE kem_prekeys
+  ADD COLUMN retired_at TIMESTAMPTZ;
+
+DROP INDEX idx_kem_prekeys_last_resort;
+CREATE UNIQUE INDEX idx_kem_prekeys_last_resort
+  ON kem_prekeys (device_id) WHERE is_last_resort AND retired_at IS NULL;
+
+CREATE INDEX idx_kem_prekeys_retired
+  ON kem_prekeys (retired_at) WHERE retired_at IS NOT NULL;
+
+-- =============================================================================
+-- Migration: replay protection for one-time prekey batches
+--
+-- Run this after sql_models/kem_prekey_retirement.sql.
+--
+-- A POST /devices/me/prekeys batch is signed over
+-- "hushnet-otpk-batch\n{device_id}\n{timestamp}\n{keys}". The timestamp must be
+-- recent and strictly greater than the device's previous batch, which is kept
+-- in devices.last_prekey_batch_ts, so a captured batch cannot be posted again.
+--
+-- consumed_one_time_prekeys keeps a SHA-256 of every OTPK handed out in a
+-- bundle. Uploads skip keys found here, so a consumed key never re-enters the
+-- pool even if a client (or an old batch) offers it again.
+-- =============================================================================
+ALTER TABLE devices
+  ADD COLUMN last_prekey_batch_ts BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE consumed_one_time_prekeys (
+  device_id    UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  key_hash     BYTEA       NOT NULL,              -- sha256 of the base64 pubkey
+  consumed_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (device_id, key_hash)
+);
+
+-- =============================================================================
+-- Migration: origins of forwarded messages, for S2S receipts
+--
+-- Run this after sql_models/prekey_batch_replay.sql.
+--
+-- POST /s2s/receipts is only accepted for a message this node forwarded to the
+-- reader. That used to be looked up in federation_outbox, whose rows are
+-- purged after OUTBOX_RETENTION_HOURS or by an operator, after which valid
+-- receipts were refused. Every direct or group message queued for a peer now
+-- leaves a row here, kept for FORWARDED_MESSAGE_RETENTION_HOURS.
+--
+-- to_user is set for a direct message (the recipient's username on the
+-- target node), chat_id for a group message (any member homed on the target
+-- node may acknowledge it).
+-- =============================================================================
+CREATE TABLE forwarded_messages (
+  target_node_id  TEXT        NOT NULL,
+  logical_msg_id  TEXT        NOT NULL,
+  sender_address  TEXT        NOT NULL,
+  to_user         TEXT,
+  chat_id         UUID        REFERENCES chats(id) ON DELETE CASCADE,
+  forwarded_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (target_node_id, logical_msg_id, sender_address),
+  CHECK ((to_user IS NULL) <> (chat_id IS NULL))
+);
+
+CREATE INDEX idx_forwarded_messages_forwarded ON forwarded_messages (forwarded_at);
+
+-- Backfill from what is still in the outbox.
+INSERT INTO forwarded_messages (target_node_id, logical_msg_id, sender_address, to_user, forwarded_at)
+SELECT target_node_id, logical_msg_id, payload->>'from_federated_address', payload->>'to_user', created_at
+FROM federation_outbox
+WHERE kind = 'messages'
+ON CONFLICT DO NOTHING;
+
+INSERT INTO forwarded_messages (target_node_id, logical_msg_id, sender_address, chat_id, forwarded_at)
+SELECT o.target_node_id, o.logical_msg_id, o.payload->>'from_federated_address',
+       (o.payload->>'chat_id')::uuid, o.created_at
+FROM federation_outbox o
+JOIN chats c ON c.id = (o.payload->>'chat_id')::uuid
+WHERE o.kind = 'group_message'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,55 @@
+-- =============================================================================
+-- Migration: sender-key group messaging
+--
+-- Run this after sql_models/group_chats.sql.
+--
+-- A member device encrypts a group message once with its sender key. The
+-- ciphertext is stored once in group_messages, and every recipient device
+-- gets a messages row that points at it (group_message_id) instead of
+-- carrying its own header and ciphertext. Pending delivery, acks, receipts
+-- and expiry keep working on messages rows as before.
+--
+-- The sender key itself reaches each recipient device as a pairwise
+-- encrypted distribution message, stored as an ordinary messages row of
+-- kind 'sender_key'. sender_keys records, per chat and sending device, the
+-- current key and the device set it was distributed to. All of a chat's
+-- sender_keys rows are dropped when its membership changes, which forces
+-- every member to rotate before its next group send.
+-- =============================================================================
+
+CREATE TABLE group_messages (
+  id             UUID        PRIMARY KEY DEFAULT gen_random_uuid(),
+  chat_id        UUID        NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  logical_msg_id TEXT        NOT NULL,
+  from_device_id UUID        REFERENCES devices(id) ON DELETE CASCADE,
+  sender_key_id  UUID        NOT NULL,
+  header         JSONB       NOT NULL,
+  ciphertext     TEXT        NOT NULL,
+  created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  CONSTRAINT uniq_group_message UNIQUE (from_device_id, logical_msg_id)
+);
+
+CREATE INDEX idx_group_messages_created ON group_messages(created_at);
+
+ALTER TABLE messages
+  ADD COLUMN kind TEXT NOT NULL DEFAULT 'pairwise'
+    CHECK (kind IN ('pairwise', 'sender_key', 'group')),
+  ADD COLUMN group_message_id UUID REFERENCES group_messages(id) ON DELETE CASCADE,
+  ALTER COLUMN header DROP NOT NULL,
+  ALTER COLUMN ciphertext DROP NOT NULL,
+  ADD CONSTRAINT messages_payload_shape CHECK (
+    (kind = 'group') = (group_message_id IS NOT NULL)
+    AND (group_message_id IS NOT NULL OR (header IS NOT NULL AND ciphertext IS NOT NULL))
+  );
+
+CREATE INDEX idx_messages_group_message ON messages(group_message_id)
+  WHERE group_message_id IS NOT NULL;
+
+CREATE TABLE sender_keys (
+  chat_id              UUID        NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  device_id            UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  key_id               UUID        NOT NULL,
+  recipient_device_ids UUID[]      NOT NULL,
+  created_at           TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (chat_id, device_id)
+);
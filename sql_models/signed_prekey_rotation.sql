@@ -0,0 +1,30 @@
+-- =============================================================================
+-- Migration: signed prekey rotation
+--
+-- Run this after sql_models/one_time_prekeys.sql.
+--
+-- devices.signed_prekey_pub/signed_prekey_sig always hold the current SPK.
+-- When a device rotates, the previous SPK is copied here with an expiry; an
+-- initiator that fetched the old bundle shortly before the rotation can still
+-- open a session against it until expires_at. The prekey maintenance worker
+-- deletes expired rows.
+--
+-- pending_sessions.recipient_spk_pub records which of the recipient's SPKs
+-- the initiator used, so the recipient knows which private key to use and the
+-- server can reject sessions built on an SPK that is past its grace window.
+-- NULL for clients that do not send it.
+-- =============================================================================
+CREATE TABLE signed_prekey_history (
+  id          BIGSERIAL   PRIMARY KEY,
+  device_id   UUID        NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  pubkey      TEXT        NOT NULL,
+  signature   TEXT        NOT NULL,
+  retired_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expires_at  TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_signed_prekey_history_device  ON signed_prekey_history (device_id, pubkey);
+CREATE INDEX idx_signed_prekey_history_expires ON signed_prekey_history (expires_at);
+
+ALTER TABLE pending_sessions
+  ADD COLUMN recipient_spk_pub TEXT;
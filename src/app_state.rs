@@ -2,6 +2,8 @@ use std::sync::Arc;
 
 use sqlx::PgPool;
 
+use crate::federation::peer_gate::PeerGate;
+use crate::storage::BlobStore;
 use crate::utils::node_keys::NodeKeys;
 
 #[derive(Clone)]
@@ -21,4 +23,27 @@ pub struct AppState {
     /// Shared HTTP client for outbound requests (registry lookups + S2S calls).
     /// reqwest::Client is Clone and internally reference-counted.
     pub http_client: reqwest::Client,
+    /// Outbound delivery limits and per-peer circuit breakers, shared by
+    /// every FederationClient (OUTBOX_CONCURRENCY, OUTBOX_PEER_CONCURRENCY).
+    pub peer_gate: Arc<PeerGate>,
+    /// Accept version 1 (timestamp-only) device signatures while clients
+    /// migrate to signed requests. Controlled by ALLOW_LEGACY_DEVICE_AUTH.
+    pub allow_legacy_device_auth: bool,
+    /// Accept S2S requests without a signed Content-Digest from peers still
+    /// on protocol 0.0.2. Controlled by ALLOW_LEGACY_NODE_AUTH.
+    pub allow_legacy_node_auth: bool,
+    /// Bearer token for the operator /admin/* API (ADMIN_TOKEN); the API is
+    /// disabled when unset.
+    pub admin_token: Option<String>,
+    /// A device whose one-time prekey count falls below this value after a
+    /// bundle fetch receives a `prekeys_low` realtime event.
+    pub prekey_low_threshold: i64,
+    /// Maximum number of unused one-time prekeys stored per device.
+    pub max_one_time_prekeys: i64,
+    /// How long a rotated-out signed prekey is still accepted for new sessions.
+    pub signed_prekey_grace_hours: i32,
+    /// Backend holding encrypted attachment blobs (ATTACHMENT_STORAGE).
+    pub attachment_store: Arc<dyn BlobStore>,
+    /// Largest attachment accepted, in bytes (ATTACHMENT_MAX_BYTES).
+    pub attachment_max_bytes: i64,
 }
@@ -0,0 +1,297 @@
+// src/controllers/admin_controller.rs
+//
+// Operator API for federation peers (/admin/federation/nodes) and the
+// federation outbox (/admin/federation/outbox).
+//
+// Every handler requires `AuthenticatedAdmin` (ADMIN_TOKEN). Blocking works
+// without restarts: AuthenticatedNode reads the peer row on every S2S request
+// and the outbox worker skips entries for blocked nodes, keeping them pending
+// until the node is unblocked.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    middlewares::{admin_auth::AuthenticatedAdmin, node_auth::fetch_registry_record},
+    models::{
+        federation::{OutboxBulkQuery, OutboxListQuery, PeerListQuery, OUTBOX_STATUSES},
+        pagination::{PageCursor, PageQuery},
+    },
+    repository::federation_repository,
+};
+
+// ─── GET /admin/federation/nodes ─────────────────────────────────────────────
+
+pub async fn list_peers(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Query(query): Query<PeerListQuery>,
+) -> Response {
+    match federation_repository::list_federation_peers(&state.pool, query.blocked).await {
+        Ok(peers) => (StatusCode::OK, Json(peers)).into_response(),
+        Err(e) => internal_error("list peers", e),
+    }
+}
+
+// ─── GET /admin/federation/nodes/:node_id ────────────────────────────────────
+
+pub async fn get_peer(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(node_id): Path<String>,
+) -> Response {
+    match federation_repository::get_federation_peer(&state.pool, &node_id).await {
+        Ok(Some(peer)) => (StatusCode::OK, Json(peer)).into_response(),
+        Ok(None) => unknown_node(),
+        Err(e) => internal_error("get peer", e),
+    }
+}
+
+// ─── POST /admin/federation/nodes/:node_id/block|unblock ─────────────────────
+
+pub async fn block_peer(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(node_id): Path<String>,
+) -> Response {
+    set_blocked(&state, &node_id, true).await
+}
+
+pub async fn unblock_peer(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(node_id): Path<String>,
+) -> Response {
+    set_blocked(&state, &node_id, false).await
+}
+
+async fn set_blocked(state: &AppState, node_id: &str, blocked: bool) -> Response {
+    match federation_repository::set_node_blocked(&state.pool, node_id, blocked).await {
+        Ok(Some(node)) => {
+            info!(%node_id, blocked, "admin: peer block state changed");
+            (StatusCode::OK, Json(node)).into_response()
+        }
+        Ok(None) => unknown_node(),
+        Err(e) => internal_error("set blocked", e),
+    }
+}
+
+// ─── POST /admin/federation/nodes/:node_id/refresh ───────────────────────────
+
+/// Take the peer's api_url and key from the registry as they are. This is the
+/// way to accept a key that changed without an endorsed rotation.
+pub async fn refresh_peer(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(node_id): Path<String>,
+) -> Response {
+    match federation_repository::get_federation_node(&state.pool, &node_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unknown_node(),
+        Err(e) => return internal_error("refresh peer", e),
+    }
+
+    let (api_url, public_key_b64) = match fetch_registry_record(&state, &node_id).await {
+        Ok(record) => record,
+        Err((_, e)) => {
+            warn!(%node_id, "admin: registry refresh failed: {e}");
+            return (StatusCode::BAD_GATEWAY, Json(json!({"error": e}))).into_response();
+        }
+    };
+
+    match federation_repository::replace_peer_identity(
+        &state.pool,
+        &node_id,
+        &api_url,
+        &public_key_b64,
+    )
+    .await
+    {
+        Ok(Some(node)) => {
+            info!(%node_id, %api_url, "admin: peer refreshed from registry");
+            (StatusCode::OK, Json(node)).into_response()
+        }
+        Ok(None) => unknown_node(),
+        Err(e) => internal_error("refresh peer", e),
+    }
+}
+
+// ─── DELETE /admin/federation/nodes/:node_id ─────────────────────────────────
+
+/// Forget a stale peer. Refused while shadow users or pending outbox entries
+/// still refer to it. A peer that makes contact again is re-created from the
+/// registry, so use block to keep one out.
+pub async fn delete_peer(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(node_id): Path<String>,
+) -> Response {
+    match federation_repository::delete_stale_node(&state.pool, &node_id).await {
+        Ok(true) => {
+            info!(%node_id, "admin: peer deleted");
+            return StatusCode::NO_CONTENT.into_response();
+        }
+        Ok(false) => {}
+        Err(e) => return internal_error("delete peer", e),
+    }
+
+    match federation_repository::get_federation_peer(&state.pool, &node_id).await {
+        Ok(Some(peer)) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "node is still in use",
+                "shadow_users": peer.shadow_users,
+                "pending_outbox": peer.pending_outbox,
+            })),
+        )
+            .into_response(),
+        Ok(None) => unknown_node(),
+        Err(e) => internal_error("delete peer", e),
+    }
+}
+
+// ─── GET /admin/federation/outbox ────────────────────────────────────────────
+
+pub async fn list_outbox(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Query(query): Query<OutboxListQuery>,
+) -> Response {
+    if let Some(status) = query.status.as_deref() {
+        if !OUTBOX_STATUSES.contains(&status) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "status must be pending, delivered or failed"})),
+            )
+                .into_response();
+        }
+    }
+    let page = PageQuery {
+        limit: query.limit,
+        after: query.after.clone(),
+    };
+    let after = match page.cursor() {
+        Ok(after) => after,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
+        }
+    };
+
+    match federation_repository::list_outbox_entries(
+        &state.pool,
+        query.status.as_deref(),
+        query.target_node_id.as_deref(),
+        after,
+        page.limit(),
+    )
+    .await
+    {
+        Ok((entries, has_more)) => {
+            let next_cursor = entries
+                .last()
+                .filter(|_| has_more)
+                .map(|e| PageCursor::new(e.created_at, e.id).encode());
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "entries": entries,
+                    "next_cursor": next_cursor,
+                    "has_more": has_more,
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => internal_error("list outbox", e),
+    }
+}
+
+// ─── POST /admin/federation/outbox/:id/requeue ───────────────────────────────
+
+pub async fn requeue_outbox_entry(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Path(id): Path<Uuid>,
+) -> Response {
+    match federation_repository::requeue_failed_outbox(&state.pool, Some(id), None).await {
+        Ok(0) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "no failed outbox entry with this id"})),
+        )
+            .into_response(),
+        Ok(requeued) => {
+            info!(entry_id = %id, "admin: outbox entry requeued");
+            (StatusCode::OK, Json(json!({ "requeued": requeued }))).into_response()
+        }
+        Err(e) => internal_error("requeue outbox entry", e),
+    }
+}
+
+// ─── POST /admin/federation/outbox/requeue ───────────────────────────────────
+
+pub async fn requeue_outbox(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Query(query): Query<OutboxBulkQuery>,
+) -> Response {
+    match federation_repository::requeue_failed_outbox(
+        &state.pool,
+        None,
+        query.target_node_id.as_deref(),
+    )
+    .await
+    {
+        Ok(requeued) => {
+            info!(target_node = ?query.target_node_id, requeued, "admin: failed outbox entries requeued");
+            (StatusCode::OK, Json(json!({ "requeued": requeued }))).into_response()
+        }
+        Err(e) => internal_error("requeue outbox", e),
+    }
+}
+
+// ─── DELETE /admin/federation/outbox/delivered ───────────────────────────────
+
+pub async fn purge_delivered_outbox(
+    State(state): State<AppState>,
+    _admin: AuthenticatedAdmin,
+    Query(query): Query<OutboxBulkQuery>,
+) -> Response {
+    let older_than_hours = query.older_than_hours.unwrap_or(0).max(0);
+    match federation_repository::purge_delivered_outbox(
+        &state.pool,
+        query.target_node_id.as_deref(),
+        older_than_hours,
+    )
+    .await
+    {
+        Ok(purged) => {
+            info!(target_node = ?query.target_node_id, purged, "admin: delivered outbox entries purged");
+            (StatusCode::OK, Json(json!({ "purged": purged }))).into_response()
+        }
+        Err(e) => internal_error("purge outbox", e),
+    }
+}
+
+fn unknown_node() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "unknown node"})),
+    )
+        .into_response()
+}
+
+fn internal_error(action: &str, e: sqlx::Error) -> Response {
+    error!(err = %e, "admin: {action} failed");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "internal error"})),
+    )
+        .into_response()
+}
@@ -0,0 +1,319 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{
+    engine::general_purpose::{STANDARD as B64, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ed25519_dalek::ed25519::signature::rand_core::{OsRng, RngCore};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    federation::client::FederationClient,
+    middlewares::auth::AuthenticatedDevice,
+    models::attachment::{CreateAttachmentBody, CreatedAttachment},
+    repository::attachment_repository,
+};
+
+use super::messages_controller::resolve_node;
+
+/// How long after POST /attachments the blob may be uploaded.
+const UPLOAD_WINDOW_SECS: i64 = 3600;
+
+pub const UPLOAD_TOKEN_HEADER: &str = "X-Upload-Token";
+pub const DOWNLOAD_TOKEN_HEADER: &str = "X-Download-Token";
+
+pub async fn create_attachment(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(body): Json<CreateAttachmentBody>,
+) -> impl IntoResponse {
+    if body.size_bytes <= 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "size_bytes must be positive"})),
+        )
+            .into_response();
+    }
+    if body.size_bytes > state.attachment_max_bytes {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({
+                "error": format!("attachments are limited to {} bytes", state.attachment_max_bytes)
+            })),
+        )
+            .into_response();
+    }
+
+    let upload_token = generate_token();
+    let download_token = generate_token();
+
+    match attachment_repository::create_attachment(
+        &state.pool,
+        &device.id,
+        body.size_bytes,
+        &hash_token(&upload_token),
+        &hash_token(&download_token),
+    )
+    .await
+    {
+        Ok((attachment_id, created_at)) => (
+            StatusCode::CREATED,
+            Json(CreatedAttachment {
+                attachment_id,
+                upload_token,
+                download_token,
+                upload_expires_at: created_at + chrono::Duration::seconds(UPLOAD_WINDOW_SECS),
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Error creating attachment: {e}");
+            internal_error()
+        }
+    }
+}
+
+/// PUT /attachments/:id/upload. Authorized by the upload token alone: the body
+/// is too large for the signed-request layer, and the token was only handed
+/// to the authenticated device that created the attachment.
+pub async fn upload_attachment(
+    State(state): State<AppState>,
+    Path(attachment_id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(token_hash) = token_hash_from(&headers, UPLOAD_TOKEN_HEADER) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "missing upload token"})),
+        )
+            .into_response();
+    };
+
+    let size = match attachment_repository::get_pending_upload_size(
+        &state.pool,
+        &attachment_id,
+        &token_hash,
+        UPLOAD_WINDOW_SECS as f64,
+    )
+    .await
+    {
+        Ok(Some(size)) => size,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "unknown attachment, bad token or upload window closed"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("Error looking up attachment: {e}");
+            return internal_error();
+        }
+    };
+
+    if body.len() as i64 != size {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("expected {size} bytes, got {}", body.len())
+            })),
+        )
+            .into_response();
+    }
+
+    // Store the blob before spending the token: if the write fails the token
+    // is still good and the client can retry.
+    let content_digest = B64.encode(Sha256::digest(&body));
+    if let Err(e) = state.attachment_store.put(attachment_id, body).await {
+        eprintln!("Error storing attachment {attachment_id}: {e:#}");
+        return internal_error();
+    }
+
+    match attachment_repository::complete_upload(
+        &state.pool,
+        &attachment_id,
+        &token_hash,
+        &content_digest,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({"error": "attachment already uploaded"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("Error completing attachment upload: {e}");
+            return internal_error();
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "attachment_id": attachment_id,
+            "size_bytes": size,
+            "content_digest": content_digest
+        })),
+    )
+        .into_response()
+}
+
+pub async fn download_attachment(
+    State(state): State<AppState>,
+    AuthenticatedDevice(_device): AuthenticatedDevice,
+    Path(attachment_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    serve_blob(&state, attachment_id, &headers, false).await
+}
+
+/// GET /attachments/remote/:node_id/:id. Fetches an attachment a user of
+/// another node sent, through that node's S2S endpoint.
+pub async fn download_remote_attachment(
+    State(state): State<AppState>,
+    AuthenticatedDevice(_device): AuthenticatedDevice,
+    Path((node_id, attachment_id)): Path<(String, Uuid)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if node_id == state.this_node_id {
+        return serve_blob(&state, attachment_id, &headers, false).await;
+    }
+
+    let Some(token) = headers
+        .get(DOWNLOAD_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "missing download token"})),
+        )
+            .into_response();
+    };
+
+    let node = match resolve_node(&state, &node_id).await {
+        Ok(n) => n,
+        Err(resp) => return resp,
+    };
+
+    let client = FederationClient::from_state(&state);
+    match client
+        .fetch_attachment(
+            &node,
+            attachment_id,
+            token,
+            state.attachment_max_bytes as u64,
+        )
+        .await
+    {
+        Ok(Some(bytes)) => blob_response(bytes),
+        Ok(None) => not_found(),
+        Err(e) => {
+            eprintln!("Error fetching attachment from {node_id}: {e:#}");
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({"error": "peer node did not serve the attachment"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Serve the blob of `attachment_id` if the download token in `headers` matches.
+/// `federated_only` restricts it to attachments held for peers (S2S fetches).
+pub(crate) async fn serve_blob(
+    state: &AppState,
+    attachment_id: Uuid,
+    headers: &HeaderMap,
+    federated_only: bool,
+) -> Response {
+    let Some(token_hash) = token_hash_from(headers, DOWNLOAD_TOKEN_HEADER) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "missing download token"})),
+        )
+            .into_response();
+    };
+
+    match attachment_repository::is_downloadable(
+        &state.pool,
+        &attachment_id,
+        &token_hash,
+        federated_only,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return not_found(),
+        Err(e) => {
+            eprintln!("Error checking attachment: {e}");
+            return internal_error();
+        }
+    }
+
+    match state.attachment_store.get(attachment_id).await {
+        Ok(Some(bytes)) => blob_response(bytes),
+        Ok(None) => not_found(),
+        Err(e) => {
+            eprintln!("Error reading attachment {attachment_id}: {e:#}");
+            internal_error()
+        }
+    }
+}
+
+fn blob_response(bytes: Bytes) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        bytes,
+    )
+        .into_response()
+}
+
+fn not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "attachment not found"})),
+    )
+        .into_response()
+}
+
+fn internal_error() -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "internal server error"})),
+    )
+        .into_response()
+}
+
+/// 32 random bytes, base64url without padding.
+fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Tokens are stored as hex SHA-256 only.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn token_hash_from(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .filter(|t| !t.is_empty())
+        .map(hash_token)
+}
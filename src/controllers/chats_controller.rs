@@ -1,10 +1,34 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde_json::json;
+use uuid::Uuid;
 
 use crate::{
-    app_state::AppState, middlewares::auth::AuthenticatedDevice, repository::chat_repository,
+    app_state::AppState,
+    federation::{groups, outbox::OutboxPayload, parse_federated_address},
+    middlewares::auth::AuthenticatedDevice,
+    models::{
+        chat::{
+            AddMembersBody, ChatExpiryBody, CreateGroupBody, GroupOrigin, GroupRole,
+            RenameGroupBody, SetRoleBody, TransferOwnershipBody,
+        },
+        federation::S2sChatExpiry,
+    },
+    repository::{chat_repository, federation_repository, user_repository},
 };
 
+use super::messages_controller::resolve_node;
+
+/// Most members a group may have, owner included.
+const MAX_GROUP_MEMBERS: usize = 256;
+
+/// Longest group name accepted, in characters.
+const MAX_GROUP_NAME_LEN: usize = 100;
+
 pub async fn get_all_chats(
     State(state): State<AppState>,
     AuthenticatedDevice(sender): AuthenticatedDevice,
@@ -23,3 +47,658 @@ pub async fn get_all_chats(
         }
     }
 }
+
+pub async fn set_chat_expiry(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(body): Json<ChatExpiryBody>,
+) -> impl IntoResponse {
+    if body.message_ttl_seconds.is_some_and(|ttl| ttl <= 0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "message_ttl_seconds must be positive or null"})),
+        )
+            .into_response();
+    }
+
+    // In groups the setting belongs to admins and, like membership, is
+    // changed on the group's home node; both sides of a direct chat may
+    // change it.
+    let is_group =
+        match chat_repository::get_member_role(&state.pool, &chat_id, &device.user_id).await {
+            Ok(Some(role)) if !role.can_administer() => return forbidden(),
+            Ok(role) => role.is_some(),
+            Err(e) => return internal_error("checking group role", e),
+        };
+    if is_group {
+        if let Err(resp) = managed_here(&state, &chat_id).await {
+            return resp;
+        }
+    }
+
+    match chat_repository::set_chat_message_ttl(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        body.message_ttl_seconds,
+    )
+    .await
+    {
+        Ok(true) => {
+            if is_group {
+                publish(&state, &chat_id, "expiry_changed", &[]).await;
+            } else {
+                send_direct_expiry(&state, &chat_id, &device.user_id, body.message_ttl_seconds)
+                    .await;
+            }
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "chat_id": chat_id,
+                    "message_ttl_seconds": body.message_ttl_seconds
+                })),
+            )
+                .into_response()
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Chat not found"})),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Error when setting chat expiry {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Internal server error"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn create_group(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(body): Json<CreateGroupBody>,
+) -> impl IntoResponse {
+    let Some(name) = valid_group_name(&body.name) else {
+        return invalid_group_name();
+    };
+
+    if body.member_ids.len() + body.member_addresses.len() + 1 > MAX_GROUP_MEMBERS {
+        return too_many_members();
+    }
+    if let Err(resp) = check_local_users(&state, &body.member_ids).await {
+        return resp;
+    }
+    let mut member_ids = match resolve_addresses(&state, &body.member_addresses).await {
+        Ok(ids) => ids,
+        Err(resp) => return resp,
+    };
+    member_ids.extend(body.member_ids);
+    member_ids.sort();
+    member_ids.dedup();
+    member_ids.retain(|id| *id != device.user_id);
+
+    let chat_id = match chat_repository::create_group(
+        &state.pool,
+        &device.user_id,
+        name,
+        &member_ids,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return internal_error("creating group", e),
+    };
+    publish(&state, &chat_id, "created", &[]).await;
+
+    match chat_repository::get_group_members(&state.pool, &chat_id).await {
+        Ok(members) => (
+            StatusCode::CREATED,
+            Json(json!({
+                "chat_id": chat_id,
+                "name": name,
+                "owner_id": device.user_id,
+                "members": members
+            })),
+        )
+            .into_response(),
+        Err(e) => internal_error("fetching group members", e),
+    }
+}
+
+pub async fn get_group_members(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if let Err(resp) = member_role(&state, &chat_id, &device.user_id).await {
+        return resp;
+    }
+
+    match chat_repository::get_group_members(&state.pool, &chat_id).await {
+        Ok(members) => (StatusCode::OK, Json(members)).into_response(),
+        Err(e) => internal_error("fetching group members", e),
+    }
+}
+
+pub async fn add_group_members(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(body): Json<AddMembersBody>,
+) -> impl IntoResponse {
+    match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(role) if role.can_administer() => {}
+        Ok(_) => return forbidden(),
+        Err(resp) => return resp,
+    }
+    if let Err(resp) = managed_here(&state, &chat_id).await {
+        return resp;
+    }
+
+    if body.user_ids.is_empty() && body.addresses.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "user_ids or addresses must not be empty"})),
+        )
+            .into_response();
+    }
+    if body.user_ids.len() + body.addresses.len() > MAX_GROUP_MEMBERS {
+        return too_many_members();
+    }
+    if let Err(resp) = check_local_users(&state, &body.user_ids).await {
+        return resp;
+    }
+    let mut user_ids = match resolve_addresses(&state, &body.addresses).await {
+        Ok(ids) => ids,
+        Err(resp) => return resp,
+    };
+    user_ids.extend(body.user_ids);
+    user_ids.sort();
+    user_ids.dedup();
+
+    let current = match chat_repository::get_group_members(&state.pool, &chat_id).await {
+        Ok(members) => members,
+        Err(e) => return internal_error("fetching group members", e),
+    };
+    let new_count = user_ids
+        .iter()
+        .filter(|id| !current.iter().any(|m| m.user_id == **id))
+        .count();
+    if current.len() + new_count > MAX_GROUP_MEMBERS {
+        return too_many_members();
+    }
+
+    match chat_repository::add_group_members(&state.pool, &chat_id, &device.user_id, &user_ids)
+        .await
+    {
+        Ok(added) => {
+            if !added.is_empty() {
+                publish(&state, &chat_id, "members_added", &[]).await;
+            }
+            (StatusCode::OK, Json(json!({ "added": added }))).into_response()
+        }
+        Err(e) => internal_error("adding group members", e),
+    }
+}
+
+pub async fn remove_group_member(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path((chat_id, user_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    if user_id == device.user_id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Use POST /chats/:id/leave to leave a group"})),
+        )
+            .into_response();
+    }
+
+    let role = match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let target = match chat_repository::get_member_role(&state.pool, &chat_id, &user_id).await {
+        Ok(Some(target)) => target,
+        Ok(None) => return member_not_found(),
+        Err(e) => return internal_error("checking group role", e),
+    };
+    if !role.can_remove(target) {
+        return forbidden();
+    }
+    if let Err(resp) = managed_here(&state, &chat_id).await {
+        return resp;
+    }
+
+    // A removed member's node no longer hosts members; tell it all the same.
+    let member_node = match federation_repository::get_user_home_node(&state.pool, user_id).await {
+        Ok(node) => node,
+        Err(e) => return internal_error("looking up member node", e),
+    };
+
+    match chat_repository::remove_group_member(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &user_id,
+        "member_removed",
+    )
+    .await
+    {
+        Ok(true) => {
+            let also_notify: Vec<String> = member_node.into_iter().collect();
+            publish(&state, &chat_id, "member_removed", &also_notify).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => member_not_found(),
+        Err(e) => internal_error("removing group member", e),
+    }
+}
+
+/// POST /chats/:id/leave. The owner must hand the group over first, unless
+/// nobody else is left, in which case the group is deleted. Leaving a group
+/// homed on another node also tells that node.
+pub async fn leave_group(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let role = match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(role) => role,
+        Err(resp) => return resp,
+    };
+    let origin = match group_origin(&state, &chat_id).await {
+        Ok(origin) => origin,
+        Err(resp) => return resp,
+    };
+
+    if role == GroupRole::Owner {
+        match chat_repository::get_group_members(&state.pool, &chat_id).await {
+            Ok(members) if members.len() > 1 => {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({"error": "Transfer ownership before leaving the group"})),
+                )
+                    .into_response()
+            }
+            Ok(_) => {}
+            Err(e) => return internal_error("fetching group members", e),
+        }
+    }
+
+    match chat_repository::remove_group_member(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &device.user_id,
+        "member_left",
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(e) => return internal_error("leaving group", e),
+    }
+
+    match origin.home_node {
+        None => publish(&state, &chat_id, "member_left", &[]).await,
+        Some(home_node) => {
+            let sent = match user_repository::find_user_by_id(&state.pool, &device.user_id).await {
+                Ok(Some(user)) => {
+                    groups::send_leave(&state, &chat_id, &home_node, &user.username).await
+                }
+                Ok(None) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = sent {
+                eprintln!("Error when telling the home node about leaving {e}");
+            }
+        }
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn rename_group(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(body): Json<RenameGroupBody>,
+) -> impl IntoResponse {
+    let Some(name) = valid_group_name(&body.name) else {
+        return invalid_group_name();
+    };
+
+    match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(role) if role.can_administer() => {}
+        Ok(_) => return forbidden(),
+        Err(resp) => return resp,
+    }
+    if let Err(resp) = managed_here(&state, &chat_id).await {
+        return resp;
+    }
+
+    match chat_repository::rename_group(&state.pool, &chat_id, &device.user_id, name).await {
+        Ok(()) => {
+            publish(&state, &chat_id, "renamed", &[]).await;
+            (
+                StatusCode::OK,
+                Json(json!({"chat_id": chat_id, "name": name})),
+            )
+                .into_response()
+        }
+        Err(e) => internal_error("renaming group", e),
+    }
+}
+
+pub async fn set_member_role(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path((chat_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(body): Json<SetRoleBody>,
+) -> impl IntoResponse {
+    if body.role == GroupRole::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Use PUT /chats/:id/owner to transfer ownership"})),
+        )
+            .into_response();
+    }
+
+    match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(GroupRole::Owner) => {}
+        Ok(_) => return forbidden(),
+        Err(resp) => return resp,
+    }
+    if let Err(resp) = managed_here(&state, &chat_id).await {
+        return resp;
+    }
+
+    match chat_repository::set_member_role(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &user_id,
+        body.role,
+    )
+    .await
+    {
+        Ok(true) => {
+            publish(&state, &chat_id, "role_changed", &[]).await;
+            (
+                StatusCode::OK,
+                Json(json!({"chat_id": chat_id, "user_id": user_id, "role": body.role})),
+            )
+                .into_response()
+        }
+        Ok(false) => member_not_found(),
+        Err(e) => internal_error("changing member role", e),
+    }
+}
+
+pub async fn transfer_ownership(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(body): Json<TransferOwnershipBody>,
+) -> impl IntoResponse {
+    match member_role(&state, &chat_id, &device.user_id).await {
+        Ok(GroupRole::Owner) => {}
+        Ok(_) => return forbidden(),
+        Err(resp) => return resp,
+    }
+    if let Err(resp) = managed_here(&state, &chat_id).await {
+        return resp;
+    }
+    if body.user_id == device.user_id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "You already own this group"})),
+        )
+            .into_response();
+    }
+    // The owner's node is the group's home; it cannot move to another node.
+    match federation_repository::get_user_home_node(&state.pool, body.user_id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Ownership can only go to a user of this node"})),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_error("looking up member node", e),
+    }
+
+    match chat_repository::transfer_group_ownership(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &body.user_id,
+    )
+    .await
+    {
+        Ok(true) => {
+            publish(&state, &chat_id, "owner_transferred", &[]).await;
+            (
+                StatusCode::OK,
+                Json(json!({"chat_id": chat_id, "owner_id": body.user_id})),
+            )
+                .into_response()
+        }
+        Ok(false) => member_not_found(),
+        Err(e) => internal_error("transferring group ownership", e),
+    }
+}
+
+/// Role of `user_id` in the group, or a 404 if it is not a group they are in.
+async fn member_role(
+    state: &AppState,
+    chat_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<GroupRole, Response> {
+    match chat_repository::get_member_role(&state.pool, chat_id, user_id).await {
+        Ok(Some(role)) => Ok(role),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Group not found"})),
+        )
+            .into_response()),
+        Err(e) => Err(internal_error("checking group role", e)),
+    }
+}
+
+async fn group_origin(state: &AppState, chat_id: &Uuid) -> Result<GroupOrigin, Response> {
+    match chat_repository::get_group_origin(&state.pool, chat_id).await {
+        Ok(Some(origin)) => Ok(origin),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Group not found"})),
+        )
+            .into_response()),
+        Err(e) => Err(internal_error("looking up group home", e)),
+    }
+}
+
+/// 409 unless this node is the group's home; mirrors of groups homed on
+/// another node only change through that node's snapshots.
+async fn managed_here(state: &AppState, chat_id: &Uuid) -> Result<(), Response> {
+    let origin = group_origin(state, chat_id).await?;
+    if origin.is_local() {
+        return Ok(());
+    }
+    Err((
+        StatusCode::CONFLICT,
+        Json(json!({
+            "error": "Group is managed by its home node",
+            "home_node": origin.home_node
+        })),
+    )
+        .into_response())
+}
+
+/// Send the group's new state to the nodes of its remote members. A failure
+/// to queue it is logged; the next change sends a complete snapshot again.
+async fn publish(state: &AppState, chat_id: &Uuid, change: &str, also_notify: &[String]) {
+    if let Err(e) = groups::publish_group_state(state, chat_id, change, also_notify).await {
+        eprintln!("Error when publishing group state {e}");
+    }
+}
+
+/// Give the other party of a direct chat, if homed on another node, the new
+/// message TTL through the outbox. A failure to queue it is logged.
+async fn send_direct_expiry(
+    state: &AppState,
+    chat_id: &Uuid,
+    user_id: &Uuid,
+    message_ttl_seconds: Option<i32>,
+) {
+    let result = async {
+        let Some((to_user, node_id)) =
+            federation_repository::get_remote_direct_peer(&state.pool, *chat_id, *user_id).await?
+        else {
+            return Ok(());
+        };
+        let Some(setter) = user_repository::find_user_by_id(&state.pool, user_id).await? else {
+            return Ok(());
+        };
+        let expiry = S2sChatExpiry {
+            from_federated_address: format!("{}@{}", setter.username, state.this_node_id),
+            to_user,
+            message_ttl_seconds,
+        };
+        let logical_msg_id = format!(
+            "chat_expiry:{}:{}:{}",
+            expiry.from_federated_address,
+            expiry.to_user,
+            Uuid::new_v4()
+        );
+        groups::send_to_nodes(
+            state,
+            &[node_id],
+            &logical_msg_id,
+            OutboxPayload::ChatExpiry(expiry),
+        )
+        .await
+    }
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Error when sending chat expiry {e}");
+    }
+}
+
+/// Resolve federated addresses to user ids: users of this node by username,
+/// users of other nodes to their shadow record, looking the node up in the
+/// registry if needed. 400 listing any address that does not resolve.
+async fn resolve_addresses(state: &AppState, addresses: &[String]) -> Result<Vec<Uuid>, Response> {
+    let mut ids = Vec::with_capacity(addresses.len());
+    let mut unknown = Vec::new();
+
+    for address in addresses {
+        let Some((username, node_id)) = parse_federated_address(address)
+            .filter(|(username, node_id)| !username.is_empty() && !node_id.is_empty())
+        else {
+            unknown.push(address.clone());
+            continue;
+        };
+
+        if node_id == state.this_node_id {
+            match federation_repository::get_local_user_id_by_username(&state.pool, username).await
+            {
+                Ok(Some(id)) => ids.push(id),
+                Ok(None) => unknown.push(address.clone()),
+                Err(e) => return Err(internal_error("resolving member addresses", e)),
+            }
+            continue;
+        }
+
+        let node = resolve_node(state, node_id).await?;
+        match federation_repository::upsert_shadow_user(&state.pool, username, address, node.id)
+            .await
+        {
+            Ok(id) => ids.push(id),
+            Err(e) => return Err(internal_error("resolving member addresses", e)),
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Unknown users", "addresses": unknown})),
+        )
+            .into_response());
+    }
+    Ok(ids)
+}
+
+/// `user_ids` must be users of this node; 400 listing any other id.
+/// Members on other nodes are added by federated address instead.
+async fn check_local_users(state: &AppState, user_ids: &[Uuid]) -> Result<(), Response> {
+    match user_repository::find_non_local_users(&state.pool, user_ids).await {
+        Ok(unknown) if unknown.is_empty() => Ok(()),
+        Ok(unknown) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Unknown users", "user_ids": unknown})),
+        )
+            .into_response()),
+        Err(e) => Err(internal_error("checking users", e)),
+    }
+}
+
+/// The trimmed name, if it is 1 to MAX_GROUP_NAME_LEN characters long.
+fn valid_group_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    (!name.is_empty() && name.chars().count() <= MAX_GROUP_NAME_LEN).then_some(name)
+}
+
+fn invalid_group_name() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": format!("Group name must be 1 to {MAX_GROUP_NAME_LEN} characters")
+        })),
+    )
+        .into_response()
+}
+
+fn too_many_members() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": format!("Groups are limited to {MAX_GROUP_MEMBERS} members")
+        })),
+    )
+        .into_response()
+}
+
+fn member_not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "Member not found"})),
+    )
+        .into_response()
+}
+
+fn forbidden() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({"error": "Your role does not allow this"})),
+    )
+        .into_response()
+}
+
+fn internal_error(action: &str, e: sqlx::Error) -> Response {
+    eprintln!("Error when {action} {e}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "error": "Internal server error"
+        })),
+    )
+        .into_response()
+}
@@ -5,13 +5,18 @@ use serde_json::json;
 use uuid::Uuid;
 
 use crate::app_state::AppState;
+use crate::middlewares::auth::AuthenticatedDevice;
 use crate::models::device::OneTimePrekeys;
 use crate::models::device::SignedPreKey;
-use crate::repository::device_repository;
-use crate::repository::enrollment_token_repository::add_used_token;
+use crate::models::federation::S2sDeviceRemoved;
+use crate::repository::device_repository::{self, PrekeyUpload};
 use crate::repository::enrollment_token_repository::enrollment_token_exists;
+use crate::repository::user_repository;
 use crate::services::auth::verify_enrollment_token;
-use crate::utils::crypto_utils::verify_signed_prekey_signature;
+use crate::utils::crypto_utils::{
+    verify_device_revocation_signature, verify_kem_prekey_signature, verify_prekey_batch_signature,
+    verify_signed_prekey_signature,
+};
 
 #[derive(Deserialize, Debug)]
 pub struct CreateDeviceBody {
@@ -20,11 +25,46 @@ pub struct CreateDeviceBody {
     pub prekey_pubkey: String,
     pub signed_prekey: SignedPreKey,
     pub one_time_prekeys: Vec<OneTimePrekeys>,
+    /// Signed ML-KEM one-time prekeys (PQXDH). Optional for classic clients.
+    #[serde(default)]
+    pub kem_one_time_prekeys: Vec<SignedPreKey>,
+    /// Signed ML-KEM last-resort prekey, served once one-time KEM keys run out.
+    #[serde(default)]
+    pub kem_last_resort_prekey: Option<SignedPreKey>,
     pub device_label: String,
     pub push_token: String,
     pub enrollment_token: String,
 }
 
+/// Confirmation for DELETE /devices/:id, signed by the calling device's
+/// identity key over `device_revocation_string(id, timestamp)`.
+#[derive(Deserialize, Debug)]
+pub struct RevokeDeviceBody {
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UploadPrekeysBody {
+    #[serde(default)]
+    pub one_time_prekeys: Vec<OneTimePrekeys>,
+    /// Identity-key signature over `prekey_batch_string(device, timestamp,
+    /// keys)`. Required with `timestamp` when `one_time_prekeys` is not empty.
+    #[serde(default)]
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    #[serde(default)]
+    pub kem_one_time_prekeys: Vec<SignedPreKey>,
+    #[serde(default)]
+    pub kem_last_resort_prekey: Option<SignedPreKey>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RotateSignedPrekeyBody {
+    pub signed_prekey: SignedPreKey,
+}
+
 pub async fn get_devices_for_user(
     State(state): State<AppState>,
     Path(user_id): Path<Uuid>,
@@ -72,14 +112,25 @@ pub async fn create_device(
                 .into_response();
         }
     }
-    let user: Option<Uuid> = verify_enrollment_token(&payload.enrollment_token, &state.jwt_secret);
+    let user: Option<Uuid> = verify_enrollment_token(
+        &payload.enrollment_token,
+        &state.jwt_secret,
+        &payload.identity_pubkey,
+    );
     // Check if the signature for the keys are valid
 
     if let Err(error) = verify_signed_prekey_signature(
         &payload.identity_pubkey,
         &payload.signed_prekey.key,
         &payload.signed_prekey.signature,
-    ) {
+    )
+    .and_then(|_| {
+        verify_kem_prekeys(
+            &payload.identity_pubkey,
+            &payload.kem_one_time_prekeys,
+            payload.kem_last_resort_prekey.as_ref(),
+        )
+    }) {
         eprintln!("Signature check failed : {}", error);
         return (
             StatusCode::UNAUTHORIZED,
@@ -87,46 +138,46 @@ pub async fn create_device(
         )
             .into_response();
     }
-    if let Some(id) = user {
-        if id != payload.user_id {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "error": "Wrong or expired enrollment token for user"
-                })),
-            )
-                .into_response();
-        }
-    };
-    let prekeys_json = json!(payload
+    if user != Some(payload.user_id) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "error": "Wrong or expired enrollment token for user"
+            })),
+        )
+            .into_response();
+    }
+    let prekeys: Vec<String> = payload
         .one_time_prekeys
         .iter()
-        .map(|p| &p.key)
-        .collect::<Vec<_>>());
+        .map(|p| p.key.clone())
+        .collect();
+    // The token is spent in the same transaction that creates the device, so
+    // a failed insert leaves it usable and a second enrollment cannot succeed.
     match device_repository::create_device(
         &state.pool,
+        &payload.enrollment_token,
         &payload.user_id,
         &payload.identity_pubkey,
         &payload.prekey_pubkey,
         &payload.signed_prekey.key,
         &payload.signed_prekey.signature,
-        &prekeys_json,
+        &prekeys,
+        &payload.kem_one_time_prekeys,
+        payload.kem_last_resort_prekey.as_ref(),
         &payload.device_label,
         &payload.push_token,
     )
     .await
     {
-        Ok(device) => match add_used_token(&state.pool, &payload.enrollment_token).await {
-            Ok(_) => (StatusCode::CREATED, Json(device)).into_response(),
-            Err(e) => {
-                eprintln!("Error when adding enrollment token to used : {}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({"error" : "Error creating device"})),
-                )
-                    .into_response()
-            }
-        },
+        Ok(Some(device)) => (StatusCode::CREATED, Json(device)).into_response(),
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "error": "Wrong or expired enrollment token for user"
+            })),
+        )
+            .into_response(),
         Err(e) => {
             if let sqlx::Error::Database(db_err) = &e {
                 if db_err.code().as_deref() == Some("23503") {
@@ -151,7 +202,9 @@ pub async fn get_user_keys(
     State(state): State<AppState>,
     Path(user_id): Path<Uuid>,
 ) -> impl IntoResponse {
-    match device_repository::get_device_bundle(&state.pool, &user_id).await {
+    match device_repository::claim_device_bundle(&state.pool, &user_id, state.prekey_low_threshold)
+        .await
+    {
         Ok(bundle) => (StatusCode::OK, Json(bundle)).into_response(),
         Err(_) => (
             StatusCode::NOT_FOUND,
@@ -174,3 +227,259 @@ pub async fn get_user_for_device(
             .into_response(),
     }
 }
+
+pub async fn upload_prekeys(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(payload): Json<UploadPrekeysBody>,
+) -> impl IntoResponse {
+    let keys: Vec<String> = payload
+        .one_time_prekeys
+        .into_iter()
+        .map(|p| p.key)
+        .collect();
+    if keys.is_empty()
+        && payload.kem_one_time_prekeys.is_empty()
+        && payload.kem_last_resort_prekey.is_none()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "No prekeys supplied"})),
+        )
+            .into_response();
+    }
+
+    let batch_timestamp = if keys.is_empty() {
+        None
+    } else {
+        payload.timestamp
+    };
+    if let Some(ts) = batch_timestamp {
+        if (chrono::Utc::now().timestamp() - ts).abs() > 30 {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Stale prekey batch"})),
+            )
+                .into_response();
+        }
+    }
+
+    let batch_check = if keys.is_empty() {
+        Ok(())
+    } else {
+        match (payload.signature.as_ref(), batch_timestamp) {
+            (Some(sig), Some(ts)) => {
+                verify_prekey_batch_signature(&device.identity_pubkey, &device.id, ts, &keys, sig)
+            }
+            _ => Err("Missing batch signature or timestamp".into()),
+        }
+    };
+    if let Err(error) = batch_check.and_then(|_| {
+        verify_kem_prekeys(
+            &device.identity_pubkey,
+            &payload.kem_one_time_prekeys,
+            payload.kem_last_resort_prekey.as_ref(),
+        )
+    }) {
+        eprintln!("Prekey batch check failed : {}", error);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Signature check failed."})),
+        )
+            .into_response();
+    }
+
+    match device_repository::add_prekeys(
+        &state.pool,
+        &device.id,
+        &keys,
+        batch_timestamp,
+        &payload.kem_one_time_prekeys,
+        payload.kem_last_resort_prekey.as_ref(),
+        state.max_one_time_prekeys,
+    )
+    .await
+    {
+        Ok(PrekeyUpload::Stored(stored, stored_kem)) => (
+            StatusCode::CREATED,
+            Json(json!({
+                "one_time_prekeys_count": stored,
+                "kem_one_time_prekeys_count": stored_kem
+            })),
+        )
+            .into_response(),
+        Ok(PrekeyUpload::Replayed) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Replayed prekey batch"})),
+        )
+            .into_response(),
+        Ok(PrekeyUpload::CapExceeded) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "Prekey cap exceeded",
+                "max_one_time_prekeys": state.max_one_time_prekeys
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error storing prekeys : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Error storing prekeys"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn rotate_signed_prekey(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(payload): Json<RotateSignedPrekeyBody>,
+) -> impl IntoResponse {
+    if let Err(error) = verify_signed_prekey_signature(
+        &device.identity_pubkey,
+        &payload.signed_prekey.key,
+        &payload.signed_prekey.signature,
+    ) {
+        eprintln!("Signature check failed : {}", error);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Signature check failed."})),
+        )
+            .into_response();
+    }
+
+    if payload.signed_prekey.key == device.signed_prekey_pub {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Signed prekey unchanged"})),
+        )
+            .into_response();
+    }
+
+    match device_repository::rotate_signed_prekey(
+        &state.pool,
+        &device.id,
+        &payload.signed_prekey.key,
+        &payload.signed_prekey.signature,
+        state.signed_prekey_grace_hours,
+    )
+    .await
+    {
+        Ok(device) => (
+            StatusCode::OK,
+            Json(json!({
+                "signed_prekey_pub": device.signed_prekey_pub,
+                "previous_valid_for_hours": state.signed_prekey_grace_hours
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error rotating signed prekey : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Error rotating signed prekey"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn revoke_device(
+    State(state): State<AppState>,
+    AuthenticatedDevice(caller): AuthenticatedDevice,
+    Path(device_id): Path<Uuid>,
+    Json(payload): Json<RevokeDeviceBody>,
+) -> impl IntoResponse {
+    if device_id == caller.id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "A device must be revoked from another device"})),
+        )
+            .into_response();
+    }
+
+    if (chrono::Utc::now().timestamp() - payload.timestamp).abs() > 30 {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Expired confirmation"})),
+        )
+            .into_response();
+    }
+    if let Err(error) = verify_device_revocation_signature(
+        &caller.identity_pubkey,
+        &device_id,
+        payload.timestamp,
+        &payload.signature,
+    ) {
+        eprintln!("Revocation confirmation failed : {}", error);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Signature check failed."})),
+        )
+            .into_response();
+    }
+
+    let username = match user_repository::find_user_by_id(&state.pool, &caller.user_id).await {
+        Ok(Some(u)) => u.username,
+        _ => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Cannot resolve user"})),
+            )
+                .into_response()
+        }
+    };
+    let removal = S2sDeviceRemoved {
+        from_federated_address: format!("{}@{}", username, state.this_node_id),
+        device_id,
+    };
+    let removal_json = match serde_json::to_value(&removal) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to serialize device removal: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal error"})),
+            )
+                .into_response();
+        }
+    };
+
+    match device_repository::delete_device(&state.pool, &device_id, &caller.user_id, &removal_json)
+        .await
+    {
+        Ok(Some(peers)) => (
+            StatusCode::OK,
+            Json(json!({"device_id": device_id, "notified_peers": peers.len()})),
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Device not found"})),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error revoking device : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Error revoking device"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Every ML-KEM prekey must be an ML-KEM-1024 public key carrying an
+/// identity-key signature over its `kem_prekey_string`.
+fn verify_kem_prekeys(
+    identity_pubkey: &str,
+    one_time: &[SignedPreKey],
+    last_resort: Option<&SignedPreKey>,
+) -> Result<(), String> {
+    for kem in one_time.iter().chain(last_resort) {
+        verify_kem_prekey_signature(identity_pubkey, &kem.key, &kem.signature)?;
+    }
+    Ok(())
+}
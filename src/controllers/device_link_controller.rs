@@ -0,0 +1,211 @@
+use axum::extract::Path;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+use crate::middlewares::auth::AuthenticatedDevice;
+use crate::repository::device_link_repository;
+use crate::services::auth::generate_link_enrollment_token;
+use crate::utils::crypto_utils::verify_device_link_approval;
+
+/// Unapproved requests a user can have open at once; keeps anonymous callers
+/// from flooding a user's devices with approval prompts.
+const MAX_PENDING_LINK_REQUESTS: i64 = 5;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateLinkRequestBody {
+    pub user_id: Uuid,
+    pub identity_pubkey: String,
+    #[serde(default)]
+    pub device_label: Option<String>,
+}
+
+/// Signature by the approving device's identity key over
+/// `device_link_approval_string(request_id, new_identity_pubkey)`.
+#[derive(Deserialize, Debug)]
+pub struct ApproveLinkRequestBody {
+    pub signature: String,
+}
+
+pub async fn create_link_request(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateLinkRequestBody>,
+) -> impl IntoResponse {
+    match device_link_repository::create_link_request(
+        &state.pool,
+        &payload.user_id,
+        &payload.identity_pubkey,
+        payload.device_label.as_deref(),
+        MAX_PENDING_LINK_REQUESTS,
+    )
+    .await
+    {
+        Ok(Some(request)) => (StatusCode::CREATED, Json(request)).into_response(),
+        Ok(None) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({"error": "Too many pending link requests"})),
+        )
+            .into_response(),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "User not found"})),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error creating link request : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Error creating link request"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn get_pending_link_requests(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+) -> impl IntoResponse {
+    match device_link_repository::get_pending_link_requests(&state.pool, &device.user_id).await {
+        Ok(requests) => (StatusCode::OK, Json(requests)).into_response(),
+        Err(e) => {
+            eprintln!("Error when fetching link requests {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal server error"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn approve_link_request(
+    State(state): State<AppState>,
+    AuthenticatedDevice(approver): AuthenticatedDevice,
+    Path(request_id): Path<Uuid>,
+    Json(payload): Json<ApproveLinkRequestBody>,
+) -> impl IntoResponse {
+    let request = match device_link_repository::get_link_request(&state.pool, &request_id).await {
+        Ok(Some(r)) if r.user_id == approver.user_id => r,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "Link request not found"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("Database error fetching link request : {:?}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal server error"})),
+            )
+                .into_response();
+        }
+    };
+
+    if let Err(error) = verify_device_link_approval(
+        &approver.identity_pubkey,
+        &request.id,
+        &request.identity_pubkey,
+        &payload.signature,
+    ) {
+        eprintln!("Link approval check failed : {}", error);
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Signature check failed."})),
+        )
+            .into_response();
+    }
+
+    let token = generate_link_enrollment_token(
+        &request.user_id,
+        &request.identity_pubkey,
+        request.expires_at,
+        &state.jwt_secret,
+    );
+    match device_link_repository::approve_link_request(
+        &state.pool,
+        &request.id,
+        &approver.user_id,
+        &approver.id,
+        &token,
+    )
+    .await
+    {
+        Ok(true) => (StatusCode::OK, Json(json!({"status": "approved"}))).into_response(),
+        Ok(false) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "Link request expired or already approved"})),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error approving link request : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Error approving link request"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Polled by the new device. The token is bound to the request's identity
+/// key, so it is useless to anyone who cannot sign with that key.
+pub async fn get_link_request_status(
+    State(state): State<AppState>,
+    Path(request_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let request = match device_link_repository::get_link_request(&state.pool, &request_id).await {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "Link request not found"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("Database error fetching link request : {:?}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal server error"})),
+            )
+                .into_response();
+        }
+    };
+
+    // The enrollment token expires with the request, approved or not.
+    let expired = request.expires_at <= chrono::Utc::now();
+    if request.status != "approved" || expired {
+        let status = if expired { "expired" } else { "pending" };
+        return (
+            StatusCode::OK,
+            Json(json!({"id": request.id, "status": status, "expires_at": request.expires_at})),
+        )
+            .into_response();
+    }
+
+    match device_link_repository::get_link_enrollment_token(&state.pool, &request.id).await {
+        Ok(token) => (
+            StatusCode::OK,
+            Json(json!({
+                "id": request.id,
+                "status": "approved",
+                "user_id": request.user_id,
+                "enrollment_token": token
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Database error fetching link token : {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal server error"})),
+            )
+                .into_response()
+        }
+    }
+}
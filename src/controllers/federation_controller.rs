@@ -1,19 +1,37 @@
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
-    federation::client::FederationClient,
+    controllers::{
+        attachments_controller,
+        messages_controller::{self, Rejection},
+    },
+    federation::{client::FederationClient, groups, parse_federated_address},
     middlewares::node_auth::AuthenticatedNode,
-    models::federation::{NodeInfo, S2sAck, S2sMessagePayload, S2sSessionPayload},
+    models::{
+        chat::GroupRole,
+        federation::{
+            FederationNode, NodeInfo, S2sAck, S2sBatchItemResult, S2sBatchResponse, S2sChatExpiry,
+            S2sDeviceRemoved, S2sGroupLeave, S2sGroupMessage, S2sGroupState, S2sMessageBatch,
+            S2sMessagePayload, S2sReceipt, S2sSenderKey, S2sSessionPayload, MAX_MESSAGE_BATCH,
+            PROTOCOL_VERSION,
+        },
+        message::{
+            DeviceMismatch, OutgoingMessage, OutgoingMessagePayload, ReceiptStatus, StoreOutcome,
+        },
+        sender_key::OutgoingGroupMessage,
+    },
     repository::{
-        device_repository, federation_repository, message_repository, session_repository,
+        chat_repository, device_repository, federation_repository, group_message_repository,
+        message_repository, session_repository,
     },
 };
 
@@ -25,7 +43,8 @@ pub async fn node_info(State(state): State<AppState>) -> impl IntoResponse {
         node_id: state.this_node_id.clone(),
         api_url: state.this_api_url.clone(),
         public_key_b64: state.node_keys.public_b64.clone(),
-        protocol_version: "0.0.2",
+        protocol_version: PROTOCOL_VERSION.into(),
+        key_rotation: state.node_keys.active_rotation().cloned(),
     };
     (StatusCode::OK, Json(info))
 }
@@ -112,7 +131,9 @@ pub async fn get_user_keys(
             }
         };
 
-    match device_repository::get_device_bundle(&state.pool, &user_id).await {
+    match device_repository::claim_device_bundle(&state.pool, &user_id, state.prekey_low_threshold)
+        .await
+    {
         Ok(bundle) => {
             debug!(%username, devices = bundle.len(), "returning key bundle");
             (StatusCode::OK, Json(bundle)).into_response()
@@ -186,8 +207,79 @@ pub async fn receive_session(
         )
             .into_response();
     }
+    if let Err(resp) =
+        check_device_owner(&state, &peer, payload.from_device_id, sender_local_id).await
+    {
+        return resp.into_response();
+    }
 
     for init in &payload.sessions_init {
+        if init.kem_prekey_id.is_some() != init.kem_ciphertext.is_some() {
+            warn!(recipient_device = %init.recipient_device_id, "session init has partial KEM fields");
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "kem_prekey_id and kem_ciphertext go together"})),
+            )
+                .into_response();
+        }
+
+        if let Some(ref spk) = init.recipient_spk_pub {
+            match device_repository::is_signed_prekey_valid(
+                &state.pool,
+                &init.recipient_device_id,
+                spk,
+            )
+            .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(recipient_device = %init.recipient_device_id, "session init uses expired signed prekey");
+                    return (
+                        StatusCode::CONFLICT,
+                        Json(json!({"error": "stale signed prekey"})),
+                    )
+                        .into_response();
+                }
+                Err(e) => {
+                    error!(recipient_device = %init.recipient_device_id, err = %e, "signed prekey lookup failed");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"error": "internal error"})),
+                    )
+                        .into_response();
+                }
+            }
+        }
+
+        if let Some(kem_prekey_id) = init.kem_prekey_id {
+            match device_repository::is_kem_prekey_valid(
+                &state.pool,
+                &init.recipient_device_id,
+                kem_prekey_id,
+                state.signed_prekey_grace_hours,
+            )
+            .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(recipient_device = %init.recipient_device_id, kem_prekey_id, "session init uses unknown or expired KEM prekey");
+                    return (
+                        StatusCode::CONFLICT,
+                        Json(json!({"error": "stale KEM prekey"})),
+                    )
+                        .into_response();
+                }
+                Err(e) => {
+                    error!(recipient_device = %init.recipient_device_id, err = %e, "KEM prekey lookup failed");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"error": "internal error"})),
+                    )
+                        .into_response();
+                }
+            }
+        }
+
         debug!(recipient_device = %init.recipient_device_id, "inserting pending session");
         if let Err(e) = session_repository::create_pending_session(
             &state.pool,
@@ -196,6 +288,9 @@ pub async fn receive_session(
             &init.ephemeral_pubkey,
             &init.sender_prekey_pub,
             &init.otpk_used,
+            init.recipient_spk_pub.as_deref(),
+            init.kem_prekey_id,
+            init.kem_ciphertext.as_deref(),
             &init.ciphertext,
         )
         .await
@@ -228,7 +323,56 @@ pub async fn receive_messages(
         device_count = payload.payloads.len(),
         "POST /s2s/messages"
     );
+    match store_messages(&state, &peer, payload).await {
+        Ok(ack) => (StatusCode::OK, Json(ack)).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// ─── POST /s2s/messages/batch ────────────────────────────────────────────────
+
+/// Several POST /s2s/messages in one request (protocol 0.0.4). Each message
+/// is handled on its own; the response lists, in request order, the status
+/// and body the single endpoint would have answered.
+pub async fn receive_message_batch(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(batch): Json<S2sMessageBatch>,
+) -> impl IntoResponse {
+    info!(peer = %peer.node_id, count = batch.messages.len(), "POST /s2s/messages/batch");
+
+    if batch.messages.len() > MAX_MESSAGE_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("at most {MAX_MESSAGE_BATCH} messages per batch")})),
+        )
+            .into_response();
+    }
+
+    let mut results = Vec::with_capacity(batch.messages.len());
+    for payload in batch.messages {
+        let result = match store_messages(&state, &peer, payload).await {
+            Ok(ack) => S2sBatchItemResult {
+                status: StatusCode::OK.as_u16(),
+                body: serde_json::to_value(ack).unwrap_or(Value::Null),
+            },
+            Err((status, Json(body))) => S2sBatchItemResult {
+                status: status.as_u16(),
+                body,
+            },
+        };
+        results.push(result);
+    }
+    (StatusCode::OK, Json(S2sBatchResponse { results })).into_response()
+}
 
+/// Store one forwarded message for a local recipient. Shared by the single
+/// and batch endpoints, which render the ack or rejection themselves.
+async fn store_messages(
+    state: &AppState,
+    peer: &FederationNode,
+    payload: S2sMessagePayload,
+) -> Result<S2sAck, Rejection> {
     let recipient_id =
         match federation_repository::get_local_user_id_by_username(&state.pool, &payload.to_user)
             .await
@@ -239,22 +383,27 @@ pub async fn receive_messages(
             }
             Ok(None) => {
                 warn!(username = %payload.to_user, "recipient not found or is a shadow record");
-                return (
+                return Err((
                     StatusCode::NOT_FOUND,
                     Json(json!({"error": "recipient not found or not local to this node"})),
-                )
-                    .into_response();
+                ));
             }
             Err(e) => {
                 error!(username = %payload.to_user, err = %e, "db error resolving recipient");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({"error": "internal error"})),
-                )
-                    .into_response();
+                return Err(internal_rejection());
             }
         };
 
+    // Same device-set check as a local send; the 409 body tells the sending
+    // node which devices to re-encrypt for.
+    let targets: Vec<Uuid> = payload.payloads.iter().map(|p| p.to_device_id).collect();
+    if let Err(resp) =
+        messages_controller::check_recipient_devices(state, &recipient_id, &targets).await
+    {
+        warn!(logical_id = %payload.logical_msg_id, status = %resp.0, "device set rejected");
+        return Err(resp);
+    }
+
     let sender_username = payload
         .from_federated_address
         .split('@')
@@ -275,11 +424,7 @@ pub async fn receive_messages(
         }
         Err(e) => {
             error!(err = %e, "shadow user upsert failed");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "internal error"})),
-            )
-                .into_response();
+            return Err(internal_rejection());
         }
     };
 
@@ -292,12 +437,9 @@ pub async fn receive_messages(
     .await
     {
         error!(device_id = %payload.from_device_id, err = %e, "shadow device upsert failed");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "internal error"})),
-        )
-            .into_response();
+        return Err(internal_rejection());
     }
+    check_device_owner(state, peer, payload.from_device_id, sender_local_id).await?;
 
     let chat_id = match federation_repository::get_or_create_direct_chat(
         &state.pool,
@@ -312,6 +454,170 @@ pub async fn receive_messages(
         }
         Err(e) => {
             error!(err = %e, "get_or_create_direct_chat failed");
+            return Err(internal_rejection());
+        }
+    };
+
+    // The whole fan-out goes through the same transactional insert as a local
+    // send, so a failure halfway leaves no partial set of rows behind.
+    let logical_msg_id = payload.logical_msg_id.clone();
+    let msg = OutgoingMessage {
+        chat_id,
+        logical_msg_id: payload.logical_msg_id,
+        to_user_id: recipient_id,
+        to_user_address: None,
+        expires_at: payload.expires_at,
+        attachment_ids: Vec::new(),
+        payloads: payload
+            .payloads
+            .into_iter()
+            .map(|dev| OutgoingMessagePayload {
+                to_device_id: dev.to_device_id,
+                header: dev.header,
+                ciphertext: dev.ciphertext,
+            })
+            .collect(),
+    };
+    let status = match message_repository::insert_message(
+        &state.pool,
+        payload.from_device_id,
+        sender_local_id,
+        msg,
+    )
+    .await
+    {
+        Ok(StoreOutcome::Stored) => "delivered",
+        Ok(StoreOutcome::Duplicate) => "duplicate",
+        Ok(StoreOutcome::IdTaken) => {
+            warn!(logical_id = %logical_msg_id, "logical id already used by another device");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "logical_msg_id is already in use"})),
+            ));
+        }
+        Err(e) => {
+            error!(logical_id = %logical_msg_id, err = %e, "message insert failed");
+            return Err(internal_rejection());
+        }
+    };
+    info!(logical_id = %logical_msg_id, %status, "messages processed");
+
+    let ack = S2sAck {
+        logical_msg_id,
+        status: status.into(),
+    };
+    Ok(ack)
+}
+
+// ─── POST /s2s/ack ───────────────────────────────────────────────────────────
+
+pub async fn receive_ack(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(ack): Json<S2sAck>,
+) -> impl IntoResponse {
+    info!(peer = %peer.node_id, logical_id = %ack.logical_msg_id, status = %ack.status, "POST /s2s/ack");
+
+    if let Err(e) =
+        federation_repository::mark_outbox_delivered_by_logical_id(&state.pool, &ack.logical_msg_id)
+            .await
+    {
+        error!(logical_id = %ack.logical_msg_id, err = %e, "ack db update failed");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "internal error"})),
+        )
+            .into_response();
+    }
+    (StatusCode::OK, Json(json!({"status": "ack received"}))).into_response()
+}
+
+// ─── POST /s2s/receipts ──────────────────────────────────────────────────────
+
+pub async fn receive_receipt(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(receipt): Json<S2sReceipt>,
+) -> impl IntoResponse {
+    info!(
+        peer       = %peer.node_id,
+        logical_id = %receipt.ack.logical_msg_id,
+        status     = %receipt.ack.status,
+        from       = %receipt.from_federated_address,
+        to_user    = %receipt.to_user,
+        "POST /s2s/receipts"
+    );
+
+    let Some(status) = ReceiptStatus::parse(&receipt.ack.status) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "status must be \"delivered\" or \"read\""})),
+        )
+            .into_response();
+    };
+
+    // A peer may only report receipts on behalf of its own users.
+    let Some((reader_username, reader_node)) = receipt.from_federated_address.split_once('@')
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid from_federated_address"})),
+        )
+            .into_response();
+    };
+    if reader_node != peer.node_id {
+        warn!(peer = %peer.node_id, from = %receipt.from_federated_address, "receipt for a foreign user");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "reader is not homed on the calling node"})),
+        )
+            .into_response();
+    }
+
+    let sender_id =
+        match federation_repository::get_local_user_id_by_username(&state.pool, &receipt.to_user)
+            .await
+        {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(json!({"error": "sender not found or not local to this node"})),
+                )
+                    .into_response();
+            }
+            Err(e) => {
+                error!(username = %receipt.to_user, err = %e, "db error resolving receipt sender");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "internal error"})),
+                )
+                    .into_response();
+            }
+        };
+
+    // Only a message this node actually forwarded to that reader can be
+    // acknowledged; anything else would be a forged receipt.
+    let group_id = match federation_repository::find_receipt_origin(
+        &state.pool,
+        &peer.node_id,
+        &receipt.ack.logical_msg_id,
+        &format!("{}@{}", receipt.to_user, state.this_node_id),
+        &receipt.from_federated_address,
+    )
+    .await
+    {
+        Ok(Some(group_id)) => group_id,
+        Ok(None) => {
+            warn!(peer = %peer.node_id, logical_id = %receipt.ack.logical_msg_id, "receipt for an unknown message");
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "no such message was sent to this reader"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!(logical_id = %receipt.ack.logical_msg_id, err = %e, "db error resolving receipt origin");
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({"error": "internal error"})),
@@ -320,71 +626,694 @@ pub async fn receive_messages(
         }
     };
 
-    let mut any_new = false;
-    for dev in &payload.payloads {
-        debug!(to_device = %dev.to_device_id, "inserting device payload");
-        match message_repository::insert_federated_message(
+    let result = async {
+        let reader_id = federation_repository::upsert_shadow_user(
             &state.pool,
-            &payload.logical_msg_id,
+            reader_username,
+            &receipt.from_federated_address,
+            peer.id,
+        )
+        .await?;
+        let chat_id = match group_id {
+            Some(group_id) => Some(group_id),
+            None => {
+                federation_repository::get_direct_chat(&state.pool, sender_id, reader_id).await?
+            }
+        };
+        message_repository::emit_receipt_event(
+            &state.pool,
+            &sender_id,
             chat_id,
-            sender_local_id,
-            payload.from_device_id,
-            recipient_id,
-            dev.to_device_id,
-            &dev.header,
-            &dev.ciphertext,
+            status,
+            std::slice::from_ref(&receipt.ack.logical_msg_id),
+            &reader_id,
+            &receipt.from_device_id,
         )
         .await
-        {
-            Ok(true) => {
-                debug!(to_device = %dev.to_device_id, "message inserted");
-                any_new = true;
+    }
+    .await;
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({"status": "receipt received"}))).into_response(),
+        Err(e) => {
+            error!(logical_id = %receipt.ack.logical_msg_id, err = %e, "receipt processing failed");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal error"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+// ─── POST /s2s/chats/expiry ──────────────────────────────────────────────────
+
+/// Apply a TTL change a user of `peer` made to their direct chat with one of
+/// our users. The chat is created if this node has not seen it yet, as for
+/// an incoming message, so the setting holds for the first messages too.
+pub async fn receive_chat_expiry(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(payload): Json<S2sChatExpiry>,
+) -> impl IntoResponse {
+    info!(
+        peer    = %peer.node_id,
+        from    = %payload.from_federated_address,
+        to_user = %payload.to_user,
+        ttl     = ?payload.message_ttl_seconds,
+        "POST /s2s/chats/expiry"
+    );
+
+    if payload.message_ttl_seconds.is_some_and(|ttl| ttl <= 0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "message_ttl_seconds must be positive or null"})),
+        )
+            .into_response();
+    }
+
+    let Some((setter_username, setter_node)) =
+        parse_federated_address(&payload.from_federated_address)
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid from_federated_address"})),
+        )
+            .into_response();
+    };
+    if setter_node != peer.node_id {
+        warn!(peer = %peer.node_id, from = %payload.from_federated_address, "chat expiry for a foreign user");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "setter is not homed on the calling node"})),
+        )
+            .into_response();
+    }
+
+    let local_id = match federation_repository::get_local_user_id_by_username(
+        &state.pool,
+        &payload.to_user,
+    )
+    .await
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "recipient not found"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!(username = %payload.to_user, err = %e, "db error resolving chat expiry target");
+            return internal_error();
+        }
+    };
+
+    let result = async {
+        let setter_id = federation_repository::upsert_shadow_user(
+            &state.pool,
+            setter_username,
+            &payload.from_federated_address,
+            peer.id,
+        )
+        .await?;
+        let chat_id =
+            federation_repository::get_or_create_direct_chat(&state.pool, setter_id, local_id)
+                .await?;
+        chat_repository::set_chat_message_ttl(
+            &state.pool,
+            &chat_id,
+            &setter_id,
+            payload.message_ttl_seconds,
+        )
+        .await
+    }
+    .await;
+
+    match result {
+        Ok(_) => (StatusCode::OK, Json(json!({"status": "expiry applied"}))).into_response(),
+        Err(e) => {
+            error!(from = %payload.from_federated_address, err = %e, "chat expiry update failed");
+            internal_error()
+        }
+    }
+}
+
+// ─── GET /s2s/attachments/:id ────────────────────────────────────────────────
+
+/// Serve an attachment that one of our users sent to a user of `peer`. The
+/// peer forwards the recipient's download token; only attachments referenced
+/// by a federated message are served, until their hold expires.
+pub async fn serve_attachment(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Path(attachment_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    info!(peer = %peer.node_id, %attachment_id, "GET /s2s/attachments/:id");
+    attachments_controller::serve_blob(&state, attachment_id, &headers, true).await
+}
+
+// ─── POST /s2s/devices/removed ───────────────────────────────────────────────
+
+pub async fn receive_device_removed(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(payload): Json<S2sDeviceRemoved>,
+) -> impl IntoResponse {
+    info!(peer = %peer.node_id, device_id = %payload.device_id, from = %payload.from_federated_address, "POST /s2s/devices/removed");
+
+    match federation_repository::delete_shadow_device(&state.pool, payload.device_id, peer.id).await
+    {
+        Ok(true) => (StatusCode::OK, Json(json!({"status": "removed"}))).into_response(),
+        Ok(false) => {
+            // Never seen here, already removed, or not homed on this peer.
+            debug!(device_id = %payload.device_id, "no shadow device to remove");
+            (StatusCode::OK, Json(json!({"status": "unknown"}))).into_response()
+        }
+        Err(e) => {
+            error!(device_id = %payload.device_id, err = %e, "shadow device removal failed");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal error"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+// ─── POST /s2s/groups/state ──────────────────────────────────────────────────
+
+/// Apply a membership snapshot from the home node of a group. Snapshots
+/// older than our mirror are ignored, as are groups without local members
+/// that we do not mirror yet.
+pub async fn receive_group_state(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(payload): Json<S2sGroupState>,
+) -> impl IntoResponse {
+    info!(
+        peer    = %peer.node_id,
+        chat_id = %payload.chat_id,
+        version = payload.version,
+        members = payload.members.len(),
+        change  = %payload.change,
+        "POST /s2s/groups/state"
+    );
+
+    let origin = match chat_repository::get_group_origin(&state.pool, &payload.chat_id).await {
+        Ok(origin) => origin,
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "db error looking up group");
+            return internal_error();
+        }
+    };
+    if let Some(ref origin) = origin {
+        if origin.home_node_id != Some(peer.id) {
+            warn!(peer = %peer.node_id, chat_id = %payload.chat_id, "group state from a node that is not its home");
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "group is not homed on the calling node"})),
+            )
+                .into_response();
+        }
+        if origin.membership_version >= payload.version {
+            debug!(chat_id = %payload.chat_id, "stale group state, ignored");
+            return (StatusCode::OK, Json(json!({"status": "stale"}))).into_response();
+        }
+    }
+
+    // The owner's node is the group's home; only it can send snapshots.
+    let mut owners = payload
+        .members
+        .iter()
+        .filter(|m| m.role == GroupRole::Owner)
+        .map(|m| parse_federated_address(&m.address).map(|(_, node)| node));
+    if owners.next() != Some(Some(peer.node_id.as_str())) || owners.next().is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "group must have one owner, homed on the calling node"})),
+        )
+            .into_response();
+    }
+
+    let mut members = Vec::with_capacity(payload.members.len());
+    let mut any_local = false;
+    for member in &payload.members {
+        let Some((username, node_id)) = parse_federated_address(&member.address) else {
+            warn!(address = %member.address, "invalid member address, skipped");
+            continue;
+        };
+        if node_id != state.this_node_id {
+            continue;
+        }
+        match federation_repository::get_local_user_id_by_username(&state.pool, username).await {
+            Ok(Some(id)) => {
+                members.push((id, member.role));
+                any_local = true;
+            }
+            Ok(None) => warn!(address = %member.address, "unknown local member, skipped"),
+            Err(e) => {
+                error!(address = %member.address, err = %e, "db error resolving member");
+                return internal_error();
             }
-            Ok(false) => {
-                debug!(to_device = %dev.to_device_id, "duplicate, skipped");
+        }
+    }
+    if !any_local && origin.is_none() {
+        debug!(chat_id = %payload.chat_id, "no local members, ignored");
+        return (StatusCode::OK, Json(json!({"status": "ignored"}))).into_response();
+    }
+
+    for member in &payload.members {
+        let Some((username, node_id)) = parse_federated_address(&member.address) else {
+            continue;
+        };
+        if node_id == state.this_node_id {
+            continue;
+        }
+        let home = if node_id == peer.node_id {
+            peer.clone()
+        } else {
+            match messages_controller::resolve_node(&state, node_id).await {
+                Ok(node) => node,
+                Err(resp) => {
+                    warn!(address = %member.address, status = %resp.status(), "member node unresolved");
+                    return resp;
+                }
             }
+        };
+        match federation_repository::upsert_shadow_user(
+            &state.pool,
+            username,
+            &member.address,
+            home.id,
+        )
+        .await
+        {
+            Ok(id) => members.push((id, member.role)),
             Err(e) => {
-                error!(to_device = %dev.to_device_id, err = %e, "message insert failed");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({"error": "internal error"})),
-                )
-                    .into_response();
+                error!(address = %member.address, err = %e, "shadow user upsert failed");
+                return internal_error();
             }
         }
     }
 
-    let status = if any_new { "delivered" } else { "duplicate" };
-    info!(logical_id = %payload.logical_msg_id, %status, "messages processed");
+    match chat_repository::apply_group_state(&state.pool, &peer.id, &payload, &members).await {
+        Ok(true) => {
+            info!(chat_id = %payload.chat_id, version = payload.version, "group state applied");
+            (StatusCode::OK, Json(json!({"status": "applied"}))).into_response()
+        }
+        Ok(false) => (StatusCode::OK, Json(json!({"status": "stale"}))).into_response(),
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "applying group state failed");
+            internal_error()
+        }
+    }
+}
 
-    let ack = S2sAck {
-        logical_msg_id: payload.logical_msg_id,
-        status: status.into(),
+// ─── POST /s2s/groups/leave ──────────────────────────────────────────────────
+
+/// A user of `peer` left a group homed here.
+pub async fn receive_group_leave(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(payload): Json<S2sGroupLeave>,
+) -> impl IntoResponse {
+    info!(peer = %peer.node_id, chat_id = %payload.chat_id, member = %payload.member_address, "POST /s2s/groups/leave");
+
+    if parse_federated_address(&payload.member_address).map(|(_, node)| node)
+        != Some(peer.node_id.as_str())
+    {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "member is not homed on the calling node"})),
+        )
+            .into_response();
+    }
+
+    match chat_repository::get_group_origin(&state.pool, &payload.chat_id).await {
+        Ok(Some(origin)) if origin.is_local() => {}
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "group not found or not homed on this node"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "db error looking up group");
+            return internal_error();
+        }
+    }
+
+    let result = async {
+        let Some(user_id) = federation_repository::get_shadow_user_id(
+            &state.pool,
+            &payload.member_address,
+            peer.id,
+        )
+        .await?
+        else {
+            return Ok(false);
+        };
+        chat_repository::remove_group_member(
+            &state.pool,
+            &payload.chat_id,
+            &user_id,
+            &user_id,
+            "member_left",
+        )
+        .await
+    }
+    .await;
+
+    match result {
+        Ok(true) => {
+            if let Err(e) = groups::publish_group_state(
+                &state,
+                &payload.chat_id,
+                "member_left",
+                std::slice::from_ref(&peer.node_id),
+            )
+            .await
+            {
+                error!(chat_id = %payload.chat_id, err = %e, "publishing group state failed");
+            }
+            (StatusCode::OK, Json(json!({"status": "left"}))).into_response()
+        }
+        Ok(false) => (StatusCode::OK, Json(json!({"status": "unknown"}))).into_response(),
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "removing group member failed");
+            internal_error()
+        }
+    }
+}
+
+// ─── POST /s2s/groups/sender-keys ────────────────────────────────────────────
+
+/// Queue a remote member's sender key for our members' devices, which the
+/// payloads must cover exactly (409 with the DeviceMismatch otherwise).
+pub async fn receive_sender_key(
+    State(state): State<AppState>,
+    AuthenticatedNode(peer): AuthenticatedNode,
+    Json(payload): Json<S2sSenderKey>,
+) -> impl IntoResponse {
+    info!(
+        peer    = %peer.node_id,
+        chat_id = %payload.chat_id,
+        key_id  = %payload.key_id,
+        from    = %payload.from_federated_address,
+        device_count = payload.payloads.len(),
+        "POST /s2s/groups/sender-keys"
+    );
+
+    let sender_id = match group_sender(
+        &state,
+        &peer,
+        &payload.chat_id,
+        &payload.from_federated_address,
+        payload.from_device_id,
+        &payload.from_identity_pubkey,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(resp) => return resp,
     };
-    (StatusCode::OK, Json(ack)).into_response()
+
+    let targets = match group_message_repository::get_group_targets(
+        &state.pool,
+        &payload.chat_id,
+        &payload.from_device_id,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "db error loading group devices");
+            return internal_error();
+        }
+    };
+
+    let target_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    let payload_ids: Vec<Uuid> = payload.payloads.iter().map(|p| p.to_device_id).collect();
+    if let Some(resp) = messages_controller::device_set_error(&target_ids, &payload_ids) {
+        warn!(key_id = %payload.key_id, status = %resp.0, "sender key device set rejected");
+        return resp.into_response();
+    }
+
+    let distributions: Vec<OutgoingMessagePayload> = payload
+        .payloads
+        .iter()
+        .map(|p| OutgoingMessagePayload {
+            to_device_id: p.to_device_id,
+            header: p.header.clone(),
+            ciphertext: p.ciphertext.clone(),
+        })
+        .collect();
+    match group_message_repository::store_sender_key(
+        &state.pool,
+        &payload.chat_id,
+        &sender_id,
+        &payload.from_device_id,
+        &payload.key_id,
+        &distributions,
+        &targets,
+    )
+    .await
+    {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({"status": "ok", "recipients": targets.len()})),
+        )
+            .into_response(),
+        Err(e) => {
+            error!(key_id = %payload.key_id, err = %e, "storing sender key failed");
+            internal_error()
+        }
+    }
 }
 
-// ─── POST /s2s/ack ───────────────────────────────────────────────────────────
+// ─── POST /s2s/groups/messages ───────────────────────────────────────────────
 
-pub async fn receive_ack(
+/// Fan a remote member's group message out to our members' devices. 409
+/// with the DeviceMismatch if they do not all hold the sender's current key.
+pub async fn receive_group_message(
     State(state): State<AppState>,
     AuthenticatedNode(peer): AuthenticatedNode,
-    Json(ack): Json<S2sAck>,
+    Json(payload): Json<S2sGroupMessage>,
 ) -> impl IntoResponse {
-    info!(peer = %peer.node_id, logical_id = %ack.logical_msg_id, status = %ack.status, "POST /s2s/ack");
+    info!(
+        peer       = %peer.node_id,
+        chat_id    = %payload.chat_id,
+        logical_id = %payload.logical_msg_id,
+        from       = %payload.from_federated_address,
+        "POST /s2s/groups/messages"
+    );
 
-    if let Err(e) =
-        federation_repository::mark_outbox_delivered_by_logical_id(&state.pool, &ack.logical_msg_id)
-            .await
+    let sender_id = match group_sender(
+        &state,
+        &peer,
+        &payload.chat_id,
+        &payload.from_federated_address,
+        payload.from_device_id,
+        &payload.from_identity_pubkey,
+    )
+    .await
     {
-        error!(logical_id = %ack.logical_msg_id, err = %e, "ack db update failed");
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    let (key, targets) = match async {
+        let key = group_message_repository::get_sender_key(
+            &state.pool,
+            &payload.chat_id,
+            &payload.from_device_id,
+        )
+        .await?;
+        let targets = group_message_repository::get_group_targets(
+            &state.pool,
+            &payload.chat_id,
+            &payload.from_device_id,
+        )
+        .await?;
+        Ok::<_, sqlx::Error>((key, targets))
+    }
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!(chat_id = %payload.chat_id, err = %e, "db error loading sender key");
+            return internal_error();
+        }
+    };
+
+    // Without the current key, none of our devices can decrypt the message.
+    let target_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    let holders = match key {
+        Some(ref key) if key.key_id == payload.sender_key_id => key.recipient_device_ids.as_slice(),
+        _ => &[],
+    };
+    if let Some(mismatch) = DeviceMismatch::compare(&target_ids, holders) {
+        warn!(logical_id = %payload.logical_msg_id, missing = mismatch.missing_devices.len(), "sender key not held by our devices");
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "internal error"})),
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "sender key not distributed to the current devices",
+                "missing_devices": mismatch.missing_devices,
+                "extra_devices": mismatch.extra_devices,
+            })),
         )
             .into_response();
     }
-    (StatusCode::OK, Json(json!({"status": "ack received"}))).into_response()
+
+    let msg = OutgoingGroupMessage {
+        logical_msg_id: payload.logical_msg_id.clone(),
+        sender_key_id: payload.sender_key_id,
+        header: payload.header,
+        ciphertext: payload.ciphertext,
+        expires_at: payload.expires_at,
+        attachment_ids: Vec::new(),
+    };
+    let status = match group_message_repository::insert_group_message(
+        &state.pool,
+        &payload.chat_id,
+        &sender_id,
+        &payload.from_device_id,
+        &msg,
+        &targets,
+    )
+    .await
+    {
+        Ok(StoreOutcome::Stored) => "delivered",
+        Ok(StoreOutcome::Duplicate) => "duplicate",
+        Ok(StoreOutcome::IdTaken) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "logical_msg_id is already in use"})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!(logical_id = %payload.logical_msg_id, err = %e, "group message insert failed");
+            return internal_error();
+        }
+    };
+
+    info!(logical_id = %payload.logical_msg_id, %status, recipients = targets.len(), "group message processed");
+    let ack = S2sAck {
+        logical_msg_id: payload.logical_msg_id,
+        status: status.into(),
+    };
+    (StatusCode::OK, Json(ack)).into_response()
+}
+
+/// Local id of the sender of a group message or sender key: a user homed on
+/// `peer` who is a member of the group. Records the sending device, which
+/// must belong to that user.
+async fn group_sender(
+    state: &AppState,
+    peer: &FederationNode,
+    chat_id: &Uuid,
+    from_federated_address: &str,
+    from_device_id: Uuid,
+    from_identity_pubkey: &str,
+) -> Result<Uuid, Response> {
+    let not_member = || {
+        (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "sender is not a member of the group"})),
+        )
+            .into_response()
+    };
+
+    if parse_federated_address(from_federated_address).map(|(_, node)| node)
+        != Some(peer.node_id.as_str())
+    {
+        warn!(peer = %peer.node_id, from = %from_federated_address, "group send for a foreign user");
+        return Err(not_member());
+    }
+
+    let sender_id = match federation_repository::get_shadow_user_id(
+        &state.pool,
+        from_federated_address,
+        peer.id,
+    )
+    .await
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => return Err(not_member()),
+        Err(e) => {
+            error!(from = %from_federated_address, err = %e, "db error resolving sender");
+            return Err(internal_error());
+        }
+    };
+
+    match chat_repository::get_member_role(&state.pool, chat_id, &sender_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(not_member()),
+        Err(e) => {
+            error!(%chat_id, err = %e, "db error checking membership");
+            return Err(internal_error());
+        }
+    }
+
+    if let Err(e) = federation_repository::upsert_shadow_device(
+        &state.pool,
+        from_device_id,
+        sender_id,
+        from_identity_pubkey,
+    )
+    .await
+    {
+        error!(device_id = %from_device_id, err = %e, "shadow device upsert failed");
+        return Err(internal_error());
+    }
+
+    // A peer must not send (or rotate sender keys) as another user's device.
+    check_device_owner(state, peer, from_device_id, sender_id)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    Ok(sender_id)
+}
+
+/// `upsert_shadow_device` keeps an existing row, so the device a peer names
+/// may belong to someone else, even a local user. 403 unless `device_id` is a
+/// device of `user_id`.
+async fn check_device_owner(
+    state: &AppState,
+    peer: &FederationNode,
+    device_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), Rejection> {
+    match federation_repository::is_device_of_user(&state.pool, device_id, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            warn!(peer = %peer.node_id, %device_id, %user_id, "peer named a device of another user");
+            Err((
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "sending device does not belong to the sender"})),
+            ))
+        }
+        Err(e) => {
+            error!(%device_id, err = %e, "db error checking device owner");
+            Err(internal_rejection())
+        }
+    }
+}
+
+fn internal_error() -> Response {
+    internal_rejection().into_response()
+}
+
+fn internal_rejection() -> Rejection {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "internal error"})),
+    )
 }
 
 // ─── GET /users/federated/:address/keys ──────────────────────────────────────
@@ -421,7 +1350,13 @@ pub async fn federated_keys(
                         .into_response();
                 }
             };
-        return match device_repository::get_device_bundle(&state.pool, &user_id).await {
+        return match device_repository::claim_device_bundle(
+            &state.pool,
+            &user_id,
+            state.prekey_low_threshold,
+        )
+        .await
+        {
             Ok(bundle) => {
                 debug!(%username, devices = bundle.len(), "local bundle returned");
                 (StatusCode::OK, Json(bundle)).into_response()
@@ -523,13 +1458,9 @@ pub async fn federated_keys(
 
     info!(%node_id, api_url = %node.api_url, %username, "proxying key fetch to remote node");
 
-    let fed_client = FederationClient::new(
-        state.http_client.clone(),
-        state.node_keys.clone(),
-        state.this_node_id.clone(),
-    );
+    let fed_client = FederationClient::from_state(&state);
 
-    match fed_client.fetch_peer_keys(&node.api_url, username).await {
+    match fed_client.fetch_peer_keys(&node, username).await {
         Ok(bundle) => {
             info!(%node_id, %username, devices = bundle.len(), "remote key fetch succeeded");
             (StatusCode::OK, Json(bundle)).into_response()
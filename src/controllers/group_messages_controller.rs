@@ -0,0 +1,346 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    federation::{groups, outbox::OutboxPayload},
+    middlewares::auth::AuthenticatedDevice,
+    models::{
+        device::Devices,
+        federation::{S2sDevicePayload, S2sGroupMessage, S2sSenderKey},
+        message::{DeviceMismatch, OutgoingMessagePayload, StoreOutcome},
+        sender_key::{distribution_logical_id, DistributeSenderKeyBody, OutgoingGroupMessage},
+    },
+    repository::{
+        attachment_repository, chat_repository, group_message_repository, message_repository,
+        user_repository,
+    },
+};
+
+use super::messages_controller::{device_set_error, earliest, hold_for_federation, sent_response};
+
+/// POST /chats/:id/sender-keys. Queue the caller's new (or re-sent) sender
+/// key for every other device in the group and make it the device's current
+/// key. The distributions must cover exactly the devices of this node; every
+/// other node hosting members gets its `remote_distributions` entry (or none)
+/// and checks it against its own devices.
+pub async fn distribute_sender_key(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(body): Json<DistributeSenderKeyBody>,
+) -> impl IntoResponse {
+    if let Err(resp) = require_member(&state, &chat_id, &device.user_id).await {
+        return resp;
+    }
+
+    let member_nodes = match chat_repository::get_member_nodes(&state.pool, &chat_id).await {
+        Ok(nodes) => nodes,
+        Err(e) => return internal_error("loading member nodes", e),
+    };
+    let mut remote_nodes: Vec<&str> = body
+        .remote_distributions
+        .iter()
+        .map(|r| r.node_id.as_str())
+        .collect();
+    if let Some(node_id) = remote_nodes
+        .iter()
+        .find(|n| !member_nodes.iter().any(|m| m == *n))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("no group members on node {node_id}")})),
+        )
+            .into_response();
+    }
+    remote_nodes.sort();
+    if remote_nodes.windows(2).any(|w| w[0] == w[1]) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "remote_distributions lists a node twice"})),
+        )
+            .into_response();
+    }
+
+    let targets = match group_message_repository::get_group_targets(
+        &state.pool,
+        &chat_id,
+        &device.id,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(e) => return internal_error("loading group devices", e),
+    };
+
+    let target_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    let payload_ids: Vec<Uuid> = body.distributions.iter().map(|d| d.to_device_id).collect();
+    if let Some(resp) = device_set_error(&target_ids, &payload_ids) {
+        return resp.into_response();
+    }
+
+    match group_message_repository::store_sender_key(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &device.id,
+        &body.key_id,
+        &body.distributions,
+        &targets,
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(e) => return internal_error("storing sender key", e),
+    }
+
+    if !member_nodes.is_empty() {
+        let from_federated_address = match sender_address(&state, &device).await {
+            Ok(a) => a,
+            Err(resp) => return resp,
+        };
+        let logical_msg_id = distribution_logical_id(&body.key_id);
+        for node_id in &member_nodes {
+            let payloads = body
+                .remote_distributions
+                .iter()
+                .find(|r| r.node_id == *node_id)
+                .map(|r| r.payloads.iter().map(to_s2s_payload).collect())
+                .unwrap_or_default();
+            let key = S2sSenderKey {
+                chat_id,
+                key_id: body.key_id,
+                from_federated_address: from_federated_address.clone(),
+                from_device_id: device.id,
+                from_identity_pubkey: device.identity_pubkey.clone(),
+                payloads,
+            };
+            if let Err(e) = groups::send_to_nodes(
+                &state,
+                std::slice::from_ref(node_id),
+                &logical_msg_id,
+                OutboxPayload::SenderKey(key),
+            )
+            .await
+            {
+                eprintln!("Error queueing sender key for {node_id}: {e}");
+            }
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "key_id": body.key_id,
+            "recipients": targets.len(),
+            "nodes": member_nodes
+        })),
+    )
+        .into_response()
+}
+
+/// POST /chats/:id/messages. Store one sender-key ciphertext for the group
+/// and fan it out by reference to every other member device.
+pub async fn send_group_message(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Path(chat_id): Path<Uuid>,
+    Json(msg): Json<OutgoingGroupMessage>,
+) -> impl IntoResponse {
+    if msg.expires_at.is_some_and(|t| t <= chrono::Utc::now()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "expires_at is in the past"})),
+        )
+            .into_response();
+    }
+
+    if let Err(resp) = require_member(&state, &chat_id, &device.user_id).await {
+        return resp;
+    }
+
+    if !msg.attachment_ids.is_empty() {
+        match attachment_repository::are_uploaded_by(&state.pool, &msg.attachment_ids, &device.id)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "unknown or not yet uploaded attachment"})),
+                )
+                    .into_response()
+            }
+            Err(e) => return internal_error("checking attachments", e),
+        }
+    }
+
+    // Retries get the original answer, even if the key was rotated since.
+    match message_repository::is_already_sent(&state.pool, &device.id, &msg.logical_msg_id).await {
+        Ok(true) => return sent_response(),
+        Ok(false) => {}
+        Err(e) => return internal_error("checking for a previous send", e),
+    }
+
+    let key =
+        match group_message_repository::get_sender_key(&state.pool, &chat_id, &device.id).await {
+            Ok(Some(key)) if key.key_id == msg.sender_key_id => key,
+            Ok(_) => {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({"error": "sender key rotation required"})),
+                )
+                    .into_response()
+            }
+            Err(e) => return internal_error("loading sender key", e),
+        };
+
+    let targets = match group_message_repository::get_group_targets(
+        &state.pool,
+        &chat_id,
+        &device.id,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(e) => return internal_error("loading group devices", e),
+    };
+
+    // Devices added since the key was distributed cannot decrypt it, and
+    // devices removed since still hold it.
+    let target_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    if let Some(mismatch) = DeviceMismatch::compare(&target_ids, &key.recipient_device_ids) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "sender key not distributed to the current devices",
+                "missing_devices": mismatch.missing_devices,
+                "extra_devices": mismatch.extra_devices,
+            })),
+        )
+            .into_response();
+    }
+
+    match group_message_repository::insert_group_message(
+        &state.pool,
+        &chat_id,
+        &device.user_id,
+        &device.id,
+        &msg,
+        &targets,
+    )
+    .await
+    {
+        Ok(StoreOutcome::Stored) => {
+            forward_group_message(&state, &device, &chat_id, &msg).await;
+            sent_response()
+        }
+        Ok(StoreOutcome::Duplicate) => sent_response(),
+        Ok(StoreOutcome::IdTaken) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "logical_msg_id is already in use"})),
+        )
+            .into_response(),
+        Err(e) => internal_error("inserting group message", e),
+    }
+}
+
+/// Queue a stored group message for every node hosting members; each fans
+/// it out to its own users' devices.
+async fn forward_group_message(
+    state: &AppState,
+    device: &Devices,
+    chat_id: &Uuid,
+    msg: &OutgoingGroupMessage,
+) {
+    let nodes = match chat_repository::get_member_nodes(&state.pool, chat_id).await {
+        Ok(nodes) if nodes.is_empty() => return,
+        Ok(nodes) => nodes,
+        Err(e) => {
+            eprintln!("Error loading member nodes: {e}");
+            return;
+        }
+    };
+    let Ok(from_federated_address) = sender_address(state, device).await else {
+        return;
+    };
+    // Member nodes that missed a TTL change still drop the message in time.
+    let expires_at = match chat_repository::get_chat_expiry(&state.pool, chat_id).await {
+        Ok(chat_expiry) => earliest(msg.expires_at, chat_expiry),
+        Err(e) => {
+            eprintln!("Error reading chat expiry: {e}");
+            msg.expires_at
+        }
+    };
+
+    let forward = S2sGroupMessage {
+        chat_id: *chat_id,
+        logical_msg_id: msg.logical_msg_id.clone(),
+        from_federated_address,
+        from_device_id: device.id,
+        from_identity_pubkey: device.identity_pubkey.clone(),
+        sender_key_id: msg.sender_key_id,
+        header: msg.header.clone(),
+        ciphertext: msg.ciphertext.clone(),
+        expires_at,
+    };
+    if let Err(e) = groups::send_to_nodes(
+        state,
+        &nodes,
+        &msg.logical_msg_id,
+        OutboxPayload::GroupMessage(forward),
+    )
+    .await
+    {
+        eprintln!("Error queueing group message for member nodes: {e}");
+    }
+    hold_for_federation(state, &msg.attachment_ids, expires_at).await;
+}
+
+/// "username@this-node" of the device's user, as peers know them.
+async fn sender_address(state: &AppState, device: &Devices) -> Result<String, Response> {
+    match user_repository::find_user_by_id(&state.pool, &device.user_id).await {
+        Ok(Some(user)) => Ok(format!("{}@{}", user.username, state.this_node_id)),
+        Ok(None) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "cannot resolve sender identity"})),
+        )
+            .into_response()),
+        Err(e) => Err(internal_error("looking up sender", e)),
+    }
+}
+
+fn to_s2s_payload(p: &OutgoingMessagePayload) -> S2sDevicePayload {
+    S2sDevicePayload {
+        to_device_id: p.to_device_id,
+        header: p.header.clone(),
+        ciphertext: p.ciphertext.clone(),
+    }
+}
+
+async fn require_member(state: &AppState, chat_id: &Uuid, user_id: &Uuid) -> Result<(), Response> {
+    match chat_repository::get_member_role(&state.pool, chat_id, user_id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Group not found"})),
+        )
+            .into_response()),
+        Err(e) => Err(internal_error("checking group membership", e)),
+    }
+}
+
+fn internal_error(action: &str, e: sqlx::Error) -> Response {
+    eprintln!("Error {action}: {e}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "internal server error"})),
+    )
+        .into_response()
+}
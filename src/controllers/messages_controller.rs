@@ -1,25 +1,38 @@
 use crate::{
     app_state::AppState,
-    federation::{client::FederationClient, parse_federated_address},
+    federation::{
+        client::FederationClient,
+        outbox::{self, OutboxPayload},
+        parse_federated_address,
+    },
     middlewares::auth::AuthenticatedDevice,
     models::{
         federation::{S2sDevicePayload, S2sMessagePayload},
-        message::OutgoingMessage,
+        message::{
+            duplicate_target, AckMessagesBody, DeviceMismatch, OutgoingMessage, ReceiptsBody,
+            StoreOutcome,
+        },
+        pagination::{PageCursor, PageQuery},
     },
     repository::{
-        federation_repository,
-        message_repository::{fetch_pending_messages, insert_message},
+        attachment_repository, device_repository, federation_repository,
+        message_repository::{
+            self, ack_messages, fetch_pending_messages, insert_message, record_receipts,
+        },
         user_repository,
     },
 };
 use axum::{
-    extract::{Json, State},
+    extract::{Json, Query, State},
     http::StatusCode,
     response::IntoResponse,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
+/// How long attachments of a forwarded message stay fetchable by the peer.
+const FEDERATED_ATTACHMENT_HOLD_HOURS: i64 = 168;
+
 pub async fn send_message(
     State(state): State<AppState>,
     AuthenticatedDevice(device): AuthenticatedDevice,
@@ -27,6 +40,37 @@ pub async fn send_message(
 ) -> impl IntoResponse {
     let from_user_id: Uuid = device.user_id;
 
+    if msg.expires_at.is_some_and(|t| t <= chrono::Utc::now()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "expires_at is in the past"})),
+        )
+            .into_response();
+    }
+
+    if !msg.attachment_ids.is_empty() {
+        match attachment_repository::are_uploaded_by(&state.pool, &msg.attachment_ids, &device.id)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "unknown or not yet uploaded attachment"})),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                eprintln!("Error checking attachments: {e}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "internal server error"})),
+                )
+                    .into_response();
+            }
+        }
+    }
+
     // ── Federated path ────────────────────────────────────────────────────────
     // When to_user_address is present and points to a different node, bypass
     // local delivery entirely and queue the message for S2S forwarding.
@@ -47,8 +91,33 @@ pub async fn send_message(
     }
 
     // ── Local delivery (existing path) ────────────────────────────────────────
+    // A retry of a send that already went through gets the original answer,
+    // even if the recipient's devices changed since.
+    match message_repository::is_already_sent(&state.pool, &device.id, &msg.logical_msg_id).await {
+        Ok(true) => return sent_response(),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Error checking for a previous send: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error"})),
+            )
+                .into_response();
+        }
+    }
+
+    let targets: Vec<Uuid> = msg.payloads.iter().map(|p| p.to_device_id).collect();
+    if let Err(resp) = check_recipient_devices(&state, &msg.to_user_id, &targets).await {
+        return resp.into_response();
+    }
+
     match insert_message(&state.pool, device.id, from_user_id, msg).await {
-        Ok(()) => (StatusCode::OK, Json(json!({"success": "true"}))).into_response(),
+        Ok(StoreOutcome::Stored | StoreOutcome::Duplicate) => sent_response(),
+        Ok(StoreOutcome::IdTaken) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "logical_msg_id is already in use"})),
+        )
+            .into_response(),
         Err(e) => {
             eprintln!("Error inserting message: {e}");
             (
@@ -60,6 +129,10 @@ pub async fn send_message(
     }
 }
 
+pub(crate) fn sent_response() -> axum::response::Response {
+    (StatusCode::OK, Json(json!({"success": "true"}))).into_response()
+}
+
 /// Build and queue a cross-node message for delivery to `username@node_id`.
 ///
 /// Steps:
@@ -90,10 +163,27 @@ async fn handle_federated_message(
     };
 
     // Resolve the target node (DB → registry).
-    let node = match resolve_node(state, target_node_id).await {
-        Ok(n) => n,
-        Err(resp) => return resp,
-    };
+    if let Err(resp) = resolve_node(state, target_node_id).await {
+        return resp;
+    }
+
+    // The peer only knows the chat's TTL if it was told; stamp the deadline
+    // on the message like local delivery does.
+    let to_address = format!("{to_username}@{target_node_id}");
+    let expires_at =
+        match federation_repository::get_direct_chat_expiry(&state.pool, from_user_id, &to_address)
+            .await
+        {
+            Ok(chat_expiry) => earliest(msg.expires_at, chat_expiry),
+            Err(e) => {
+                eprintln!("Error reading chat expiry: {e}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "internal error"})),
+                )
+                    .into_response();
+            }
+        };
 
     let s2s_payload = S2sMessagePayload {
         logical_msg_id: msg.logical_msg_id.clone(),
@@ -101,6 +191,7 @@ async fn handle_federated_message(
         from_device_id: device.id,
         from_identity_pubkey: device.identity_pubkey.clone(),
         to_user: to_username.to_string(),
+        expires_at,
         payloads: msg
             .payloads
             .iter()
@@ -112,70 +203,169 @@ async fn handle_federated_message(
             .collect(),
     };
 
-    let payload_json = match serde_json::to_value(&s2s_payload) {
-        Ok(v) => v,
+    let fed_client = FederationClient::from_state(state);
+
+    // Write to outbox for durability, then attempt delivery right away;
+    // failures are handled by the outbox worker.
+    if let Err(e) = outbox::enqueue_and_deliver(
+        &state.pool,
+        &fed_client,
+        target_node_id,
+        &msg.logical_msg_id,
+        OutboxPayload::Messages(s2s_payload),
+    )
+    .await
+    {
+        eprintln!("Failed to enqueue outbox entry: {e}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "internal error"})),
+        )
+            .into_response();
+    }
+
+    hold_for_federation(state, &msg.attachment_ids, expires_at).await;
+
+    // The peer will hold a shadow copy of this device from now on; remember it
+    // so the peer can be told if the device is ever revoked.
+    if let Err(e) =
+        federation_repository::record_device_peer(&state.pool, device.id, target_node_id).await
+    {
+        eprintln!("Failed to record device peer: {e}");
+    }
+
+    (StatusCode::ACCEPTED, Json(json!({"status": "queued"}))).into_response()
+}
+
+/// Most IDs accepted by one POST /messages/ack.
+const MAX_ACK_BATCH: usize = 500;
+
+pub async fn get_pending_messages(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Query(page): Query<PageQuery>,
+) -> impl IntoResponse {
+    let after = match page.cursor() {
+        Ok(after) => after,
         Err(e) => {
-            eprintln!("Failed to serialize S2S payload: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "internal error"})),
-            )
-                .into_response();
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
         }
     };
 
-    // Write to outbox for durability before attempting delivery.
-    let outbox_id = match federation_repository::enqueue_outbox(
+    match fetch_pending_messages(
         &state.pool,
-        target_node_id,
-        &msg.logical_msg_id,
-        &payload_json,
+        AuthenticatedDevice(device),
+        after,
+        page.limit(),
     )
     .await
     {
-        Ok(id) => id,
+        Ok((messages, has_more)) => {
+            let next_cursor = messages
+                .last()
+                .filter(|_| has_more)
+                .and_then(|m| m.created_at.map(|t| PageCursor::new(t, m.id).encode()));
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "messages": messages,
+                    "next_cursor": next_cursor,
+                    "has_more": has_more,
+                })),
+            )
+                .into_response()
+        }
         Err(e) => {
-            eprintln!("Failed to enqueue outbox entry: {e}");
-            return (
+            eprintln!("Error fetching pending messages: {e}");
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "internal error"})),
+                Json(json!({"error": "internal server error"})),
             )
-                .into_response();
+                .into_response()
         }
-    };
+    }
+}
 
-    // Spawn immediate delivery attempt; failures are handled by the outbox worker.
-    let pool = state.pool.clone();
-    let fed_client = FederationClient::new(
-        state.http_client.clone(),
-        state.node_keys.clone(),
-        state.this_node_id.clone(),
-    );
-    let api_url = node.api_url.clone();
-
-    tokio::spawn(async move {
-        match fed_client.forward_messages(&api_url, &s2s_payload).await {
-            Ok(_) => {
-                let _ = federation_repository::mark_outbox_delivered(&pool, outbox_id).await;
-            }
-            Err(e) => {
-                eprintln!("[federated send] immediate delivery failed, will retry: {e}");
-                // Outbox worker schedules the next attempt automatically.
-            }
-        }
-    });
+pub async fn acknowledge_messages(
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(body): Json<AckMessagesBody>,
+) -> impl IntoResponse {
+    if body.message_ids.len() > MAX_ACK_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("at most {MAX_ACK_BATCH} message ids per ack")
+            })),
+        )
+            .into_response();
+    }
 
-    (StatusCode::ACCEPTED, Json(json!({"status": "queued"}))).into_response()
+    match ack_messages(&state.pool, &device.id, &body.message_ids).await {
+        Ok(acknowledged) => (
+            StatusCode::OK,
+            Json(json!({ "acknowledged": acknowledged })),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Error acknowledging messages: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error"})),
+            )
+                .into_response()
+        }
+    }
 }
 
-pub async fn get_pending_messages(
+pub async fn send_receipts(
     State(state): State<AppState>,
     AuthenticatedDevice(device): AuthenticatedDevice,
+    Json(body): Json<ReceiptsBody>,
 ) -> impl IntoResponse {
-    match fetch_pending_messages(&state.pool, AuthenticatedDevice(device)).await {
-        Ok(messages) => (StatusCode::OK, Json(messages)).into_response(),
+    if body.logical_msg_ids.len() > MAX_ACK_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("at most {MAX_ACK_BATCH} message ids per receipt")
+            })),
+        )
+            .into_response();
+    }
+
+    let reader_address = match user_repository::find_user_by_id(&state.pool, &device.user_id).await
+    {
+        Ok(Some(u)) => format!("{}@{}", u.username, state.this_node_id),
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "user not found"})),
+            )
+                .into_response()
+        }
         Err(e) => {
-            eprintln!("Error fetching pending messages: {e}");
+            eprintln!("Error resolving receipt sender: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error"})),
+            )
+                .into_response();
+        }
+    };
+
+    match record_receipts(
+        &state.pool,
+        &device.id,
+        &device.user_id,
+        &reader_address,
+        &body.logical_msg_ids,
+        body.status,
+    )
+    .await
+    {
+        Ok(updated) => (StatusCode::OK, Json(json!({ "updated": updated }))).into_response(),
+        Err(e) => {
+            eprintln!("Error recording receipts: {e}");
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({"error": "internal server error"})),
@@ -187,6 +377,86 @@ pub async fn get_pending_messages(
 
 // ── Shared helper ─────────────────────────────────────────────────────────────
 
+/// Recipients on a peer fetch attachments through GET /s2s/attachments/:id;
+/// keep them around for that even though no local message references them.
+pub(crate) async fn hold_for_federation(
+    state: &AppState,
+    attachment_ids: &[Uuid],
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+) {
+    if attachment_ids.is_empty() {
+        return;
+    }
+    let hold_until = chrono::Utc::now() + chrono::Duration::hours(FEDERATED_ATTACHMENT_HOLD_HOURS);
+    let hold_until = expires_at.map_or(hold_until, |t| t.min(hold_until));
+    if let Err(e) =
+        attachment_repository::hold_for_federation(&state.pool, attachment_ids, hold_until).await
+    {
+        eprintln!("Failed to hold attachments for federation: {e}");
+    }
+}
+
+/// The earlier of two optional message deadlines.
+pub(crate) fn earliest(
+    a: Option<chrono::DateTime<chrono::Utc>>,
+    b: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A rejected request: status and JSON error body. Kept unrendered so the
+/// S2S batch endpoint can put it into its results as is.
+pub(crate) type Rejection = (StatusCode, Json<Value>);
+
+/// Reject a fan-out whose payload targets differ from `user_id`'s devices:
+/// 400 if a device appears twice, 409 with the DeviceMismatch otherwise.
+pub(crate) async fn check_recipient_devices(
+    state: &AppState,
+    user_id: &Uuid,
+    targets: &[Uuid],
+) -> Result<(), Rejection> {
+    let devices = match device_repository::get_device_ids_by_user_id(&state.pool, user_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Error loading recipient devices: {e}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "internal server error"})),
+            ));
+        }
+    };
+
+    match device_set_error(&devices, targets) {
+        None => Ok(()),
+        Some(resp) => Err(resp),
+    }
+}
+
+/// The 400 (a device targeted twice) or 409 (DeviceMismatch) response for
+/// payload `targets` that do not cover exactly `devices`, if any.
+pub(crate) fn device_set_error(devices: &[Uuid], targets: &[Uuid]) -> Option<Rejection> {
+    if let Some(device_id) = duplicate_target(targets) {
+        return Some((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("duplicate payload for device {device_id}")})),
+        ));
+    }
+
+    DeviceMismatch::compare(devices, targets).map(|mismatch| {
+        (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "device list mismatch",
+                "missing_devices": mismatch.missing_devices,
+                "extra_devices": mismatch.extra_devices,
+            })),
+        )
+    })
+}
+
 /// Look up a FederationNode by node_id, falling back to the central registry
 /// if the node is not yet cached locally.
 pub(crate) async fn resolve_node(
@@ -1,6 +1,10 @@
+pub mod admin_controller;
+pub mod attachments_controller;
 pub mod chats_controller;
 pub mod device_controller;
+pub mod device_link_controller;
 pub mod federation_controller;
+pub mod group_messages_controller;
 pub mod messages_controller;
 pub mod root_controller;
 pub mod session_controller;
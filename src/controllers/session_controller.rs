@@ -1,4 +1,9 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
 use serde::Deserialize;
 use serde_json::json;
 use uuid::Uuid;
@@ -7,7 +12,10 @@ use crate::app_state::AppState;
 use crate::federation::{client::FederationClient, parse_federated_address};
 use crate::middlewares::auth::AuthenticatedDevice;
 use crate::models::federation::{S2sSessionInit, S2sSessionPayload};
-use crate::repository::{session_repository, user_repository};
+use crate::models::pagination::{PageCursor, PageQuery};
+use crate::repository::{
+    device_repository, federation_repository, session_repository, user_repository,
+};
 
 use super::messages_controller::resolve_node;
 
@@ -17,6 +25,16 @@ pub struct SessionInit {
     pub ephemeral_pubkey: String,
     pub sender_prekey_pub: String,
     pub otpk_used: String,
+    /// Recipient signed prekey used for this init. When present it must be the
+    /// device's current SPK or a retired one still inside its grace window.
+    #[serde(default)]
+    pub recipient_spk_pub: Option<String>,
+    /// Hybrid (PQXDH) sessions: id of the recipient's ML-KEM prekey from the
+    /// bundle and the KEM ciphertext encapsulated to it. Both or neither.
+    #[serde(default)]
+    pub kem_prekey_id: Option<i64>,
+    #[serde(default)]
+    pub kem_ciphertext: Option<String>,
     pub ciphertext: String,
 }
 
@@ -66,6 +84,40 @@ pub async fn create_session(
     })?;
 
     for init in &payload.sessions_init {
+        if init.kem_prekey_id.is_some() != init.kem_ciphertext.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "kem_prekey_id and kem_ciphertext go together",
+            ));
+        }
+
+        if let Some(ref spk) = init.recipient_spk_pub {
+            let valid = device_repository::is_signed_prekey_valid(
+                &state.pool,
+                &init.recipient_device_id,
+                spk,
+            )
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+            if !valid {
+                return Err((StatusCode::CONFLICT, "Stale signed prekey"));
+            }
+        }
+
+        if let Some(kem_prekey_id) = init.kem_prekey_id {
+            let valid = device_repository::is_kem_prekey_valid(
+                &state.pool,
+                &init.recipient_device_id,
+                kem_prekey_id,
+                state.signed_prekey_grace_hours,
+            )
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+            if !valid {
+                return Err((StatusCode::CONFLICT, "Stale KEM prekey"));
+            }
+        }
+
         session_repository::create_pending_session(
             &state.pool,
             &sender.id,
@@ -73,6 +125,9 @@ pub async fn create_session(
             &init.ephemeral_pubkey,
             &init.sender_prekey_pub,
             &init.otpk_used,
+            init.recipient_spk_pub.as_deref(),
+            init.kem_prekey_id,
+            init.kem_ciphertext.as_deref(),
             &init.ciphertext,
         )
         .await
@@ -128,21 +183,23 @@ async fn handle_federated_session(
                 ephemeral_pubkey: i.ephemeral_pubkey.clone(),
                 sender_prekey_pub: i.sender_prekey_pub.clone(),
                 otpk_used: i.otpk_used.clone(),
+                recipient_spk_pub: i.recipient_spk_pub.clone(),
+                kem_prekey_id: i.kem_prekey_id,
+                kem_ciphertext: i.kem_ciphertext.clone(),
                 ciphertext: i.ciphertext.clone(),
             })
             .collect(),
     };
 
-    let fed_client = FederationClient::new(
-        state.http_client.clone(),
-        state.node_keys.clone(),
-        state.this_node_id.clone(),
-    );
-
-    if let Err(e) = fed_client
-        .forward_session(&node.api_url, &s2s_payload)
-        .await
+    if let Err(e) =
+        federation_repository::record_device_peer(&state.pool, sender.id, target_node_id).await
     {
+        eprintln!("[federated session] failed to record device peer: {e}");
+    }
+
+    let fed_client = FederationClient::from_state(state);
+
+    if let Err(e) = fed_client.forward_session(&node, &s2s_payload).await {
         eprintln!("[federated session] forward failed: {e}");
         return Ok((
             StatusCode::BAD_GATEWAY,
@@ -157,22 +214,37 @@ async fn handle_federated_session(
 pub async fn get_pending_sessions_handler(
     State(state): State<AppState>,
     AuthenticatedDevice(device): AuthenticatedDevice,
+    Query(page): Query<PageQuery>,
 ) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let sessions =
-        session_repository::get_pending_sessions(&state.pool, AuthenticatedDevice(device))
-            .await
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to fetch pending sessions",
-                )
-            })?;
-
-    if sessions.is_empty() {
-        return Ok((StatusCode::OK, Json(json!({ "sessions": [] }))));
-    }
+    let after = page.cursor().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
 
-    Ok((StatusCode::OK, Json(json!({ "sessions": sessions }))))
+    let (sessions, has_more) = session_repository::get_pending_sessions(
+        &state.pool,
+        AuthenticatedDevice(device),
+        after,
+        page.limit(),
+    )
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to fetch pending sessions",
+        )
+    })?;
+
+    let next_cursor = sessions.last().filter(|_| has_more).and_then(|p| {
+        p.created_at
+            .map(|t| PageCursor::new(t.and_utc(), p.id).encode())
+    });
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "sessions": sessions,
+            "next_cursor": next_cursor,
+            "has_more": has_more,
+        })),
+    ))
 }
 
 pub async fn confirm_session(
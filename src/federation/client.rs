@@ -6,71 +6,124 @@
 // that the receiving node can verify the sender's identity against the public
 // key stored in the central registry.
 //
-// Canonical string signed (UTF-8, fields separated by "\n"):
+// Canonical string signed (UTF-8, fields separated by "\n"; see
+// middlewares::node_auth):
 //
-//   {HTTP_METHOD}\n{path}\n{timestamp}\n{nonce}
+//   {HTTP_METHOD}\n{path}\n{timestamp}\n{nonce}\n{content_digest}
 //
 // The path component is extracted from the full URL by stripping the scheme
 // and authority, making it consistent with what the receiver reconstructs from
 // the incoming request URI.  Only the path+query portion is signed, not the
 // host, so that node API URLs can change without invalidating the signing logic.
 //
-// Four headers carry the authentication material:
+// These headers carry the authentication material:
 //
 //   X-Node-ID        — this node's canonical identifier
 //   X-Timestamp      — Unix seconds (string)
 //   X-Nonce          — 16 random bytes, base64-encoded
+//   Content-Digest   — "sha-256=:{base64 SHA-256 of the exact body sent}:"
 //   X-Node-Signature — Ed25519(canonical), base64-encoded
+//
+// Protocol negotiation: peers still on 0.0.2 neither send nor check
+// Content-Digest, and only peers on 0.0.4 serve POST /s2s/messages/batch.
+// A peer not yet known to speak our PROTOCOL_VERSION has its public
+// GET /s2s/info probed (at most once per PROTOCOL_RECHECK_HOURS) and is sent
+// what its recorded version understands until then. Once recorded, a peer's
+// version is never lowered, so it keeps getting the newer form from then on.
 
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
+use axum::body::Bytes;
 use base64::{engine::general_purpose::STANDARD as B64, Engine};
 use ed25519_dalek::Signer;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::Serialize;
+use sqlx::PgPool;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use uuid::Uuid;
 
+use super::peer_gate::PeerGate;
 use crate::{
+    app_state::AppState,
+    controllers::attachments_controller::DOWNLOAD_TOKEN_HEADER,
+    middlewares::{
+        body_digest::BodyDigest,
+        node_auth::{content_digest_value, node_canonical_string, CONTENT_DIGEST_HEADER},
+    },
     models::{
         device::DeviceBundle,
-        federation::{S2sAck, S2sMessagePayload, S2sSessionPayload},
+        federation::{
+            parse_protocol_version, FederationNode, S2sAck, S2sBatchResponse, S2sChatExpiry,
+            S2sDeviceRemoved, S2sGroupLeave, S2sGroupMessage, S2sGroupState, S2sMessageBatch,
+            S2sMessagePayload, S2sReceipt, S2sSenderKey, S2sSessionPayload, PROTOCOL_VERSION,
+        },
+        message::DeviceMismatch,
     },
+    repository::federation_repository,
     utils::node_keys::NodeKeys,
 };
 
+/// How long a peer reported as older than our PROTOCOL_VERSION is trusted
+/// before its GET /s2s/info is probed again.
+const PROTOCOL_RECHECK_HOURS: i64 = 24;
+
 /// HTTP client for outbound S2S communication.
 ///
-/// Clone is cheap: both `http` (reqwest::Client) and `node_keys` (Arc) are
-/// reference-counted internally.
+/// Clone is cheap: `http` (reqwest::Client), `node_keys` and `gate` (Arc) and
+/// `pool` are reference-counted internally.
 #[derive(Clone)]
 pub struct FederationClient {
     pub http: Client,
     node_keys: Arc<NodeKeys>,
     pub this_node_id: String,
+    /// Used to read and record the protocol version of peers.
+    pool: PgPool,
+    /// Delivery limits and circuit breakers shared with every other client.
+    pub gate: Arc<PeerGate>,
 }
 
 impl FederationClient {
-    pub fn new(http: Client, node_keys: Arc<NodeKeys>, this_node_id: String) -> Self {
+    pub fn new(
+        http: Client,
+        node_keys: Arc<NodeKeys>,
+        this_node_id: String,
+        pool: PgPool,
+        gate: Arc<PeerGate>,
+    ) -> Self {
         Self {
             http,
             node_keys,
             this_node_id,
+            pool,
+            gate,
         }
     }
 
+    pub fn from_state(state: &AppState) -> Self {
+        Self::new(
+            state.http_client.clone(),
+            state.node_keys.clone(),
+            state.this_node_id.clone(),
+            state.pool.clone(),
+            state.peer_gate.clone(),
+        )
+    }
+
     /// Fetch the prekey bundle for `username` from a peer node.
     ///
     /// The returned Vec has one DeviceBundle per device registered for that
-    /// user on the remote node. One-time prekeys are consumed by the remote
-    /// node on fetch (same semantics as the local GET /users/:id/keys endpoint).
+    /// user on the remote node. Each bundle carries at most one one-time
+    /// prekey, which the remote node removes on fetch (same semantics as the
+    /// local GET /users/:id/keys endpoint).
     pub async fn fetch_peer_keys(
         &self,
-        api_url: &str,
+        peer: &FederationNode,
         username: &str,
     ) -> Result<Vec<DeviceBundle>> {
-        let url = format!("{api_url}/s2s/users/{username}/keys");
-        self.signed_get(&url)
+        let url = format!("{}/s2s/users/{username}/keys", peer.api_url);
+        self.signed_get(peer, &url)
             .await?
             .error_for_status()
             .context("peer returned error for key fetch")?
@@ -80,8 +133,12 @@ impl FederationClient {
     }
 
     /// Forward an X3DH session initiation to the peer that hosts the recipient.
-    pub async fn forward_session(&self, api_url: &str, payload: &S2sSessionPayload) -> Result<()> {
-        self.signed_post(api_url, "/s2s/sessions", payload)
+    pub async fn forward_session(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sSessionPayload,
+    ) -> Result<()> {
+        self.signed_post(peer, "/s2s/sessions", payload)
             .await?
             .error_for_status()
             .context("peer rejected session forward")?;
@@ -89,13 +146,15 @@ impl FederationClient {
     }
 
     /// Forward a batch of device-specific ciphertexts to the peer.
-    /// Returns the S2sAck the receiving node sends back.
+    /// Returns the S2sAck the receiving node sends back. If the peer refuses
+    /// the device set (409), the error is a `DeviceMismatch`.
     pub async fn forward_messages(
         &self,
-        api_url: &str,
+        peer: &FederationNode,
         payload: &S2sMessagePayload,
     ) -> Result<S2sAck> {
-        self.signed_post(api_url, "/s2s/messages", payload)
+        let resp = self.signed_post(peer, "/s2s/messages", payload).await?;
+        reject_conflict(resp)
             .await?
             .error_for_status()
             .context("peer rejected message forward")?
@@ -104,47 +163,328 @@ impl FederationClient {
             .context("invalid ack in peer response")
     }
 
+    /// Forward several message fan-outs in one POST /s2s/messages/batch
+    /// (peers on protocol 0.0.4). The outer error covers the request as a
+    /// whole; otherwise there is one result per message, in order, failing
+    /// with a `DeviceMismatch` where the peer refused the device set.
+    pub async fn forward_message_batch(
+        &self,
+        peer: &FederationNode,
+        batch: &S2sMessageBatch,
+    ) -> Result<Vec<Result<S2sAck>>> {
+        let resp = self
+            .signed_post(peer, "/s2s/messages/batch", batch)
+            .await?
+            .error_for_status()
+            .context("peer rejected message batch")?
+            .json::<S2sBatchResponse>()
+            .await
+            .context("invalid batch response from peer")?;
+        if resp.results.len() != batch.messages.len() {
+            anyhow::bail!(
+                "peer answered {} results for {} messages",
+                resp.results.len(),
+                batch.messages.len()
+            );
+        }
+
+        Ok(resp
+            .results
+            .into_iter()
+            .map(|item| match item.status {
+                200..=299 => serde_json::from_value::<S2sAck>(item.body)
+                    .context("invalid ack in peer response"),
+                409 => {
+                    match serde_json::from_value::<DeviceMismatch>(item.body) {
+                        Ok(mismatch) => Err(mismatch.into()),
+                        Err(e) => Err(anyhow::Error::new(e)
+                            .context("invalid device mismatch in peer response")),
+                    }
+                }
+                status => Err(anyhow::anyhow!(
+                    "peer rejected message forward ({status}): {}",
+                    item.body["error"].as_str().unwrap_or("no reason given")
+                )),
+            })
+            .collect())
+    }
+
+    /// Tell a peer that one of our devices was revoked.
+    pub async fn forward_device_removed(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sDeviceRemoved,
+    ) -> Result<()> {
+        self.signed_post(peer, "/s2s/devices/removed", payload)
+            .await?
+            .error_for_status()
+            .context("peer rejected device removal")?;
+        Ok(())
+    }
+
+    /// Report a delivered/read receipt to the node of the message's sender.
+    pub async fn forward_receipt(&self, peer: &FederationNode, payload: &S2sReceipt) -> Result<()> {
+        self.signed_post(peer, "/s2s/receipts", payload)
+            .await?
+            .error_for_status()
+            .context("peer rejected receipt")?;
+        Ok(())
+    }
+
+    /// Send a direct chat's new message TTL to the other party's node.
+    pub async fn forward_chat_expiry(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sChatExpiry,
+    ) -> Result<()> {
+        self.signed_post(peer, "/s2s/chats/expiry", payload)
+            .await?
+            .error_for_status()
+            .context("peer rejected chat expiry")?;
+        Ok(())
+    }
+
+    /// Send a group's membership snapshot to a node hosting members.
+    pub async fn forward_group_state(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sGroupState,
+    ) -> Result<()> {
+        self.signed_post(peer, "/s2s/groups/state", payload)
+            .await?
+            .error_for_status()
+            .context("peer rejected group state")?;
+        Ok(())
+    }
+
+    /// Tell a group's home node that one of our users left it.
+    pub async fn forward_group_leave(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sGroupLeave,
+    ) -> Result<()> {
+        self.signed_post(peer, "/s2s/groups/leave", payload)
+            .await?
+            .error_for_status()
+            .context("peer rejected group leave")?;
+        Ok(())
+    }
+
+    /// Forward a sender-key group message to a node hosting members. If the
+    /// peer's devices do not hold the sender key (409), the error is a
+    /// `DeviceMismatch`.
+    pub async fn forward_group_message(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sGroupMessage,
+    ) -> Result<()> {
+        let resp = self
+            .signed_post(peer, "/s2s/groups/messages", payload)
+            .await?;
+        reject_conflict(resp)
+            .await?
+            .error_for_status()
+            .context("peer rejected group message")?;
+        Ok(())
+    }
+
+    /// Forward the distribution of a sender key to a node hosting members.
+    /// If the payloads do not match the peer's devices (409), the error is a
+    /// `DeviceMismatch`.
+    pub async fn forward_sender_key(
+        &self,
+        peer: &FederationNode,
+        payload: &S2sSenderKey,
+    ) -> Result<()> {
+        let resp = self
+            .signed_post(peer, "/s2s/groups/sender-keys", payload)
+            .await?;
+        reject_conflict(resp)
+            .await?
+            .error_for_status()
+            .context("peer rejected sender key")?;
+        Ok(())
+    }
+
+    /// Download an attachment blob a user of the peer sent to one of ours.
+    /// Returns None if the peer does not know it (or the token is wrong).
+    /// Blobs larger than `max_bytes` are refused.
+    pub async fn fetch_attachment(
+        &self,
+        peer: &FederationNode,
+        attachment_id: Uuid,
+        download_token: &str,
+        max_bytes: u64,
+    ) -> Result<Option<Bytes>> {
+        let url = format!("{}/s2s/attachments/{attachment_id}", peer.api_url);
+        let resp = self
+            .signed_get_request(peer, &url)
+            .await?
+            .header(DOWNLOAD_TOKEN_HEADER, download_token)
+            .send()
+            .await
+            .context("S2S GET request failed")?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = resp
+            .error_for_status()
+            .context("peer returned error for attachment fetch")?;
+        if resp.content_length().is_some_and(|len| len > max_bytes) {
+            anyhow::bail!("peer attachment exceeds {max_bytes} bytes");
+        }
+        let bytes = resp.bytes().await.context("attachment download failed")?;
+        if bytes.len() as u64 > max_bytes {
+            anyhow::bail!("peer attachment exceeds {max_bytes} bytes");
+        }
+        Ok(Some(bytes))
+    }
+
+    /// Check that a peer answers its public GET /s2s/info, recording the
+    /// protocol version it reports. Used to probe a peer whose circuit
+    /// breaker is open.
+    pub async fn probe_info(&self, peer: &FederationNode) -> Result<()> {
+        let version = self.probe_protocol_version(&peer.api_url).await?;
+        self.record_probe(peer, &version).await;
+        Ok(())
+    }
+
+    /// `peer` as far as protocol negotiation knows it.
+    ///
+    /// A peer recorded below our PROTOCOL_VERSION is probed through
+    /// GET /s2s/info when its last check is older than PROTOCOL_RECHECK_HOURS.
+    /// The copy returned carries the raised version, and counts as checked
+    /// just now even if the probe failed, so requests made with it do not
+    /// probe again; a failed probe is retried with the next fresh record.
+    pub async fn negotiate(&self, peer: &FederationNode) -> FederationNode {
+        let mut peer = peer.clone();
+        if peer.speaks_at_least(PROTOCOL_VERSION) {
+            return peer;
+        }
+        let recently_checked = peer.protocol_checked_at.is_some_and(|at| {
+            chrono::Utc::now() - at < chrono::Duration::hours(PROTOCOL_RECHECK_HOURS)
+        });
+        if recently_checked {
+            return peer;
+        }
+
+        match self.probe_protocol_version(&peer.api_url).await {
+            Ok(version) => {
+                self.record_probe(&peer, &version).await;
+                if !peer.speaks_at_least(&version) {
+                    peer.protocol_version = Some(version);
+                }
+            }
+            Err(e) => warn!(node_id = %peer.node_id, "protocol probe failed: {e:#}"),
+        }
+        peer.protocol_checked_at = Some(chrono::Utc::now());
+        peer
+    }
+
     // ── Private helpers ───────────────────────────────────────────────────────
 
-    async fn signed_get(&self, url: &str) -> Result<reqwest::Response> {
-        let path = url_path(url);
-        let (ts, nonce, sig) = self.sign("GET", path)?;
-        self.http
+    async fn signed_get(&self, peer: &FederationNode, url: &str) -> Result<reqwest::Response> {
+        self.signed_get_request(peer, url)
+            .await?
+            .send()
+            .await
+            .context("S2S GET request failed")
+    }
+
+    async fn signed_get_request(
+        &self,
+        peer: &FederationNode,
+        url: &str,
+    ) -> Result<reqwest::RequestBuilder> {
+        let digest = self
+            .negotiate(peer)
+            .await
+            .uses_content_digest()
+            .then(|| content_digest_value(&BodyDigest::of(&[]).0));
+        let (ts, nonce, sig) = self.sign("GET", url_path(url), digest.as_deref())?;
+        let mut req = self
+            .http
             .get(url)
             .header("X-Node-ID", &self.this_node_id)
             .header("X-Timestamp", &ts)
             .header("X-Nonce", &nonce)
-            .header("X-Node-Signature", &sig)
-            .send()
-            .await
-            .context("S2S GET request failed")
+            .header("X-Node-Signature", &sig);
+        if let Some(digest) = digest {
+            req = req.header(CONTENT_DIGEST_HEADER, digest);
+        }
+        Ok(req)
     }
 
     async fn signed_post<T: Serialize>(
         &self,
-        api_url: &str,
+        peer: &FederationNode,
         path: &str,
         body: &T,
     ) -> Result<reqwest::Response> {
-        let (ts, nonce, sig) = self.sign("POST", path)?;
-        let url = format!("{api_url}{path}");
-        self.http
+        // Serialize once so the digest covers exactly the bytes sent.
+        let body = serde_json::to_vec(body).context("failed to serialize S2S body")?;
+        let digest = self
+            .negotiate(peer)
+            .await
+            .uses_content_digest()
+            .then(|| content_digest_value(&BodyDigest::of(&body).0));
+        let (ts, nonce, sig) = self.sign("POST", path, digest.as_deref())?;
+        let url = format!("{}{path}", peer.api_url);
+        let mut req = self
+            .http
             .post(&url)
             .header("Content-Type", "application/json")
             .header("X-Node-ID", &self.this_node_id)
             .header("X-Timestamp", &ts)
             .header("X-Nonce", &nonce)
-            .header("X-Node-Signature", &sig)
-            .json(body)
+            .header("X-Node-Signature", &sig);
+        if let Some(digest) = digest {
+            req = req.header(CONTENT_DIGEST_HEADER, digest);
+        }
+        req.body(body)
             .send()
             .await
             .context("S2S POST request failed")
     }
 
+    async fn record_probe(&self, peer: &FederationNode, version: &str) {
+        info!(node_id = %peer.node_id, %version, "probed peer protocol version");
+        if let Err(e) =
+            federation_repository::record_protocol_version(&self.pool, &peer.node_id, version, true)
+                .await
+        {
+            warn!(node_id = %peer.node_id, "failed to record protocol version: {e}");
+        }
+    }
+
+    /// Read the protocol version a peer reports in its public GET /s2s/info.
+    async fn probe_protocol_version(&self, api_url: &str) -> Result<String> {
+        let info = self
+            .http
+            .get(format!("{api_url}/s2s/info"))
+            .send()
+            .await
+            .context("S2S info request failed")?
+            .error_for_status()
+            .context("peer returned error for info")?
+            .json::<serde_json::Value>()
+            .await
+            .context("invalid info in peer response")?;
+        let version = info["protocol_version"]
+            .as_str()
+            .filter(|v| parse_protocol_version(v).is_some())
+            .context("peer info has no valid protocol_version")?;
+        Ok(version.to_string())
+    }
+
     /// Build the canonical string and sign it with this node's private key.
-    ///
-    /// canonical = "{method}\n{path}\n{ts}\n{nonce}"
-    fn sign(&self, method: &str, path: &str) -> Result<(String, String, String)> {
+    /// `content_digest` is None for peers still on protocol 0.0.2.
+    fn sign(
+        &self,
+        method: &str,
+        path: &str,
+        content_digest: Option<&str>,
+    ) -> Result<(String, String, String)> {
         let ts = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -158,7 +498,7 @@ impl FederationClient {
             B64.encode(buf)
         };
 
-        let canonical = format!("{method}\n{path}\n{ts}\n{nonce}");
+        let canonical = node_canonical_string(method, path, &ts, &nonce, content_digest);
         let signing_key = self.node_keys.signing_key()?;
         let signature = signing_key.sign(canonical.as_bytes());
         let sig_b64 = B64.encode(signature.to_bytes());
@@ -167,6 +507,29 @@ impl FederationClient {
     }
 }
 
+/// Turn a 409 from a fan-out endpoint into a `DeviceMismatch` error.
+async fn reject_conflict(resp: reqwest::Response) -> Result<reqwest::Response> {
+    if resp.status() != StatusCode::CONFLICT {
+        return Ok(resp);
+    }
+    let mismatch = resp
+        .json::<DeviceMismatch>()
+        .await
+        .context("invalid device mismatch in peer response")?;
+    Err(mismatch.into())
+}
+
+/// Whether `err` means the peer could not be reached at all: no connection,
+/// a timeout, or a 5xx. Only these count against its circuit breaker.
+pub fn is_unreachable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_connect()
+            || e.is_timeout()
+            || e.is_request()
+            || e.status().is_some_and(|s| s.is_server_error())
+    })
+}
+
 /// Extract the path+query portion from a full URL.
 ///
 /// "https://node-a.hushnet.net/api/s2s/messages?x=1" → "/api/s2s/messages?x=1"
@@ -0,0 +1,119 @@
+// src/federation/groups.rs
+//
+// Outbound side of federated group chats.
+//
+// A group is homed on its owner's node, which is authoritative for its name,
+// membership and roles. After every change the home node sends a full,
+// versioned snapshot (S2sGroupState) to each node hosting a member, and to
+// the nodes of members just removed; those nodes keep a mirror of the group
+// (chats.home_node_id set) and apply only snapshots newer than their copy.
+// Members on a mirror can leave it (S2sGroupLeave to the home node) but not
+// manage it.
+//
+// Group messages and sender keys do not go through the home node: the
+// sender's node forwards them to every node hosting members, which fans them
+// out to its own users' devices.
+//
+// Everything goes through federation_outbox, so a node that is down gets the
+// latest snapshot and the messages it missed once it is back.
+
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    models::federation::S2sGroupLeave,
+    repository::{chat_repository, federation_repository},
+};
+
+use super::{
+    client::FederationClient,
+    outbox::{self, OutboxPayload},
+};
+
+/// Send the current state of group `chat_id`, which this node is home to, to
+/// every node hosting a member plus `also_notify`. `change` is the
+/// `group_updated` change that led to it. Does nothing for groups without
+/// members on other nodes.
+pub async fn publish_group_state(
+    state: &AppState,
+    chat_id: &Uuid,
+    change: &str,
+    also_notify: &[String],
+) -> anyhow::Result<()> {
+    let mut nodes = chat_repository::get_member_nodes(&state.pool, chat_id).await?;
+    nodes.extend(also_notify.iter().cloned());
+    nodes.sort();
+    nodes.dedup();
+    nodes.retain(|n| *n != state.this_node_id);
+    if nodes.is_empty() {
+        return Ok(());
+    }
+
+    let Some(snapshot) =
+        chat_repository::snapshot_group_state(&state.pool, chat_id, &state.this_node_id, change)
+            .await?
+    else {
+        return Ok(());
+    };
+
+    let logical_msg_id = format!("group_state:{chat_id}:{}", snapshot.version);
+    send_to_nodes(
+        state,
+        &nodes,
+        &logical_msg_id,
+        OutboxPayload::GroupState(snapshot),
+    )
+    .await
+}
+
+/// Tell the home node of a mirrored group that local user `username` left.
+pub async fn send_leave(
+    state: &AppState,
+    chat_id: &Uuid,
+    home_node: &str,
+    username: &str,
+) -> anyhow::Result<()> {
+    let leave = S2sGroupLeave {
+        chat_id: *chat_id,
+        member_address: format!("{username}@{}", state.this_node_id),
+    };
+    let logical_msg_id = format!("group_leave:{chat_id}:{}", leave.member_address);
+    send_to_nodes(
+        state,
+        &[home_node.to_string()],
+        &logical_msg_id,
+        OutboxPayload::GroupLeave(leave),
+    )
+    .await
+}
+
+/// Queue `payload` for each of `nodes` and attempt delivery right away.
+/// Blocked nodes are skipped.
+pub async fn send_to_nodes(
+    state: &AppState,
+    nodes: &[String],
+    logical_msg_id: &str,
+    payload: OutboxPayload,
+) -> anyhow::Result<()> {
+    let client = FederationClient::from_state(state);
+
+    for node_id in nodes {
+        match federation_repository::get_federation_node(&state.pool, node_id).await? {
+            Some(node) if node.is_blocked => {
+                warn!(%node_id, %logical_msg_id, "group: not sending to blocked node");
+                continue;
+            }
+            _ => {}
+        }
+        outbox::enqueue_and_deliver(
+            &state.pool,
+            &client,
+            node_id,
+            logical_msg_id,
+            payload.clone(),
+        )
+        .await?;
+    }
+    Ok(())
+}
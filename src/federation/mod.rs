@@ -1,5 +1,7 @@
 pub mod client;
+pub mod groups;
 pub mod outbox;
+pub mod peer_gate;
 
 /// Parse a federated user address into its local and node components.
 ///
@@ -13,6 +13,18 @@
 // network call: Node A returns 202 Accepted to the client as soon as the
 // entry is written to the outbox, regardless of Node B's availability.
 //
+// Each poll groups the due entries by target node and drains every node in
+// its own task. Message fan-outs to a peer on protocol 0.0.4 go out together
+// in POST /s2s/messages/batch requests; other entries go out one by one. The
+// poll waits for all drains before the next one, so no entry is sent twice.
+//
+// Requests go through the shared PeerGate (federation::peer_gate), which
+// bounds concurrency globally and per peer and keeps a circuit breaker per
+// peer. While a peer's breaker is open its entries are deferred without
+// counting an attempt; when the cooldown ends the peer's GET /s2s/info is
+// probed first, and a failed probe counts an attempt against every entry
+// waiting for it.
+//
 // Backoff schedule (seconds):
 //   attempt 0 → immediate (spawned task at request time)
 //   attempt 1 → 10 s
@@ -21,39 +33,197 @@
 //   ...
 //   attempt 12+ → 3600 s (1 hour, cap)
 //
-// After MAX_ATTEMPTS the entry is marked 'failed'. A separate mechanism
-// (not implemented here) could push a delivery-failure event to the
-// originating client's WebSocket connection.
+// A message, group message or sender key the peer refuses with 409 (device
+// list mismatch) is not retried: the entry is marked 'failed' at once and the
+// sending device gets a `device_mismatch` event so it can re-encrypt for the
+// right devices.
+//
+// After MAX_ATTEMPTS the entry is marked 'failed' and abandoned. If it
+// carried a message, group message or sender key, the sending device gets a
+// `delivery_failed` event. Operators can requeue failed entries through the
+// admin API (controllers::admin_controller).
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use sqlx::PgPool;
-use tokio::time;
+use tokio::{task::JoinSet, time};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use crate::{
-    models::federation::S2sMessagePayload, repository::federation_repository,
-    utils::node_keys::NodeKeys,
+    models::federation::{
+        FederationNode, FederationOutboxEntry, S2sChatExpiry, S2sDeviceRemoved, S2sGroupLeave,
+        S2sGroupMessage, S2sGroupState, S2sMessageBatch, S2sMessagePayload, S2sReceipt,
+        S2sSenderKey, MAX_MESSAGE_BATCH, OUTBOX_KIND_CHAT_EXPIRY, OUTBOX_KIND_DEVICE_REMOVED,
+        OUTBOX_KIND_GROUP_LEAVE, OUTBOX_KIND_GROUP_MESSAGE, OUTBOX_KIND_GROUP_STATE,
+        OUTBOX_KIND_MESSAGES, OUTBOX_KIND_RECEIPT, OUTBOX_KIND_SENDER_KEY,
+    },
+    models::{message::DeviceMismatch, sender_key::distribution_logical_id},
+    repository::{device_repository, federation_repository, message_repository},
 };
 
-use super::client::FederationClient;
+use super::{
+    client::{is_unreachable, FederationClient},
+    peer_gate::Breaker,
+};
 
 const POLL_INTERVAL: Duration = Duration::from_secs(10);
 const MAX_ATTEMPTS: i32 = 10;
+/// Most bytes of message payloads packed into one batch request.
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Typed body of an outbox entry, selected by its `kind` column.
+#[derive(Clone)]
+pub enum OutboxPayload {
+    Messages(S2sMessagePayload),
+    DeviceRemoved(S2sDeviceRemoved),
+    Receipt(S2sReceipt),
+    GroupState(S2sGroupState),
+    GroupLeave(S2sGroupLeave),
+    GroupMessage(S2sGroupMessage),
+    SenderKey(S2sSenderKey),
+    ChatExpiry(S2sChatExpiry),
+}
+
+/// A decoded outbox entry about to be attempted.
+struct Due {
+    id: Uuid,
+    attempt_count: i32,
+    payload: OutboxPayload,
+}
+
+/// One S2S request carrying one or more outbox entries.
+enum Request {
+    Single(Due),
+    /// Only `OutboxPayload::Messages` entries.
+    Batch(Vec<Due>),
+}
+
+impl Request {
+    fn batch(mut entries: Vec<Due>) -> Self {
+        if entries.len() == 1 {
+            Request::Single(entries.pop().unwrap())
+        } else {
+            Request::Batch(entries)
+        }
+    }
+}
+
+/// Sending device of an outbox payload, see `OutboxPayload::origin`.
+struct Origin {
+    device_id: Uuid,
+    logical_msg_id: String,
+    chat_id: Option<Uuid>,
+    to_user_address: Option<String>,
+}
+
+impl OutboxPayload {
+    fn decode(kind: &str, payload: serde_json::Value) -> Result<Self, String> {
+        let decoded = match kind {
+            OUTBOX_KIND_MESSAGES => serde_json::from_value(payload).map(OutboxPayload::Messages),
+            OUTBOX_KIND_DEVICE_REMOVED => {
+                serde_json::from_value(payload).map(OutboxPayload::DeviceRemoved)
+            }
+            OUTBOX_KIND_RECEIPT => serde_json::from_value(payload).map(OutboxPayload::Receipt),
+            OUTBOX_KIND_GROUP_STATE => {
+                serde_json::from_value(payload).map(OutboxPayload::GroupState)
+            }
+            OUTBOX_KIND_GROUP_LEAVE => {
+                serde_json::from_value(payload).map(OutboxPayload::GroupLeave)
+            }
+            OUTBOX_KIND_GROUP_MESSAGE => {
+                serde_json::from_value(payload).map(OutboxPayload::GroupMessage)
+            }
+            OUTBOX_KIND_SENDER_KEY => serde_json::from_value(payload).map(OutboxPayload::SenderKey),
+            OUTBOX_KIND_CHAT_EXPIRY => {
+                serde_json::from_value(payload).map(OutboxPayload::ChatExpiry)
+            }
+            other => return Err(format!("unknown outbox kind {other:?}")),
+        };
+        decoded.map_err(|e| e.to_string())
+    }
+
+    /// The `federation_outbox.kind` this payload is stored under.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboxPayload::Messages(_) => OUTBOX_KIND_MESSAGES,
+            OutboxPayload::DeviceRemoved(_) => OUTBOX_KIND_DEVICE_REMOVED,
+            OutboxPayload::Receipt(_) => OUTBOX_KIND_RECEIPT,
+            OutboxPayload::GroupState(_) => OUTBOX_KIND_GROUP_STATE,
+            OutboxPayload::GroupLeave(_) => OUTBOX_KIND_GROUP_LEAVE,
+            OutboxPayload::GroupMessage(_) => OUTBOX_KIND_GROUP_MESSAGE,
+            OutboxPayload::SenderKey(_) => OUTBOX_KIND_SENDER_KEY,
+            OutboxPayload::ChatExpiry(_) => OUTBOX_KIND_CHAT_EXPIRY,
+        }
+    }
+
+    /// The JSON stored in `federation_outbox.payload`.
+    pub fn encode(&self) -> serde_json::Result<serde_json::Value> {
+        match self {
+            OutboxPayload::Messages(p) => serde_json::to_value(p),
+            OutboxPayload::DeviceRemoved(p) => serde_json::to_value(p),
+            OutboxPayload::Receipt(p) => serde_json::to_value(p),
+            OutboxPayload::GroupState(p) => serde_json::to_value(p),
+            OutboxPayload::GroupLeave(p) => serde_json::to_value(p),
+            OutboxPayload::GroupMessage(p) => serde_json::to_value(p),
+            OutboxPayload::SenderKey(p) => serde_json::to_value(p),
+            OutboxPayload::ChatExpiry(p) => serde_json::to_value(p),
+        }
+    }
+
+    /// The device this payload was sent from and what to tell it about,
+    /// for payloads a device sent. None for node-generated traffic.
+    fn origin(&self, target_node_id: &str) -> Option<Origin> {
+        match self {
+            OutboxPayload::Messages(p) => Some(Origin {
+                device_id: p.from_device_id,
+                logical_msg_id: p.logical_msg_id.clone(),
+                chat_id: None,
+                to_user_address: Some(format!("{}@{}", p.to_user, target_node_id)),
+            }),
+            OutboxPayload::GroupMessage(p) => Some(Origin {
+                device_id: p.from_device_id,
+                logical_msg_id: p.logical_msg_id.clone(),
+                chat_id: Some(p.chat_id),
+                to_user_address: None,
+            }),
+            OutboxPayload::SenderKey(p) => Some(Origin {
+                device_id: p.from_device_id,
+                logical_msg_id: distribution_logical_id(&p.key_id),
+                chat_id: Some(p.chat_id),
+                to_user_address: None,
+            }),
+            _ => None,
+        }
+    }
+
+    async fn deliver(
+        &self,
+        client: &FederationClient,
+        peer: &FederationNode,
+    ) -> anyhow::Result<()> {
+        match self {
+            OutboxPayload::Messages(p) => client.forward_messages(peer, p).await.map(|_| ()),
+            OutboxPayload::DeviceRemoved(p) => client.forward_device_removed(peer, p).await,
+            OutboxPayload::Receipt(p) => client.forward_receipt(peer, p).await,
+            OutboxPayload::GroupState(p) => client.forward_group_state(peer, p).await,
+            OutboxPayload::GroupLeave(p) => client.forward_group_leave(peer, p).await,
+            OutboxPayload::GroupMessage(p) => client.forward_group_message(peer, p).await,
+            OutboxPayload::SenderKey(p) => client.forward_sender_key(peer, p).await,
+            OutboxPayload::ChatExpiry(p) => client.forward_chat_expiry(peer, p).await,
+        }
+    }
+}
 
 /// Long-running task: poll the outbox and retry failed deliveries.
 ///
 /// Spawn this once at startup:
 /// ```rust
-/// tokio::spawn(federation::outbox::run(pool, node_keys, node_id, http));
+/// tokio::spawn(federation::outbox::run(pool, FederationClient::from_state(&state)));
 /// ```
-pub async fn run(
-    pool: PgPool,
-    node_keys: Arc<NodeKeys>,
-    this_node_id: String,
-    http_client: reqwest::Client,
-) {
+pub async fn run(pool: PgPool, client: FederationClient) {
     let mut interval = time::interval(POLL_INTERVAL);
     // Delay mode: if a tick is missed (the previous iteration took longer than
     // POLL_INTERVAL), skip the missed ticks rather than bursting.
@@ -62,10 +232,13 @@ pub async fn run(
     loop {
         interval.tick().await;
 
-        // Housekeeping: purge nonces older than 5 minutes.
+        // Housekeeping: purge node and device nonces older than 5 minutes.
         if let Err(e) = federation_repository::purge_expired_nonces(&pool).await {
             warn!(err = %e, "outbox: nonce purge failed");
         }
+        if let Err(e) = device_repository::purge_expired_device_nonces(&pool).await {
+            warn!(err = %e, "outbox: device nonce purge failed");
+        }
 
         let entries = match federation_repository::fetch_due_outbox_entries(&pool).await {
             Ok(v) => v,
@@ -79,87 +252,487 @@ pub async fn run(
             info!(count = entries.len(), "outbox: processing due entries");
         }
 
+        let mut by_node: HashMap<String, Vec<FederationOutboxEntry>> = HashMap::new();
         for entry in entries {
+            by_node
+                .entry(entry.target_node_id.clone())
+                .or_default()
+                .push(entry);
+        }
+
+        let mut drains = JoinSet::new();
+        for (node_id, entries) in by_node {
             let pool = pool.clone();
-            let client =
-                FederationClient::new(http_client.clone(), node_keys.clone(), this_node_id.clone());
-
-            tokio::spawn(async move {
-                let payload: S2sMessagePayload = match serde_json::from_value(entry.payload) {
-                    Ok(p) => p,
-                    Err(e) => {
-                        error!(entry_id = %entry.id, err = %e, "outbox: cannot deserialize entry, marking failed");
-                        let _ = federation_repository::record_outbox_failure(
-                            &pool,
-                            entry.id,
-                            MAX_ATTEMPTS,
-                            MAX_ATTEMPTS,
-                        )
-                        .await;
-                        return;
-                    }
-                };
-
-                let node =
-                    match federation_repository::get_federation_node(&pool, &entry.target_node_id)
-                        .await
-                    {
-                        Ok(Some(n)) => n,
-                        Ok(None) => {
-                            warn!(
-                                target_node = %entry.target_node_id,
-                                entry_id = %entry.id,
-                                "outbox: unknown target node"
-                            );
-                            let _ = federation_repository::record_outbox_failure(
-                                &pool,
-                                entry.id,
-                                entry.attempt_count + 1,
-                                MAX_ATTEMPTS,
-                            )
-                            .await;
-                            return;
-                        }
-                        Err(e) => {
-                            error!(err = %e, "outbox: db error looking up node");
-                            return;
-                        }
-                    };
-
-                debug!(
-                    entry_id = %entry.id,
-                    target_node = %entry.target_node_id,
-                    attempt = entry.attempt_count + 1,
-                    "outbox: attempting delivery"
-                );
-
-                match client.forward_messages(&node.api_url, &payload).await {
-                    Ok(_) => {
-                        info!(
-                            entry_id = %entry.id,
-                            target_node = %entry.target_node_id,
-                            "outbox: delivery succeeded"
-                        );
-                        let _ = federation_repository::mark_outbox_delivered(&pool, entry.id).await;
+            let client = client.clone();
+            drains.spawn(async move { drain_node(&pool, &client, &node_id, entries).await });
+        }
+        while drains.join_next().await.is_some() {}
+    }
+}
+
+/// Attempt every due entry for `node_id`, batching what the peer accepts in
+/// batches, unless its circuit breaker says to leave it alone.
+async fn drain_node(
+    pool: &PgPool,
+    client: &FederationClient,
+    node_id: &str,
+    entries: Vec<FederationOutboxEntry>,
+) {
+    let mut due = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match OutboxPayload::decode(&entry.kind, entry.payload) {
+            Ok(payload) => due.push(Due {
+                id: entry.id,
+                attempt_count: entry.attempt_count,
+                payload,
+            }),
+            Err(e) => {
+                error!(entry_id = %entry.id, err = %e, "outbox: cannot deserialize entry, marking failed");
+                let _ = federation_repository::record_outbox_failure(
+                    pool,
+                    entry.id,
+                    MAX_ATTEMPTS,
+                    MAX_ATTEMPTS,
+                )
+                .await;
+            }
+        }
+    }
+    if due.is_empty() {
+        return;
+    }
+
+    let Some(node) = lookup_node(pool, node_id, &due).await else {
+        return;
+    };
+
+    match client.gate.check(node_id) {
+        Breaker::Closed => {}
+        Breaker::Open(until) => {
+            debug!(target_node = %node_id, "outbox: circuit open, deferring entries");
+            defer_node(pool, node_id, until).await;
+            return;
+        }
+        Breaker::Probe => {
+            if let Err(e) = client.probe_info(&node).await {
+                let until = client.gate.record_probe_failure(node_id);
+                warn!(target_node = %node_id, err = %e, "outbox: peer still unreachable");
+                for d in &due {
+                    record_failure(pool, d.id, d.attempt_count + 1, &d.payload, node_id).await;
+                }
+                defer_node(pool, node_id, until).await;
+                return;
+            }
+            info!(target_node = %node_id, "outbox: peer reachable again, resuming deliveries");
+            client.gate.record_reachable(node_id);
+        }
+    }
+
+    let node = Arc::new(client.negotiate(&node).await);
+    let mut sends = JoinSet::new();
+    for request in plan_requests(due, node.accepts_message_batches()) {
+        let pool = pool.clone();
+        let client = client.clone();
+        let node = node.clone();
+        sends.spawn(async move { send(&pool, &client, &node, request).await });
+    }
+    while sends.join_next().await.is_some() {}
+}
+
+/// Split a peer's due entries into requests: message fan-outs go in batches
+/// of up to MAX_MESSAGE_BATCH entries and MAX_BATCH_BYTES of payload when the
+/// peer accepts them, everything else on its own.
+fn plan_requests(due: Vec<Due>, batches: bool) -> Vec<Request> {
+    let mut requests = Vec::new();
+    let mut batch: Vec<Due> = Vec::new();
+    let mut batch_bytes = 0;
+    for d in due {
+        let size = match &d.payload {
+            OutboxPayload::Messages(p) if batches => serde_json::to_vec(p).map_or(0, |b| b.len()),
+            _ => {
+                requests.push(Request::Single(d));
+                continue;
+            }
+        };
+        if !batch.is_empty()
+            && (batch.len() == MAX_MESSAGE_BATCH || batch_bytes + size > MAX_BATCH_BYTES)
+        {
+            requests.push(Request::batch(std::mem::take(&mut batch)));
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+        batch.push(d);
+    }
+    if !batch.is_empty() {
+        requests.push(Request::batch(batch));
+    }
+    requests
+}
+
+/// Write `payload` to the outbox for `target_node_id` and try to deliver it
+/// right away in a spawned task; the worker retries if that attempt fails.
+pub async fn enqueue_and_deliver(
+    pool: &PgPool,
+    client: &FederationClient,
+    target_node_id: &str,
+    logical_msg_id: &str,
+    payload: OutboxPayload,
+) -> anyhow::Result<Uuid> {
+    // Receipts for a forwarded message are accepted from the peer long after
+    // the outbox entry itself is gone.
+    match &payload {
+        OutboxPayload::Messages(p) => {
+            federation_repository::record_forwarded_message(
+                pool,
+                target_node_id,
+                &p.logical_msg_id,
+                &p.from_federated_address,
+                Some(&p.to_user),
+                None,
+            )
+            .await?
+        }
+        OutboxPayload::GroupMessage(p) => {
+            federation_repository::record_forwarded_message(
+                pool,
+                target_node_id,
+                &p.logical_msg_id,
+                &p.from_federated_address,
+                None,
+                Some(p.chat_id),
+            )
+            .await?
+        }
+        _ => {}
+    }
+
+    let json = payload.encode()?;
+    let entry_id = federation_repository::enqueue_outbox(
+        pool,
+        target_node_id,
+        logical_msg_id,
+        payload.kind(),
+        &json,
+    )
+    .await?;
+
+    let pool = pool.clone();
+    let client = client.clone();
+    let target_node_id = target_node_id.to_string();
+    tokio::spawn(async move {
+        let due = Due {
+            id: entry_id,
+            attempt_count: 0,
+            payload,
+        };
+        if let Some(node) = lookup_node(&pool, &target_node_id, std::slice::from_ref(&due)).await {
+            send(&pool, &client, &node, Request::Single(due)).await;
+        }
+    });
+
+    Ok(entry_id)
+}
+
+/// The peer `due` is for, if it can be delivered to now. Entries for an
+/// unknown node count a failed attempt; entries for a blocked node stay
+/// pending, untouched, until it is unblocked (fetch_due_outbox_entries skips
+/// them meanwhile).
+async fn lookup_node(pool: &PgPool, node_id: &str, due: &[Due]) -> Option<FederationNode> {
+    match federation_repository::get_federation_node(pool, node_id).await {
+        Ok(Some(node)) if node.is_blocked => {
+            debug!(target_node = %node_id, "outbox: target node is blocked");
+            None
+        }
+        Ok(Some(node)) => Some(node),
+        Ok(None) => {
+            warn!(target_node = %node_id, entries = due.len(), "outbox: unknown target node");
+            for d in due {
+                record_failure(pool, d.id, d.attempt_count + 1, &d.payload, node_id).await;
+            }
+            None
+        }
+        Err(e) => {
+            error!(err = %e, "outbox: db error looking up node");
+            None
+        }
+    }
+}
+
+/// Push the pending entries for `node_id` back until its breaker's next probe.
+async fn defer_node(pool: &PgPool, node_id: &str, until: Instant) {
+    let secs = until.saturating_duration_since(Instant::now()).as_secs() as i64 + 1;
+    if let Err(e) = federation_repository::defer_outbox_for_node(pool, node_id, secs).await {
+        error!(target_node = %node_id, err = %e, "outbox: failed to defer entries");
+    }
+}
+
+/// Make one S2S request within the gate's limits and record the outcome of
+/// every entry it carries.
+async fn send(pool: &PgPool, client: &FederationClient, node: &FederationNode, request: Request) {
+    let node_id = node.node_id.as_str();
+    let _permit = client.gate.acquire(node_id).await;
+    // The breaker may have opened while this request waited for a slot; the
+    // entries stay pending and the next poll defers them.
+    if client.gate.is_open(node_id) {
+        return;
+    }
+
+    match request {
+        Request::Single(due) => {
+            debug!(
+                entry_id = %due.id,
+                target_node = %node_id,
+                attempt = due.attempt_count + 1,
+                "outbox: attempting delivery"
+            );
+            let result = due.payload.deliver(client, node).await;
+            settle(pool, client, node_id, &due, result).await;
+        }
+        Request::Batch(entries) => {
+            debug!(target_node = %node_id, count = entries.len(), "outbox: attempting batch delivery");
+            let batch = S2sMessageBatch {
+                messages: entries
+                    .iter()
+                    .filter_map(|d| match &d.payload {
+                        OutboxPayload::Messages(p) => Some(p.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+            };
+            match client.forward_message_batch(node, &batch).await {
+                Ok(results) => {
+                    for (due, result) in entries.iter().zip(results) {
+                        settle(pool, client, node_id, due, result.map(|_| ())).await;
                     }
-                    Err(e) => {
-                        warn!(
-                            entry_id = %entry.id,
-                            target_node = %entry.target_node_id,
-                            attempt = entry.attempt_count + 1,
-                            err = %e,
-                            "outbox: delivery failed"
-                        );
-                        let _ = federation_repository::record_outbox_failure(
-                            &pool,
-                            entry.id,
-                            entry.attempt_count + 1,
-                            MAX_ATTEMPTS,
-                        )
-                        .await;
+                }
+                Err(e) => {
+                    warn!(
+                        target_node = %node_id,
+                        count = entries.len(),
+                        err = %e,
+                        "outbox: batch delivery failed"
+                    );
+                    note_outcome(client, node_id, &e);
+                    for d in &entries {
+                        record_failure(pool, d.id, d.attempt_count + 1, &d.payload, node_id).await;
                     }
                 }
-            });
+            }
+        }
+    }
+}
+
+/// Record the outcome of one delivery attempt of `due`.
+async fn settle(
+    pool: &PgPool,
+    client: &FederationClient,
+    node_id: &str,
+    due: &Due,
+    result: anyhow::Result<()>,
+) {
+    match result {
+        Ok(()) => {
+            client.gate.record_reachable(node_id);
+            info!(
+                entry_id = %due.id,
+                target_node = %node_id,
+                "outbox: delivery succeeded"
+            );
+            let _ = federation_repository::mark_outbox_delivered(pool, due.id).await;
         }
+        Err(e) => {
+            if let Some(mismatch) = e.downcast_ref::<DeviceMismatch>() {
+                client.gate.record_reachable(node_id);
+                reject_device_mismatch(pool, due.id, &due.payload, node_id, mismatch).await;
+                return;
+            }
+            warn!(
+                entry_id = %due.id,
+                target_node = %node_id,
+                attempt = due.attempt_count + 1,
+                err = %e,
+                "outbox: delivery failed"
+            );
+            note_outcome(client, node_id, &e);
+            record_failure(pool, due.id, due.attempt_count + 1, &due.payload, node_id).await;
+        }
+    }
+}
+
+/// Feed a failed request into the peer's circuit breaker: only failures that
+/// mean the peer could not be reached count against it.
+fn note_outcome(client: &FederationClient, node_id: &str, err: &anyhow::Error) {
+    if !is_unreachable(err) {
+        client.gate.record_reachable(node_id);
+    } else if client.gate.record_unreachable(node_id) {
+        warn!(target_node = %node_id, "outbox: peer unreachable, pausing deliveries");
+    }
+}
+
+/// Record failed attempt number `attempt_count`. When that abandons the
+/// entry, tell the sending device with a `delivery_failed` event.
+async fn record_failure(
+    pool: &PgPool,
+    entry_id: Uuid,
+    attempt_count: i32,
+    payload: &OutboxPayload,
+    target_node_id: &str,
+) {
+    let abandoned = match federation_repository::record_outbox_failure(
+        pool,
+        entry_id,
+        attempt_count,
+        MAX_ATTEMPTS,
+    )
+    .await
+    {
+        Ok(abandoned) => abandoned,
+        Err(e) => {
+            error!(entry_id = %entry_id, err = %e, "outbox: failed to record delivery failure");
+            return;
+        }
+    };
+    if !abandoned {
+        return;
+    }
+
+    warn!(
+        entry_id = %entry_id,
+        target_node = %target_node_id,
+        attempts = attempt_count,
+        "outbox: giving up on entry"
+    );
+    let Some(origin) = payload.origin(target_node_id) else {
+        return;
+    };
+    if let Err(e) = message_repository::emit_delivery_failed(
+        pool,
+        &origin.device_id,
+        &origin.logical_msg_id,
+        target_node_id,
+        origin.chat_id.as_ref(),
+        origin.to_user_address.as_deref(),
+        attempt_count,
+    )
+    .await
+    {
+        error!(err = %e, "outbox: failed to notify sender of delivery failure");
+    }
+}
+
+/// Give up on a fan-out the peer refused for its device set and tell the
+/// sending device which devices to re-encrypt for.
+async fn reject_device_mismatch(
+    pool: &PgPool,
+    entry_id: Uuid,
+    payload: &OutboxPayload,
+    target_node_id: &str,
+    mismatch: &DeviceMismatch,
+) {
+    let Some(origin) = payload.origin(target_node_id) else {
+        warn!(entry_id = %entry_id, "outbox: unexpected device mismatch");
+        return;
+    };
+
+    warn!(
+        entry_id = %entry_id,
+        target_node = %target_node_id,
+        logical_id = %origin.logical_msg_id,
+        missing = mismatch.missing_devices.len(),
+        extra = mismatch.extra_devices.len(),
+        "outbox: peer rejected device set, not retrying"
+    );
+    let _ =
+        federation_repository::record_outbox_failure(pool, entry_id, MAX_ATTEMPTS, MAX_ATTEMPTS)
+            .await;
+    if let Err(e) = message_repository::emit_device_mismatch(
+        pool,
+        &origin.device_id,
+        &origin.logical_msg_id,
+        target_node_id,
+        origin.chat_id.as_ref(),
+        origin.to_user_address.as_deref(),
+        mismatch,
+    )
+    .await
+    {
+        error!(err = %e, "outbox: failed to notify sender of device mismatch");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::federation::{S2sAck, S2sGroupLeave};
+
+    fn due(payload: OutboxPayload) -> Due {
+        Due {
+            id: Uuid::new_v4(),
+            attempt_count: 0,
+            payload,
+        }
+    }
+
+    fn message(n: usize) -> OutboxPayload {
+        OutboxPayload::Messages(S2sMessagePayload {
+            logical_msg_id: format!("msg-{n}"),
+            from_federated_address: "alice@node-a.hushnet.net".into(),
+            from_device_id: Uuid::new_v4(),
+            from_identity_pubkey: "pk".into(),
+            to_user: "bob".into(),
+            expires_at: None,
+            payloads: vec![],
+        })
+    }
+
+    #[test]
+    fn payload_round_trips_through_its_kind() {
+        let leave = OutboxPayload::GroupLeave(S2sGroupLeave {
+            chat_id: Uuid::new_v4(),
+            member_address: "bob@node-b.hushnet.net".into(),
+        });
+        let decoded = OutboxPayload::decode(leave.kind(), leave.encode().unwrap()).unwrap();
+        assert!(
+            matches!(decoded, OutboxPayload::GroupLeave(ref l) if l.member_address == "bob@node-b.hushnet.net")
+        );
+
+        assert!(OutboxPayload::decode("group_state", leave.encode().unwrap()).is_err());
+        assert!(OutboxPayload::decode("carrier_pigeon", serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn messages_are_batched_only_for_peers_that_accept_it() {
+        let receipt = || {
+            OutboxPayload::Receipt(S2sReceipt {
+                ack: S2sAck {
+                    logical_msg_id: "msg-0".into(),
+                    status: "delivered".into(),
+                },
+                from_federated_address: "bob@node-b.hushnet.net".into(),
+                from_device_id: Uuid::new_v4(),
+                to_user: "alice".into(),
+            })
+        };
+        let entries = || {
+            let mut v: Vec<Due> = (0..MAX_MESSAGE_BATCH * 2 + 1)
+                .map(|n| due(message(n)))
+                .collect();
+            v.push(due(receipt()));
+            v
+        };
+
+        let sizes: Vec<usize> = plan_requests(entries(), true)
+            .iter()
+            .map(|r| match r {
+                Request::Single(_) => 1,
+                Request::Batch(b) => b.len(),
+            })
+            .collect();
+        // The lone leftover message goes out on its own, like the receipt.
+        assert_eq!(sizes, vec![MAX_MESSAGE_BATCH, MAX_MESSAGE_BATCH, 1, 1]);
+
+        let requests = plan_requests(entries(), false);
+        assert_eq!(requests.len(), MAX_MESSAGE_BATCH * 2 + 2);
+        assert!(requests.iter().all(|r| matches!(r, Request::Single(_))));
     }
 }
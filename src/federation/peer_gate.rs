@@ -0,0 +1,194 @@
+// src/federation/peer_gate.rs
+//
+// Limits on outbound outbox deliveries, shared by the outbox worker and the
+// immediate delivery attempt made when an entry is queued.
+//
+// Concurrency: at most `global` S2S requests in flight overall and at most
+// `per_peer` to any one node (OUTBOX_CONCURRENCY, OUTBOX_PEER_CONCURRENCY).
+//
+// Circuit breaker, one per peer:
+//
+//   closed  — deliveries go out. BREAKER_THRESHOLD consecutive failures that
+//             point at the peer being unreachable (connection errors,
+//             timeouts, 5xx) open it.
+//   open    — nothing is sent to the peer; its entries wait, without using up
+//             attempts, until the cooldown ends.
+//   probe   — the first caller after the cooldown probes GET /s2s/info while
+//             the others still see the breaker open. Success closes it; a
+//             failure reopens it with twice the cooldown, up to
+//             BREAKER_MAX_COOLDOWN.
+//
+// State is in memory: a restart starts every breaker closed.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Consecutive unreachable failures that open a peer's breaker.
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_BASE_COOLDOWN: Duration = Duration::from_secs(30);
+const BREAKER_MAX_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+
+/// What a caller may do with a peer right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breaker {
+    Closed,
+    /// Leave the peer alone until the given instant.
+    Open(Instant),
+    /// The cooldown is over and this caller was chosen to probe the peer.
+    Probe,
+}
+
+struct PeerState {
+    slots: Arc<Semaphore>,
+    failures: u32,
+    open_until: Option<Instant>,
+    cooldown: Duration,
+}
+
+pub struct PeerGate {
+    global: Arc<Semaphore>,
+    per_peer: usize,
+    peers: Mutex<HashMap<String, PeerState>>,
+}
+
+/// Held while a request to a peer is in flight.
+pub struct DeliveryPermit {
+    _global: OwnedSemaphorePermit,
+    _peer: OwnedSemaphorePermit,
+}
+
+impl PeerGate {
+    pub fn new(global: usize, per_peer: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(global.max(1))),
+            per_peer: per_peer.max(1),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn with_peer<T>(&self, node_id: &str, f: impl FnOnce(&mut PeerState) -> T) -> T {
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        let peer = peers
+            .entry(node_id.to_string())
+            .or_insert_with(|| PeerState {
+                slots: Arc::new(Semaphore::new(self.per_peer)),
+                failures: 0,
+                open_until: None,
+                cooldown: BREAKER_BASE_COOLDOWN,
+            });
+        f(peer)
+    }
+
+    /// Wait for a free slot, overall and for `node_id`.
+    pub async fn acquire(&self, node_id: &str) -> DeliveryPermit {
+        let slots = self.with_peer(node_id, |p| p.slots.clone());
+        // The semaphores are never closed, so acquiring cannot fail.
+        let _global = self.global.clone().acquire_owned().await.unwrap();
+        let _peer = slots.acquire_owned().await.unwrap();
+        DeliveryPermit { _global, _peer }
+    }
+
+    /// Breaker state of `node_id`. Returns `Probe` to one caller once the
+    /// cooldown is over; that caller must report the probe's outcome.
+    pub fn check(&self, node_id: &str) -> Breaker {
+        self.check_at(node_id, Instant::now())
+    }
+
+    fn check_at(&self, node_id: &str, now: Instant) -> Breaker {
+        self.with_peer(node_id, |p| match p.open_until {
+            None => Breaker::Closed,
+            Some(until) if now < until => Breaker::Open(until),
+            Some(_) => {
+                // Others keep seeing the breaker open while the probe runs.
+                p.open_until = Some(now + p.cooldown);
+                Breaker::Probe
+            }
+        })
+    }
+
+    /// Whether the breaker of `node_id` is open (or being probed).
+    pub fn is_open(&self, node_id: &str) -> bool {
+        self.with_peer(node_id, |p| p.open_until.is_some())
+    }
+
+    /// The peer answered, whatever it answered: close its breaker.
+    pub fn record_reachable(&self, node_id: &str) {
+        self.with_peer(node_id, |p| {
+            p.failures = 0;
+            p.open_until = None;
+            p.cooldown = BREAKER_BASE_COOLDOWN;
+        });
+    }
+
+    /// A request to the peer failed without an answer. Returns true if this
+    /// opened the breaker.
+    pub fn record_unreachable(&self, node_id: &str) -> bool {
+        self.record_unreachable_at(node_id, Instant::now())
+    }
+
+    fn record_unreachable_at(&self, node_id: &str, now: Instant) -> bool {
+        self.with_peer(node_id, |p| {
+            p.failures += 1;
+            if p.open_until.is_none() && p.failures >= BREAKER_THRESHOLD {
+                p.open_until = Some(now + p.cooldown);
+                return true;
+            }
+            false
+        })
+    }
+
+    /// The probe after a cooldown failed: reopen with a longer cooldown.
+    /// Returns when the next probe is due.
+    pub fn record_probe_failure(&self, node_id: &str) -> Instant {
+        self.record_probe_failure_at(node_id, Instant::now())
+    }
+
+    fn record_probe_failure_at(&self, node_id: &str, now: Instant) -> Instant {
+        self.with_peer(node_id, |p| {
+            p.cooldown = (p.cooldown * 2).min(BREAKER_MAX_COOLDOWN);
+            let until = now + p.cooldown;
+            p.open_until = Some(until);
+            until
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_opens_probes_once_and_backs_off() {
+        let gate = PeerGate::new(4, 2);
+        let now = Instant::now();
+        let node = "node-b.hushnet.net";
+
+        for _ in 1..BREAKER_THRESHOLD {
+            assert!(!gate.record_unreachable_at(node, now));
+        }
+        assert_eq!(gate.check_at(node, now), Breaker::Closed);
+        assert!(gate.record_unreachable_at(node, now));
+        assert_eq!(
+            gate.check_at(node, now),
+            Breaker::Open(now + BREAKER_BASE_COOLDOWN)
+        );
+        assert_eq!(gate.check_at("node-c.hushnet.net", now), Breaker::Closed);
+
+        // After the cooldown exactly one caller gets to probe.
+        let later = now + BREAKER_BASE_COOLDOWN;
+        assert_eq!(gate.check_at(node, later), Breaker::Probe);
+        assert!(matches!(gate.check_at(node, later), Breaker::Open(_)));
+
+        let next = gate.record_probe_failure_at(node, later);
+        assert_eq!(next, later + BREAKER_BASE_COOLDOWN * 2);
+
+        gate.record_reachable(node);
+        assert_eq!(gate.check_at(node, next), Breaker::Closed);
+        assert!(!gate.is_open(node));
+    }
+}
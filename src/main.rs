@@ -5,11 +5,11 @@ mod models;
 mod repository;
 mod routes;
 mod services;
-use axum::{Extension, Router};
+mod storage;
+use axum::{middleware, Extension, Router};
 use sqlx::PgPool;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::broadcast;
 mod app_state;
 mod realtime;
 mod registry;
@@ -18,10 +18,14 @@ mod utils;
 use std::env;
 
 use crate::app_state::AppState;
-use crate::models::realtime::RealtimeEvent;
+use crate::federation::{client::FederationClient, peer_gate::PeerGate};
+use crate::middlewares::{admin_auth::MIN_ADMIN_TOKEN_LEN, body_digest};
 use crate::realtime::listener::start_pg_listeners;
+use crate::realtime::registry::ConnectionRegistry;
+use crate::storage::{local::LocalFsStore, BlobStore};
 use crate::utils::node_keys::NodeKeys;
 use registry::register::register_with_registry;
+use registry::rotate::rotate_node_key;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -39,6 +43,93 @@ async fn main() -> Result<(), anyhow::Error> {
     let node_api_url =
         env::var("NODE_API_URL").unwrap_or_else(|_| format!("https://{node_host}/api"));
 
+    // `hushnet-backend rotate-node-key`: replace the node signing key and exit.
+    if env::args().nth(1).as_deref() == Some("rotate-node-key") {
+        let overlap_hours: i64 = env::var("NODE_KEY_OVERLAP_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(168);
+        return rotate_node_key(&registry_url, overlap_hours).await;
+    }
+
+    let allow_legacy_device_auth = env::var("ALLOW_LEGACY_DEVICE_AUTH")
+        .unwrap_or_else(|_| "false".into())
+        .to_lowercase()
+        == "true";
+    if allow_legacy_device_auth {
+        tracing::warn!(
+            "ALLOW_LEGACY_DEVICE_AUTH is enabled: timestamp-only device signatures (auth \
+             version 1) are accepted and can be replayed within 30 seconds; disable it once \
+             clients send X-Auth-Version: 2"
+        );
+    }
+    let allow_legacy_node_auth = env::var("ALLOW_LEGACY_NODE_AUTH")
+        .unwrap_or_else(|_| "true".into())
+        .to_lowercase()
+        == "true";
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    if admin_token
+        .as_ref()
+        .is_some_and(|t| t.len() < MIN_ADMIN_TOKEN_LEN)
+    {
+        anyhow::bail!("ADMIN_TOKEN must be at least {MIN_ADMIN_TOKEN_LEN} characters");
+    }
+
+    let prekey_low_threshold: i64 = env::var("PREKEY_LOW_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    let max_one_time_prekeys: i64 = env::var("MAX_ONE_TIME_PREKEYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+    let signed_prekey_grace_hours: i32 = env::var("SIGNED_PREKEY_GRACE_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(168);
+    let event_retention_hours: i32 = env::var("REALTIME_EVENT_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(168);
+    let delivered_retention_hours: i32 = env::var("DELIVERED_MESSAGE_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24);
+    let unread_retention_hours: i32 = env::var("UNREAD_MESSAGE_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(720);
+    let outbox_retention_hours: i32 = env::var("OUTBOX_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(168);
+    let forwarded_retention_hours: i32 = env::var("FORWARDED_MESSAGE_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(720);
+    let pending_session_retention_hours: i32 = env::var("PENDING_SESSION_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(720);
+    let outbox_concurrency: usize = env::var("OUTBOX_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(32);
+    let outbox_peer_concurrency: usize = env::var("OUTBOX_PEER_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4);
+    let attachment_storage = env::var("ATTACHMENT_STORAGE").unwrap_or_else(|_| "local".into());
+    let attachment_dir = env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".into());
+    let attachment_max_bytes: i64 = env::var("ATTACHMENT_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100 * 1024 * 1024);
+    let attachment_retention_hours: i32 = env::var("ATTACHMENT_RETENTION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24);
+
     let pool: sqlx::Pool<sqlx::Postgres> = PgPool::connect(&database_url).await?;
     let keys = NodeKeys::load_or_generate()?;
     println!("Public key (base64): {}", keys.public_b64);
@@ -52,6 +143,11 @@ async fn main() -> Result<(), anyhow::Error> {
         register_with_registry(&registry_url).await?;
     }
 
+    let attachment_store: Arc<dyn BlobStore> = match attachment_storage.as_str() {
+        "local" => Arc::new(LocalFsStore::new(&attachment_dir).await?),
+        other => anyhow::bail!("unsupported ATTACHMENT_STORAGE {other:?}"),
+    };
+
     let http_client = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(10))
         .build()?;
@@ -63,18 +159,47 @@ async fn main() -> Result<(), anyhow::Error> {
         this_node_id: node_host.clone(),
         this_api_url: node_api_url,
         registry_url: registry_url.clone(),
-        http_client: http_client.clone(),
+        http_client,
+        peer_gate: Arc::new(PeerGate::new(outbox_concurrency, outbox_peer_concurrency)),
+        allow_legacy_device_auth,
+        allow_legacy_node_auth,
+        admin_token,
+        prekey_low_threshold,
+        max_one_time_prekeys,
+        signed_prekey_grace_hours,
+        attachment_store,
+        attachment_max_bytes,
     };
 
-    let (tx, _rx) = broadcast::channel::<RealtimeEvent>(100);
-    tokio::spawn(start_pg_listeners(pool.clone(), tx.clone()));
+    let connections = Arc::new(ConnectionRegistry::default());
+    tokio::spawn(start_pg_listeners(pool.clone(), connections.clone()));
 
     // Outbox worker: retries failed cross-node message deliveries.
     tokio::spawn(federation::outbox::run(
         pool.clone(),
-        state.node_keys.clone(),
-        state.this_node_id.clone(),
-        http_client,
+        FederationClient::from_state(&state),
+    ));
+
+    // Maintenance: drops retired signed and KEM prekeys and old realtime events.
+    tokio::spawn(services::maintenance::run(
+        pool.clone(),
+        signed_prekey_grace_hours,
+        event_retention_hours,
+    ));
+
+    // Reaper: deletes expired messages, rows past their retention window and
+    // attachments nothing refers to anymore.
+    tokio::spawn(services::reaper::run(
+        pool.clone(),
+        state.attachment_store.clone(),
+        services::reaper::Retention {
+            delivered_messages: delivered_retention_hours,
+            unread_messages: unread_retention_hours,
+            outbox: outbox_retention_hours,
+            forwarded_messages: forwarded_retention_hours,
+            pending_sessions: pending_session_retention_hours,
+            attachments: attachment_retention_hours,
+        },
     ));
 
     let app = Router::new()
@@ -85,8 +210,15 @@ async fn main() -> Result<(), anyhow::Error> {
         .merge(routes::chats::routes().with_state(state.clone()))
         .merge(routes::messages::routes().with_state(state.clone()))
         .merge(routes::federation::routes().with_state(state.clone()))
-        .merge(routes::websocket::routes())
-        .layer(Extension(tx));
+        .merge(routes::websocket::routes().with_state(state.clone()))
+        .merge(routes::attachments::routes().with_state(state.clone()))
+        .merge(routes::admin::routes().with_state(state.clone()))
+        .layer(Extension(connections))
+        .layer(middleware::from_fn(body_digest::compute_body_digest))
+        .merge(
+            routes::attachments::upload_routes(attachment_max_bytes as usize)
+                .with_state(state.clone()),
+        );
 
     let addr = SocketAddr::new(server_host.parse().unwrap(), server_port.parse().unwrap());
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
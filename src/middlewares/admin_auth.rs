@@ -0,0 +1,51 @@
+// src/middlewares/admin_auth.rs
+//
+// Authenticates operator requests to the /admin/* endpoints.
+//
+// Operators are not users or devices: they present a bearer token configured
+// out of band in ADMIN_TOKEN, and nothing a device can obtain works here.
+//
+//   Authorization: Bearer {ADMIN_TOKEN}
+//
+// The admin API is disabled (404) while ADMIN_TOKEN is unset. Tokens are
+// compared through their SHA-256 digests so the comparison time does not
+// depend on how much of the token was guessed right.
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+use sha2::{Digest, Sha256};
+
+use crate::app_state::AppState;
+
+/// Shortest ADMIN_TOKEN the node starts with.
+pub const MIN_ADMIN_TOKEN_LEN: usize = 32;
+
+/// Extractor for handlers only an operator may call.
+pub struct AuthenticatedAdmin;
+
+impl FromRequestParts<AppState> for AuthenticatedAdmin {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = state.admin_token.as_deref() else {
+            return Err((StatusCode::NOT_FOUND, "admin API disabled".into()));
+        };
+
+        let presented = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or((StatusCode::UNAUTHORIZED, "missing admin token".into()))?;
+
+        if Sha256::digest(presented.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+            return Err((StatusCode::UNAUTHORIZED, "invalid admin token".into()));
+        }
+        Ok(AuthenticatedAdmin)
+    }
+}
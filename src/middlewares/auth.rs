@@ -1,14 +1,49 @@
 // src/middlewares/auth.rs
-use crate::{app_state::AppState, models::device::Devices, repository::device_repository};
+//
+// Device request authentication.
+//
+// Version 2 (X-Auth-Version: 2) signs the whole request:
+//
+//   {HTTP_METHOD}\n{path}\n{timestamp}\n{nonce}\n{body_sha256_b64}
+//
+// The body digest comes from the `body_digest` layer, and the (device, nonce)
+// pair is claimed in used_device_nonces so a captured request cannot be
+// replayed inside the timestamp window.
+//
+// Version 1 (no X-Auth-Version header) signs the timestamp alone. It is kept
+// only so older clients can be migrated, is off by default and is accepted
+// only while ALLOW_LEGACY_DEVICE_AUTH=true.
+//
+// The signing key must belong to exactly one local device; shadow devices of
+// remote users never authenticate.
+use crate::{
+    app_state::AppState, middlewares::body_digest::BodyDigest, models::device::Devices,
+    repository::device_repository,
+};
 use axum::{
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
 };
 use base64::{engine::general_purpose::STANDARD as b64, Engine};
 use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use tracing::warn;
+
+pub const AUTH_VERSION_LEGACY: &str = "1";
+pub const AUTH_VERSION_CURRENT: &str = "2";
 
 pub struct AuthenticatedDevice(pub Devices);
 
+/// Build the string a device signs for a version 2 request.
+pub fn device_canonical_string(
+    method: &str,
+    path: &str,
+    timestamp: &str,
+    nonce: &str,
+    body_digest: &str,
+) -> String {
+    format!("{method}\n{path}\n{timestamp}\n{nonce}\n{body_digest}")
+}
+
 impl FromRequestParts<AppState> for AuthenticatedDevice {
     type Rejection = (StatusCode, String);
 
@@ -32,6 +67,11 @@ impl FromRequestParts<AppState> for AuthenticatedDevice {
             .get("X-Timestamp")
             .and_then(|v| v.to_str().ok())
             .ok_or((StatusCode::UNAUTHORIZED, "Missing X-Timestamp".into()))?;
+        let version = parts
+            .headers
+            .get("X-Auth-Version")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(AUTH_VERSION_LEGACY);
 
         // anti-replay
         let now = chrono::Utc::now().timestamp();
@@ -40,6 +80,40 @@ impl FromRequestParts<AppState> for AuthenticatedDevice {
             return Err((StatusCode::UNAUTHORIZED, "Expired timestamp".into()));
         }
 
+        // Signed message
+        let (signed, nonce) = match version {
+            AUTH_VERSION_CURRENT => {
+                let nonce = parts
+                    .headers
+                    .get("X-Nonce")
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or((StatusCode::UNAUTHORIZED, "Missing X-Nonce".into()))?;
+                let digest = parts.extensions.get::<BodyDigest>().ok_or((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Body digest unavailable".into(),
+                ))?;
+                let path = parts
+                    .uri
+                    .path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or("/");
+                let canonical =
+                    device_canonical_string(parts.method.as_str(), path, ts, nonce, &digest.0);
+                (canonical, Some(nonce.to_string()))
+            }
+            AUTH_VERSION_LEGACY if state.allow_legacy_device_auth => {
+                warn!(identity_key = %ik_b64, "device used legacy timestamp-only auth");
+                (ts.to_string(), None)
+            }
+            AUTH_VERSION_LEGACY => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "Legacy auth disabled, use X-Auth-Version: 2".into(),
+                ))
+            }
+            _ => return Err((StatusCode::BAD_REQUEST, "Unsupported X-Auth-Version".into())),
+        };
+
         let sig_bytes: [u8; 64] = b64
             .decode(sig_b64)
             .map_err(|_| (StatusCode::BAD_REQUEST, "Bad signature b64".into()))?
@@ -56,8 +130,7 @@ impl FromRequestParts<AppState> for AuthenticatedDevice {
         let vk = VerifyingKey::from_bytes(&vk_arr)
             .map_err(|_| (StatusCode::BAD_REQUEST, "Bad pubkey".into()))?;
 
-        // Signed message
-        vk.verify(ts.as_bytes(), &sig)
+        vk.verify(signed.as_bytes(), &sig)
             .map_err(|_| (StatusCode::UNAUTHORIZED, "Signature mismatch".into()))?;
 
         // Fetch Device based on signature
@@ -65,6 +138,28 @@ impl FromRequestParts<AppState> for AuthenticatedDevice {
             .await
             .map_err(|_| (StatusCode::UNAUTHORIZED, "Unknown device".into()))?;
 
+        // Claim the nonce only once the signature is known to be valid, so an
+        // attacker cannot burn a legitimate client's nonces.
+        if let Some(nonce) = nonce {
+            let fresh = device_repository::claim_device_nonce(&state.pool, &device.id, &nonce)
+                .await
+                .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
+            if !fresh {
+                return Err((StatusCode::UNAUTHORIZED, "Replayed nonce".into()));
+            }
+        }
+
         Ok(AuthenticatedDevice(device))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_string_layout() {
+        let s = device_canonical_string("POST", "/messages", "1700000000", "bm9uY2U=", "ZGlnZXN0");
+        assert_eq!(s, "POST\n/messages\n1700000000\nbm9uY2U=\nZGlnZXN0");
+    }
+}
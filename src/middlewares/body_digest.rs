@@ -0,0 +1,65 @@
+// src/middlewares/body_digest.rs
+//
+// Buffers every request body once and records its SHA-256 digest in the
+// request extensions.
+//
+// Signature extractors (`AuthenticatedDevice`, `AuthenticatedNode`) run as
+// `FromRequestParts` and therefore never see the body. This layer runs before
+// them, hashes the raw bytes and puts them back untouched, so the extractors
+// can fold the digest into their canonical string and the `Json<T>` extractor
+// still deserializes the exact bytes that were signed.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use sha2::{Digest, Sha256};
+
+/// Matches axum's default `Json` body limit.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Base64-encoded SHA-256 digest of the request body (empty body included).
+#[derive(Debug, Clone)]
+pub struct BodyDigest(pub String);
+
+impl BodyDigest {
+    pub fn of(bytes: &[u8]) -> Self {
+        BodyDigest(B64.encode(Sha256::digest(bytes)))
+    }
+}
+
+pub async fn compute_body_digest(
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_BODY_BYTES).await.map_err(|_| {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body too large".into(),
+        )
+    })?;
+
+    parts.extensions.insert(BodyDigest::of(&bytes));
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_body_digest() {
+        // SHA-256("") = e3b0c442...b855
+        assert_eq!(
+            BodyDigest::of(b"").0,
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod admin_auth;
 pub mod auth;
+pub mod body_digest;
 pub mod node_auth;
@@ -2,18 +2,25 @@
 //
 // Authenticates inbound S2S requests from peer nodes.
 //
-// Every request to a /s2s/* endpoint (except /s2s/info) must carry four
+// Every request to a /s2s/* endpoint (except /s2s/info) must carry these
 // headers that together prove the request was sent by the node that owns the
 // private key registered at the central registry:
 //
 //   X-Node-ID        — canonical node identifier ("node-a.hushnet.net")
 //   X-Timestamp      — Unix seconds as a decimal string
 //   X-Nonce          — random 16-byte value, base64-encoded
+//   Content-Digest   — "sha-256=:{base64 SHA-256 of the body}:" (RFC 9530),
+//                      protocol 0.0.3 and later; empty bodies included
 //   X-Node-Signature — Ed25519 signature, base64-encoded
 //
 // Canonical string (UTF-8, signed verbatim, fields separated by "\n"):
 //
-//   {HTTP_METHOD}\n{path}\n{timestamp}\n{nonce}
+//   {HTTP_METHOD}\n{path}\n{timestamp}\n{nonce}\n{content_digest}
+//
+// Peers still on protocol 0.0.2 send no Content-Digest and sign the first
+// four fields only. That form is accepted while ALLOW_LEGACY_NODE_AUTH is
+// on, and only from peers not yet seen speaking 0.0.3: once a peer has sent
+// a digest-signed request, unsigned bodies from it are refused.
 //
 // The path component is the request URI path only (no scheme or host), so
 // that the canonical string is independent of which domain name the caller
@@ -24,22 +31,57 @@
 // 1. Reject if |now − timestamp| > 60 s.
 // 2. Look up the peer's FederationNode record (DB cache → registry fallback).
 // 3. Reject if the node is flagged is_blocked.
-// 4. Verify the Ed25519 signature over the canonical string.
-// 5. Atomically claim the (node_id, nonce) pair in used_node_nonces; reject
+// 4. Check Content-Digest against the body actually received, or decide
+//    whether the legacy form is acceptable from this peer.
+// 5. Verify the Ed25519 signature over the canonical string with the cached
+//    key, or the peer's previous key while its rotation overlap lasts. If
+//    neither verifies, look the key up again (see `refresh_peer_key`) and
+//    retry once with the result.
+// 6. Atomically claim the (node_id, nonce) pair in used_node_nonces; reject
 //    if the pair was already present (replay attack).
+// 7. If the request was digest-signed, record that the peer speaks 0.0.3.
 //
 // On success the FederationNode record is inserted into request Extensions so
 // that handlers can access it with `Extension<FederationNode>`.
 
 use crate::{
-    app_state::AppState, models::federation::FederationNode, repository::federation_repository,
+    app_state::AppState,
+    middlewares::body_digest::BodyDigest,
+    models::federation::{FederationNode, NodeInfo, CONTENT_DIGEST_PROTOCOL_VERSION},
+    repository::federation_repository,
+    utils::node_keys::verify_with_key,
 };
 use axum::{
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
 };
 use base64::{engine::general_purpose::STANDARD as B64, Engine};
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use tracing::{info, warn};
+
+/// Minimum time between two key re-fetches for the same peer.
+const KEY_RECHECK_SECS: i64 = 300;
+
+pub const CONTENT_DIGEST_HEADER: &str = "Content-Digest";
+
+/// Content-Digest header value for a body whose base64 SHA-256 is given.
+pub fn content_digest_value(body_sha256_b64: &str) -> String {
+    format!("sha-256=:{body_sha256_b64}:")
+}
+
+/// Build the string a node signs. `content_digest` is the Content-Digest
+/// header value; None gives the protocol 0.0.2 form.
+pub fn node_canonical_string(
+    method: &str,
+    path: &str,
+    timestamp: &str,
+    nonce: &str,
+    content_digest: Option<&str>,
+) -> String {
+    match content_digest {
+        Some(digest) => format!("{method}\n{path}\n{timestamp}\n{nonce}\n{digest}"),
+        None => format!("{method}\n{path}\n{timestamp}\n{nonce}"),
+    }
+}
 
 /// Extractor that validates the four S2S authentication headers and returns the
 /// authenticated peer's FederationNode record on success.
@@ -88,47 +130,72 @@ impl FromRequestParts<AppState> for AuthenticatedNode {
             return Err((StatusCode::FORBIDDEN, "node is blocked".into()));
         }
 
-        // ── 4. signature verification ────────────────────────────────────────
+        // ── 4. body digest ───────────────────────────────────────────────────
+        let content_digest = match parts.headers.get(CONTENT_DIGEST_HEADER) {
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| (StatusCode::BAD_REQUEST, "bad Content-Digest header".into()))?;
+                let body = parts.extensions.get::<BodyDigest>().ok_or((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "body digest unavailable".into(),
+                ))?;
+                if value != content_digest_value(&body.0) {
+                    return Err((
+                        StatusCode::UNAUTHORIZED,
+                        "Content-Digest does not match body".into(),
+                    ));
+                }
+                Some(value.to_string())
+            }
+            None if state.allow_legacy_node_auth && !node.uses_content_digest() => {
+                warn!(%node_id, "peer used legacy S2S auth without Content-Digest");
+                None
+            }
+            None => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "missing header: Content-Digest".into(),
+                ))
+            }
+        };
+
+        // ── 5. signature verification ────────────────────────────────────────
         let path = parts
             .uri
             .path_and_query()
             .map(|pq| pq.as_str())
             .unwrap_or("/");
-        let canonical = format!("{}\n{}\n{}\n{}", parts.method.as_str(), path, ts_str, nonce);
+        let canonical = node_canonical_string(
+            parts.method.as_str(),
+            path,
+            &ts_str,
+            &nonce,
+            content_digest.as_deref(),
+        );
 
-        let sig_bytes: [u8; 64] = B64
+        let sig_len = B64
             .decode(&sig_b64)
             .map_err(|_| (StatusCode::BAD_REQUEST, "bad signature base64".into()))?
-            .try_into()
-            .map_err(|_| (StatusCode::BAD_REQUEST, "signature must be 64 bytes".into()))?;
-        let sig = Signature::from_bytes(&sig_bytes);
-
-        let vk_bytes: [u8; 32] = B64
-            .decode(&node.public_key_b64)
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "bad cached peer pubkey".into(),
-                )
-            })?
-            .try_into()
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "peer pubkey must be 32 bytes".into(),
-                )
-            })?;
-        let vk = VerifyingKey::from_bytes(&vk_bytes).map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "invalid peer pubkey".into(),
-            )
-        })?;
+            .len();
+        if sig_len != 64 {
+            return Err((StatusCode::BAD_REQUEST, "signature must be 64 bytes".into()));
+        }
 
-        vk.verify(canonical.as_bytes(), &sig)
-            .map_err(|_| (StatusCode::UNAUTHORIZED, "invalid node signature".into()))?;
+        let signed_by = |key_b64: &str| verify_with_key(key_b64, canonical.as_bytes(), &sig_b64);
+        let node = if signed_by(&node.public_key_b64) || node.previous_key().is_some_and(signed_by)
+        {
+            node
+        } else {
+            match refresh_peer_key(state, &node).await? {
+                Some(updated) if signed_by(&updated.public_key_b64) => updated,
+                _ => {
+                    return Err((StatusCode::UNAUTHORIZED, "invalid node signature".into()));
+                }
+            }
+        };
 
-        // ── 5. nonce claim (replay prevention) ───────────────────────────────
+        // ── 6. nonce claim (replay prevention) ───────────────────────────────
         let fresh = federation_repository::claim_nonce(&state.pool, &node_id, &nonce)
             .await
             .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
@@ -136,6 +203,18 @@ impl FromRequestParts<AppState> for AuthenticatedNode {
             return Err((StatusCode::UNAUTHORIZED, "replayed nonce".into()));
         }
 
+        // ── 7. protocol upgrade ──────────────────────────────────────────────
+        if content_digest.is_some() && !node.uses_content_digest() {
+            federation_repository::record_protocol_version(
+                &state.pool,
+                &node_id,
+                CONTENT_DIGEST_PROTOCOL_VERSION,
+                false,
+            )
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
+        }
+
         Ok(AuthenticatedNode(node))
     }
 }
@@ -168,6 +247,20 @@ async fn resolve_peer(
     }
 
     // Cache miss: ask the central registry.
+    let (api_url, pubkey) = fetch_registry_record(state, node_id).await?;
+    let node =
+        federation_repository::upsert_federation_node(&state.pool, node_id, &api_url, &pubkey)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
+
+    Ok(node)
+}
+
+/// Fetch a node's `(api_url, public_key_b64)` from the central registry.
+pub(crate) async fn fetch_registry_record(
+    state: &AppState,
+    node_id: &str,
+) -> Result<(String, String), (StatusCode, String)> {
     let url = format!("{}/api/registry/nodes/{}", state.registry_url, node_id);
     let resp = state
         .http_client
@@ -205,9 +298,109 @@ async fn resolve_peer(
         "registry response missing public_key_b64".into(),
     ))?;
 
-    let node = federation_repository::upsert_federation_node(&state.pool, node_id, api_url, pubkey)
+    Ok((api_url.to_string(), pubkey.to_string()))
+}
+
+/// Look a peer's key up again after a request failed to verify with the
+/// cached one, at most once per KEY_RECHECK_SECS.
+///
+/// The registry's key is adopted only if the peer's GET /s2s/info serves it
+/// together with a rotation endorsed by the key cached here. A key that
+/// merely differs (registry compromise, re-registration after key loss) is
+/// not trusted on its own; an operator has to accept it. Returns the updated
+/// record, or None if the key was left as it was.
+pub(crate) async fn refresh_peer_key(
+    state: &AppState,
+    node: &FederationNode,
+) -> Result<Option<FederationNode>, (StatusCode, String)> {
+    let node_id = &node.node_id;
+    let claimed = federation_repository::claim_key_check(&state.pool, node_id, KEY_RECHECK_SECS)
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
+    if !claimed {
+        return Ok(None);
+    }
 
-    Ok(node)
+    let (api_url, registry_key) = match fetch_registry_record(state, node_id).await {
+        Ok(record) => record,
+        Err((_, e)) => {
+            warn!(%node_id, "key re-fetch: {e}");
+            return Ok(None);
+        }
+    };
+    if registry_key == node.public_key_b64 {
+        return Ok(None);
+    }
+
+    let info = match fetch_node_info(state, &api_url).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!(%node_id, "key re-fetch: peer info unavailable: {e:#}");
+            return Ok(None);
+        }
+    };
+    let rotation = info.key_rotation.filter(|r| {
+        info.node_id == *node_id
+            && info.public_key_b64 == registry_key
+            && r.previous_public_key_b64 == node.public_key_b64
+            && r.overlap_until > chrono::Utc::now()
+            && r.endorses(node_id, &registry_key)
+    });
+    let Some(rotation) = rotation else {
+        warn!(%node_id, "peer key changed without a rotation endorsed by the cached key");
+        return Ok(None);
+    };
+
+    let updated = federation_repository::rotate_peer_key(
+        &state.pool,
+        node_id,
+        &node.public_key_b64,
+        &registry_key,
+        rotation.overlap_until,
+    )
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error".into()))?;
+    if updated.is_some() {
+        info!(%node_id, overlap_until = %rotation.overlap_until, "accepted rotated peer key");
+    }
+    Ok(updated)
+}
+
+async fn fetch_node_info(state: &AppState, api_url: &str) -> anyhow::Result<NodeInfo> {
+    Ok(state
+        .http_client
+        .get(format!("{api_url}/s2s/info"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<NodeInfo>()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_string_covers_content_digest() {
+        let digest = content_digest_value(&BodyDigest::of(b"{}").0);
+        assert_eq!(
+            digest,
+            "sha-256=:RBNvo1WzZ4oRRq0W9+hknpT7T8If536DEMBg9hyq/4o=:"
+        );
+        assert_eq!(
+            node_canonical_string(
+                "POST",
+                "/s2s/messages",
+                "1700000000",
+                "bm9uY2U=",
+                Some(&digest)
+            ),
+            format!("POST\n/s2s/messages\n1700000000\nbm9uY2U=\n{digest}")
+        );
+        assert_eq!(
+            node_canonical_string("GET", "/s2s/info", "1700000000", "bm9uY2U=", None),
+            "GET\n/s2s/info\n1700000000\nbm9uY2U="
+        );
+    }
 }
@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of POST /attachments.
+#[derive(Debug, Deserialize)]
+pub struct CreateAttachmentBody {
+    /// Exact size of the encrypted blob that will be uploaded.
+    pub size_bytes: i64,
+}
+
+/// Response of POST /attachments. The tokens are only returned here; the
+/// server keeps their hashes.
+#[derive(Debug, Serialize)]
+pub struct CreatedAttachment {
+    pub attachment_id: uuid::Uuid,
+    pub upload_token: String,
+    pub download_token: String,
+    pub upload_expires_at: chrono::DateTime<chrono::Utc>,
+}
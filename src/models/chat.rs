@@ -12,6 +12,8 @@ pub struct Chat {
     pub name: Option<String>,      // for group chats
     pub owner_id: Option<Uuid>,    // for group chats
     pub last_message_id: Option<Uuid>,
+    /// Disappearing messages: lifetime of every message in the chat.
+    pub message_ttl_seconds: Option<i32>,
     pub created_at: Option<NaiveDateTime>,
     pub updated_at: Option<NaiveDateTime>,
 }
@@ -25,5 +27,143 @@ pub struct ChatView {
     pub partner_federated_address: Option<String>,
     pub name: Option<String>,
     pub last_message_id: Option<Uuid>,
+    pub message_ttl_seconds: Option<i32>,
     pub updated_at: Option<NaiveDateTime>,
 }
+
+/// Which node manages a group. `home_node_id` (and its `home_node`
+/// node_id) is None when this node is the group's home.
+#[derive(Debug)]
+pub struct GroupOrigin {
+    pub home_node_id: Option<Uuid>,
+    pub home_node: Option<String>,
+    pub membership_version: i64,
+}
+
+impl GroupOrigin {
+    pub fn is_local(&self) -> bool {
+        self.home_node_id.is_none()
+    }
+}
+
+/// Body of PUT /chats/:id/expiry. `None` turns disappearing messages off.
+#[derive(Debug, Deserialize)]
+pub struct ChatExpiryBody {
+    pub message_ttl_seconds: Option<i32>,
+}
+
+/// Role of a group member. Ordered by rank: Member < Admin < Owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl GroupRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupRole::Member => "member",
+            GroupRole::Admin => "admin",
+            GroupRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "member" => Some(GroupRole::Member),
+            "admin" => Some(GroupRole::Admin),
+            "owner" => Some(GroupRole::Owner),
+            _ => None,
+        }
+    }
+
+    /// Whether a member with this role may add members, rename the group
+    /// or change its settings.
+    pub fn can_administer(self) -> bool {
+        self >= GroupRole::Admin
+    }
+
+    /// Whether a member with this role may remove a member with `target`'s:
+    /// admins remove members, the owner removes anyone.
+    pub fn can_remove(self, target: GroupRole) -> bool {
+        self.can_administer() && self > target
+    }
+}
+
+/// A row of GET /chats/:id/members.
+#[derive(Debug, Serialize)]
+pub struct GroupMember {
+    pub user_id: Uuid,
+    pub username: String,
+    /// "bob@node-b.hushnet.net" for members homed on another node.
+    pub federated_address: Option<String>,
+    pub role: GroupRole,
+    pub joined_at: Option<NaiveDateTime>,
+}
+
+/// Body of POST /chats/groups. The creator becomes the owner and need not
+/// be listed. Users of other nodes are listed by federated address.
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupBody {
+    pub name: String,
+    #[serde(default)]
+    pub member_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub member_addresses: Vec<String>,
+}
+
+/// Body of POST /chats/:id/members. Local users by id, users of other nodes
+/// by federated address.
+#[derive(Debug, Deserialize)]
+pub struct AddMembersBody {
+    #[serde(default)]
+    pub user_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+}
+
+/// Body of PUT /chats/:id/name.
+#[derive(Debug, Deserialize)]
+pub struct RenameGroupBody {
+    pub name: String,
+}
+
+/// Body of PUT /chats/:id/owner.
+#[derive(Debug, Deserialize)]
+pub struct TransferOwnershipBody {
+    pub user_id: Uuid,
+}
+
+/// Body of PUT /chats/:id/members/:user_id/role. Ownership is moved with
+/// PUT /chats/:id/owner instead.
+#[derive(Debug, Deserialize)]
+pub struct SetRoleBody {
+    pub role: GroupRole,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_parse_and_print() {
+        for role in [GroupRole::Member, GroupRole::Admin, GroupRole::Owner] {
+            assert_eq!(GroupRole::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(GroupRole::parse("moderator"), None);
+    }
+
+    #[test]
+    fn removal_needs_a_higher_rank() {
+        use GroupRole::*;
+        assert!(Owner.can_remove(Admin));
+        assert!(Owner.can_remove(Member));
+        assert!(Admin.can_remove(Member));
+        assert!(!Admin.can_remove(Admin));
+        assert!(!Admin.can_remove(Owner));
+        assert!(!Member.can_remove(Member));
+        assert!(!Owner.can_remove(Owner));
+    }
+}
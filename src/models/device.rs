@@ -18,7 +18,7 @@ pub struct Devices {
     pub created_at: Option<NaiveDateTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedPreKey {
     pub key: String,
     pub signature: String,
@@ -29,6 +29,28 @@ pub struct OneTimePrekeys {
     pub key: String,
 }
 
+/// Signed ML-KEM public prekey used for PQXDH hybrid sessions.
+///
+/// The signature is Ed25519 by the device identity key over the raw KEM
+/// public key bytes, the same scheme as the X25519 signed prekey.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KemPrekey {
+    /// Server-assigned id; the initiator echoes it back as `kem_prekey_id`.
+    pub id: i64,
+    pub key: String,
+    pub signature: String,
+    /// True for the device's reusable last-resort key.
+    pub last_resort: bool,
+}
+
+/// X3DH prekey bundle for one device.
+///
+/// `one_time_prekey` is the single OTPK reserved for the caller by this fetch,
+/// or None once the device has run out. Peers still on the old format send a
+/// `one_time_prekeys` array instead, which is ignored.
+///
+/// `kem_prekey` is present when the device supports PQXDH; initiators that
+/// see it should establish a hybrid session.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceBundle {
     pub device_id: Uuid,
@@ -36,5 +58,8 @@ pub struct DeviceBundle {
     pub prekey_pubkey: String,
     pub signed_prekey_pub: String,
     pub signed_prekey_sig: String,
-    pub one_time_prekeys: Vec<String>,
+    #[serde(default)]
+    pub one_time_prekey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kem_prekey: Option<KemPrekey>,
 }
@@ -0,0 +1,22 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A new device asking to be linked to an existing account.
+///
+/// The enrollment token issued on approval is deliberately not part of this
+/// struct: it is only handed to the requesting device.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DeviceLinkRequest {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// Identity key of the device asking to be linked; shown to the approving
+    /// device so the user can compare fingerprints.
+    pub identity_pubkey: String,
+    pub device_label: Option<String>,
+    /// "pending" | "approved"
+    pub status: String,
+    pub approved_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
@@ -4,6 +4,10 @@ use serde::{Deserialize, Serialize};
 pub struct EnrollmentClaims {
     pub sub: String,
     pub exp: usize,
+    /// Identity key the token is bound to. Set for tokens issued by device
+    /// linking; only a device registering with this key may use them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ik: Option<String>,
 }
 
 #[cfg(test)]
@@ -15,6 +19,7 @@ mod tests {
         let claims = EnrollmentClaims {
             sub: String::from("user123"),
             exp: 1234567890,
+            ik: None,
         };
         assert_eq!(claims.sub, "user123");
         assert_eq!(claims.exp, 1234567890);
@@ -3,6 +3,9 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
 
+use super::chat::GroupRole;
+use crate::utils::node_keys::KeyRotation;
+
 // ─── Peer node record ────────────────────────────────────────────────────────
 
 /// A peer node as stored in the federation_nodes table.
@@ -19,16 +22,158 @@ pub struct FederationNode {
     pub last_seen: Option<DateTime<Utc>>,
     pub is_blocked: bool,
     pub created_at: DateTime<Utc>,
+    /// Highest S2S protocol version seen for the peer; None until known.
+    pub protocol_version: Option<String>,
+    /// Last time the peer's GET /s2s/info was probed for its version.
+    pub protocol_checked_at: Option<DateTime<Utc>>,
+    /// Key replaced by the peer's last rotation, still accepted until
+    /// `previous_key_expires_at`.
+    pub previous_public_key_b64: Option<String>,
+    pub previous_key_expires_at: Option<DateTime<Utc>>,
+    /// Last time the peer's key was looked up again after a failed check.
+    pub key_checked_at: Option<DateTime<Utc>>,
+}
+
+/// A peer as listed by the admin API.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct FederationPeer {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub node: FederationNode,
+    /// Users of the peer known here; while any exist the node cannot be
+    /// deleted.
+    pub shadow_users: i64,
+    /// Outbox entries still waiting to be delivered to the peer.
+    pub pending_outbox: i64,
+}
+
+/// Outbox entry as listed by the admin API: everything but the payload,
+/// which is ciphertext the operator has no use for.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct OutboxEntryView {
+    pub id: Uuid,
+    pub target_node_id: String,
+    pub logical_msg_id: String,
+    pub kind: String,
+    pub attempt_count: i32,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub next_attempt: DateTime<Utc>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Outbox entry statuses, see federation_outbox.status.
+pub const OUTBOX_STATUSES: [&str; 3] = ["pending", "delivered", "failed"];
+
+/// Query string of GET /admin/federation/outbox.
+#[derive(Debug, Deserialize)]
+pub struct OutboxListQuery {
+    /// "pending", "delivered" or "failed"
+    pub status: Option<String>,
+    pub target_node_id: Option<String>,
+    pub limit: Option<i64>,
+    pub after: Option<String>,
+}
+
+/// Query string of POST /admin/federation/outbox/requeue and
+/// DELETE /admin/federation/outbox/delivered.
+#[derive(Debug, Deserialize)]
+pub struct OutboxBulkQuery {
+    /// Only entries for this node.
+    pub target_node_id: Option<String>,
+    /// Purge only entries created at least this many hours ago.
+    pub older_than_hours: Option<i32>,
+}
+
+/// Query string of GET /admin/federation/nodes.
+#[derive(Debug, Deserialize)]
+pub struct PeerListQuery {
+    /// Only blocked (true) or only unblocked (false) peers.
+    pub blocked: Option<bool>,
+}
+
+impl FederationNode {
+    /// The peer's previous key, while its rotation overlap lasts.
+    pub fn previous_key(&self) -> Option<&str> {
+        match (&self.previous_public_key_b64, self.previous_key_expires_at) {
+            (Some(key), Some(until)) if until > Utc::now() => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer signs and verifies the Content-Digest of requests.
+    pub fn uses_content_digest(&self) -> bool {
+        self.speaks_at_least(CONTENT_DIGEST_PROTOCOL_VERSION)
+    }
+
+    /// Whether the peer accepts POST /s2s/messages/batch.
+    pub fn accepts_message_batches(&self) -> bool {
+        self.speaks_at_least(MESSAGE_BATCH_PROTOCOL_VERSION)
+    }
+
+    /// Whether the peer is known to speak protocol `version` or later.
+    pub fn speaks_at_least(&self, version: &str) -> bool {
+        self.protocol_version
+            .as_deref()
+            .is_some_and(|v| protocol_at_least(v, version))
+    }
+}
+
+// ─── Protocol version ────────────────────────────────────────────────────────
+
+/// S2S protocol version this node speaks, served in GET /s2s/info.
+pub const PROTOCOL_VERSION: &str = "0.0.4";
+
+/// First protocol version whose signatures cover the Content-Digest header.
+/// Older peers (0.0.2) sign method, path, timestamp and nonce only.
+pub const CONTENT_DIGEST_PROTOCOL_VERSION: &str = "0.0.3";
+
+/// First protocol version serving POST /s2s/messages/batch.
+pub const MESSAGE_BATCH_PROTOCOL_VERSION: &str = "0.0.4";
+
+/// Parse a dotted protocol version ("0.0.3") into its numeric parts.
+pub fn parse_protocol_version(version: &str) -> Option<Vec<u32>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Whether `version` is `minimum` or later. Unparseable versions are not.
+pub fn protocol_at_least(version: &str, minimum: &str) -> bool {
+    match (
+        parse_protocol_version(version),
+        parse_protocol_version(minimum),
+    ) {
+        (Some(v), Some(min)) => v >= min,
+        _ => false,
+    }
 }
 
 // ─── Outbox entry ────────────────────────────────────────────────────────────
 
+/// `federation_outbox.kind` for a POST /s2s/messages body.
+pub const OUTBOX_KIND_MESSAGES: &str = "messages";
+/// `federation_outbox.kind` for a POST /s2s/devices/removed body.
+pub const OUTBOX_KIND_DEVICE_REMOVED: &str = "device_removed";
+/// `federation_outbox.kind` for a POST /s2s/receipts body.
+pub const OUTBOX_KIND_RECEIPT: &str = "receipt";
+/// `federation_outbox.kind` for a POST /s2s/groups/state body.
+pub const OUTBOX_KIND_GROUP_STATE: &str = "group_state";
+/// `federation_outbox.kind` for a POST /s2s/groups/leave body.
+pub const OUTBOX_KIND_GROUP_LEAVE: &str = "group_leave";
+/// `federation_outbox.kind` for a POST /s2s/groups/messages body.
+pub const OUTBOX_KIND_GROUP_MESSAGE: &str = "group_message";
+/// `federation_outbox.kind` for a POST /s2s/groups/sender-keys body.
+pub const OUTBOX_KIND_SENDER_KEY: &str = "sender_key";
+/// `federation_outbox.kind` for a POST /s2s/chats/expiry body.
+pub const OUTBOX_KIND_CHAT_EXPIRY: &str = "chat_expiry";
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct FederationOutboxEntry {
     pub id: Uuid,
     pub target_node_id: String,
     pub logical_msg_id: String,
-    /// Verbatim JSON body to POST to /s2s/messages on the target node.
+    /// Which S2S endpoint `payload` is for, see the `OUTBOX_KIND_*` constants.
+    pub kind: String,
+    /// Verbatim JSON body to POST to the target node.
     pub payload: Value,
     pub attempt_count: i32,
     pub last_attempt: Option<DateTime<Utc>>,
@@ -62,6 +207,9 @@ pub struct S2sMessagePayload {
     pub from_identity_pubkey: String,
     /// Local username of the recipient on Node B.
     pub to_user: String,
+    /// Disappearing message deadline set by the sender; absent from older peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
     pub payloads: Vec<S2sDevicePayload>,
 }
 
@@ -94,22 +242,165 @@ pub struct S2sSessionInit {
     pub ephemeral_pubkey: String,
     pub sender_prekey_pub: String,
     pub otpk_used: String,
+    /// Recipient signed prekey the initiator used; absent from older peers.
+    #[serde(default)]
+    pub recipient_spk_pub: Option<String>,
+    /// Hybrid (PQXDH) sessions only: KEM prekey id and its encapsulation.
+    #[serde(default)]
+    pub kem_prekey_id: Option<i64>,
+    #[serde(default)]
+    pub kem_ciphertext: Option<String>,
     pub ciphertext: String,
 }
 
+/// Body of POST /s2s/devices/removed (Node A → Node B).
+///
+/// Sent to every peer that received traffic from a device once that device is
+/// revoked on its home node, so the peer drops its shadow copy and stops
+/// accepting the device's identity key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sDeviceRemoved {
+    /// "alice@node-a.hushnet.net"
+    pub from_federated_address: String,
+    pub device_id: Uuid,
+}
+
 /// Body of POST /s2s/ack (Node B → Node A).
 ///
 /// Advisory: the outbox worker already marks entries delivered when it receives
 /// a 2xx from forward_messages, so this ack is redundant in the happy path.
 /// It exists as an explicit signal for cases where Node B wants to proactively
 /// confirm delivery without waiting for Node A to poll.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct S2sAck {
     pub logical_msg_id: String,
-    /// "delivered" | "duplicate"
+    /// "delivered" | "duplicate", or "delivered" | "read" inside an S2sReceipt
     pub status: String,
 }
 
+/// Body of POST /s2s/receipts (reader's node → sender's node).
+///
+/// An S2sAck for one message, reported by a recipient device once it has
+/// stored or read it, plus who reported it and which local user sent it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sReceipt {
+    #[serde(flatten)]
+    pub ack: S2sAck,
+    /// Reader, "bob@node-b.hushnet.net"
+    pub from_federated_address: String,
+    pub from_device_id: Uuid,
+    /// Username of the original sender on the receiving node.
+    pub to_user: String,
+}
+
+/// Most messages in one POST /s2s/messages/batch.
+pub const MAX_MESSAGE_BATCH: usize = 50;
+
+/// Body of POST /s2s/messages/batch: several S2sMessagePayload at once,
+/// typically everything the outbox holds for the receiving node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sMessageBatch {
+    pub messages: Vec<S2sMessagePayload>,
+}
+
+/// Outcome of one message of a batch: the HTTP status and JSON body
+/// POST /s2s/messages would have answered for it on its own.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2sBatchItemResult {
+    pub status: u16,
+    pub body: Value,
+}
+
+/// Response of POST /s2s/messages/batch, one result per message in request
+/// order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2sBatchResponse {
+    pub results: Vec<S2sBatchItemResult>,
+}
+
+/// One member in an S2sGroupState.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sGroupMember {
+    /// "bob@node-b.hushnet.net"
+    pub address: String,
+    pub role: GroupRole,
+}
+
+/// Body of POST /s2s/groups/state (group's home node → member nodes).
+///
+/// Full membership snapshot of a group, sent after every change to each node
+/// hosting a member, and to the nodes of members just removed. Receivers
+/// replace their mirror with it unless they already have a newer `version`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sGroupState {
+    pub chat_id: Uuid,
+    /// Increases with every change on the home node.
+    pub version: i64,
+    pub name: Option<String>,
+    /// The `change` of the `group_updated` event ("members_added", ...).
+    pub change: String,
+    pub members: Vec<S2sGroupMember>,
+    /// Disappearing message TTL of the group; absent from older peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_ttl_seconds: Option<i32>,
+}
+
+/// Body of POST /s2s/chats/expiry (setter's node → other party's node).
+///
+/// A user changed the disappearing message TTL of their direct chat with a
+/// user of the receiving node, who gets the same setting. Group TTLs travel
+/// in S2sGroupState instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sChatExpiry {
+    /// Setter, "bob@node-b.hushnet.net"
+    pub from_federated_address: String,
+    /// Username of the other party on the receiving node.
+    pub to_user: String,
+    /// None turns disappearing messages off.
+    pub message_ttl_seconds: Option<i32>,
+}
+
+/// Body of POST /s2s/groups/leave (member's node → group's home node).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sGroupLeave {
+    pub chat_id: Uuid,
+    /// "bob@node-b.hushnet.net", a user of the calling node.
+    pub member_address: String,
+}
+
+/// Body of POST /s2s/groups/messages (sender's node → each member node).
+///
+/// A sender-key group message, sent once per node; the receiving node fans
+/// it out by reference to its members' devices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sGroupMessage {
+    pub chat_id: Uuid,
+    pub logical_msg_id: String,
+    pub from_federated_address: String,
+    pub from_device_id: Uuid,
+    pub from_identity_pubkey: String,
+    pub sender_key_id: Uuid,
+    pub header: Value,
+    pub ciphertext: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Body of POST /s2s/groups/sender-keys (sender's node → each member node).
+///
+/// The distribution messages of a sender key for the receiving node's member
+/// devices, which must be covered exactly; `payloads` may be empty if the
+/// sender knows none, in which case the receiver answers 409 listing them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S2sSenderKey {
+    pub chat_id: Uuid,
+    pub key_id: Uuid,
+    pub from_federated_address: String,
+    pub from_device_id: Uuid,
+    pub from_identity_pubkey: String,
+    pub payloads: Vec<S2sDevicePayload>,
+}
+
 /// Response body for GET /s2s/info.
 ///
 /// Used by peers during bootstrapping to obtain this node's public key before
@@ -120,5 +411,51 @@ pub struct NodeInfo {
     pub node_id: String,
     pub api_url: String,
     pub public_key_b64: String,
-    pub protocol_version: &'static str,
+    pub protocol_version: String,
+    /// Present while a key rotation's overlap lasts: the key `public_key_b64`
+    /// replaced, and its signature endorsing the new one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_rotation: Option<KeyRotation>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_versions_compare_numerically() {
+        assert!(protocol_at_least("0.0.3", CONTENT_DIGEST_PROTOCOL_VERSION));
+        assert!(protocol_at_least("0.0.10", "0.0.3"));
+        assert!(protocol_at_least("0.1", "0.0.3"));
+        assert!(!protocol_at_least("0.0.2", CONTENT_DIGEST_PROTOCOL_VERSION));
+        assert!(!protocol_at_least("0.0.x", "0.0.2"));
+        assert_eq!(
+            parse_protocol_version(PROTOCOL_VERSION),
+            Some(vec![0, 0, 4])
+        );
+        assert!(protocol_at_least(
+            PROTOCOL_VERSION,
+            MESSAGE_BATCH_PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn group_state_from_older_peers_has_no_ttl() {
+        let state: S2sGroupState = serde_json::from_value(serde_json::json!({
+            "chat_id": Uuid::nil(),
+            "version": 3,
+            "name": "team",
+            "change": "renamed",
+            "members": [],
+        }))
+        .unwrap();
+        assert_eq!(state.message_ttl_seconds, None);
+
+        let with_ttl = S2sGroupState {
+            message_ttl_seconds: Some(3600),
+            ..state
+        };
+        let json = serde_json::to_value(&with_ttl).unwrap();
+        assert_eq!(json["message_ttl_seconds"], 3600);
+    }
 }
@@ -1,4 +1,4 @@
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
@@ -13,8 +13,10 @@ pub struct Message {
     pub from_device_id: Uuid,
     pub to_user_id: Uuid,
     pub to_device_id: Uuid,
-    pub header: Value,      // Double Ratchet header (JSON)
-    pub ciphertext: String, // base64(nonce || cipher || mac)
+    pub kind: String,                   // 'pairwise' | 'sender_key' | 'group'
+    pub group_message_id: Option<Uuid>, // shared ciphertext of a 'group' row
+    pub header: Option<Value>,          // Double Ratchet header (JSON), None for 'group'
+    pub ciphertext: Option<String>,     // base64(nonce || cipher || mac), None for 'group'
     pub delivered_at: Option<NaiveDateTime>,
     pub read_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
@@ -44,9 +46,29 @@ pub struct OutgoingMessage {
     /// Format: "username@node-host" (e.g. "bob@node-b.hushnet.net").
     #[serde(default)]
     pub to_user_address: Option<String>,
+    /// Disappearing message: the server deletes every copy at this time, even
+    /// if undelivered. The chat's message TTL, if shorter, still applies.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Attachments (uploaded by the sending device) the message refers to.
+    /// Their keys travel inside the ciphertext; the ids let the server keep
+    /// each blob until every recipient copy is delivered or expired.
+    #[serde(default)]
+    pub attachment_ids: Vec<Uuid>,
     pub payloads: Vec<OutgoingMessagePayload>,
 }
 
+/// What happened to a message fan-out handed to `insert_message`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOutcome {
+    /// At least one row was written.
+    Stored,
+    /// Every row already existed: a retry of a send that succeeded.
+    Duplicate,
+    /// The logical id is already used by another device's message.
+    IdTaken,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct MessageView {
     pub id: Uuid,
@@ -54,7 +76,147 @@ pub struct MessageView {
     pub chat_id: Option<Uuid>,
     pub from_user_id: Option<Uuid>,
     pub from_device_id: Option<Uuid>,
+    /// "pairwise", "sender_key" (a sender key distribution) or "group"
+    /// (encrypted with the sender key `sender_key_id`).
+    pub kind: String,
+    pub sender_key_id: Option<Uuid>,
     pub header: Value,
     pub ciphertext: String,
-    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Body of POST /messages/ack.
+#[derive(Debug, Deserialize)]
+pub struct AckMessagesBody {
+    pub message_ids: Vec<Uuid>,
+}
+
+/// Receipt a recipient device reports for messages it got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+impl ReceiptStatus {
+    /// `status` of the S2S receipt.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReceiptStatus::Delivered => "delivered",
+            ReceiptStatus::Read => "read",
+        }
+    }
+
+    /// Type of the realtime event pushed to the sender's devices.
+    pub fn event_type(self) -> &'static str {
+        match self {
+            ReceiptStatus::Delivered => "delivery_receipt",
+            ReceiptStatus::Read => "read_receipt",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "delivered" => Some(ReceiptStatus::Delivered),
+            "read" => Some(ReceiptStatus::Read),
+            _ => None,
+        }
+    }
+}
+
+/// Body of POST /messages/receipts.
+#[derive(Debug, Deserialize)]
+pub struct ReceiptsBody {
+    pub status: ReceiptStatus,
+    pub logical_msg_ids: Vec<String>,
+}
+
+/// Difference between the devices a message was encrypted for and the
+/// recipient's current devices. Returned as the body of a 409 so the client
+/// can refresh its device list and re-encrypt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceMismatch {
+    /// Recipient devices the message has no payload for.
+    pub missing_devices: Vec<Uuid>,
+    /// Payload targets that are not (or no longer) the recipient's devices.
+    pub extra_devices: Vec<Uuid>,
+}
+
+impl DeviceMismatch {
+    /// Compare payload targets against the recipient's devices.
+    /// None when both sets are equal.
+    pub fn compare(recipient_devices: &[Uuid], targets: &[Uuid]) -> Option<Self> {
+        let mut missing_devices: Vec<Uuid> = recipient_devices
+            .iter()
+            .filter(|d| !targets.contains(d))
+            .copied()
+            .collect();
+        let mut extra_devices: Vec<Uuid> = targets
+            .iter()
+            .filter(|d| !recipient_devices.contains(d))
+            .copied()
+            .collect();
+        if missing_devices.is_empty() && extra_devices.is_empty() {
+            return None;
+        }
+        missing_devices.sort();
+        extra_devices.sort();
+        extra_devices.dedup();
+        Some(Self {
+            missing_devices,
+            extra_devices,
+        })
+    }
+}
+
+impl std::fmt::Display for DeviceMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "device list mismatch ({} missing, {} extra)",
+            self.missing_devices.len(),
+            self.extra_devices.len()
+        )
+    }
+}
+
+impl std::error::Error for DeviceMismatch {}
+
+/// First device id that appears in more than one payload, if any.
+pub fn duplicate_target(targets: &[Uuid]) -> Option<Uuid> {
+    let mut seen = std::collections::HashSet::with_capacity(targets.len());
+    targets.iter().copied().find(|d| !seen.insert(*d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_device_sets_pass() {
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        assert_eq!(DeviceMismatch::compare(&[a, b], &[b, a]), None);
+        assert_eq!(DeviceMismatch::compare(&[], &[]), None);
+    }
+
+    #[test]
+    fn missing_and_extra_devices_are_reported() {
+        let kept = Uuid::new_v4();
+        let new_phone = Uuid::new_v4();
+        let stale = Uuid::new_v4();
+        let mismatch = DeviceMismatch::compare(&[kept, new_phone], &[kept, stale]).unwrap();
+        assert_eq!(mismatch.missing_devices, vec![new_phone]);
+        assert_eq!(mismatch.extra_devices, vec![stale]);
+    }
+
+    #[test]
+    fn duplicate_targets_are_found() {
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        assert_eq!(duplicate_target(&[a, b]), None);
+        assert_eq!(duplicate_target(&[a, b, a]), Some(a));
+    }
 }
@@ -1,8 +1,12 @@
+pub mod attachment;
 pub mod chat;
 pub mod device;
+pub mod device_link;
 pub mod enrollment_token;
 pub mod federation;
 pub mod message;
+pub mod pagination;
 pub mod realtime;
+pub mod sender_key;
 pub mod session;
 pub mod user;
@@ -0,0 +1,110 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// Page size used when the client does not pass `limit`.
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/// Largest page the server will return, whatever the client asks for.
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// `?limit=&after=` query of the paginated pending endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub after: Option<String>,
+}
+
+impl PageQuery {
+    /// Requested page size, clamped to 1..=MAX_PAGE_SIZE.
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Decoded `after` cursor. Err if one was given but is malformed.
+    pub fn cursor(&self) -> Result<Option<PageCursor>, &'static str> {
+        match &self.after {
+            None => Ok(None),
+            Some(raw) => PageCursor::decode(raw).map(Some).ok_or("invalid cursor"),
+        }
+    }
+}
+
+/// Keyset position in a `(created_at, id)` ordered listing.
+///
+/// Encoded as `<created_at in µs since epoch>_<id>`. Clients treat it as
+/// opaque and only echo back a `next_cursor` they were given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageCursor {
+    pub created_at_micros: i64,
+    pub id: Uuid,
+}
+
+impl PageCursor {
+    pub fn new(created_at: DateTime<Utc>, id: Uuid) -> Self {
+        Self {
+            created_at_micros: created_at.timestamp_micros(),
+            id,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at_micros, self.id)
+    }
+
+    pub fn decode(raw: &str) -> Option<Self> {
+        let (micros, id) = raw.split_once('_')?;
+        let created_at_micros = micros.parse().ok()?;
+        DateTime::from_timestamp_micros(created_at_micros)?;
+        Some(Self {
+            created_at_micros,
+            id: id.parse().ok()?,
+        })
+    }
+
+    /// Cursor position for TIMESTAMPTZ columns.
+    pub fn created_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_micros(self.created_at_micros).unwrap_or_default()
+    }
+
+    /// Cursor position for TIMESTAMP columns, which hold UTC wall-clock time.
+    pub fn created_at_naive(&self) -> NaiveDateTime {
+        self.created_at().naive_utc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = PageCursor::new(
+            DateTime::from_timestamp_micros(1_730_557_800_123_456).unwrap(),
+            Uuid::new_v4(),
+        );
+        assert_eq!(PageCursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        assert_eq!(PageCursor::decode(""), None);
+        assert_eq!(PageCursor::decode("123"), None);
+        assert_eq!(PageCursor::decode("abc_not-a-uuid"), None);
+        let query = PageQuery {
+            limit: None,
+            after: Some("garbage".into()),
+        };
+        assert!(query.cursor().is_err());
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        let query = |limit| PageQuery { limit, after: None };
+        assert_eq!(query(None).limit(), DEFAULT_PAGE_SIZE);
+        assert_eq!(query(Some(0)).limit(), 1);
+        assert_eq!(query(Some(10_000)).limit(), MAX_PAGE_SIZE);
+    }
+}
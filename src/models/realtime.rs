@@ -1,7 +1,27 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RealtimeEvent {
-    pub event_type: String, // "message" | "session" | "device"
+    pub event_type: String, // "message" | "session" | "device" | "device_removed" | "link_request" | "prekeys_low" | "delivery_receipt" | "read_receipt" | "chat_updated" | "device_mismatch" | "group_updated" | "resync"
     pub payload: serde_json::Value,
+    /// Per-device sequence number (`payload.seq`). Clients pass the last one
+    /// they saw as `?since=` when reconnecting. None for `resync`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+}
+
+impl RealtimeEvent {
+    /// Build an event from a notification payload written by emit_device_event.
+    pub fn from_payload(payload: Value) -> Self {
+        RealtimeEvent {
+            event_type: payload
+                .get("type")
+                .and_then(|v| v.as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+            seq: payload.get("seq").and_then(|v| v.as_i64()),
+            payload,
+        }
+    }
 }
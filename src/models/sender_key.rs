@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::message::OutgoingMessagePayload;
+
+/// Body of POST /chats/:id/sender-keys: the sender key `key_id`, pairwise
+/// encrypted once for every other device in the group. `distributions`
+/// covers the devices of this node; `remote_distributions` those of each
+/// other node hosting members, which that node checks itself.
+#[derive(Debug, Deserialize)]
+pub struct DistributeSenderKeyBody {
+    pub key_id: Uuid,
+    pub distributions: Vec<OutgoingMessagePayload>,
+    #[serde(default)]
+    pub remote_distributions: Vec<RemoteDistribution>,
+}
+
+/// The distribution messages for the member devices homed on `node_id`.
+#[derive(Debug, Deserialize)]
+pub struct RemoteDistribution {
+    pub node_id: String,
+    pub payloads: Vec<OutgoingMessagePayload>,
+}
+
+/// Body of POST /chats/:id/messages: one ciphertext, encrypted with the
+/// sending device's current sender key, for the whole group.
+#[derive(Debug, Deserialize)]
+pub struct OutgoingGroupMessage {
+    pub logical_msg_id: String,
+    pub sender_key_id: Uuid,
+    pub header: Value,
+    pub ciphertext: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub attachment_ids: Vec<Uuid>,
+}
+
+/// The sender key a device currently uses in a chat, and the devices it was
+/// distributed to.
+#[derive(Debug)]
+pub struct SenderKey {
+    pub key_id: Uuid,
+    pub recipient_device_ids: Vec<Uuid>,
+}
+
+/// A device a group message or sender key is fanned out to.
+#[derive(Debug, Clone, Copy)]
+pub struct GroupTarget {
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+}
+
+/// logical_msg_id of the distribution messages of sender key `key_id`.
+pub fn distribution_logical_id(key_id: &Uuid) -> String {
+    format!("sender_key:{key_id}")
+}
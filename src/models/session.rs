@@ -10,6 +10,11 @@ pub struct PendingSession {
     pub ephemeral_pubkey: String,
     pub sender_prekey_pub: String,
     pub otpk_used: String,
+    /// Recipient signed prekey the initiator used (None for older clients).
+    pub recipient_spk_pub: Option<String>,
+    /// ML-KEM prekey encapsulated to for a hybrid (PQXDH) session.
+    pub kem_prekey_id: Option<i64>,
+    pub kem_ciphertext: Option<String>,
     pub ciphertext: String,
     pub created_at: Option<NaiveDateTime>,
 }
@@ -1,11 +1,12 @@
+use std::sync::Arc;
+
 use serde_json::Value;
 use sqlx::{postgres::PgListener, PgPool};
-use tokio::sync::broadcast;
 use tracing::{error, info, warn};
 
-use crate::models::realtime::RealtimeEvent;
+use crate::{models::realtime::RealtimeEvent, realtime::registry::ConnectionRegistry};
 
-pub async fn start_pg_listeners(pool: PgPool, tx: broadcast::Sender<RealtimeEvent>) {
+pub async fn start_pg_listeners(pool: PgPool, registry: Arc<ConnectionRegistry>) {
     let mut listener = PgListener::connect_with(&pool).await.unwrap();
     listener
         .listen_all(vec![
@@ -13,11 +14,12 @@ pub async fn start_pg_listeners(pool: PgPool, tx: broadcast::Sender<RealtimeEven
             "sessions_channel",
             "pending_sessions_channel",
             "devices_channel",
+            "prekeys_channel",
         ])
         .await
         .unwrap();
 
-    info!("PG listener started, watching 4 channels");
+    info!("PG listener started, watching 5 channels");
 
     loop {
         match listener.recv().await {
@@ -25,30 +27,23 @@ pub async fn start_pg_listeners(pool: PgPool, tx: broadcast::Sender<RealtimeEven
                 let channel = notif.channel();
                 match serde_json::from_str::<Value>(notif.payload()) {
                     Ok(payload) => {
-                        let event_type = payload
-                            .get("type")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("unknown");
-                        let user_id = payload
+                        let event = RealtimeEvent::from_payload(payload);
+                        let user_id = event
+                            .payload
                             .get("user_id")
                             .and_then(|v| v.as_str())
                             .unwrap_or("?");
 
                         info!(
                             %channel,
-                            %event_type,
+                            event_type = %event.event_type,
                             %user_id,
-                            subscribers = tx.receiver_count(),
-                            "PG notify received, forwarding to broadcast"
+                            seq = ?event.seq,
+                            connections = registry.connection_count(),
+                            "PG notify received, dispatching to sockets"
                         );
 
-                        let event = RealtimeEvent {
-                            event_type: event_type.to_string(),
-                            payload,
-                        };
-                        if let Err(e) = tx.send(event) {
-                            warn!(%channel, err = %e, "broadcast send failed (no subscribers?)");
-                        }
+                        registry.dispatch(event);
                     }
                     Err(e) => {
                         warn!(%channel, err = %e, "PG notify payload parse failed");
@@ -0,0 +1,178 @@
+// src/realtime/registry.rs
+//
+// Routes realtime events straight to the sockets that should receive them.
+//
+// Every WebSocket registers a bounded mpsc sender under its user id. The PG
+// listener looks the event's user_id up and only touches that user's
+// connections, instead of every socket receiving and filtering every event.
+//
+// A socket that cannot keep up does not stall the listener or other users:
+// when its buffer is full the event is dropped for that socket only and the
+// connection is flagged. The socket task sends a `resync` event as soon as it
+// has room again, telling the client to refetch pending state over REST.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use dashmap::DashMap;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::models::realtime::RealtimeEvent;
+
+/// Events buffered per socket before it is considered lagging.
+pub const CONNECTION_BUFFER: usize = 256;
+
+struct Connection {
+    id: u64,
+    device_id: Uuid,
+    sender: mpsc::Sender<RealtimeEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    by_user: DashMap<Uuid, Vec<Connection>>,
+    next_id: AtomicU64,
+}
+
+/// Receiving side of one registered socket. Unregisters itself on drop.
+pub struct ConnectionHandle {
+    registry: Arc<ConnectionRegistry>,
+    user_id: Uuid,
+    id: u64,
+    pub receiver: mpsc::Receiver<RealtimeEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl ConnectionHandle {
+    /// True once if events were dropped since the last call.
+    pub fn take_lagged(&self) -> bool {
+        self.lagged.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.registry.remove(&self.user_id, self.id);
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn register(self: &Arc<Self>, user_id: Uuid, device_id: Uuid) -> ConnectionHandle {
+        let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let lagged = Arc::new(AtomicBool::new(false));
+
+        self.by_user.entry(user_id).or_default().push(Connection {
+            id,
+            device_id,
+            sender,
+            lagged: lagged.clone(),
+        });
+
+        ConnectionHandle {
+            registry: self.clone(),
+            user_id,
+            id,
+            receiver,
+            lagged,
+        }
+    }
+
+    fn remove(&self, user_id: &Uuid, id: u64) {
+        self.by_user.remove_if_mut(user_id, |_, conns| {
+            conns.retain(|c| c.id != id);
+            conns.is_empty()
+        });
+    }
+
+    /// Number of open sockets, for logging.
+    pub fn connection_count(&self) -> usize {
+        self.by_user.iter().map(|e| e.value().len()).sum()
+    }
+
+    /// Hand `event` to every socket it is addressed to; never blocks.
+    pub fn dispatch(&self, event: RealtimeEvent) {
+        let Some(user_id) = payload_uuid(&event, "user_id") else {
+            debug!(event_type = %event.event_type, "realtime event without user_id dropped");
+            return;
+        };
+        let to_device = payload_uuid(&event, "to_device_id");
+
+        let Some(conns) = self.by_user.get(&user_id) else {
+            return;
+        };
+        for conn in conns.iter() {
+            if to_device.is_some_and(|d| d != conn.device_id) {
+                continue;
+            }
+            match conn.sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    if !conn.lagged.swap(true, Ordering::AcqRel) {
+                        warn!(%user_id, device_id = %conn.device_id, "WS buffer full, client must resync");
+                    }
+                }
+                // The socket task is gone; its handle removes the entry.
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+    }
+}
+
+fn payload_uuid(event: &RealtimeEvent, field: &str) -> Option<Uuid> {
+    event
+        .payload
+        .get(field)
+        .and_then(|v| v.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(payload: serde_json::Value) -> RealtimeEvent {
+        RealtimeEvent::from_payload(payload)
+    }
+
+    #[test]
+    fn device_scoped_events_only_reach_their_device() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let (user, device, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut mine = registry.register(user, device);
+        let mut theirs = registry.register(user, other);
+
+        registry.dispatch(event(json!({"user_id": user.to_string()})));
+        registry.dispatch(event(
+            json!({"user_id": user.to_string(), "to_device_id": other.to_string()}),
+        ));
+        registry.dispatch(event(json!({"user_id": Uuid::new_v4().to_string()})));
+
+        assert!(mine.receiver.try_recv().is_ok());
+        assert!(mine.receiver.try_recv().is_err());
+        assert!(theirs.receiver.try_recv().is_ok());
+        assert!(theirs.receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn full_buffer_flags_resync_and_drop_unregisters() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let user = Uuid::new_v4();
+        let handle = registry.register(user, Uuid::new_v4());
+
+        for _ in 0..=CONNECTION_BUFFER {
+            registry.dispatch(event(json!({"user_id": user.to_string()})));
+        }
+        assert!(handle.take_lagged());
+        assert!(!handle.take_lagged());
+
+        drop(handle);
+        assert_eq!(registry.connection_count(), 0);
+    }
+}
@@ -1,67 +1,230 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Path, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
     },
     response::IntoResponse,
     Extension,
 };
 
-use tokio::sync::broadcast;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
 use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    middlewares::auth::AuthenticatedDevice,
+    models::realtime::RealtimeEvent,
+    realtime::registry::{ConnectionHandle, ConnectionRegistry},
+    repository::realtime_repository,
+};
 
-use crate::models::realtime::RealtimeEvent;
+/// Most events replayed on connect; a longer gap gets a resync instead.
+const REPLAY_LIMIT: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    /// Last `seq` the client processed. Omit on first connect.
+    pub since: Option<i64>,
+}
 
+/// The upgrade request carries the same signed headers as any other device
+/// request (sign `GET /ws` with an empty body), so the socket is bound to the
+/// device that proved possession of its identity key.
 pub async fn ws_route(
-    Path(user_id): Path<String>,
+    State(state): State<AppState>,
+    AuthenticatedDevice(device): AuthenticatedDevice,
+    Query(params): Query<WsParams>,
     ws: WebSocketUpgrade,
-    Extension(tx): Extension<broadcast::Sender<RealtimeEvent>>,
+    Extension(registry): Extension<Arc<ConnectionRegistry>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, tx, user_id))
+    ws.on_upgrade(move |socket| {
+        // Register before replaying so nothing emitted meanwhile is lost;
+        // duplicates are skipped by seq.
+        let handle = registry.register(device.user_id, device.id);
+        info!(
+            user_id = %device.user_id,
+            device_id = %device.id,
+            since = ?params.since,
+            connections = registry.connection_count(),
+            "WS connected"
+        );
+        handle_socket(
+            socket,
+            handle,
+            state.pool,
+            device.user_id,
+            device.id,
+            params.since,
+        )
+    })
+}
+
+fn resync_event(user_id: Uuid, device_id: Uuid, last_seq: i64) -> RealtimeEvent {
+    RealtimeEvent {
+        event_type: "resync".into(),
+        payload: json!({
+            "type": "resync",
+            "user_id": user_id,
+            "device_id": device_id,
+            "last_seq": last_seq
+        }),
+        seq: None,
+    }
 }
 
 async fn handle_socket(
     mut socket: WebSocket,
-    tx: broadcast::Sender<RealtimeEvent>,
-    user_id: String,
+    mut handle: ConnectionHandle,
+    pool: PgPool,
+    user_id: Uuid,
+    device_id: Uuid,
+    since: Option<i64>,
 ) {
-    let mut rx = tx.subscribe();
-
-    info!(%user_id, subscribers = tx.receiver_count(), "WS connected");
-
-    tokio::spawn(async move {
-        loop {
-            match rx.recv().await {
-                Ok(event) => {
-                    let payload_user = event
-                        .payload
-                        .get("user_id")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or_default();
-
-                    if payload_user == user_id {
-                        info!(
-                            %user_id,
-                            event_type = %event.event_type,
-                            "WS dispatching event to client"
-                        );
-                        if let Ok(json) = serde_json::to_string(&event) {
-                            if socket.send(Message::Text(json.into())).await.is_err() {
-                                info!(%user_id, "WS send failed, closing");
-                                break;
-                            }
+    // Highest seq already sent on this socket; live events at or below it
+    // were covered by the replay.
+    let mut sent_seq = None;
+
+    if let Some(since) = since {
+        match replay(&mut socket, &pool, user_id, device_id, since).await {
+            Ok(seq) => sent_seq = Some(seq),
+            Err(e) => {
+                warn!(%user_id, %device_id, err = %e, "WS replay failed, closing");
+                return;
+            }
+        }
+    }
+
+    // Read the socket alongside the event channel: control frames need an
+    // answer, and a client that closes or drops shows up here rather than on
+    // the next failed send.
+    loop {
+        let event = tokio::select! {
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(Message::Ping(data))) => {
+                        if socket.send(Message::Pong(data)).await.is_err() {
+                            break;
                         }
                     }
+                    Some(Ok(Message::Close(_))) => {
+                        info!(%user_id, %device_id, "WS closed by client");
+                        let _ = socket.send(Message::Close(None)).await;
+                        break;
+                    }
+                    // Clients have nothing to send on this stream.
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        info!(%user_id, %device_id, err = %e, "WS read failed, closing");
+                        break;
+                    }
+                    None => break,
                 }
-                Err(broadcast::error::RecvError::Lagged(n)) => {
-                    warn!(%user_id, skipped = n, "WS broadcast lagged, events dropped");
-                }
-                Err(broadcast::error::RecvError::Closed) => {
-                    info!(%user_id, "WS broadcast channel closed");
-                    break;
-                }
+                continue;
+            }
+            event = handle.receiver.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
+        if let (Some(seq), Some(sent)) = (event.seq, sent_seq) {
+            if seq <= sent {
+                continue;
             }
         }
-        info!(%user_id, "WS disconnected");
-    });
+
+        // Events were dropped while the buffer was full: tell the client
+        // before anything newer so it refetches pending state over REST.
+        if handle.take_lagged() {
+            let last_seq = realtime_repository::get_last_device_seq(&pool, &device_id)
+                .await
+                .unwrap_or_default();
+            if send_event(&mut socket, &resync_event(user_id, device_id, last_seq))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        info!(
+            %user_id,
+            %device_id,
+            event_type = %event.event_type,
+            seq = ?event.seq,
+            "WS dispatching event to client"
+        );
+        if send_event(&mut socket, &event).await.is_err() {
+            info!(%user_id, %device_id, "WS send failed, closing");
+            break;
+        }
+        if event.seq.is_some() {
+            sent_seq = event.seq;
+        }
+
+        // A revoked device gets told, then loses its stream.
+        let revoked_id = event.payload.get("device_id").and_then(|v| v.as_str());
+        if event.event_type == "device_removed"
+            && revoked_id == Some(device_id.to_string().as_str())
+        {
+            info!(%user_id, %device_id, "WS device revoked, closing");
+            let _ = socket.send(Message::Close(None)).await;
+            break;
+        }
+    }
+    info!(%user_id, %device_id, "WS disconnected");
+}
+
+/// Send every logged event after `since`, or a single resync if some of them
+/// were already purged or there are too many. Returns the last seq covered.
+async fn replay(
+    socket: &mut WebSocket,
+    pool: &PgPool,
+    user_id: Uuid,
+    device_id: Uuid,
+    since: i64,
+) -> anyhow::Result<i64> {
+    let last_seq = realtime_repository::get_last_device_seq(pool, &device_id).await?;
+    if since >= last_seq {
+        if since > last_seq {
+            // Cursor from the future (e.g. another server's database).
+            send_event(socket, &resync_event(user_id, device_id, last_seq)).await?;
+        }
+        return Ok(last_seq);
+    }
+
+    let events: Vec<RealtimeEvent> =
+        realtime_repository::get_device_events_since(pool, &device_id, since, REPLAY_LIMIT + 1)
+            .await?
+            .into_iter()
+            .map(RealtimeEvent::from_payload)
+            .collect();
+
+    let complete = events.first().and_then(|e| e.seq) == Some(since + 1)
+        && events.len() as i64 <= REPLAY_LIMIT;
+    if !complete {
+        info!(%user_id, %device_id, since, last_seq, "WS cursor too old, sending resync");
+        send_event(socket, &resync_event(user_id, device_id, last_seq)).await?;
+        return Ok(last_seq);
+    }
+
+    info!(%user_id, %device_id, since, count = events.len(), "WS replaying missed events");
+    let mut covered = since;
+    for event in &events {
+        send_event(socket, event).await?;
+        covered = event.seq.unwrap_or(covered);
+    }
+    Ok(covered)
+}
+
+async fn send_event(socket: &mut WebSocket, event: &RealtimeEvent) -> Result<(), axum::Error> {
+    match serde_json::to_string(event) {
+        Ok(json) => socket.send(Message::Text(json.into())).await,
+        Err(_) => Ok(()),
+    }
 }
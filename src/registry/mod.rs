@@ -1 +1,2 @@
 pub mod register;
+pub mod rotate;
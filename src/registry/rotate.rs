@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use chrono::{Duration, Utc};
+use ed25519_dalek::Signer;
+use reqwest::Client;
+use serde_json::json;
+
+use crate::utils::node_keys::NodeKeys;
+
+/// Replace this node's signing key.
+///
+/// A new key is generated and endorsed by the current one. The registry is
+/// told about it in a request signed by the current key, and only once it
+/// accepts is the node_keys file replaced (the old file is kept next to it as
+/// node_keys.<unix time>.bak). Until `overlap_hours` have passed, GET
+/// /s2s/info serves the previous key and the endorsement, which is what
+/// peers check before switching to the new key.
+///
+/// The running node keeps signing with the key it loaded at startup until it
+/// is restarted; peers accept both keys for the length of the overlap.
+pub async fn rotate_node_key(registry_url: &str, overlap_hours: i64) -> Result<()> {
+    let path = NodeKeys::get_node_keys_path();
+    anyhow::ensure!(path.exists(), "no node key at {}", path.display());
+    let keys = NodeKeys::load_or_generate()?;
+    let node_host =
+        std::env::var("NODE_HOST").unwrap_or_else(|_| "node-unknown.hushnet.net".into());
+
+    let overlap_until = Utc::now() + Duration::hours(overlap_hours);
+    let next = keys.rotated(&node_host, overlap_until)?;
+    let rotation = next
+        .rotation
+        .as_ref()
+        .context("rotated key carries no rotation")?;
+
+    let client = Client::new();
+    let challenge_res = client
+        .post(format!("{registry_url}/api/registry/challenge"))
+        .json(&json!({ "pubkey_b64": keys.public_b64 }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
+    let nonce = challenge_res["nonce"]
+        .as_str()
+        .context("registry challenge has no nonce")?;
+
+    let payload = json!({
+        "host": node_host,
+        "new_pubkey_b64": next.public_b64,
+        "rotation_signature_b64": rotation.signature_b64,
+        "overlap_until": overlap_until,
+    });
+
+    // Signed by the key being replaced, like registration itself.
+    let canon = serde_json::to_string(&payload)?;
+    let message = [canon.as_bytes(), nonce.as_bytes()].concat();
+    let sig = keys.signing_key()?.sign(&message);
+    let sig_b64 = B64.encode(sig.to_bytes());
+
+    let rotate_res = client
+        .post(format!("{registry_url}/api/registry/rotate"))
+        .json(&json!({
+            "nonce": nonce,
+            "pubkey_b64": keys.public_b64,
+            "signature_b64": sig_b64,
+            "payload": payload
+        }))
+        .send()
+        .await?
+        .error_for_status()
+        .context("registry refused the key rotation; node key unchanged")?
+        .text()
+        .await?;
+    println!("Registry response: {rotate_res}");
+
+    let backup = path.with_extension(format!("{}.bak", Utc::now().timestamp()));
+    std::fs::copy(&path, &backup)?;
+    next.save()?;
+
+    println!("Previous key kept at {}", backup.display());
+    println!("New public key (base64): {}", next.public_b64);
+    println!(
+        "Both keys are announced until {overlap_until}. Restart the node to sign with the new key."
+    );
+    Ok(())
+}
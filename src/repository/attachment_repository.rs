@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+/// Register an attachment `owner_device_id` is about to upload. Returns its id
+/// and creation time.
+pub async fn create_attachment(
+    pool: &PgPool,
+    owner_device_id: &Uuid,
+    size_bytes: i64,
+    upload_token_hash: &str,
+    download_token_hash: &str,
+) -> Result<(Uuid, DateTime<Utc>)> {
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO attachments (owner_device_id, size_bytes, upload_token_hash, download_token_hash)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, created_at
+        "#,
+        owner_device_id,
+        size_bytes,
+        upload_token_hash,
+        download_token_hash
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((row.id, row.created_at))
+}
+
+/// Declared size of an attachment still waiting for its upload, if
+/// `upload_token_hash` matches and the upload window of `window_secs` is open.
+pub async fn get_pending_upload_size(
+    pool: &PgPool,
+    attachment_id: &Uuid,
+    upload_token_hash: &str,
+    window_secs: f64,
+) -> Result<Option<i64>> {
+    let size = sqlx::query_scalar!(
+        r#"
+        SELECT size_bytes FROM attachments
+        WHERE id = $1 AND upload_token_hash = $2 AND uploaded_at IS NULL
+          AND created_at > NOW() - make_interval(secs => $3)
+        "#,
+        attachment_id,
+        upload_token_hash,
+        window_secs
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(size)
+}
+
+/// Spend the upload token and record the upload in one statement, once the
+/// blob is stored. Returns false if the token was already used, in which case
+/// another upload of the same attachment finished first and owns the row.
+/// A failed blob write never reaches this, so the client can retry with the
+/// same token.
+pub async fn complete_upload(
+    pool: &PgPool,
+    attachment_id: &Uuid,
+    upload_token_hash: &str,
+    content_digest: &str,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE attachments
+        SET upload_token_hash = '', uploaded_at = NOW(), content_digest = $3
+        WHERE id = $1 AND upload_token_hash = $2 AND uploaded_at IS NULL
+        "#,
+        attachment_id,
+        upload_token_hash,
+        content_digest
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Whether an uploaded attachment exists with this download token. With
+/// `federated_only`, the attachment must also still be held for a peer.
+pub async fn is_downloadable(
+    pool: &PgPool,
+    attachment_id: &Uuid,
+    download_token_hash: &str,
+    federated_only: bool,
+) -> Result<bool> {
+    let found = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM attachments
+            WHERE id = $1 AND download_token_hash = $2 AND uploaded_at IS NOT NULL
+              AND (NOT $3 OR federated_until > NOW())
+        ) AS "exists!"
+        "#,
+        attachment_id,
+        download_token_hash,
+        federated_only
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(found)
+}
+
+/// Whether every id names an attachment `owner_device_id` has uploaded.
+pub async fn are_uploaded_by(
+    pool: &PgPool,
+    attachment_ids: &[Uuid],
+    owner_device_id: &Uuid,
+) -> Result<bool> {
+    let count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM attachments
+        WHERE id = ANY($1) AND owner_device_id = $2 AND uploaded_at IS NOT NULL
+        "#,
+        attachment_ids,
+        owner_device_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let mut distinct = attachment_ids.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    Ok(count == distinct.len() as i64)
+}
+
+/// Keep attachments fetchable over S2S until `until`, for a message that was
+/// forwarded to another node.
+pub async fn hold_for_federation(
+    pool: &PgPool,
+    attachment_ids: &[Uuid],
+    until: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE attachments
+        SET referenced_at = COALESCE(referenced_at, NOW()),
+            federated_until = GREATEST(federated_until, $2)
+        WHERE id = ANY($1)
+        "#,
+        attachment_ids,
+        until
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Up to `limit` attachments nothing needs anymore: no federated hold, and
+/// either never referenced and older than `retention_hours`, or every message
+/// copy referencing them was delivered more than `retention_hours` ago or
+/// is gone.
+pub async fn get_collectable_attachments(
+    pool: &PgPool,
+    retention_hours: i32,
+    limit: i64,
+) -> Result<Vec<Uuid>> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT a.id FROM attachments a
+        WHERE (a.federated_until IS NULL OR a.federated_until < NOW())
+          AND CASE
+                WHEN a.referenced_at IS NULL
+                    THEN a.created_at < NOW() - make_interval(hours => $1)
+                ELSE NOT EXISTS (
+                    SELECT 1 FROM message_attachments ma
+                    JOIN messages m ON m.id = ma.message_id
+                    WHERE ma.attachment_id = a.id
+                      AND (m.delivered_at IS NULL
+                           OR m.delivered_at > NOW() - make_interval(hours => $1))
+                )
+              END
+        LIMIT $2
+        "#,
+        retention_hours,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(ids)
+}
+
+pub async fn delete_attachment(pool: &PgPool, attachment_id: &Uuid) -> Result<()> {
+    sqlx::query!("DELETE FROM attachments WHERE id = $1", attachment_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
@@ -1,7 +1,15 @@
-use sqlx::{PgPool, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::{PgConnection, PgPool, Result};
 use uuid::Uuid;
 
-use crate::{middlewares::auth::AuthenticatedDevice, models::chat::ChatView};
+use crate::{
+    middlewares::auth::AuthenticatedDevice,
+    models::{
+        chat::{ChatView, GroupMember, GroupOrigin, GroupRole},
+        federation::{S2sGroupMember, S2sGroupState},
+    },
+};
 
 pub async fn get_chats_for_device(
     pool: &PgPool,
@@ -47,6 +55,7 @@ pub async fn get_chats_for_device(
             ) AS partner_federated_address,
             c.name,
             c.last_message_id,
+            c.message_ttl_seconds,
             c.updated_at
         FROM chats c
         WHERE
@@ -63,3 +72,711 @@ pub async fn get_chats_for_device(
 
     Ok(chats)
 }
+
+/// Set or clear the message TTL of a chat `user_id` takes part in, and tell
+/// every participant with a `chat_updated` event. Returns false if the chat
+/// does not exist or the user is not in it. Messages already stored keep
+/// the expiry they were given.
+pub async fn set_chat_message_ttl(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    user_id: &Uuid,
+    message_ttl_seconds: Option<i32>,
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE chats SET message_ttl_seconds = $3, updated_at = NOW()
+        WHERE id = $1
+        AND (
+            $2 IN (user_a, user_b)
+            OR EXISTS (SELECT 1 FROM chat_members m WHERE m.chat_id = $1 AND m.user_id = $2)
+        )
+        "#,
+        chat_id,
+        user_id,
+        message_ttl_seconds
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    emit_chat_updated(&mut tx, chat_id, message_ttl_seconds).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Expiry a message stored now in `chat_id` gets from the chat's TTL, or
+/// None if the chat has none.
+pub async fn get_chat_expiry(pool: &PgPool, chat_id: &Uuid) -> Result<Option<DateTime<Utc>>> {
+    let expiry = sqlx::query_scalar!(r#"SELECT chat_expiry($1) AS "expiry""#, chat_id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(expiry)
+}
+
+/// Create a group owned by `owner_id` with `member_ids` as plain members and
+/// tell everyone in it with a `group_updated` event. Returns the chat id.
+pub async fn create_group(
+    pool: &PgPool,
+    owner_id: &Uuid,
+    name: &str,
+    member_ids: &[Uuid],
+) -> Result<Uuid> {
+    let mut tx = pool.begin().await?;
+
+    let chat_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO chats (chat_type, name, owner_id)
+        VALUES ('group', $1, $2)
+        RETURNING id
+        "#,
+        name,
+        owner_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO chat_members (chat_id, user_id, role)
+        SELECT $1::uuid, $2::uuid, 'owner'
+        UNION ALL
+        SELECT $1, u, 'member' FROM unnest($3::uuid[]) AS u WHERE u <> $2
+        "#,
+        chat_id,
+        owner_id,
+        member_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    emit_group_event(
+        &mut tx,
+        &chat_id,
+        Some(owner_id),
+        json!({"change": "created", "name": name}),
+        &[],
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(chat_id)
+}
+
+/// Role of `user_id` in group `chat_id`. None if the chat is not a group or
+/// the user is not in it.
+pub async fn get_member_role(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<GroupRole>> {
+    let role = sqlx::query_scalar!(
+        r#"
+        SELECT m.role
+        FROM chat_members m
+        JOIN chats c ON c.id = m.chat_id
+        WHERE m.chat_id = $1 AND m.user_id = $2 AND c.chat_type = 'group'
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(role.and_then(|r| GroupRole::parse(&r)))
+}
+
+pub async fn get_group_members(pool: &PgPool, chat_id: &Uuid) -> Result<Vec<GroupMember>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            m.user_id AS "user_id!",
+            u.username,
+            CASE WHEN u.home_node_id IS NOT NULL THEN u.federated_address END AS federated_address,
+            m.role,
+            m.joined_at
+        FROM chat_members m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.chat_id = $1
+        ORDER BY m.joined_at, u.username
+        "#,
+        chat_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|r| {
+            Some(GroupMember {
+                user_id: r.user_id,
+                username: r.username,
+                federated_address: r.federated_address,
+                role: GroupRole::parse(&r.role)?,
+                joined_at: r.joined_at,
+            })
+        })
+        .collect())
+}
+
+/// Add `user_ids` to the group as members. Users already in it are skipped.
+/// Resets the group's sender keys if anyone was added. Returns the users
+/// actually added.
+pub async fn add_group_members(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    actor_id: &Uuid,
+    user_ids: &[Uuid],
+) -> Result<Vec<Uuid>> {
+    let mut tx = pool.begin().await?;
+
+    let added = sqlx::query_scalar!(
+        r#"
+        INSERT INTO chat_members (chat_id, user_id, role)
+        SELECT $1, u, 'member' FROM unnest($2::uuid[]) AS u
+        ON CONFLICT (chat_id, user_id) DO NOTHING
+        RETURNING user_id AS "user_id!"
+        "#,
+        chat_id,
+        user_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if !added.is_empty() {
+        touch_chat(&mut tx, chat_id).await?;
+        reset_sender_keys(&mut tx, chat_id).await?;
+        emit_group_event(
+            &mut tx,
+            chat_id,
+            Some(actor_id),
+            json!({"change": "members_added", "user_ids": added}),
+            &[],
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(added)
+}
+
+/// Remove `user_id` from the group. `change` is "member_removed" when
+/// `actor_id` removed them or "member_left" when they left. The event also
+/// reaches the removed user. Resets the group's sender keys; a group left
+/// without members of this node is deleted. Returns false if the user was
+/// not in the group.
+pub async fn remove_group_member(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    actor_id: &Uuid,
+    user_id: &Uuid,
+    change: &str,
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    let removed = sqlx::query!(
+        "DELETE FROM chat_members WHERE chat_id = $1 AND user_id = $2",
+        chat_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if removed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    touch_chat(&mut tx, chat_id).await?;
+    reset_sender_keys(&mut tx, chat_id).await?;
+    emit_group_event(
+        &mut tx,
+        chat_id,
+        Some(actor_id),
+        json!({"change": change, "user_ids": [user_id]}),
+        &[*user_id],
+    )
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM chats
+        WHERE id = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM chat_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.chat_id = $1 AND u.home_node_id IS NULL
+        )
+        "#,
+        chat_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn rename_group(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    actor_id: &Uuid,
+    name: &str,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "UPDATE chats SET name = $2, updated_at = NOW() WHERE id = $1",
+        chat_id,
+        name
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    emit_group_event(
+        &mut tx,
+        chat_id,
+        Some(actor_id),
+        json!({"change": "renamed", "name": name}),
+        &[],
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Make `user_id` an admin or a plain member. Owners are left alone; returns
+/// false if the user is not a non-owner member of the group.
+pub async fn set_member_role(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    actor_id: &Uuid,
+    user_id: &Uuid,
+    role: GroupRole,
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE chat_members SET role = $3
+        WHERE chat_id = $1 AND user_id = $2 AND role <> 'owner'
+        "#,
+        chat_id,
+        user_id,
+        role.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    touch_chat(&mut tx, chat_id).await?;
+    emit_group_event(
+        &mut tx,
+        chat_id,
+        Some(actor_id),
+        json!({"change": "role_changed", "user_ids": [user_id], "role": role.as_str()}),
+        &[],
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Hand the group from `owner_id` to `new_owner_id`, who must already be a
+/// member. The previous owner stays on as an admin. Returns false if
+/// `owner_id` is not the owner or `new_owner_id` is not in the group.
+pub async fn transfer_group_ownership(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    owner_id: &Uuid,
+    new_owner_id: &Uuid,
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    // Demote first: uniq_chat_owner allows one owner row at a time.
+    let demoted = sqlx::query!(
+        r#"
+        UPDATE chat_members SET role = 'admin'
+        WHERE chat_id = $1 AND user_id = $2 AND role = 'owner'
+        "#,
+        chat_id,
+        owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let promoted = sqlx::query!(
+        r#"
+        UPDATE chat_members SET role = 'owner'
+        WHERE chat_id = $1 AND user_id = $2
+        "#,
+        chat_id,
+        new_owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if demoted.rows_affected() == 0 || promoted.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "UPDATE chats SET owner_id = $2, updated_at = NOW() WHERE id = $1",
+        chat_id,
+        new_owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    emit_group_event(
+        &mut tx,
+        chat_id,
+        Some(owner_id),
+        json!({"change": "owner_transferred", "user_ids": [new_owner_id]}),
+        &[],
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Where group `chat_id` is managed, or None if there is no such group.
+pub async fn get_group_origin(pool: &PgPool, chat_id: &Uuid) -> Result<Option<GroupOrigin>> {
+    let origin = sqlx::query_as!(
+        GroupOrigin,
+        r#"
+        SELECT c.home_node_id, n.node_id AS "home_node?", c.membership_version
+        FROM chats c
+        LEFT JOIN federation_nodes n ON n.id = c.home_node_id
+        WHERE c.id = $1 AND c.chat_type = 'group'
+        "#,
+        chat_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(origin)
+}
+
+/// node_id of every other node hosting a member of `chat_id`.
+pub async fn get_member_nodes(pool: &PgPool, chat_id: &Uuid) -> Result<Vec<String>> {
+    let nodes = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT n.node_id
+        FROM chat_members m
+        JOIN users u ON u.id = m.user_id
+        JOIN federation_nodes n ON n.id = u.home_node_id
+        WHERE m.chat_id = $1
+        ORDER BY n.node_id
+        "#,
+        chat_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(nodes)
+}
+
+/// Bump the membership version of a group this node is home to and return
+/// the snapshot to send to member nodes. Members are listed by federated
+/// address, local users as `username@this_node_id`. None if the group is
+/// gone or managed elsewhere.
+pub async fn snapshot_group_state(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    this_node_id: &str,
+    change: &str,
+) -> Result<Option<S2sGroupState>> {
+    let mut tx = pool.begin().await?;
+
+    let chat = sqlx::query!(
+        r#"
+        UPDATE chats SET membership_version = membership_version + 1
+        WHERE id = $1 AND chat_type = 'group' AND home_node_id IS NULL
+        RETURNING name, membership_version, message_ttl_seconds
+        "#,
+        chat_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(chat) = chat else {
+        return Ok(None);
+    };
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            CASE
+                WHEN u.home_node_id IS NULL THEN u.username || '@' || $2
+                ELSE u.federated_address
+            END AS "address!",
+            m.role
+        FROM chat_members m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.chat_id = $1
+        ORDER BY m.joined_at
+        "#,
+        chat_id,
+        this_node_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(S2sGroupState {
+        chat_id: *chat_id,
+        version: chat.membership_version,
+        name: chat.name,
+        change: change.to_string(),
+        members: rows
+            .into_iter()
+            .filter_map(|r| {
+                Some(S2sGroupMember {
+                    address: r.address,
+                    role: GroupRole::parse(&r.role)?,
+                })
+            })
+            .collect(),
+        message_ttl_seconds: chat.message_ttl_seconds,
+    }))
+}
+
+/// Replace this node's mirror of a group managed by `home_node_id` with
+/// `state`, whose addresses resolve to `members`. Creates the mirror if
+/// needed and tells local members old and new with a `group_updated` event,
+/// plus a `chat_updated` event if the message TTL changed. Sender keys are
+/// reset only if the membership changed.
+/// A mirror left without local members is deleted. Returns false, changing
+/// nothing, if the chat is not a mirror of that node or `state` is not newer.
+pub async fn apply_group_state(
+    pool: &PgPool,
+    home_node_id: &Uuid,
+    state: &S2sGroupState,
+    members: &[(Uuid, GroupRole)],
+) -> Result<bool> {
+    let user_ids: Vec<Uuid> = members.iter().map(|(id, _)| *id).collect();
+    let roles: Vec<String> = members
+        .iter()
+        .map(|(_, r)| r.as_str().to_string())
+        .collect();
+    let owner_id = members
+        .iter()
+        .find(|(_, r)| *r == GroupRole::Owner)
+        .map(|(id, _)| *id);
+
+    let mut tx = pool.begin().await?;
+
+    let previous = sqlx::query!(
+        r#"
+        SELECT c.message_ttl_seconds,
+               ARRAY(
+                   SELECT m.user_id FROM chat_members m
+                   WHERE m.chat_id = c.id ORDER BY m.user_id
+               ) AS "member_ids!: Vec<Uuid>"
+        FROM chats c WHERE c.id = $1
+        FOR UPDATE
+        "#,
+        state.chat_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let applied = sqlx::query_scalar!(
+        r#"
+        INSERT INTO chats (id, chat_type, name, owner_id, home_node_id, membership_version,
+                           message_ttl_seconds)
+        VALUES ($1, 'group', $2, $3, $4, $5, $6)
+        ON CONFLICT (id) DO UPDATE
+        SET name = EXCLUDED.name,
+            owner_id = EXCLUDED.owner_id,
+            membership_version = EXCLUDED.membership_version,
+            message_ttl_seconds = EXCLUDED.message_ttl_seconds,
+            updated_at = NOW()
+        WHERE chats.home_node_id = EXCLUDED.home_node_id
+        AND chats.membership_version < EXCLUDED.membership_version
+        RETURNING id
+        "#,
+        state.chat_id,
+        state.name,
+        owner_id,
+        home_node_id,
+        state.version,
+        state.message_ttl_seconds
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if applied.is_none() {
+        tx.rollback().await?;
+        return Ok(false);
+    }
+
+    let removed = sqlx::query_scalar!(
+        r#"
+        DELETE FROM chat_members
+        WHERE chat_id = $1 AND user_id <> ALL($2::uuid[])
+        RETURNING user_id AS "user_id!"
+        "#,
+        state.chat_id,
+        &user_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // Demote everyone first: uniq_chat_owner allows one owner row at a time.
+    sqlx::query!(
+        "UPDATE chat_members SET role = 'member' WHERE chat_id = $1",
+        state.chat_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO chat_members (chat_id, user_id, role)
+        SELECT $1, u, r FROM unnest($2::uuid[], $3::text[]) AS t(u, r)
+        ON CONFLICT (chat_id, user_id) DO UPDATE SET role = EXCLUDED.role
+        "#,
+        state.chat_id,
+        &user_ids,
+        &roles
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // A snapshot that only changed the name or TTL keeps everyone's keys.
+    let mut sorted_ids = user_ids.clone();
+    sorted_ids.sort();
+    if previous.as_ref().map(|p| &p.member_ids) != Some(&sorted_ids) {
+        reset_sender_keys(&mut tx, &state.chat_id).await?;
+    }
+    emit_group_event(
+        &mut tx,
+        &state.chat_id,
+        None,
+        json!({"change": state.change, "name": state.name}),
+        &removed,
+    )
+    .await?;
+    if previous.is_some_and(|p| p.message_ttl_seconds != state.message_ttl_seconds) {
+        emit_chat_updated(&mut tx, &state.chat_id, state.message_ttl_seconds).await?;
+    }
+
+    sqlx::query!(
+        r#"
+        DELETE FROM chats
+        WHERE id = $1
+        AND NOT EXISTS (
+            SELECT 1 FROM chat_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.chat_id = $1 AND u.home_node_id IS NULL
+        )
+        "#,
+        state.chat_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+async fn touch_chat(conn: &mut PgConnection, chat_id: &Uuid) -> Result<()> {
+    sqlx::query!("UPDATE chats SET updated_at = NOW() WHERE id = $1", chat_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Drop every member's sender key after a membership change. Each member
+/// must distribute a new key (POST /chats/:id/sender-keys) before its next
+/// group send, so removed members cannot read later messages and new members
+/// get a key from everyone.
+async fn reset_sender_keys(conn: &mut PgConnection, chat_id: &Uuid) -> Result<()> {
+    sqlx::query!("DELETE FROM sender_keys WHERE chat_id = $1", chat_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Send a `chat_updated` event with the chat's new message TTL to everyone
+/// in it.
+async fn emit_chat_updated(
+    conn: &mut PgConnection,
+    chat_id: &Uuid,
+    message_ttl_seconds: Option<i32>,
+) -> Result<()> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_user_event(
+            p.user_id,
+            'messages_channel',
+            jsonb_build_object(
+                'type', 'chat_updated',
+                'chat_id', $1::uuid,
+                'message_ttl_seconds', $2::int
+            )
+        )
+        FROM (
+            SELECT user_a AS user_id FROM chats WHERE id = $1
+            UNION SELECT user_b FROM chats WHERE id = $1
+            UNION SELECT user_id FROM chat_members WHERE chat_id = $1
+        ) p
+        WHERE p.user_id IS NOT NULL
+        "#,
+        chat_id,
+        message_ttl_seconds
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Send a `group_updated` event to every member's devices, plus `also_notify`
+/// (members who just left). `details` carries the change-specific fields.
+/// `actor_id` is None for changes made on the group's home node.
+async fn emit_group_event(
+    conn: &mut PgConnection,
+    chat_id: &Uuid,
+    actor_id: Option<&Uuid>,
+    details: Value,
+    also_notify: &[Uuid],
+) -> Result<()> {
+    let mut payload = json!({
+        "type": "group_updated",
+        "chat_id": chat_id,
+        "actor_user_id": actor_id,
+    });
+    if let (Some(payload), Value::Object(details)) = (payload.as_object_mut(), details) {
+        payload.extend(details);
+    }
+
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_user_event(p.user_id, 'messages_channel', $2::jsonb)
+        FROM (
+            SELECT user_id FROM chat_members WHERE chat_id = $1
+            UNION SELECT unnest($3::uuid[])
+        ) p
+        WHERE p.user_id IS NOT NULL
+        "#,
+        chat_id,
+        payload,
+        also_notify
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+use crate::models::device_link::DeviceLinkRequest;
+
+/// File a link request for `user_id`, unless the user already has
+/// `max_pending` unexpired pending requests (returns None).
+///
+/// The user row is locked so concurrent requests cannot overshoot the cap,
+/// and expired requests of that user are dropped first. A `link_request`
+/// event goes out on devices_channel so enrolled devices can prompt for
+/// approval. Fails with `RowNotFound` if the user does not exist.
+pub async fn create_link_request(
+    pool: &PgPool,
+    user_id: &Uuid,
+    identity_pubkey: &str,
+    device_label: Option<&str>,
+    max_pending: i64,
+) -> Result<Option<DeviceLinkRequest>> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query_scalar!(
+        "SELECT id FROM users WHERE id = $1 AND home_node_id IS NULL FOR UPDATE",
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM device_link_requests WHERE user_id = $1 AND expires_at <= NOW()",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let pending = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM device_link_requests WHERE user_id = $1 AND status = 'pending'"#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if pending >= max_pending {
+        return Ok(None);
+    }
+
+    let request = sqlx::query_as!(
+        DeviceLinkRequest,
+        r#"
+        INSERT INTO device_link_requests (user_id, identity_pubkey, device_label)
+        VALUES ($1, $2, $3)
+        RETURNING id, user_id, identity_pubkey, device_label, status, approved_by,
+                  created_at, expires_at
+        "#,
+        user_id,
+        identity_pubkey,
+        device_label
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_user_event(
+            $1,
+            'devices_channel',
+            jsonb_build_object(
+                'type', 'link_request',
+                'link_request_id', $2::uuid,
+                'identity_pubkey', $3::text,
+                'device_label', $4::text
+            )
+        )
+        "#,
+        user_id,
+        request.id,
+        identity_pubkey,
+        device_label
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(request))
+}
+
+pub async fn get_link_request(
+    pool: &PgPool,
+    request_id: &Uuid,
+) -> Result<Option<DeviceLinkRequest>> {
+    let request = sqlx::query_as!(
+        DeviceLinkRequest,
+        r#"
+        SELECT id, user_id, identity_pubkey, device_label, status, approved_by,
+               created_at, expires_at
+        FROM device_link_requests
+        WHERE id = $1
+        "#,
+        request_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(request)
+}
+
+/// Unexpired requests still waiting for approval.
+pub async fn get_pending_link_requests(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Vec<DeviceLinkRequest>> {
+    let requests = sqlx::query_as!(
+        DeviceLinkRequest,
+        r#"
+        SELECT id, user_id, identity_pubkey, device_label, status, approved_by,
+               created_at, expires_at
+        FROM device_link_requests
+        WHERE user_id = $1 AND status = 'pending' AND expires_at > NOW()
+        ORDER BY created_at ASC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(requests)
+}
+
+/// Mark a pending, unexpired request of `user_id` approved and attach the
+/// enrollment token. Returns false if the request is gone, expired, already
+/// approved or belongs to someone else.
+pub async fn approve_link_request(
+    pool: &PgPool,
+    request_id: &Uuid,
+    user_id: &Uuid,
+    approved_by: &Uuid,
+    enrollment_token: &str,
+) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE device_link_requests
+        SET status = 'approved', approved_by = $3, enrollment_token = $4
+        WHERE id = $1 AND user_id = $2 AND status = 'pending' AND expires_at > NOW()
+        "#,
+        request_id,
+        user_id,
+        approved_by,
+        enrollment_token
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Enrollment token of an approved request, for the requesting device to pick up.
+pub async fn get_link_enrollment_token(pool: &PgPool, request_id: &Uuid) -> Result<Option<String>> {
+    let token = sqlx::query_scalar!(
+        "SELECT enrollment_token FROM device_link_requests WHERE id = $1 AND status = 'approved'",
+        request_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(token.flatten())
+}
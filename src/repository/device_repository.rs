@@ -1,29 +1,53 @@
-use std::borrow::Cow;
-
 use crate::models::{
-    device::{DeviceBundle, Devices},
+    device::{DeviceBundle, Devices, KemPrekey, SignedPreKey},
     user::User,
 };
 use sqlx::{PgPool, Result};
 use uuid::Uuid;
 
+/// Spend `enrollment_token` and insert a device together with its initial
+/// batch of one-time prekeys and, for PQXDH-capable clients, its signed
+/// ML-KEM prekeys.
+///
+/// The token is recorded in used_tokens in the same transaction, so it is
+/// spent if and only if the device is created. Returns None if the token was
+/// already spent, which also keeps two concurrent enrollments from both
+/// succeeding.
+///
+/// OTPKs live in the one_time_prekeys table; the legacy devices.one_time_prekeys
+/// column is left empty.
 #[allow(clippy::too_many_arguments)]
 pub async fn create_device(
     pool: &PgPool,
+    enrollment_token: &str,
     user_id: &Uuid,
     identity_pubkey: &str,
     prekey_pubkey: &str,
     signed_prekey_pub: &str,
     signed_prekey_sig: &str,
-    one_time_prekeys: &serde_json::Value,
+    one_time_prekeys: &[String],
+    kem_one_time_prekeys: &[SignedPreKey],
+    kem_last_resort_prekey: Option<&SignedPreKey>,
     device_label: &str,
     push_token: &str,
-) -> Result<Devices> {
+) -> Result<Option<Devices>> {
+    let mut tx = pool.begin().await?;
+
+    let spent = sqlx::query!(
+        "INSERT INTO used_tokens (token) VALUES ($1) ON CONFLICT (token) DO NOTHING",
+        enrollment_token
+    )
+    .execute(&mut *tx)
+    .await?;
+    if spent.rows_affected() == 0 {
+        return Ok(None);
+    }
+
     let device = sqlx::query_as!(
         Devices,
         r#"
         INSERT INTO devices (user_id, identity_pubkey, prekey_pubkey, signed_prekey_pub, signed_prekey_sig, one_time_prekeys, device_label, push_token)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        VALUES ($1, $2, $3, $4, $5, '[]'::jsonb, $6, $7)
         RETURNING id, user_id, identity_pubkey, prekey_pubkey, signed_prekey_pub, signed_prekey_sig, one_time_prekeys, device_label, push_token, last_seen, created_at
         "#,
         user_id,
@@ -31,14 +55,35 @@ pub async fn create_device(
         prekey_pubkey,
         signed_prekey_pub,
         signed_prekey_sig,
-        one_time_prekeys,
         device_label,
         push_token
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    Ok(device)
+    sqlx::query!(
+        r#"
+        INSERT INTO one_time_prekeys (device_id, pubkey)
+        SELECT $1, UNNEST($2::text[])
+        ON CONFLICT DO NOTHING
+        "#,
+        device.id,
+        one_time_prekeys
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    insert_kem_prekeys(
+        &mut tx,
+        &device.id,
+        kem_one_time_prekeys,
+        kem_last_resort_prekey,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(device))
 }
 
 pub async fn get_devices_by_user_id(
@@ -72,60 +117,136 @@ pub async fn get_devices_by_user_id(
     Ok(devices)
 }
 
+/// Ids of all devices of a user, used to validate message fan-out.
+pub async fn get_device_ids_by_user_id(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!("SELECT id FROM devices WHERE user_id = $1", user_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// The local device holding identity key `id_key`, used to authenticate
+/// device requests.
+///
+/// Shadow devices of remote users are never returned: a peer can create one
+/// with any public key, and it must neither authenticate here nor shadow the
+/// local device with the same key. If the key matches more than one local
+/// device, none is returned rather than guessing.
 pub async fn get_device_by_identity_key(
     pool: &PgPool,
     id_key: &str,
 ) -> Result<Devices, sqlx::Error> {
-    let devices = sqlx::query_as!(
+    let mut devices = sqlx::query_as!(
         Devices,
         r#"
         SELECT
-            id,
-            user_id,
-            identity_pubkey,
-            prekey_pubkey,
-            signed_prekey_pub,
-            signed_prekey_sig,
-            one_time_prekeys,
-            device_label,
-            push_token,
-            last_seen,
-            created_at
-        FROM devices
-        WHERE identity_pubkey = $1
-        ORDER BY created_at DESC
+            d.id,
+            d.user_id,
+            d.identity_pubkey,
+            d.prekey_pubkey,
+            d.signed_prekey_pub,
+            d.signed_prekey_sig,
+            d.one_time_prekeys,
+            d.device_label,
+            d.push_token,
+            d.last_seen,
+            d.created_at
+        FROM devices d
+        JOIN users u ON u.id = d.user_id
+        WHERE d.identity_pubkey = $1 AND u.home_node_id IS NULL
+        LIMIT 2
         "#,
         id_key
     )
-    .fetch_one(pool)
+    .fetch_all(pool)
     .await?;
 
-    Ok(devices)
+    match (devices.pop(), devices.is_empty()) {
+        (Some(device), true) => Ok(device),
+        _ => Err(sqlx::Error::RowNotFound),
+    }
 }
 
-pub async fn get_device_bundle(
+/// Build one X3DH bundle per device of `user_id`, consuming one OTPK each.
+///
+/// Every call removes the oldest remaining one-time prekey of each device in
+/// the same transaction that reads it, leaving a hash of it in
+/// consumed_one_time_prekeys so it can never be uploaded again. `FOR UPDATE SKIP LOCKED` makes
+/// concurrent initiators pick different keys instead of waiting on (or
+/// sharing) the same row. A device with no OTPK left gets a bundle without
+/// one and the initiator falls back to the signed prekey only.
+///
+/// The same applies to ML-KEM prekeys: one one-time KEM prekey is consumed
+/// per device, falling back to the device's reusable last-resort KEM prekey.
+/// Devices that never uploaded KEM material get `kem_prekey: None` and the
+/// initiator uses plain X3DH.
+///
+/// When a consumed key leaves a device with fewer than `low_threshold` keys of
+/// that kind, a `prekeys_low` notification is queued on prekeys_channel;
+/// Postgres only delivers it if the transaction commits.
+pub async fn claim_device_bundle(
     pool: &PgPool,
     user_id: &Uuid,
+    low_threshold: i64,
 ) -> Result<Vec<DeviceBundle>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
     let rows = sqlx::query!(
         r#"
-        SELECT id, identity_pubkey, prekey_pubkey, signed_prekey_pub, signed_prekey_sig, one_time_prekeys
+        SELECT id, identity_pubkey, prekey_pubkey, signed_prekey_pub, signed_prekey_sig
         FROM devices
         WHERE user_id = $1
         "#,
         user_id
     )
-    .fetch_all(pool)
+    .fetch_all(&mut *tx)
     .await?;
 
     let mut bundles = Vec::new();
     for row in rows {
-        let otpks: Vec<String> = serde_json::from_value(row.one_time_prekeys).map_err(|e| {
-            sqlx::Error::ColumnDecode {
-                index: Cow::from("one_time_prekeys").to_string(),
-                source: Box::new(e), // serde_json::Error impl Error + Send + Sync
+        let otpk = sqlx::query_scalar!(
+            r#"
+            WITH claimed AS (
+                DELETE FROM one_time_prekeys
+                WHERE id = (
+                    SELECT id FROM one_time_prekeys
+                    WHERE device_id = $1
+                    ORDER BY id
+                    LIMIT 1
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING device_id, pubkey
+            ), tombstone AS (
+                INSERT INTO consumed_one_time_prekeys (device_id, key_hash)
+                SELECT device_id, sha256(convert_to(pubkey, 'UTF8')) FROM claimed
+                ON CONFLICT DO NOTHING
+            )
+            SELECT pubkey AS "pubkey!" FROM claimed
+            "#,
+            row.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if otpk.is_some() {
+            let remaining = count_one_time_prekeys(&mut tx, &row.id).await?;
+            if remaining < low_threshold {
+                notify_prekeys_low(&mut tx, &row.id, "ec", remaining).await?;
+            }
+        }
+
+        let kem_prekey = match claim_kem_one_time_prekey(&mut tx, &row.id).await? {
+            Some(k) => {
+                let remaining = count_kem_one_time_prekeys(&mut tx, &row.id).await?;
+                if remaining < low_threshold {
+                    notify_prekeys_low(&mut tx, &row.id, "kem", remaining).await?;
+                }
+                Some(k)
             }
-        })?;
+            None => get_kem_last_resort_prekey(&mut tx, &row.id).await?,
+        };
 
         bundles.push(DeviceBundle {
             device_id: row.id,
@@ -133,10 +254,13 @@ pub async fn get_device_bundle(
             prekey_pubkey: row.prekey_pubkey,
             signed_prekey_pub: row.signed_prekey_pub,
             signed_prekey_sig: row.signed_prekey_sig,
-            one_time_prekeys: otpks,
+            one_time_prekey: otpk,
+            kem_prekey,
         });
     }
 
+    tx.commit().await?;
+
     Ok(bundles)
 }
 
@@ -172,3 +296,501 @@ pub async fn get_user_for_device(
 
     Ok(user_data)
 }
+
+/// Returns true if the nonce was fresh for this device, false on replay.
+pub async fn claim_device_nonce(
+    pool: &PgPool,
+    device_id: &Uuid,
+    nonce: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO used_device_nonces (nonce, device_id)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        nonce,
+        device_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn purge_expired_device_nonces(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query!("DELETE FROM used_device_nonces WHERE used_at < NOW() - INTERVAL '5 minutes'")
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Outcome of `add_prekeys`.
+pub enum PrekeyUpload {
+    /// Number of (X25519, ML-KEM) one-time keys stored afterwards.
+    Stored(i64, i64),
+    /// The batch would exceed a cap; nothing was written.
+    CapExceeded,
+    /// The batch timestamp is not newer than the device's last batch.
+    Replayed,
+}
+
+/// Append uploaded prekeys to a device, enforcing `max_keys` separately on
+/// X25519 one-time prekeys and on ML-KEM one-time prekeys. A new KEM
+/// last-resort key replaces the previous one, which is retired rather than
+/// deleted so in-flight session inits naming it still validate.
+///
+/// `batch_timestamp` is the signed timestamp of the X25519 batch, if any. It
+/// must be greater than that of the device's previous batch and is recorded on
+/// success. Keys already present, or consumed earlier (see
+/// consumed_one_time_prekeys), are ignored.
+///
+/// The device row is locked for the duration of the transaction so that two
+/// concurrent uploads cannot both pass the cap or timestamp check.
+pub async fn add_prekeys(
+    pool: &PgPool,
+    device_id: &Uuid,
+    one_time_prekeys: &[String],
+    batch_timestamp: Option<i64>,
+    kem_one_time_prekeys: &[SignedPreKey],
+    kem_last_resort_prekey: Option<&SignedPreKey>,
+    max_keys: i64,
+) -> Result<PrekeyUpload, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let last_batch_ts = sqlx::query_scalar!(
+        "SELECT last_prekey_batch_ts FROM devices WHERE id = $1 FOR UPDATE",
+        device_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if batch_timestamp.is_some_and(|ts| ts <= last_batch_ts) {
+        return Ok(PrekeyUpload::Replayed);
+    }
+
+    let current = count_one_time_prekeys(&mut tx, device_id).await?;
+    let current_kem = count_kem_one_time_prekeys(&mut tx, device_id).await?;
+    if current + one_time_prekeys.len() as i64 > max_keys
+        || current_kem + kem_one_time_prekeys.len() as i64 > max_keys
+    {
+        return Ok(PrekeyUpload::CapExceeded);
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO one_time_prekeys (device_id, pubkey)
+        SELECT $1, k.pubkey
+        FROM UNNEST($2::text[]) AS k(pubkey)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM consumed_one_time_prekeys c
+            WHERE c.device_id = $1 AND c.key_hash = sha256(convert_to(k.pubkey, 'UTF8'))
+        )
+        ON CONFLICT DO NOTHING
+        "#,
+        device_id,
+        one_time_prekeys
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(ts) = batch_timestamp {
+        sqlx::query!(
+            "UPDATE devices SET last_prekey_batch_ts = $2 WHERE id = $1",
+            device_id,
+            ts
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    insert_kem_prekeys(
+        &mut tx,
+        device_id,
+        kem_one_time_prekeys,
+        kem_last_resort_prekey,
+    )
+    .await?;
+
+    let stored = count_one_time_prekeys(&mut tx, device_id).await?;
+    let stored_kem = count_kem_one_time_prekeys(&mut tx, device_id).await?;
+    tx.commit().await?;
+
+    Ok(PrekeyUpload::Stored(stored, stored_kem))
+}
+
+async fn count_one_time_prekeys(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+) -> Result<i64, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM one_time_prekeys WHERE device_id = $1"#,
+        device_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(count)
+}
+
+async fn notify_prekeys_low(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+    kind: &str,
+    remaining: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_device_event(
+            $1,
+            'prekeys_channel',
+            jsonb_build_object(
+                'type', 'prekeys_low',
+                'device_id', $1::uuid,
+                'kind', $2::text,
+                'remaining', $3::bigint
+            )
+        )
+        "#,
+        device_id,
+        kind,
+        remaining
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Replace a device's signed prekey, keeping the previous one valid for
+/// `grace_hours` in signed_prekey_history.
+pub async fn rotate_signed_prekey(
+    pool: &PgPool,
+    device_id: &Uuid,
+    signed_prekey_pub: &str,
+    signed_prekey_sig: &str,
+    grace_hours: i32,
+) -> Result<Devices, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO signed_prekey_history (device_id, pubkey, signature, expires_at)
+        SELECT id, signed_prekey_pub, signed_prekey_sig, NOW() + make_interval(hours => $2::int)
+        FROM devices
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        device_id,
+        grace_hours
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let device = sqlx::query_as!(
+        Devices,
+        r#"
+        UPDATE devices
+        SET signed_prekey_pub = $2, signed_prekey_sig = $3
+        WHERE id = $1
+        RETURNING id, user_id, identity_pubkey, prekey_pubkey, signed_prekey_pub, signed_prekey_sig, one_time_prekeys, device_label, push_token, last_seen, created_at
+        "#,
+        device_id,
+        signed_prekey_pub,
+        signed_prekey_sig
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(device)
+}
+
+/// True if `spk_pub` is the device's current signed prekey or a retired one
+/// still inside its grace window.
+pub async fn is_signed_prekey_valid(
+    pool: &PgPool,
+    device_id: &Uuid,
+    spk_pub: &str,
+) -> Result<bool, sqlx::Error> {
+    let valid = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM devices WHERE id = $1 AND signed_prekey_pub = $2
+        ) OR EXISTS (
+            SELECT 1 FROM signed_prekey_history
+            WHERE device_id = $1 AND pubkey = $2 AND expires_at > NOW()
+        ) AS "valid!"
+        "#,
+        device_id,
+        spk_pub
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(valid)
+}
+
+/// True if `kem_prekey_id` is a KEM prekey of `device_id` that is still
+/// handed out, or was retired (claimed or replaced) less than `grace_hours`
+/// ago.
+pub async fn is_kem_prekey_valid(
+    pool: &PgPool,
+    device_id: &Uuid,
+    kem_prekey_id: i64,
+    grace_hours: i32,
+) -> Result<bool, sqlx::Error> {
+    let valid = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM kem_prekeys
+            WHERE id = $2 AND device_id = $1
+            AND (retired_at IS NULL OR retired_at > NOW() - make_interval(hours => $3::int))
+        ) AS "valid!"
+        "#,
+        device_id,
+        kem_prekey_id,
+        grace_hours
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(valid)
+}
+
+/// Delete KEM prekeys retired more than `grace_hours` ago.
+pub async fn purge_retired_kem_prekeys(
+    pool: &PgPool,
+    grace_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM kem_prekeys WHERE retired_at <= NOW() - make_interval(hours => $1::int)",
+        grace_hours
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn purge_expired_signed_prekeys(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM signed_prekey_history WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn insert_kem_prekeys(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+    one_time: &[SignedPreKey],
+    last_resort: Option<&SignedPreKey>,
+) -> Result<(), sqlx::Error> {
+    let keys: Vec<String> = one_time.iter().map(|k| k.key.clone()).collect();
+    let sigs: Vec<String> = one_time.iter().map(|k| k.signature.clone()).collect();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO kem_prekeys (device_id, pubkey, signature, is_last_resort)
+        SELECT $1, t.pubkey, t.signature, FALSE
+        FROM UNNEST($2::text[], $3::text[]) AS t(pubkey, signature)
+        ON CONFLICT DO NOTHING
+        "#,
+        device_id,
+        &keys,
+        &sigs
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // The previous last-resort key is retired, not deleted: initiators that
+    // fetched it shortly before may still name it in a session init.
+    if let Some(lr) = last_resort {
+        sqlx::query!(
+            r#"
+            UPDATE kem_prekeys SET retired_at = NOW()
+            WHERE device_id = $1 AND is_last_resort AND retired_at IS NULL
+            "#,
+            device_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO kem_prekeys (device_id, pubkey, signature, is_last_resort)
+            VALUES ($1, $2, $3, TRUE)
+            ON CONFLICT (device_id, pubkey) DO UPDATE
+            SET signature = EXCLUDED.signature, retired_at = NULL
+            WHERE kem_prekeys.is_last_resort
+            "#,
+            device_id,
+            lr.key,
+            lr.signature
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn claim_kem_one_time_prekey(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+) -> Result<Option<KemPrekey>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        UPDATE kem_prekeys SET retired_at = NOW()
+        WHERE id = (
+            SELECT id FROM kem_prekeys
+            WHERE device_id = $1 AND NOT is_last_resort AND retired_at IS NULL
+            ORDER BY id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, pubkey, signature
+        "#,
+        device_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(|r| KemPrekey {
+        id: r.id,
+        key: r.pubkey,
+        signature: r.signature,
+        last_resort: false,
+    }))
+}
+
+async fn get_kem_last_resort_prekey(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+) -> Result<Option<KemPrekey>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT id, pubkey, signature
+        FROM kem_prekeys
+        WHERE device_id = $1 AND is_last_resort AND retired_at IS NULL
+        "#,
+        device_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(|r| KemPrekey {
+        id: r.id,
+        key: r.pubkey,
+        signature: r.signature,
+        last_resort: true,
+    }))
+}
+
+async fn count_kem_one_time_prekeys(
+    conn: &mut sqlx::PgConnection,
+    device_id: &Uuid,
+) -> Result<i64, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM kem_prekeys
+        WHERE device_id = $1 AND NOT is_last_resort AND retired_at IS NULL
+        "#,
+        device_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(count)
+}
+
+/// Revoke a device of `user_id` and everything queued for or by it.
+///
+/// Prekeys, nonces, pending sessions, sessions and messages addressed to the
+/// device are removed by the ON DELETE CASCADE constraints; messages it sent
+/// that nobody fetched yet and its queued federated forwards are deleted here.
+/// Messages it already delivered keep their row with from_device_id = NULL.
+///
+/// One `device_removed` outbox entry carrying `removal_payload` is queued for
+/// every peer node that holds a shadow copy of the device, and a
+/// `device_removed` event is sent on devices_channel; both only take effect
+/// if the transaction commits. Returns the notified peers, or None if the
+/// device does not exist or belongs to another user.
+pub async fn delete_device(
+    pool: &PgPool,
+    device_id: &Uuid,
+    user_id: &Uuid,
+    removal_payload: &serde_json::Value,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let owned = sqlx::query_scalar!(
+        "SELECT id FROM devices WHERE id = $1 AND user_id = $2 FOR UPDATE",
+        device_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if owned.is_none() {
+        return Ok(None);
+    }
+
+    let peers = sqlx::query_scalar!(
+        r#"
+        INSERT INTO federation_outbox (target_node_id, logical_msg_id, kind, payload)
+        SELECT node_id, 'device-removed:' || $1::uuid, 'device_removed', $2
+        FROM device_federation_peers
+        WHERE device_id = $1
+        RETURNING target_node_id
+        "#,
+        device_id,
+        removal_payload
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM messages WHERE from_device_id = $1 AND delivered_at IS NULL",
+        device_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM federation_outbox
+        WHERE status = 'pending'
+          AND kind = 'messages'
+          AND payload->>'from_device_id' = $1::uuid::text
+        "#,
+        device_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Emitted before the delete so the revoked device's own socket is told too.
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_user_event(
+            $1,
+            'devices_channel',
+            jsonb_build_object('type', 'device_removed', 'device_id', $2::uuid)
+        )
+        "#,
+        user_id,
+        device_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM devices WHERE id = $1", device_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(peers))
+}
@@ -1,13 +1,19 @@
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
 use uuid::Uuid;
 
-use crate::models::federation::{FederationNode, FederationOutboxEntry};
+use crate::models::{
+    federation::{FederationNode, FederationOutboxEntry, FederationPeer, OutboxEntryView},
+    pagination::PageCursor,
+};
 
 // Non-macro sqlx throughout: avoids compile-time DATABASE_URL requirement and
 // the need to run `cargo sqlx prepare` every time a query changes.
 
 // ─── federation_nodes ────────────────────────────────────────────────────────
 
+/// Cache a peer looked up at the registry. An existing row keeps its key:
+/// keys only change through `rotate_peer_key`.
 pub async fn upsert_federation_node(
     pool: &PgPool,
     node_id: &str,
@@ -19,10 +25,11 @@ pub async fn upsert_federation_node(
         INSERT INTO federation_nodes (node_id, api_url, public_key_b64)
         VALUES ($1, $2, $3)
         ON CONFLICT (node_id) DO UPDATE
-          SET api_url        = EXCLUDED.api_url,
-              public_key_b64 = EXCLUDED.public_key_b64,
-              last_seen      = NOW()
-        RETURNING id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at
+          SET api_url   = EXCLUDED.api_url,
+              last_seen = NOW()
+        RETURNING id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at,
+                  protocol_version, protocol_checked_at,
+                  previous_public_key_b64, previous_key_expires_at, key_checked_at
         "#,
     )
     .bind(node_id)
@@ -37,7 +44,9 @@ pub async fn get_federation_node(
     node_id: &str,
 ) -> Result<Option<FederationNode>, sqlx::Error> {
     sqlx::query_as::<_, FederationNode>(
-        "SELECT id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at
+        "SELECT id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at,
+                protocol_version, protocol_checked_at,
+                previous_public_key_b64, previous_key_expires_at, key_checked_at
          FROM federation_nodes WHERE node_id = $1",
     )
     .bind(node_id)
@@ -45,6 +54,190 @@ pub async fn get_federation_node(
     .await
 }
 
+/// All known peers with how much this node still holds for them, optionally
+/// only blocked or only unblocked ones.
+pub async fn list_federation_peers(
+    pool: &PgPool,
+    blocked: Option<bool>,
+) -> Result<Vec<FederationPeer>, sqlx::Error> {
+    sqlx::query_as::<_, FederationPeer>(
+        "SELECT n.id, n.node_id, n.api_url, n.public_key_b64, n.last_seen, n.is_blocked,
+                n.created_at, n.protocol_version, n.protocol_checked_at,
+                n.previous_public_key_b64, n.previous_key_expires_at, n.key_checked_at,
+                (SELECT COUNT(*) FROM users u WHERE u.home_node_id = n.id) AS shadow_users,
+                (SELECT COUNT(*) FROM federation_outbox o
+                 WHERE o.target_node_id = n.node_id AND o.status = 'pending') AS pending_outbox
+         FROM federation_nodes n
+         WHERE $1::boolean IS NULL OR n.is_blocked = $1
+         ORDER BY n.node_id",
+    )
+    .bind(blocked)
+    .fetch_all(pool)
+    .await
+}
+
+/// One peer with the same counts as `list_federation_peers`.
+pub async fn get_federation_peer(
+    pool: &PgPool,
+    node_id: &str,
+) -> Result<Option<FederationPeer>, sqlx::Error> {
+    sqlx::query_as::<_, FederationPeer>(
+        "SELECT n.id, n.node_id, n.api_url, n.public_key_b64, n.last_seen, n.is_blocked,
+                n.created_at, n.protocol_version, n.protocol_checked_at,
+                n.previous_public_key_b64, n.previous_key_expires_at, n.key_checked_at,
+                (SELECT COUNT(*) FROM users u WHERE u.home_node_id = n.id) AS shadow_users,
+                (SELECT COUNT(*) FROM federation_outbox o
+                 WHERE o.target_node_id = n.node_id AND o.status = 'pending') AS pending_outbox
+         FROM federation_nodes n
+         WHERE n.node_id = $1",
+    )
+    .bind(node_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Block or unblock a peer. Returns None if the node is unknown.
+pub async fn set_node_blocked(
+    pool: &PgPool,
+    node_id: &str,
+    blocked: bool,
+) -> Result<Option<FederationNode>, sqlx::Error> {
+    sqlx::query_as::<_, FederationNode>(
+        "UPDATE federation_nodes SET is_blocked = $2 WHERE node_id = $1
+         RETURNING id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at,
+                   protocol_version, protocol_checked_at,
+                   previous_public_key_b64, previous_key_expires_at, key_checked_at",
+    )
+    .bind(node_id)
+    .bind(blocked)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Overwrite a peer's api_url and key with what the registry lists, on an
+/// operator's say-so. Unlike `rotate_peer_key` no previous key stays valid,
+/// and the peer's protocol is probed again on the next request.
+pub async fn replace_peer_identity(
+    pool: &PgPool,
+    node_id: &str,
+    api_url: &str,
+    public_key_b64: &str,
+) -> Result<Option<FederationNode>, sqlx::Error> {
+    sqlx::query_as::<_, FederationNode>(
+        "UPDATE federation_nodes
+         SET api_url                 = $2,
+             public_key_b64          = $3,
+             previous_public_key_b64 = NULL,
+             previous_key_expires_at = NULL,
+             key_checked_at          = NOW(),
+             protocol_checked_at     = NULL
+         WHERE node_id = $1
+         RETURNING id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at,
+                   protocol_version, protocol_checked_at,
+                   previous_public_key_b64, previous_key_expires_at, key_checked_at",
+    )
+    .bind(node_id)
+    .bind(api_url)
+    .bind(public_key_b64)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Delete a peer nothing refers to anymore: no shadow users (whose
+/// home_node_id would otherwise be cleared, making them look local) and no
+/// pending outbox entries. Returns false if the node is unknown or still in
+/// use; the caller tells those apart with `get_federation_peer`.
+pub async fn delete_stale_node(pool: &PgPool, node_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM federation_nodes n
+         WHERE n.node_id = $1
+           AND NOT EXISTS (SELECT 1 FROM users u WHERE u.home_node_id = n.id)
+           AND NOT EXISTS (SELECT 1 FROM federation_outbox o
+                           WHERE o.target_node_id = n.node_id AND o.status = 'pending')",
+    )
+    .bind(node_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Claim a key re-fetch for `node_id`. Returns false if the key was already
+/// looked up within the last `min_interval_secs` seconds.
+pub async fn claim_key_check(
+    pool: &PgPool,
+    node_id: &str,
+    min_interval_secs: i64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE federation_nodes SET key_checked_at = NOW()
+         WHERE node_id = $1
+           AND (key_checked_at IS NULL
+                OR key_checked_at < NOW() - make_interval(secs => $2))",
+    )
+    .bind(node_id)
+    .bind(min_interval_secs as f64)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Replace a peer's key, keeping the old one as its previous key until
+/// `previous_expires_at`. Only applies while the stored key is still
+/// `current_key_b64`, so concurrent re-fetches cannot interleave.
+pub async fn rotate_peer_key(
+    pool: &PgPool,
+    node_id: &str,
+    current_key_b64: &str,
+    new_key_b64: &str,
+    previous_expires_at: DateTime<Utc>,
+) -> Result<Option<FederationNode>, sqlx::Error> {
+    sqlx::query_as::<_, FederationNode>(
+        "UPDATE federation_nodes
+         SET public_key_b64          = $3,
+             previous_public_key_b64 = public_key_b64,
+             previous_key_expires_at = $4,
+             last_seen               = NOW()
+         WHERE node_id = $1 AND public_key_b64 = $2
+         RETURNING id, node_id, api_url, public_key_b64, last_seen, is_blocked, created_at,
+                   protocol_version, protocol_checked_at,
+                   previous_public_key_b64, previous_key_expires_at, key_checked_at",
+    )
+    .bind(node_id)
+    .bind(current_key_b64)
+    .bind(new_key_b64)
+    .bind(previous_expires_at)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Record that `node_id` speaks protocol `version`. The stored version is
+/// only ever raised, so a peer cannot be moved back to an older protocol.
+/// `probed` also stamps protocol_checked_at. `version` must parse as a
+/// dotted version.
+pub async fn record_protocol_version(
+    pool: &PgPool,
+    node_id: &str,
+    version: &str,
+    probed: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE federation_nodes
+         SET protocol_version = CASE
+                 WHEN protocol_version IS NULL
+                   OR string_to_array(protocol_version, '.')::int[]
+                      < string_to_array($2, '.')::int[]
+                 THEN $2 ELSE protocol_version END,
+             protocol_checked_at = CASE WHEN $3 THEN NOW() ELSE protocol_checked_at END
+         WHERE node_id = $1",
+    )
+    .bind(node_id)
+    .bind(version)
+    .bind(probed)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 // ─── used_node_nonces ────────────────────────────────────────────────────────
 
 /// Returns true if the nonce was fresh (not seen before), false on replay.
@@ -73,14 +266,16 @@ pub async fn enqueue_outbox(
     pool: &PgPool,
     target_node_id: &str,
     logical_msg_id: &str,
+    kind: &str,
     payload: &serde_json::Value,
 ) -> Result<Uuid, sqlx::Error> {
     let row: (Uuid,) = sqlx::query_as(
-        "INSERT INTO federation_outbox (target_node_id, logical_msg_id, payload)
-         VALUES ($1, $2, $3) RETURNING id",
+        "INSERT INTO federation_outbox (target_node_id, logical_msg_id, kind, payload)
+         VALUES ($1, $2, $3, $4) RETURNING id",
     )
     .bind(target_node_id)
     .bind(logical_msg_id)
+    .bind(kind)
     .bind(payload)
     .fetch_one(pool)
     .await?;
@@ -91,11 +286,12 @@ pub async fn fetch_due_outbox_entries(
     pool: &PgPool,
 ) -> Result<Vec<FederationOutboxEntry>, sqlx::Error> {
     sqlx::query_as::<_, FederationOutboxEntry>(
-        "SELECT id, target_node_id, logical_msg_id, payload,
+        "SELECT id, target_node_id, logical_msg_id, kind, payload,
                 attempt_count, last_attempt, next_attempt, status, created_at
          FROM federation_outbox
          WHERE status = 'pending' AND next_attempt <= NOW()
-         ORDER BY next_attempt ASC LIMIT 100",
+           AND target_node_id NOT IN (SELECT node_id FROM federation_nodes WHERE is_blocked)
+         ORDER BY next_attempt ASC LIMIT 500",
     )
     .fetch_all(pool)
     .await
@@ -111,6 +307,26 @@ pub async fn mark_outbox_delivered(pool: &PgPool, id: Uuid) -> Result<(), sqlx::
     Ok(())
 }
 
+/// Push the pending entries for `node_id` back to at least `secs` seconds
+/// from now without counting an attempt (the peer's circuit breaker is open).
+pub async fn defer_outbox_for_node(
+    pool: &PgPool,
+    node_id: &str,
+    secs: i64,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE federation_outbox
+         SET next_attempt = NOW() + make_interval(secs => $2)
+         WHERE target_node_id = $1 AND status = 'pending'
+           AND next_attempt < NOW() + make_interval(secs => $2)",
+    )
+    .bind(node_id)
+    .bind(secs as f64)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
 pub async fn mark_outbox_delivered_by_logical_id(
     pool: &PgPool,
     logical_msg_id: &str,
@@ -125,24 +341,55 @@ pub async fn mark_outbox_delivered_by_logical_id(
     Ok(())
 }
 
-/// Exponential backoff: 10s * 2^attempt, capped at 3600s.
-/// Marks 'failed' after max_attempts.
+/// Drop queued message forwards whose disappearing-message deadline passed
+/// before the peer accepted them.
+pub async fn delete_expired_outbox_messages(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM federation_outbox
+         WHERE status = 'pending' AND kind = 'messages'
+           AND (payload->>'expires_at')::timestamptz <= NOW()",
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Drop delivered and failed entries created more than `retention_hours` ago.
+pub async fn purge_finished_outbox(
+    pool: &PgPool,
+    retention_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM federation_outbox
+         WHERE status <> 'pending' AND created_at < NOW() - make_interval(hours => $1)",
+    )
+    .bind(retention_hours)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Record failed attempt number `attempt_count` of entry `id`: reschedule it
+/// with exponential backoff (10s * 2^attempt, capped at 3600s), or mark it
+/// 'failed' once `max_attempts` is reached. Returns true if this call
+/// abandoned the entry.
 pub async fn record_outbox_failure(
     pool: &PgPool,
     id: Uuid,
     attempt_count: i32,
     max_attempts: i32,
-) -> Result<(), sqlx::Error> {
+) -> Result<bool, sqlx::Error> {
     if attempt_count >= max_attempts {
-        sqlx::query(
+        let result = sqlx::query(
             "UPDATE federation_outbox
              SET status = 'failed', last_attempt = NOW(), attempt_count = $2
-             WHERE id = $1",
+             WHERE id = $1 AND status = 'pending'",
         )
         .bind(id)
         .bind(attempt_count)
         .execute(pool)
         .await?;
+        Ok(result.rows_affected() == 1)
     } else {
         let backoff_secs = (10_i64 * (1_i64 << attempt_count.min(12))).min(3600);
         sqlx::query(
@@ -157,10 +404,165 @@ pub async fn record_outbox_failure(
         .bind(backoff_secs.to_string())
         .execute(pool)
         .await?;
+        Ok(false)
     }
+}
+
+/// One page of outbox entries, oldest first, optionally filtered by status
+/// and target node. The flag tells whether more entries follow the page.
+pub async fn list_outbox_entries(
+    pool: &PgPool,
+    status: Option<&str>,
+    target_node_id: Option<&str>,
+    after: Option<PageCursor>,
+    limit: i64,
+) -> Result<(Vec<OutboxEntryView>, bool), sqlx::Error> {
+    let mut entries = sqlx::query_as::<_, OutboxEntryView>(
+        "SELECT id, target_node_id, logical_msg_id, kind, attempt_count,
+                last_attempt, next_attempt, status, created_at
+         FROM federation_outbox
+         WHERE ($1::text IS NULL OR status = $1)
+           AND ($2::text IS NULL OR target_node_id = $2)
+           AND ($3::timestamptz IS NULL OR (created_at, id) > ($3, $4::uuid))
+         ORDER BY created_at ASC, id ASC
+         LIMIT $5",
+    )
+    .bind(status)
+    .bind(target_node_id)
+    .bind(after.map(|c| c.created_at()))
+    .bind(after.map(|c| c.id))
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await?;
+
+    let has_more = entries.len() as i64 > limit;
+    entries.truncate(limit as usize);
+    Ok((entries, has_more))
+}
+
+/// Put failed entries back in the queue with a fresh attempt budget: entry
+/// `id` if given, otherwise every failed entry (for `target_node_id` if
+/// given). Returns how many entries were requeued.
+pub async fn requeue_failed_outbox(
+    pool: &PgPool,
+    id: Option<Uuid>,
+    target_node_id: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE federation_outbox
+         SET status = 'pending', attempt_count = 0, next_attempt = NOW()
+         WHERE status = 'failed'
+           AND ($1::uuid IS NULL OR id = $1)
+           AND ($2::text IS NULL OR target_node_id = $2)",
+    )
+    .bind(id)
+    .bind(target_node_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Delete delivered entries created at least `older_than_hours` ago, for
+/// `target_node_id` if given. Returns how many were deleted.
+pub async fn purge_delivered_outbox(
+    pool: &PgPool,
+    target_node_id: Option<&str>,
+    older_than_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM federation_outbox
+         WHERE status = 'delivered'
+           AND ($1::text IS NULL OR target_node_id = $1)
+           AND created_at <= NOW() - make_interval(hours => $2)",
+    )
+    .bind(target_node_id)
+    .bind(older_than_hours)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// ─── forwarded_messages ──────────────────────────────────────────────────────
+
+/// Remember that `logical_msg_id` from `sender_address` was queued for
+/// `target_node_id`, addressed to `to_user` there (a direct message) or to
+/// the group `chat_id`. Receipts from that node are checked against it.
+pub async fn record_forwarded_message(
+    pool: &PgPool,
+    target_node_id: &str,
+    logical_msg_id: &str,
+    sender_address: &str,
+    to_user: Option<&str>,
+    chat_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO forwarded_messages
+             (target_node_id, logical_msg_id, sender_address, to_user, chat_id)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(target_node_id)
+    .bind(logical_msg_id)
+    .bind(sender_address)
+    .bind(to_user)
+    .bind(chat_id)
+    .execute(pool)
+    .await?;
     Ok(())
 }
 
+/// The message a receipt from `node_id` is about: `logical_msg_id`, forwarded
+/// by `sender_address` to that node and addressed to `reader_address` (a
+/// direct message) or to a group `reader_address` is a member of. Returns
+/// Some with the group's chat for a group message and Some(None) for a direct
+/// message.
+///
+/// Receipts arriving after the record was purged
+/// (FORWARDED_MESSAGE_RETENTION_HOURS) find nothing.
+pub async fn find_receipt_origin(
+    pool: &PgPool,
+    node_id: &str,
+    logical_msg_id: &str,
+    sender_address: &str,
+    reader_address: &str,
+) -> Result<Option<Option<Uuid>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Option<Uuid>,)>(
+        "SELECT f.chat_id
+         FROM forwarded_messages f
+         WHERE f.target_node_id = $1
+           AND f.logical_msg_id = $2
+           AND f.sender_address = $3
+           AND (
+             f.to_user || '@' || f.target_node_id = $4
+             OR EXISTS (
+               SELECT 1 FROM chat_members m
+               JOIN users u ON u.id = m.user_id
+               WHERE m.chat_id = f.chat_id AND u.federated_address = $4)
+           )",
+    )
+    .bind(node_id)
+    .bind(logical_msg_id)
+    .bind(sender_address)
+    .bind(reader_address)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
+pub async fn purge_forwarded_messages(
+    pool: &PgPool,
+    retention_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM forwarded_messages
+         WHERE forwarded_at < NOW() - make_interval(hours => $1)",
+    )
+    .bind(retention_hours)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
 // ─── Shadow user / device ────────────────────────────────────────────────────
 
 pub async fn upsert_shadow_user(
@@ -183,6 +585,38 @@ pub async fn upsert_shadow_user(
     Ok(row.0)
 }
 
+/// The shadow user for `federated_address`, if it is homed on `home_node_id`.
+pub async fn get_shadow_user_id(
+    pool: &PgPool,
+    federated_address: &str,
+    home_node_id: Uuid,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Uuid,)>(
+        "SELECT id FROM users WHERE federated_address = $1 AND home_node_id = $2",
+    )
+    .bind(federated_address)
+    .bind(home_node_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
+/// node_id of the node `user_id` is homed on; None for a local user.
+pub async fn get_user_home_node(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (String,)>(
+        "SELECT n.node_id FROM users u
+         JOIN federation_nodes n ON n.id = u.home_node_id
+         WHERE u.id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
 pub async fn upsert_shadow_device(
     pool: &PgPool,
     device_id: Uuid,
@@ -203,28 +637,117 @@ pub async fn upsert_shadow_device(
     Ok(())
 }
 
-pub async fn get_or_create_direct_chat(
+/// Whether `device_id` is a device of `user_id`.
+pub async fn is_device_of_user(
+    pool: &PgPool,
+    device_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let row: (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM devices WHERE id = $1 AND user_id = $2)")
+            .bind(device_id)
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?;
+    Ok(row.0)
+}
+
+/// Delete the shadow copy of a revoked remote device, together with messages
+/// from it that no local device has fetched yet.
+///
+/// Only a device whose user is homed on `home_node_id` is removed, so a peer
+/// can never revoke devices it does not own. Returns false if there was no
+/// such device (unknown, or already removed).
+pub async fn delete_shadow_device(
+    pool: &PgPool,
+    device_id: Uuid,
+    home_node_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let owned = sqlx::query_as::<_, (Uuid,)>(
+        "SELECT d.id FROM devices d
+         JOIN users u ON u.id = d.user_id
+         WHERE d.id = $1 AND u.home_node_id = $2
+         FOR UPDATE OF d",
+    )
+    .bind(device_id)
+    .bind(home_node_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if owned.is_none() {
+        return Ok(false);
+    }
+
+    sqlx::query("DELETE FROM messages WHERE from_device_id = $1 AND delivered_at IS NULL")
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM devices WHERE id = $1")
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+// ─── device_federation_peers ─────────────────────────────────────────────────
+
+/// Remember that `node_id` now holds a shadow copy of a local device.
+pub async fn record_device_peer(
+    pool: &PgPool,
+    device_id: Uuid,
+    node_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO device_federation_peers (device_id, node_id)
+         VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(device_id)
+    .bind(node_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The direct chat between two users, if there is one.
+pub async fn get_direct_chat(
     pool: &PgPool,
     user_x: Uuid,
     user_y: Uuid,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let (ua, ub) = if user_x < user_y {
         (user_x, user_y)
     } else {
         (user_y, user_x)
     };
 
-    if let Some(row) = sqlx::query_as::<_, (Uuid,)>(
+    let row = sqlx::query_as::<_, (Uuid,)>(
         "SELECT id FROM chats WHERE user_a = $1 AND user_b = $2 AND chat_type = 'direct'",
     )
     .bind(ua)
     .bind(ub)
     .fetch_optional(pool)
-    .await?
-    {
-        return Ok(row.0);
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
+pub async fn get_or_create_direct_chat(
+    pool: &PgPool,
+    user_x: Uuid,
+    user_y: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    if let Some(id) = get_direct_chat(pool, user_x, user_y).await? {
+        return Ok(id);
     }
 
+    let (ua, ub) = if user_x < user_y {
+        (user_x, user_y)
+    } else {
+        (user_y, user_x)
+    };
+
     let row: (Uuid,) = sqlx::query_as(
         "INSERT INTO chats (user_a, user_b, chat_type) VALUES ($1, $2, 'direct') RETURNING id",
     )
@@ -235,6 +758,47 @@ pub async fn get_or_create_direct_chat(
     Ok(row.0)
 }
 
+/// Username and home node_id of the other party of direct chat `chat_id`,
+/// if `user_id` is in it and the other party is homed on another node.
+pub async fn get_remote_direct_peer(
+    pool: &PgPool,
+    chat_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, String)>(
+        "SELECT u.username, n.node_id
+         FROM chats c
+         JOIN users u ON u.id = CASE WHEN c.user_a = $2 THEN c.user_b ELSE c.user_a END
+         JOIN federation_nodes n ON n.id = u.home_node_id
+         WHERE c.id = $1 AND c.chat_type = 'direct' AND $2 IN (c.user_a, c.user_b)",
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Expiry a message sent now from local `user_id` to `federated_address`
+/// gets from the TTL of their direct chat, or None.
+pub async fn get_direct_chat_expiry(
+    pool: &PgPool,
+    user_id: Uuid,
+    federated_address: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Option<DateTime<Utc>>,)>(
+        "SELECT chat_expiry(c.id)
+         FROM users u
+         JOIN chats c ON c.chat_type = 'direct'
+          AND (c.user_a, c.user_b) IN (($1, u.id), (u.id, $1))
+         WHERE u.federated_address = $2",
+    )
+    .bind(user_id)
+    .bind(federated_address)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.and_then(|r| r.0))
+}
+
 /// Returns the UUID of a local (non-shadow) user by username.
 /// Returns None if the user does not exist or is a shadow record.
 pub async fn get_local_user_id_by_username(
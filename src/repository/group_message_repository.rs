@@ -0,0 +1,232 @@
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+use crate::models::{
+    message::{OutgoingMessagePayload, StoreOutcome},
+    sender_key::{distribution_logical_id, GroupTarget, OutgoingGroupMessage, SenderKey},
+};
+
+/// Every device of every local member of `chat_id` except
+/// `sender_device_id`: the devices of this node a group message or sender
+/// key must reach. Members on other nodes are reached through their node.
+pub async fn get_group_targets(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    sender_device_id: &Uuid,
+) -> Result<Vec<GroupTarget>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT d.id, d.user_id
+        FROM chat_members m
+        JOIN users u ON u.id = m.user_id
+        JOIN devices d ON d.user_id = m.user_id
+        WHERE m.chat_id = $1 AND d.id <> $2 AND u.home_node_id IS NULL
+        "#,
+        chat_id,
+        sender_device_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| GroupTarget {
+            device_id: r.id,
+            user_id: r.user_id,
+        })
+        .collect())
+}
+
+/// Current sender key of `device_id` in `chat_id`, if it has a valid one.
+pub async fn get_sender_key(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    device_id: &Uuid,
+) -> Result<Option<SenderKey>> {
+    let key = sqlx::query_as!(
+        SenderKey,
+        r#"
+        SELECT key_id, recipient_device_ids
+        FROM sender_keys
+        WHERE chat_id = $1 AND device_id = $2
+        "#,
+        chat_id,
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(key)
+}
+
+/// Queue the distribution messages of sender key `key_id` and make it the
+/// device's current key in the chat. `targets` must match `distributions`
+/// one to one. Distributions already queued for a device under the same
+/// key are kept, so re-sending a key to newly added devices is safe.
+pub async fn store_sender_key(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    from_user_id: &Uuid,
+    from_device_id: &Uuid,
+    key_id: &Uuid,
+    distributions: &[OutgoingMessagePayload],
+    targets: &[GroupTarget],
+) -> Result<()> {
+    let logical_msg_id = distribution_logical_id(key_id);
+    let mut tx = pool.begin().await?;
+
+    for payload in distributions {
+        let Some(target) = targets.iter().find(|t| t.device_id == payload.to_device_id) else {
+            continue;
+        };
+        sqlx::query!(
+            r#"
+            INSERT INTO messages (
+                logical_msg_id, chat_id, from_user_id, from_device_id,
+                to_user_id, to_device_id, kind, header, ciphertext
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, 'sender_key', $7, $8)
+            ON CONFLICT (logical_msg_id, to_device_id) DO NOTHING
+            "#,
+            logical_msg_id,
+            chat_id,
+            from_user_id,
+            from_device_id,
+            target.user_id,
+            target.device_id,
+            payload.header,
+            payload.ciphertext
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let recipient_device_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO sender_keys (chat_id, device_id, key_id, recipient_device_ids)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (chat_id, device_id) DO UPDATE
+        SET key_id = EXCLUDED.key_id,
+            recipient_device_ids = EXCLUDED.recipient_device_ids,
+            created_at = NOW()
+        "#,
+        chat_id,
+        from_device_id,
+        key_id,
+        &recipient_device_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Store a group message's ciphertext once and give every target device a
+/// messages row referencing it, in one transaction. Retries of the same
+/// `logical_msg_id` from the same device return `StoreOutcome::Duplicate`.
+pub async fn insert_group_message(
+    pool: &PgPool,
+    chat_id: &Uuid,
+    from_user_id: &Uuid,
+    from_device_id: &Uuid,
+    msg: &OutgoingGroupMessage,
+    targets: &[GroupTarget],
+) -> Result<StoreOutcome> {
+    let mut tx = pool.begin().await?;
+
+    let group_message_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO group_messages (
+            chat_id, logical_msg_id, from_device_id, sender_key_id, header, ciphertext
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (from_device_id, logical_msg_id) DO NOTHING
+        RETURNING id
+        "#,
+        chat_id,
+        msg.logical_msg_id,
+        from_device_id,
+        msg.sender_key_id,
+        msg.header,
+        msg.ciphertext
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(group_message_id) = group_message_id else {
+        tx.rollback().await?;
+        return Ok(StoreOutcome::Duplicate);
+    };
+
+    let device_ids: Vec<Uuid> = targets.iter().map(|t| t.device_id).collect();
+    let user_ids: Vec<Uuid> = targets.iter().map(|t| t.user_id).collect();
+    let message_ids = sqlx::query_scalar!(
+        r#"
+        INSERT INTO messages (
+            logical_msg_id, chat_id, from_user_id, from_device_id,
+            to_user_id, to_device_id, kind, group_message_id, expires_at
+        )
+        SELECT $1, $2, $3, $4, t.user_id, t.device_id, 'group', $7, LEAST($8, chat_expiry($2))
+        FROM unnest($5::uuid[], $6::uuid[]) AS t(device_id, user_id)
+        ON CONFLICT (logical_msg_id, to_device_id) DO NOTHING
+        RETURNING id
+        "#,
+        msg.logical_msg_id,
+        chat_id,
+        from_user_id,
+        from_device_id,
+        &device_ids,
+        &user_ids,
+        group_message_id,
+        msg.expires_at
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // Another device's message already holds this logical id for some target.
+    if message_ids.len() != targets.len() {
+        tx.rollback().await?;
+        return Ok(StoreOutcome::IdTaken);
+    }
+
+    if !msg.attachment_ids.is_empty() {
+        sqlx::query!(
+            r#"
+            INSERT INTO message_attachments (message_id, attachment_id)
+            SELECT m, a FROM unnest($1::uuid[]) AS m, unnest($2::uuid[]) AS a
+            ON CONFLICT DO NOTHING
+            "#,
+            &message_ids,
+            &msg.attachment_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE attachments SET referenced_at = COALESCE(referenced_at, NOW()) WHERE id = ANY($1)",
+            &msg.attachment_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(StoreOutcome::Stored)
+}
+
+/// Delete group ciphertexts no messages row refers to anymore (every copy
+/// was delivered and purged, or expired).
+pub async fn delete_orphan_group_messages(pool: &PgPool) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM group_messages g
+        WHERE NOT EXISTS (SELECT 1 FROM messages m WHERE m.group_message_id = g.id)
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
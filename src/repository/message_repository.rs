@@ -1,19 +1,34 @@
 use crate::{
     middlewares::auth::AuthenticatedDevice,
-    models::message::{MessageView, OutgoingMessage},
+    models::{
+        federation::{S2sAck, S2sReceipt, OUTBOX_KIND_RECEIPT},
+        message::{DeviceMismatch, MessageView, OutgoingMessage, ReceiptStatus, StoreOutcome},
+        pagination::PageCursor,
+    },
 };
 use serde_json::Value;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
+/// Store the fan-out of a message, one row per payload, in a single
+/// transaction. Used for local sends and for fan-outs forwarded by peers.
+///
+/// Retries are safe: rows that already exist for `(logical_msg_id,
+/// to_device_id)` are skipped, and a fan-out that was stored in full before
+/// comes back as `StoreOutcome::Duplicate`. If an existing row was written by
+/// another device, nothing is stored and `StoreOutcome::IdTaken` is returned.
 pub async fn insert_message(
     pool: &PgPool,
     from_device_id: Uuid,
     from_user_id: Uuid,
     msg: OutgoingMessage,
-) -> Result<(), sqlx::Error> {
+) -> Result<StoreOutcome, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut new_ids = Vec::with_capacity(msg.payloads.len());
+
     for payload in msg.payloads {
-        sqlx::query!(
+        let inserted = sqlx::query_scalar!(
             r#"
             INSERT INTO messages (
                 logical_msg_id,
@@ -23,9 +38,12 @@ pub async fn insert_message(
                 to_user_id,
                 to_device_id,
                 header,
-                ciphertext
+                ciphertext,
+                expires_at
             )
-            VALUES ($1,$2,$3,$4,$5,$6,$7,$8)
+            VALUES ($1,$2,$3,$4,$5,$6,$7,$8, LEAST($9, chat_expiry($2)))
+            ON CONFLICT (logical_msg_id, to_device_id) DO NOTHING
+            RETURNING id
             "#,
             msg.logical_msg_id,
             msg.chat_id,
@@ -34,91 +52,414 @@ pub async fn insert_message(
             msg.to_user_id,
             payload.to_device_id,
             payload.header,
-            payload.ciphertext
+            payload.ciphertext,
+            msg.expires_at
         )
-        .execute(pool)
+        .fetch_optional(&mut *tx)
         .await?;
+
+        match inserted {
+            Some(id) => new_ids.push(id),
+            None => {
+                let owner = sqlx::query_scalar!(
+                    "SELECT from_device_id FROM messages WHERE logical_msg_id = $1 AND to_device_id = $2",
+                    msg.logical_msg_id,
+                    payload.to_device_id
+                )
+                .fetch_optional(&mut *tx)
+                .await?;
+                // The row may have expired in between; only a row written by
+                // someone else makes this a conflict.
+                if owner.flatten().is_some_and(|d| d != from_device_id) {
+                    tx.rollback().await?;
+                    return Ok(StoreOutcome::IdTaken);
+                }
+            }
+        }
     }
 
-    Ok(())
+    if new_ids.is_empty() {
+        tx.rollback().await?;
+        return Ok(StoreOutcome::Duplicate);
+    }
+
+    if !msg.attachment_ids.is_empty() {
+        sqlx::query!(
+            r#"
+            INSERT INTO message_attachments (message_id, attachment_id)
+            SELECT m, a FROM unnest($1::uuid[]) AS m, unnest($2::uuid[]) AS a
+            ON CONFLICT DO NOTHING
+            "#,
+            &new_ids,
+            &msg.attachment_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE attachments SET referenced_at = COALESCE(referenced_at, NOW()) WHERE id = ANY($1)",
+            &msg.attachment_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(StoreOutcome::Stored)
 }
 
-/// Insert a message that arrived via S2S forwarding from a peer node.
-///
-/// Idempotent: if a row with the same (logical_msg_id, to_device_id) already
-/// exists (duplicate delivery from outbox retry), the INSERT is skipped and
-/// the function returns Ok(false). Returns Ok(true) when a new row is created.
-///
-/// The unique constraint `uniq_message_per_device` (added in federation.sql)
-/// makes the ON CONFLICT clause safe without a preceding SELECT.
-#[allow(clippy::too_many_arguments)]
-pub async fn insert_federated_message(
+/// Whether `from_device_id` already sent a message with this logical id.
+/// Lets a retried POST /messages return its original result even if the
+/// recipient's devices changed in between.
+pub async fn is_already_sent(
     pool: &PgPool,
+    from_device_id: &Uuid,
     logical_msg_id: &str,
-    chat_id: Uuid,
-    from_user_id: Uuid,
-    from_device_id: Uuid,
-    to_user_id: Uuid,
-    to_device_id: Uuid,
-    header: &Value,
-    ciphertext: &str,
 ) -> Result<bool, sqlx::Error> {
-    let result = sqlx::query(
-        "INSERT INTO messages (
-             logical_msg_id, chat_id,
-             from_user_id, from_device_id,
-             to_user_id, to_device_id,
-             header, ciphertext
-         )
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-         ON CONFLICT (logical_msg_id, to_device_id) DO NOTHING",
+    let sent = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM messages WHERE logical_msg_id = $1 AND from_device_id = $2
+        ) AS "sent!"
+        "#,
+        logical_msg_id,
+        from_device_id
     )
-    .bind(logical_msg_id)
-    .bind(chat_id)
-    .bind(from_user_id)
-    .bind(from_device_id)
-    .bind(to_user_id)
-    .bind(to_device_id)
-    .bind(header)
-    .bind(ciphertext)
-    .execute(pool)
+    .fetch_one(pool)
     .await?;
-    Ok(result.rows_affected() == 1)
+
+    Ok(sent)
 }
 
+/// One page of undelivered messages for `device`, oldest first, starting
+/// after `after`. The flag tells whether more messages follow the page.
+///
+/// This is a read only: rows stay pending until the device acknowledges them
+/// with `ack_messages`, so a lost response just means the same page is
+/// served again.
 pub async fn fetch_pending_messages(
     pool: &PgPool,
     AuthenticatedDevice(device): AuthenticatedDevice,
-) -> Result<Vec<MessageView>, sqlx::Error> {
-    let messages = sqlx::query_as!(
+    after: Option<PageCursor>,
+    limit: i64,
+) -> Result<(Vec<MessageView>, bool), sqlx::Error> {
+    let mut messages = sqlx::query_as!(
         MessageView,
         r#"
             SELECT 
-                id,
-                logical_msg_id,
-                chat_id,
-                from_user_id,
-                from_device_id,
-                header as "header: Value",
-                ciphertext,
-                created_at as "created_at?"
-            FROM messages
-            WHERE to_device_id = $1
-            AND delivered_at IS NULL
-            ORDER BY created_at ASC
+                m.id,
+                m.logical_msg_id,
+                m.chat_id,
+                m.from_user_id,
+                m.from_device_id,
+                m.kind,
+                g.sender_key_id as "sender_key_id?",
+                COALESCE(m.header, g.header) as "header!: Value",
+                COALESCE(m.ciphertext, g.ciphertext) as "ciphertext!",
+                m.created_at as "created_at?",
+                m.expires_at
+            FROM messages m
+            LEFT JOIN group_messages g ON g.id = m.group_message_id
+            WHERE m.to_device_id = $1
+            AND m.delivered_at IS NULL
+            AND (m.expires_at IS NULL OR m.expires_at > NOW())
+            AND ($2::timestamptz IS NULL OR (m.created_at, m.id) > ($2, $3::uuid))
+            ORDER BY m.created_at ASC, m.id ASC
+            LIMIT $4
         "#,
-        device.id
+        device.id,
+        after.map(|c| c.created_at()),
+        after.map(|c| c.id),
+        limit + 1
     )
     .fetch_all(pool)
     .await?;
 
-    // Mark as delivered
-    sqlx::query!(
-        "UPDATE messages SET delivered_at = NOW() WHERE to_device_id = $1 AND delivered_at IS NULL",
-        device.id
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    Ok((messages, has_more))
+}
+
+/// Mark the given messages delivered. Only rows addressed to `device_id`
+/// that are still pending are touched; unknown or foreign IDs are ignored.
+/// Returns how many rows were acknowledged.
+pub async fn ack_messages(
+    pool: &PgPool,
+    device_id: &Uuid,
+    message_ids: &[Uuid],
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE messages SET delivered_at = NOW()
+        WHERE to_device_id = $1 AND id = ANY($2) AND delivered_at IS NULL
+        "#,
+        device_id,
+        message_ids
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Record a delivered/read receipt from `device_id` (of `user_id`, known to
+/// peers as `reader_address`) for its copies of the given logical messages.
+///
+/// `delivered_at`, and for `Read` also `read_at`, are set where still empty.
+/// Local senders get one receipt event per chat on messages_channel; for
+/// senders homed on another node an S2sReceipt per message is queued in the
+/// outbox. A user's receipts for messages from their own devices go nowhere.
+/// Returns how many message rows matched.
+pub async fn record_receipts(
+    pool: &PgPool,
+    device_id: &Uuid,
+    user_id: &Uuid,
+    reader_address: &str,
+    logical_msg_ids: &[String],
+    status: ReceiptStatus,
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        r#"
+        WITH updated AS (
+            UPDATE messages
+            SET delivered_at = COALESCE(delivered_at, NOW()),
+                read_at = CASE WHEN $3 THEN COALESCE(read_at, NOW()) ELSE read_at END
+            WHERE to_device_id = $1 AND logical_msg_id = ANY($2)
+            RETURNING logical_msg_id, chat_id, from_user_id
+        )
+        SELECT up.logical_msg_id, up.chat_id, s.id AS "sender_id?",
+               s.federated_address, n.node_id AS "sender_node_id?"
+        FROM updated up
+        LEFT JOIN users s ON s.id = up.from_user_id
+        LEFT JOIN federation_nodes n ON n.id = s.home_node_id
+        "#,
+        device_id,
+        logical_msg_ids,
+        status == ReceiptStatus::Read
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut local: BTreeMap<(Uuid, Option<Uuid>), Vec<String>> = BTreeMap::new();
+    for row in &rows {
+        let Some(sender_id) = row.sender_id.filter(|id| id != user_id) else {
+            continue;
+        };
+        match (&row.sender_node_id, &row.federated_address) {
+            (None, _) => local
+                .entry((sender_id, row.chat_id))
+                .or_default()
+                .push(row.logical_msg_id.clone()),
+            (Some(node_id), Some(address)) => {
+                let receipt = S2sReceipt {
+                    ack: S2sAck {
+                        logical_msg_id: row.logical_msg_id.clone(),
+                        status: status.as_str().into(),
+                    },
+                    from_federated_address: reader_address.to_string(),
+                    from_device_id: *device_id,
+                    to_user: address.split('@').next().unwrap_or_default().to_string(),
+                };
+                sqlx::query!(
+                    r#"
+                    INSERT INTO federation_outbox (target_node_id, logical_msg_id, kind, payload)
+                    VALUES ($1, $2, $3, $4)
+                    "#,
+                    node_id,
+                    format!("receipt:{}:{}", status.as_str(), row.logical_msg_id),
+                    OUTBOX_KIND_RECEIPT,
+                    serde_json::to_value(&receipt).unwrap_or_default()
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            (Some(_), None) => {}
+        }
+    }
+
+    for ((sender_id, chat_id), ids) in local {
+        emit_receipt(
+            &mut tx, &sender_id, chat_id, status, &ids, user_id, device_id,
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(rows.len() as u64)
+}
+
+/// Push a receipt reported by a remote reader to the devices of `sender_id`.
+pub async fn emit_receipt_event(
+    pool: &PgPool,
+    sender_id: &Uuid,
+    chat_id: Option<Uuid>,
+    status: ReceiptStatus,
+    logical_msg_ids: &[String],
+    reader_user_id: &Uuid,
+    reader_device_id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    emit_receipt(
+        &mut conn,
+        sender_id,
+        chat_id,
+        status,
+        logical_msg_ids,
+        reader_user_id,
+        reader_device_id,
+    )
+    .await
+}
+
+async fn emit_receipt(
+    conn: &mut PgConnection,
+    sender_id: &Uuid,
+    chat_id: Option<Uuid>,
+    status: ReceiptStatus,
+    logical_msg_ids: &[String],
+    reader_user_id: &Uuid,
+    reader_device_id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_user_event(
+            $1,
+            'messages_channel',
+            jsonb_build_object(
+                'type', $2::text,
+                'chat_id', $3::uuid,
+                'logical_msg_ids', to_jsonb($4::text[]),
+                'reader_user_id', $5::uuid,
+                'reader_device_id', $6::uuid
+            )
+        )
+        "#,
+        sender_id,
+        status.event_type(),
+        chat_id,
+        logical_msg_ids,
+        reader_user_id,
+        reader_device_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Tell the sending device that `node_id` refused a forwarded message or
+/// sender key because it was encrypted for the wrong set of its devices.
+/// `chat_id` is set for group sends, `to_user_address` for direct ones.
+pub async fn emit_device_mismatch(
+    pool: &PgPool,
+    device_id: &Uuid,
+    logical_msg_id: &str,
+    node_id: &str,
+    chat_id: Option<&Uuid>,
+    to_user_address: Option<&str>,
+    mismatch: &DeviceMismatch,
+) -> Result<(), sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_device_event(
+            $1,
+            'messages_channel',
+            jsonb_build_object(
+                'type', 'device_mismatch',
+                'logical_msg_id', $2::text,
+                'node_id', $3::text,
+                'chat_id', $4::uuid,
+                'to_user_address', $5::text,
+                'missing_devices', to_jsonb($6::uuid[]),
+                'extra_devices', to_jsonb($7::uuid[])
+            )
+        )
+        "#,
+        device_id,
+        logical_msg_id,
+        node_id,
+        chat_id,
+        to_user_address,
+        &mismatch.missing_devices,
+        &mismatch.extra_devices
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Tell `device_id` that its outbox entry for `logical_msg_id` to `node_id`
+/// was abandoned after `attempts` delivery attempts.
+pub async fn emit_delivery_failed(
+    pool: &PgPool,
+    device_id: &Uuid,
+    logical_msg_id: &str,
+    node_id: &str,
+    chat_id: Option<&Uuid>,
+    to_user_address: Option<&str>,
+    attempts: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT emit_device_event(
+            $1,
+            'messages_channel',
+            jsonb_build_object(
+                'type', 'delivery_failed',
+                'logical_msg_id', $2::text,
+                'node_id', $3::text,
+                'chat_id', $4::uuid,
+                'to_user_address', $5::text,
+                'attempts', $6::int
+            )
+        )
+        "#,
+        device_id,
+        logical_msg_id,
+        node_id,
+        chat_id,
+        to_user_address,
+        attempts
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Hard-delete every message whose `expires_at` has passed, delivered or not.
+pub async fn delete_expired_messages(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM messages WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Drop messages acknowledged more than `retention_hours` ago that are
+/// already read. Unread ones are kept until they are read or
+/// `unread_retention_hours` have passed, because `record_receipts` needs the
+/// row to send a read receipt.
+pub async fn purge_delivered_messages(
+    pool: &PgPool,
+    retention_hours: i32,
+    unread_retention_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM messages
+        WHERE delivered_at IS NOT NULL
+        AND delivered_at < NOW() - make_interval(hours => $1)
+        AND (read_at IS NOT NULL OR delivered_at < NOW() - make_interval(hours => $2))
+        "#,
+        retention_hours,
+        unread_retention_hours
     )
     .execute(pool)
     .await?;
 
-    Ok(messages)
+    Ok(result.rows_affected())
 }
@@ -1,8 +1,12 @@
+pub mod attachment_repository;
 pub mod chat_repository;
+pub mod device_link_repository;
 pub mod device_repository;
 pub mod enrollment_token_repository;
 pub mod federation_repository;
+pub mod group_message_repository;
 pub mod keys_repository;
 pub mod message_repository;
+pub mod realtime_repository;
 pub mod session_repository;
 pub mod user_repository;
@@ -0,0 +1,51 @@
+use serde_json::Value;
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+/// Highest sequence number handed out to a device so far (0 if none).
+pub async fn get_last_device_seq(pool: &PgPool, device_id: &Uuid) -> Result<i64> {
+    let seq = sqlx::query_scalar!(
+        "SELECT last_seq FROM device_event_counters WHERE device_id = $1",
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(seq.unwrap_or(0))
+}
+
+/// Logged event payloads for a device with seq > `since`, oldest first.
+pub async fn get_device_events_since(
+    pool: &PgPool,
+    device_id: &Uuid,
+    since: i64,
+    limit: i64,
+) -> Result<Vec<Value>> {
+    let events = sqlx::query_scalar!(
+        r#"
+        SELECT payload
+        FROM device_events
+        WHERE device_id = $1 AND seq > $2
+        ORDER BY seq ASC
+        LIMIT $3
+        "#,
+        device_id,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(events)
+}
+
+pub async fn purge_old_device_events(pool: &PgPool, retention_hours: i32) -> Result<u64> {
+    let result = sqlx::query!(
+        "DELETE FROM device_events WHERE created_at < NOW() - make_interval(hours => $1)",
+        retention_hours
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,8 +1,12 @@
 use sqlx::{PgPool, Result};
 use uuid::Uuid;
 
-use crate::{middlewares::auth::AuthenticatedDevice, models::session::PendingSession};
+use crate::{
+    middlewares::auth::AuthenticatedDevice,
+    models::{pagination::PageCursor, session::PendingSession},
+};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn create_pending_session(
     pool: &PgPool,
     sender_device_id: &Uuid,
@@ -10,6 +14,9 @@ pub async fn create_pending_session(
     ephemeral_pubkey: &str,
     sender_prekey_pub: &str,
     otpk_used: &str,
+    recipient_spk_pub: Option<&str>,
+    kem_prekey_id: Option<i64>,
+    kem_ciphertext: Option<&str>,
     ciphertext: &str,
 ) -> Result<(), sqlx::Error> {
     sqlx::query_as!(
@@ -21,9 +28,12 @@ pub async fn create_pending_session(
             ephemeral_pubkey,
             sender_prekey_pub,
             otpk_used,
+            recipient_spk_pub,
+            kem_prekey_id,
+            kem_ciphertext,
             ciphertext
         )
-        VALUES ($1, $2, $3, $4, $5, $6)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
         ON CONFLICT DO NOTHING
         "#,
         sender_device_id,
@@ -31,6 +41,9 @@ pub async fn create_pending_session(
         ephemeral_pubkey,
         sender_prekey_pub,
         otpk_used,
+        recipient_spk_pub,
+        kem_prekey_id,
+        kem_ciphertext,
         ciphertext
     )
     .execute(pool)
@@ -39,11 +52,15 @@ pub async fn create_pending_session(
     Ok(())
 }
 
+/// One page of pending sessions addressed to `device`, oldest first,
+/// starting after `after`. The flag tells whether more sessions follow.
 pub async fn get_pending_sessions(
     pool: &PgPool,
     AuthenticatedDevice(device): AuthenticatedDevice,
-) -> Result<Vec<PendingSession>, sqlx::Error> {
-    let sessions = sqlx::query_as!(
+    after: Option<PageCursor>,
+    limit: i64,
+) -> Result<(Vec<PendingSession>, bool), sqlx::Error> {
+    let mut sessions = sqlx::query_as!(
         PendingSession,
         r#"
         SELECT 
@@ -54,16 +71,27 @@ pub async fn get_pending_sessions(
             recipient_device_id,
             sender_prekey_pub,
             otpk_used,
+            recipient_spk_pub,
+            kem_prekey_id,
+            kem_ciphertext,
             created_at
         FROM pending_sessions
         WHERE recipient_device_id = $1
+        AND ($2::timestamp IS NULL OR (created_at, id) > ($2, $3::uuid))
+        ORDER BY created_at ASC, id ASC
+        LIMIT $4
         "#,
-        device.id
+        device.id,
+        after.map(|c| c.created_at_naive()),
+        after.map(|c| c.id),
+        limit + 1
     )
     .fetch_all(pool)
     .await?;
 
-    Ok(sessions)
+    let has_more = sessions.len() as i64 > limit;
+    sessions.truncate(limit as usize);
+    Ok((sessions, has_more))
 }
 
 pub async fn get_pending_session_by_id(
@@ -82,6 +110,9 @@ pub async fn get_pending_session_by_id(
             recipient_device_id,
             sender_prekey_pub,
             otpk_used,
+            recipient_spk_pub,
+            kem_prekey_id,
+            kem_ciphertext,
             created_at
         FROM pending_sessions
         WHERE id = $1 AND recipient_device_id = $2
@@ -180,3 +211,19 @@ pub async fn delete_pending_session(pool: &PgPool, pending_id: &Uuid) -> Result<
 
     Ok(())
 }
+
+/// Drop session inits that their recipient never picked up within
+/// `retention_hours`.
+pub async fn purge_stale_pending_sessions(
+    pool: &PgPool,
+    retention_hours: i32,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM pending_sessions WHERE created_at < NOW() - make_interval(hours => $1)",
+        retention_hours
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -64,3 +64,24 @@ pub async fn find_user_by_id(pool: &PgPool, user_id: &uuid::Uuid) -> Result<Opti
 
     Ok(user)
 }
+
+/// The ids among `user_ids` that are not local users of this node.
+pub async fn find_non_local_users(
+    pool: &PgPool,
+    user_ids: &[uuid::Uuid],
+) -> Result<Vec<uuid::Uuid>> {
+    let missing = sqlx::query_scalar!(
+        r#"
+        SELECT u AS "id!"
+        FROM unnest($1::uuid[]) AS u
+        WHERE NOT EXISTS (
+            SELECT 1 FROM users WHERE id = u AND home_node_id IS NULL
+        )
+        "#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(missing)
+}
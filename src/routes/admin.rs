@@ -0,0 +1,44 @@
+use axum::{
+    routing::{delete, get, post},
+    Router,
+};
+
+use crate::{app_state::AppState, controllers::admin_controller};
+
+/// Operator routes, authorized by ADMIN_TOKEN (AuthenticatedAdmin).
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/federation/nodes", get(admin_controller::list_peers))
+        .route(
+            "/admin/federation/nodes/:node_id",
+            get(admin_controller::get_peer).delete(admin_controller::delete_peer),
+        )
+        .route(
+            "/admin/federation/nodes/:node_id/block",
+            post(admin_controller::block_peer),
+        )
+        .route(
+            "/admin/federation/nodes/:node_id/unblock",
+            post(admin_controller::unblock_peer),
+        )
+        .route(
+            "/admin/federation/nodes/:node_id/refresh",
+            post(admin_controller::refresh_peer),
+        )
+        .route(
+            "/admin/federation/outbox",
+            get(admin_controller::list_outbox),
+        )
+        .route(
+            "/admin/federation/outbox/requeue",
+            post(admin_controller::requeue_outbox),
+        )
+        .route(
+            "/admin/federation/outbox/delivered",
+            delete(admin_controller::purge_delivered_outbox),
+        )
+        .route(
+            "/admin/federation/outbox/:id/requeue",
+            post(admin_controller::requeue_outbox_entry),
+        )
+}
@@ -0,0 +1,35 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
+    Router,
+};
+
+use crate::{app_state::AppState, controllers::attachments_controller};
+
+/// Signed routes, served behind the body digest layer like every other route.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/attachments",
+            post(attachments_controller::create_attachment),
+        )
+        .route(
+            "/attachments/:id",
+            get(attachments_controller::download_attachment),
+        )
+        .route(
+            "/attachments/remote/:node_id/:id",
+            get(attachments_controller::download_remote_attachment),
+        )
+}
+
+/// Blob upload, authorized by the upload token. Mounted outside the body
+/// digest layer, which caps bodies at 2 MiB, with its own limit.
+pub fn upload_routes(max_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/attachments/:id/upload",
+            put(attachments_controller::upload_attachment),
+        )
+        .layer(DefaultBodyLimit::max(max_bytes))
+}
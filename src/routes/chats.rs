@@ -1,9 +1,43 @@
-use axum::{routing::get, Router};
+use axum::{
+    routing::{delete, get, post, put},
+    Router,
+};
 
-use crate::{app_state::AppState, controllers::chats_controller};
+use crate::{
+    app_state::AppState,
+    controllers::{chats_controller, group_messages_controller},
+};
 
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/chats", get(chats_controller::get_all_chats))
         .route("/chats/:id", get(chats_controller::get_all_chats))
+        .route("/chats/:id/expiry", put(chats_controller::set_chat_expiry))
+        .route("/chats/groups", post(chats_controller::create_group))
+        .route(
+            "/chats/:id/members",
+            get(chats_controller::get_group_members).post(chats_controller::add_group_members),
+        )
+        .route(
+            "/chats/:id/members/:user_id",
+            delete(chats_controller::remove_group_member),
+        )
+        .route(
+            "/chats/:id/members/:user_id/role",
+            put(chats_controller::set_member_role),
+        )
+        .route("/chats/:id/leave", post(chats_controller::leave_group))
+        .route("/chats/:id/name", put(chats_controller::rename_group))
+        .route(
+            "/chats/:id/owner",
+            put(chats_controller::transfer_ownership),
+        )
+        .route(
+            "/chats/:id/sender-keys",
+            post(group_messages_controller::distribute_sender_key),
+        )
+        .route(
+            "/chats/:id/messages",
+            post(group_messages_controller::send_group_message),
+        )
 }
@@ -1,9 +1,12 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 
-use crate::{app_state::AppState, controllers::device_controller};
+use crate::{
+    app_state::AppState,
+    controllers::{device_controller, device_link_controller},
+};
 
 pub fn routes() -> Router<AppState> {
     Router::new()
@@ -13,6 +16,28 @@ pub fn routes() -> Router<AppState> {
             get(device_controller::get_devices_for_user),
         )
         .route("/users/:id/keys", get(device_controller::get_user_keys))
+        .route(
+            "/devices/me/prekeys",
+            post(device_controller::upload_prekeys),
+        )
+        .route(
+            "/devices/me/signed-prekey",
+            post(device_controller::rotate_signed_prekey),
+        )
+        .route(
+            "/devices/link-requests",
+            post(device_link_controller::create_link_request)
+                .get(device_link_controller::get_pending_link_requests),
+        )
+        .route(
+            "/devices/link-requests/:id",
+            get(device_link_controller::get_link_request_status),
+        )
+        .route(
+            "/devices/link-requests/:id/approve",
+            post(device_link_controller::approve_link_request),
+        )
+        .route("/devices/:id", delete(device_controller::revoke_device))
         .route(
             "/devices/:id/user",
             get(device_controller::get_user_for_device),
@@ -26,7 +26,43 @@ pub fn routes() -> Router<AppState> {
             "/s2s/messages",
             post(federation_controller::receive_messages),
         )
+        .route(
+            "/s2s/messages/batch",
+            post(federation_controller::receive_message_batch),
+        )
+        .route(
+            "/s2s/devices/removed",
+            post(federation_controller::receive_device_removed),
+        )
         .route("/s2s/ack", post(federation_controller::receive_ack))
+        .route(
+            "/s2s/receipts",
+            post(federation_controller::receive_receipt),
+        )
+        .route(
+            "/s2s/chats/expiry",
+            post(federation_controller::receive_chat_expiry),
+        )
+        .route(
+            "/s2s/attachments/:id",
+            get(federation_controller::serve_attachment),
+        )
+        .route(
+            "/s2s/groups/state",
+            post(federation_controller::receive_group_state),
+        )
+        .route(
+            "/s2s/groups/leave",
+            post(federation_controller::receive_group_leave),
+        )
+        .route(
+            "/s2s/groups/sender-keys",
+            post(federation_controller::receive_sender_key),
+        )
+        .route(
+            "/s2s/groups/messages",
+            post(federation_controller::receive_group_message),
+        )
         // ── Client-facing federated proxy ────────────────────────────────────
         .route(
             "/s2s/federated/:username/:node_id/keys",
@@ -12,4 +12,12 @@ pub fn routes() -> Router<AppState> {
             "/messages/pending",
             get(messages_controller::get_pending_messages),
         )
+        .route(
+            "/messages/ack",
+            post(messages_controller::acknowledge_messages),
+        )
+        .route(
+            "/messages/receipts",
+            post(messages_controller::send_receipts),
+        )
 }
@@ -1,3 +1,5 @@
+pub mod admin;
+pub mod attachments;
 pub mod chats;
 pub mod devices;
 pub mod federation;
@@ -1,6 +1,6 @@
-use crate::realtime::websocket::ws_route;
+use crate::{app_state::AppState, realtime::websocket::ws_route};
 use axum::{routing::get, Router};
 
-pub fn routes() -> Router {
-    Router::new().route("/ws/:user_id", get(ws_route))
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/ws", get(ws_route))
 }
@@ -1,12 +1,39 @@
 use crate::models::enrollment_token::EnrollmentClaims;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use uuid::Uuid;
 
 pub fn generate_enrollment_tokens(user_id: &Uuid, secret: &str) -> String {
+    sign_enrollment_claims(user_id, None, Utc::now() + Duration::minutes(5), secret)
+}
+
+/// Enrollment token for a linked device, only valid with `identity_pubkey`.
+/// It expires with the link request (`expires_at`), so it stays usable for as
+/// long as the request reports `approved`.
+pub fn generate_link_enrollment_token(
+    user_id: &Uuid,
+    identity_pubkey: &str,
+    expires_at: DateTime<Utc>,
+    secret: &str,
+) -> String {
+    sign_enrollment_claims(
+        user_id,
+        Some(identity_pubkey.to_string()),
+        expires_at,
+        secret,
+    )
+}
+
+fn sign_enrollment_claims(
+    user_id: &Uuid,
+    ik: Option<String>,
+    expires_at: DateTime<Utc>,
+    secret: &str,
+) -> String {
     let claims = EnrollmentClaims {
         sub: user_id.to_string(),
-        exp: (Utc::now() + Duration::minutes(5)).timestamp() as usize,
+        exp: expires_at.timestamp() as usize,
+        ik,
     };
     encode(
         &Header::default(),
@@ -16,12 +43,36 @@ pub fn generate_enrollment_tokens(user_id: &Uuid, secret: &str) -> String {
     .expect("Failed to generate enrollment token")
 }
 
-pub fn verify_enrollment_token(token: &str, secret: &str) -> Option<Uuid> {
+/// Returns the user the token enrolls into, if it is valid for a device with
+/// `identity_pubkey`.
+pub fn verify_enrollment_token(token: &str, secret: &str, identity_pubkey: &str) -> Option<Uuid> {
     let data = decode::<EnrollmentClaims>(
         token,
         &DecodingKey::from_secret(secret.as_bytes()),
         &Validation::default(),
     )
     .ok()?;
+    if let Some(ref ik) = data.claims.ik {
+        if ik != identity_pubkey {
+            return None;
+        }
+    }
     Uuid::parse_str(&data.claims.sub).ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_token_is_bound_to_identity_key() {
+        let user = Uuid::new_v4();
+        let expires_at = Utc::now() + Duration::minutes(10);
+        let token = generate_link_enrollment_token(&user, "ik-a", expires_at, "secret");
+        assert_eq!(
+            verify_enrollment_token(&token, "secret", "ik-a"),
+            Some(user)
+        );
+        assert_eq!(verify_enrollment_token(&token, "secret", "ik-b"), None);
+    }
+}
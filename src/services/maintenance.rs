@@ -0,0 +1,58 @@
+// src/services/maintenance.rs
+//
+// Background worker for hourly housekeeping.
+//
+// - Retired signed prekeys. A rotation (POST /devices/me/signed-prekey) moves
+//   the previous SPK into signed_prekey_history with an expires_at of now +
+//   SIGNED_PREKEY_GRACE_HOURS. Until then, session inits naming that SPK are
+//   still accepted. Once the grace window has passed the row is useless.
+//
+// - Retired KEM prekeys. Claimed one-time KEM prekeys and replaced last-resort
+//   KEM prekeys get a retired_at instead of being deleted, so session inits
+//   naming them still validate for SIGNED_PREKEY_GRACE_HOURS. After that they
+//   are deleted here.
+//
+// - Realtime event log. device_events only exists so reconnecting sockets can
+//   be replayed what they missed; entries older than
+//   REALTIME_EVENT_RETENTION_HOURS are dropped and such clients get a resync.
+//
+// Message expiry and retention of messages, outbox entries and pending
+// sessions are handled by services::reaper, which runs every minute.
+
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::repository::{device_repository, realtime_repository};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Long-running task: run the purges once an hour.
+pub async fn run(pool: PgPool, prekey_grace_hours: i32, event_retention_hours: i32) {
+    let mut interval = time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match device_repository::purge_expired_signed_prekeys(&pool).await {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "prekeys: expired signed prekeys removed"),
+            Err(e) => warn!(err = %e, "prekeys: signed prekey purge failed"),
+        }
+
+        match device_repository::purge_retired_kem_prekeys(&pool, prekey_grace_hours).await {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "prekeys: retired KEM prekeys removed"),
+            Err(e) => warn!(err = %e, "prekeys: KEM prekey purge failed"),
+        }
+
+        match realtime_repository::purge_old_device_events(&pool, event_retention_hours).await {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "realtime: old device events removed"),
+            Err(e) => warn!(err = %e, "realtime: device event purge failed"),
+        }
+    }
+}
@@ -1 +1,3 @@
 pub mod auth;
+pub mod maintenance;
+pub mod reaper;
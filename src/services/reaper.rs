@@ -0,0 +1,162 @@
+// src/services/reaper.rs
+//
+// Background worker that enforces message expiry and retention.
+//
+// - Disappearing messages. Messages get an expires_at from the sender or the
+//   chat's message_ttl_seconds. Once it has passed, every copy is deleted,
+//   delivered or not, and queued federated forwards of it are dropped so the
+//   peer never receives an already expired message.
+//
+// - Retention. Acknowledged messages, delivered or failed outbox entries,
+//   records of forwarded messages (kept for S2S receipts), and pending
+//   sessions their recipient never picked up are kept only for their
+//   retention window, then deleted. Acknowledged messages not read yet stay
+//   until they are read or UNREAD_MESSAGE_RETENTION_HOURS pass, so a late
+//   read still produces a receipt.
+//
+// - Group ciphertexts. A sender-key group message is stored once and
+//   referenced by each recipient's messages row; it is deleted once the last
+//   of those rows is gone.
+//
+// - Attachments. A blob is removed from the storage backend once every
+//   message copy referencing it has been delivered for
+//   ATTACHMENT_RETENTION_HOURS (leaving recipients time to download it) or
+//   has expired, and no federated forward holds it anymore. Uploads no
+//   message ever referenced go after the same window.
+//
+// Expiry is checked every minute, so a message may outlive its deadline by up
+// to REAP_INTERVAL. Clients are expected to hide expired messages themselves.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    repository::{
+        attachment_repository, federation_repository, group_message_repository, message_repository,
+        session_repository,
+    },
+    storage::BlobStore,
+};
+
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+/// Most attachments collected per pass.
+const ATTACHMENT_BATCH: i64 = 100;
+
+/// How long finished rows are kept, in hours.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Acknowledged messages (DELIVERED_MESSAGE_RETENTION_HOURS).
+    pub delivered_messages: i32,
+    /// Acknowledged but unread messages (UNREAD_MESSAGE_RETENTION_HOURS).
+    pub unread_messages: i32,
+    /// Delivered and failed outbox entries (OUTBOX_RETENTION_HOURS).
+    pub outbox: i32,
+    /// Records of forwarded messages that S2S receipts are checked against
+    /// (FORWARDED_MESSAGE_RETENTION_HOURS).
+    pub forwarded_messages: i32,
+    /// Unclaimed pending sessions (PENDING_SESSION_RETENTION_HOURS).
+    pub pending_sessions: i32,
+    /// Delivered or unreferenced attachments (ATTACHMENT_RETENTION_HOURS).
+    pub attachments: i32,
+}
+
+/// Long-running task: reap expired and out-of-retention rows every minute.
+pub async fn run(pool: PgPool, store: Arc<dyn BlobStore>, retention: Retention) {
+    let mut interval = time::interval(REAP_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match message_repository::delete_expired_messages(&pool).await {
+            Ok(0) => {}
+            Ok(n) => info!(deleted = n, "reaper: expired messages deleted"),
+            Err(e) => warn!(err = %e, "reaper: expired message delete failed"),
+        }
+
+        match federation_repository::delete_expired_outbox_messages(&pool).await {
+            Ok(0) => {}
+            Ok(n) => info!(deleted = n, "reaper: expired outbox forwards dropped"),
+            Err(e) => warn!(err = %e, "reaper: expired outbox delete failed"),
+        }
+
+        match message_repository::purge_delivered_messages(
+            &pool,
+            retention.delivered_messages,
+            retention.unread_messages,
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "reaper: acknowledged messages removed"),
+            Err(e) => warn!(err = %e, "reaper: delivered message purge failed"),
+        }
+
+        match group_message_repository::delete_orphan_group_messages(&pool).await {
+            Ok(0) => {}
+            Ok(n) => info!(deleted = n, "reaper: unreferenced group messages removed"),
+            Err(e) => warn!(err = %e, "reaper: group message delete failed"),
+        }
+
+        match federation_repository::purge_finished_outbox(&pool, retention.outbox).await {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "reaper: finished outbox entries removed"),
+            Err(e) => warn!(err = %e, "reaper: outbox purge failed"),
+        }
+
+        match federation_repository::purge_forwarded_messages(&pool, retention.forwarded_messages)
+            .await
+        {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "reaper: forwarded message records removed"),
+            Err(e) => warn!(err = %e, "reaper: forwarded message purge failed"),
+        }
+
+        match session_repository::purge_stale_pending_sessions(&pool, retention.pending_sessions)
+            .await
+        {
+            Ok(0) => {}
+            Ok(n) => info!(purged = n, "reaper: stale pending sessions removed"),
+            Err(e) => warn!(err = %e, "reaper: pending session purge failed"),
+        }
+
+        collect_attachments(&pool, store.as_ref(), retention.attachments).await;
+    }
+}
+
+/// Delete the blobs, then the rows, of attachments nothing needs anymore.
+/// A row whose blob could not be deleted is kept and retried next pass.
+async fn collect_attachments(pool: &PgPool, store: &dyn BlobStore, retention_hours: i32) {
+    let ids = match attachment_repository::get_collectable_attachments(
+        pool,
+        retention_hours,
+        ATTACHMENT_BATCH,
+    )
+    .await
+    {
+        Ok(ids) => ids,
+        Err(e) => {
+            warn!(err = %e, "reaper: attachment lookup failed");
+            return;
+        }
+    };
+
+    let mut collected = 0;
+    for id in ids {
+        if let Err(e) = store.delete(id).await {
+            warn!(attachment_id = %id, err = %e, "reaper: blob delete failed");
+            continue;
+        }
+        match attachment_repository::delete_attachment(pool, &id).await {
+            Ok(()) => collected += 1,
+            Err(e) => warn!(attachment_id = %id, err = %e, "reaper: attachment delete failed"),
+        }
+    }
+    if collected > 0 {
+        info!(collected, "reaper: unused attachments removed");
+    }
+}
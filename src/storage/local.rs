@@ -0,0 +1,96 @@
+// src/storage/local.rs
+//
+// Filesystem BlobStore. Blobs live under ATTACHMENT_DIR as
+// <first two hex digits of the id>/<id>, written to a temporary file first and
+// renamed into place so a reader never sees a partial blob.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use tokio::fs;
+use uuid::Uuid;
+
+use super::BlobStore;
+
+pub struct LocalFsStore {
+    root: PathBuf,
+}
+
+impl LocalFsStore {
+    /// Use `root` as storage directory, creating it if needed.
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("cannot create attachment dir {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    fn path_of(&self, id: Uuid) -> PathBuf {
+        let name = id.simple().to_string();
+        self.root.join(&name[..2]).join(name)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalFsStore {
+    async fn put(&self, id: Uuid, bytes: Bytes) -> Result<()> {
+        let path = self.path_of(id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let tmp = path.with_extension("part");
+        fs::write(&tmp, &bytes)
+            .await
+            .with_context(|| format!("cannot write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: Uuid) -> Result<Option<Bytes>> {
+        match fs::read(self.path_of(id)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<()> {
+        match fs::remove_file(self.path_of(id)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn put_get_delete_round_trip() {
+        let root = std::env::temp_dir().join(format!("hushnet-blobs-{}", Uuid::new_v4()));
+        let store = LocalFsStore::new(&root).await.unwrap();
+        let id = Uuid::new_v4();
+
+        assert!(store.get(id).await.unwrap().is_none());
+        store
+            .put(id, Bytes::from_static(b"ciphertext"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get(id).await.unwrap().as_deref(),
+            Some(&b"ciphertext"[..])
+        );
+
+        store.delete(id).await.unwrap();
+        assert!(store.get(id).await.unwrap().is_none());
+        // Deleting twice is fine.
+        store.delete(id).await.unwrap();
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,28 @@
+// src/storage/mod.rs
+//
+// Blob storage for encrypted attachments.
+//
+// The server never sees attachment plaintext: clients upload ciphertext and
+// share the key inside their end-to-end encrypted messages. A backend only has
+// to store opaque bytes under the attachment id. The filesystem backend is the
+// only one so far; an S3-compatible one can be added behind the same trait and
+// selected with ATTACHMENT_STORAGE.
+
+pub mod local;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use uuid::Uuid;
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store the blob of attachment `id`, replacing any previous one.
+    async fn put(&self, id: Uuid, bytes: Bytes) -> Result<()>;
+
+    /// Blob of attachment `id`, or None if nothing is stored for it.
+    async fn get(&self, id: Uuid) -> Result<Option<Bytes>>;
+
+    /// Remove the blob of attachment `id`. Removing a missing blob is not an error.
+    async fn delete(&self, id: Uuid) -> Result<()>;
+}
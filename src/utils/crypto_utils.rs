@@ -1,5 +1,6 @@
 use base64::{engine::general_purpose, Engine as _};
 use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use uuid::Uuid;
 
 pub fn verify_signed_prekey_signature(
     identity_pubkey_b64: &str,
@@ -56,3 +57,214 @@ pub fn verify_message_signature(
     }
     Ok(())
 }
+
+/// String a device signs with its identity key to upload the one-time
+/// prekeys `keys_b64`. `timestamp` (unix seconds) makes every batch unique so
+/// the server can refuse stale or replayed uploads.
+pub fn prekey_batch_string(device_id: &Uuid, timestamp: i64, keys_b64: &[String]) -> String {
+    format!(
+        "hushnet-otpk-batch\n{device_id}\n{timestamp}\n{}",
+        keys_b64.join("\n")
+    )
+}
+
+/// Verify a batch of one-time prekeys uploaded by a device.
+///
+/// Each key must be a base64 X25519 public key (32 bytes). The signature is
+/// Ed25519 by the device identity key over `prekey_batch_string`, with the
+/// keys in the order they were sent.
+pub fn verify_prekey_batch_signature(
+    identity_pubkey_b64: &str,
+    device_id: &Uuid,
+    timestamp: i64,
+    keys_b64: &[String],
+    signature_b64: &str,
+) -> Result<(), String> {
+    for key in keys_b64 {
+        let raw = general_purpose::STANDARD
+            .decode(key)
+            .map_err(|_| "Invalid Base64 in one_time_prekeys")?;
+        if raw.len() != 32 {
+            return Err("one_time_prekeys entries must be 32 bytes".into());
+        }
+    }
+
+    verify_identity_signature(
+        identity_pubkey_b64,
+        prekey_batch_string(device_id, timestamp, keys_b64).as_bytes(),
+        signature_b64,
+    )
+    .map_err(|e| e.unwrap_or("Invalid prekey batch signature").into())
+}
+
+/// Length in bytes of an ML-KEM-1024 public (encapsulation) key, the
+/// parameter set PQXDH prekeys use.
+pub const ML_KEM_PUBLIC_KEY_LEN: usize = 1568;
+
+/// String a device signs with its identity key to publish ML-KEM prekey
+/// `key_b64`. The tag keeps it from being mistaken for a signed prekey
+/// signature, which covers the raw key.
+pub fn kem_prekey_string(key_b64: &str) -> String {
+    format!("hushnet-kem-prekey\n{key_b64}")
+}
+
+/// Check that `key_b64` is a base64 ML-KEM-1024 public key signed by the
+/// device identity key over `kem_prekey_string`.
+pub fn verify_kem_prekey_signature(
+    identity_pubkey_b64: &str,
+    key_b64: &str,
+    signature_b64: &str,
+) -> Result<(), String> {
+    let raw = general_purpose::STANDARD
+        .decode(key_b64)
+        .map_err(|_| "Invalid Base64 in KEM prekey")?;
+    if raw.len() != ML_KEM_PUBLIC_KEY_LEN {
+        return Err(format!(
+            "KEM prekeys must be {ML_KEM_PUBLIC_KEY_LEN} bytes (ML-KEM-1024)"
+        ));
+    }
+
+    verify_identity_signature(
+        identity_pubkey_b64,
+        kem_prekey_string(key_b64).as_bytes(),
+        signature_b64,
+    )
+    .map_err(|e| e.unwrap_or("Invalid KEM prekey signature").into())
+}
+
+/// String a device signs with its identity key to confirm that it wants
+/// `device_id` (another device of the same user) removed.
+pub fn device_revocation_string(device_id: &Uuid, timestamp: i64) -> String {
+    format!("revoke-device\n{device_id}\n{timestamp}")
+}
+
+pub fn verify_device_revocation_signature(
+    identity_pubkey_b64: &str,
+    device_id: &Uuid,
+    timestamp: i64,
+    signature_b64: &str,
+) -> Result<(), String> {
+    verify_identity_signature(
+        identity_pubkey_b64,
+        device_revocation_string(device_id, timestamp).as_bytes(),
+        signature_b64,
+    )
+    .map_err(|e| e.unwrap_or("Invalid revocation signature").into())
+}
+
+/// String an enrolled device signs with its identity key to approve link
+/// request `request_id` from a new device holding `new_identity_pubkey`.
+pub fn device_link_approval_string(request_id: &Uuid, new_identity_pubkey: &str) -> String {
+    format!("link-device\n{request_id}\n{new_identity_pubkey}")
+}
+
+pub fn verify_device_link_approval(
+    identity_pubkey_b64: &str,
+    request_id: &Uuid,
+    new_identity_pubkey: &str,
+    signature_b64: &str,
+) -> Result<(), String> {
+    verify_identity_signature(
+        identity_pubkey_b64,
+        device_link_approval_string(request_id, new_identity_pubkey).as_bytes(),
+        signature_b64,
+    )
+    .map_err(|e| e.unwrap_or("Invalid link approval signature").into())
+}
+
+/// Ed25519 check of `message` against a base64 identity key. Err(Some) names a
+/// malformed input, Err(None) means the signature simply does not verify.
+fn verify_identity_signature(
+    identity_pubkey_b64: &str,
+    message: &[u8],
+    signature_b64: &str,
+) -> Result<(), Option<&'static str>> {
+    let identity_bytes = general_purpose::STANDARD
+        .decode(identity_pubkey_b64)
+        .map_err(|_| "Invalid Base64 in identity_pubkey")?;
+    let sig_bytes = general_purpose::STANDARD
+        .decode(signature_b64)
+        .map_err(|_| "Invalid Base64 in signature")?;
+
+    let ik_pub =
+        VerifyingKey::try_from(&identity_bytes[..]).map_err(|_| "Invalid identity_pubkey bytes")?;
+    let signature = Signature::try_from(&sig_bytes[..]).map_err(|_| "Invalid signature bytes")?;
+
+    ik_pub.verify(message, &signature).map_err(|_| None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn prekey_batch_signature_roundtrip() {
+        let ik = SigningKey::from_bytes(&[7u8; 32]);
+        let ik_b64 = general_purpose::STANDARD.encode(ik.verifying_key().to_bytes());
+        let device = Uuid::from_u128(1);
+        let ts = 1_700_000_000;
+        let keys = vec![
+            general_purpose::STANDARD.encode([1u8; 32]),
+            general_purpose::STANDARD.encode([2u8; 32]),
+        ];
+        let sig = ik.sign(prekey_batch_string(&device, ts, &keys).as_bytes());
+        let sig_b64 = general_purpose::STANDARD.encode(sig.to_bytes());
+
+        assert!(verify_prekey_batch_signature(&ik_b64, &device, ts, &keys, &sig_b64).is_ok());
+
+        let reordered = vec![keys[1].clone(), keys[0].clone()];
+        assert!(verify_prekey_batch_signature(&ik_b64, &device, ts, &reordered, &sig_b64).is_err());
+
+        // The same batch cannot be moved to another device or timestamp.
+        let other = Uuid::from_u128(2);
+        assert!(verify_prekey_batch_signature(&ik_b64, &other, ts, &keys, &sig_b64).is_err());
+        assert!(verify_prekey_batch_signature(&ik_b64, &device, ts + 1, &keys, &sig_b64).is_err());
+
+        // The bare key list, as signed by older clients, is refused.
+        let legacy = ik.sign(keys.join("\n").as_bytes());
+        let legacy_b64 = general_purpose::STANDARD.encode(legacy.to_bytes());
+        assert!(verify_prekey_batch_signature(&ik_b64, &device, ts, &keys, &legacy_b64).is_err());
+    }
+
+    #[test]
+    fn kem_prekey_signature_needs_tag_and_size() {
+        let ik = SigningKey::from_bytes(&[5u8; 32]);
+        let ik_b64 = general_purpose::STANDARD.encode(ik.verifying_key().to_bytes());
+        let key = general_purpose::STANDARD.encode([3u8; ML_KEM_PUBLIC_KEY_LEN]);
+
+        let tagged = ik.sign(kem_prekey_string(&key).as_bytes());
+        let tagged_b64 = general_purpose::STANDARD.encode(tagged.to_bytes());
+        assert!(verify_kem_prekey_signature(&ik_b64, &key, &tagged_b64).is_ok());
+
+        // A signature over the raw key, as for a signed prekey, is not enough.
+        let raw = general_purpose::STANDARD.decode(&key).unwrap();
+        let untagged_b64 = general_purpose::STANDARD.encode(ik.sign(&raw).to_bytes());
+        assert!(verify_kem_prekey_signature(&ik_b64, &key, &untagged_b64).is_err());
+
+        let short = general_purpose::STANDARD.encode([3u8; 32]);
+        let short_sig = ik.sign(kem_prekey_string(&short).as_bytes());
+        let short_sig_b64 = general_purpose::STANDARD.encode(short_sig.to_bytes());
+        assert!(verify_kem_prekey_signature(&ik_b64, &short, &short_sig_b64).is_err());
+    }
+
+    #[test]
+    fn revocation_signature_is_bound_to_device() {
+        let ik = SigningKey::from_bytes(&[9u8; 32]);
+        let ik_b64 = general_purpose::STANDARD.encode(ik.verifying_key().to_bytes());
+        let target = Uuid::new_v4();
+        let sig = ik.sign(device_revocation_string(&target, 1_700_000_000).as_bytes());
+        let sig_b64 = general_purpose::STANDARD.encode(sig.to_bytes());
+
+        assert!(
+            verify_device_revocation_signature(&ik_b64, &target, 1_700_000_000, &sig_b64).is_ok()
+        );
+        assert!(verify_device_revocation_signature(
+            &ik_b64,
+            &Uuid::new_v4(),
+            1_700_000_000,
+            &sig_b64
+        )
+        .is_err());
+    }
+}
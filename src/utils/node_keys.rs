@@ -1,4 +1,5 @@
 use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use chrono::{DateTime, Utc};
 use ed25519_dalek::Signer;
 use ed25519_dalek::Verifier;
 use ed25519_dalek::{ed25519::signature::rand_core::OsRng, Signature, SigningKey, VerifyingKey};
@@ -9,6 +10,59 @@ use std::{fs, path::Path};
 pub struct NodeKeys {
     pub public_b64: String,
     pub private_b64: String,
+    /// Set by `rotated` and served in GET /s2s/info until `overlap_until`,
+    /// so peers that cached the previous key can move to this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<KeyRotation>,
+}
+
+/// Proof that the previous node key handed over to the current one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub previous_public_key_b64: String,
+    /// Previous key's signature over `rotation_message`.
+    pub signature_b64: String,
+    pub overlap_until: DateTime<Utc>,
+}
+
+/// The string a node's previous key signs to endorse its new key.
+pub fn rotation_message(node_id: &str, previous_b64: &str, new_b64: &str) -> String {
+    format!("hushnet-node-key-rotation\n{node_id}\n{previous_b64}\n{new_b64}")
+}
+
+/// Verify a base64 Ed25519 signature over `message` with a base64 public
+/// key. Malformed keys or signatures do not verify.
+pub fn verify_with_key(public_b64: &str, message: &[u8], signature_b64: &str) -> bool {
+    let Some(key_bytes) = B64
+        .decode(public_b64)
+        .ok()
+        .and_then(|b| <[u8; 32]>::try_from(b).ok())
+    else {
+        return false;
+    };
+    let Some(sig_bytes) = B64
+        .decode(signature_b64)
+        .ok()
+        .and_then(|b| <[u8; 64]>::try_from(b).ok())
+    else {
+        return false;
+    };
+    VerifyingKey::from_bytes(&key_bytes).is_ok_and(|vk| {
+        vk.verify(message, &Signature::from_bytes(&sig_bytes))
+            .is_ok()
+    })
+}
+
+impl KeyRotation {
+    /// Whether the previous key endorsed `new_b64` as the key of `node_id`.
+    pub fn endorses(&self, node_id: &str, new_b64: &str) -> bool {
+        let message = rotation_message(node_id, &self.previous_public_key_b64, new_b64);
+        verify_with_key(
+            &self.previous_public_key_b64,
+            message.as_bytes(),
+            &self.signature_b64,
+        )
+    }
 }
 
 impl NodeKeys {
@@ -20,23 +74,51 @@ impl NodeKeys {
         home_dir.join("node_keys")
     }
 
-    pub fn generate_and_save() -> anyhow::Result<Self> {
+    fn generate() -> Self {
         let signing_key = SigningKey::generate(&mut OsRng);
         let verifying_key: VerifyingKey = signing_key.verifying_key();
 
-        let private_b64 = B64.encode(signing_key.to_bytes());
-        let public_b64 = B64.encode(verifying_key.to_bytes());
+        NodeKeys {
+            public_b64: B64.encode(verifying_key.to_bytes()),
+            private_b64: B64.encode(signing_key.to_bytes()),
+            rotation: None,
+        }
+    }
 
-        let keys = NodeKeys {
-            public_b64,
-            private_b64,
-        };
+    pub fn generate_and_save() -> anyhow::Result<Self> {
+        let keys = Self::generate();
+        keys.save()?;
+        Ok(keys)
+    }
 
+    /// Write the keys to the node_keys file, replacing it atomically.
+    pub fn save(&self) -> anyhow::Result<()> {
         let path = Self::get_node_keys_path();
         fs::create_dir_all(path.parent().unwrap())?;
-        fs::write(&path, serde_json::to_string_pretty(&keys)?)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
 
-        Ok(keys)
+    /// A fresh key pair for `node_id`, endorsed by this one and announced
+    /// alongside it until `overlap_until`.
+    pub fn rotated(&self, node_id: &str, overlap_until: DateTime<Utc>) -> anyhow::Result<Self> {
+        let mut next = Self::generate();
+        let message = rotation_message(node_id, &self.public_b64, &next.public_b64);
+        next.rotation = Some(KeyRotation {
+            previous_public_key_b64: self.public_b64.clone(),
+            signature_b64: self.sign_message(message.as_bytes())?,
+            overlap_until,
+        });
+        Ok(next)
+    }
+
+    /// The rotation to announce in GET /s2s/info, while its overlap lasts.
+    pub fn active_rotation(&self) -> Option<&KeyRotation> {
+        self.rotation
+            .as_ref()
+            .filter(|r| r.overlap_until > Utc::now())
     }
 
     pub fn load_or_generate() -> anyhow::Result<Self> {
@@ -69,7 +151,6 @@ impl NodeKeys {
         Ok(vk)
     }
 
-    #[allow(dead_code)]
     pub fn sign_message(&self, message: &[u8]) -> anyhow::Result<String> {
         let signing_key = self.signing_key()?;
         let signature: Signature = signing_key.sign(message);
@@ -87,3 +168,37 @@ impl NodeKeys {
         Ok(vk.verify(message, &sig).is_ok())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_is_endorsed_by_the_previous_key_only() {
+        let old = NodeKeys::generate();
+        let overlap_until = Utc::now() + chrono::Duration::hours(1);
+        let new = old.rotated("node-a.hushnet.net", overlap_until).unwrap();
+        let rotation = new.active_rotation().unwrap();
+
+        assert_eq!(rotation.previous_public_key_b64, old.public_b64);
+        assert!(rotation.endorses("node-a.hushnet.net", &new.public_b64));
+        assert!(!rotation.endorses("node-b.hushnet.net", &new.public_b64));
+
+        // A key the previous one never endorsed is refused.
+        let other = NodeKeys::generate();
+        assert!(!rotation.endorses("node-a.hushnet.net", &other.public_b64));
+
+        // A rotation "signed" by the new key itself proves nothing.
+        let forged = KeyRotation {
+            previous_public_key_b64: old.public_b64.clone(),
+            signature_b64: other
+                .sign_message(
+                    rotation_message("node-a.hushnet.net", &old.public_b64, &other.public_b64)
+                        .as_bytes(),
+                )
+                .unwrap(),
+            overlap_until,
+        };
+        assert!(!forged.endorses("node-a.hushnet.net", &other.public_b64));
+    }
+}